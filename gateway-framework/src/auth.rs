@@ -8,6 +8,7 @@ use std::{
 
 use anyhow::ensure;
 use ordered_float::NotNan;
+use serde::Deserialize;
 use thegraph_core::types::{alloy_primitives::Address, SubgraphId};
 use tokio::sync::watch;
 
@@ -20,6 +21,53 @@ pub struct AuthSettings {
     pub user: Address,
     pub authorized_subgraphs: Vec<SubgraphId>,
     pub budget_usd: Option<NotNan<f64>>,
+    /// Return gateway metadata in the `extensions` of client responses.
+    pub response_extensions: bool,
+    /// Require agreement between indexers on the responses to block-pinned queries.
+    pub cross_check: Option<CrossCheck>,
+    /// Limits on the complexity of client queries.
+    pub query_limits: Option<QueryLimits>,
+}
+
+/// Cross-checking settings. Attested responses from multiple indexers are compared, and the client
+/// response is only returned once `quorum` indexers agree on it.
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(try_from = "CrossCheckSettings")]
+pub struct CrossCheck {
+    /// Maximum number of successful indexer responses to wait for.
+    pub responses: usize,
+    /// Number of identical indexer responses required.
+    pub quorum: usize,
+}
+
+#[derive(Deserialize)]
+struct CrossCheckSettings {
+    responses: usize,
+    quorum: usize,
+}
+
+impl TryFrom<CrossCheckSettings> for CrossCheck {
+    type Error = anyhow::Error;
+    fn try_from(settings: CrossCheckSettings) -> Result<Self, Self::Error> {
+        let CrossCheckSettings { responses, quorum } = settings;
+        ensure!(quorum > 0, "cross-check quorum must be at least 1");
+        ensure!(
+            quorum <= responses,
+            "cross-check quorum ({quorum}) exceeds responses ({responses})"
+        );
+        Ok(Self { responses, quorum })
+    }
+}
+
+/// Limits on the complexity of client queries, checked before the queries are sent to indexers.
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+pub struct QueryLimits {
+    /// Maximum nesting depth of fields.
+    pub max_depth: Option<usize>,
+    /// Maximum number of fields in a single selection set.
+    pub max_breadth: Option<usize>,
+    /// Maximum estimated number of entities returned.
+    pub max_entities: Option<u64>,
 }
 
 impl AuthSettings {
@@ -6,7 +6,7 @@ use serde_with::serde_as;
 use thegraph_core::types::SubgraphId;
 
 use super::common::is_domain_authorized;
-use crate::auth::{AuthContext, AuthSettings};
+use crate::auth::{AuthContext, AuthSettings, CrossCheck, QueryLimits};
 
 #[serde_as]
 #[derive(Clone, Debug, Default, Deserialize)]
@@ -21,6 +21,12 @@ pub struct APIKey {
     pub subgraphs: Vec<SubgraphId>,
     #[serde(default)]
     pub domains: Vec<String>,
+    #[serde(default)]
+    pub response_extensions: bool,
+    #[serde(default)]
+    pub cross_check: Option<CrossCheck>,
+    #[serde(default)]
+    pub query_limits: Option<QueryLimits>,
 }
 
 #[derive(Clone, Copy, Debug, Default, Deserialize)]
@@ -41,6 +47,9 @@ pub fn check(ctx: &AuthContext, token: &str, domain: &str) -> anyhow::Result<Aut
             user: Address::default(),
             authorized_subgraphs: vec![],
             budget_usd: None,
+            response_extensions: false,
+            cross_check: None,
+            query_limits: None,
         });
     }
 
@@ -71,6 +80,9 @@ pub fn check(ctx: &AuthContext, token: &str, domain: &str) -> anyhow::Result<Aut
         user: api_key.user_address,
         authorized_subgraphs: api_key.subgraphs.clone(),
         budget_usd: api_key.max_budget_usd,
+        response_extensions: api_key.response_extensions,
+        cross_check: api_key.cross_check,
+        query_limits: api_key.query_limits,
     })
 }
 
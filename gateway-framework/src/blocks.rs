@@ -1,26 +1,56 @@
-use std::fmt;
+use std::{cmp::Ordering, fmt};
 
 use alloy_primitives::{BlockHash, BlockNumber};
 use serde::Deserialize;
 
-#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+/// Blocks are identified, and ordered, by `(number, hash)`. The `parent_hash` is excluded, since
+/// the same block may be reported with and without it.
+#[derive(Clone, Debug, Deserialize)]
 pub struct Block {
     pub number: BlockNumber,
     pub hash: BlockHash,
+    /// Unknown for blocks reported by indexers running a graph-node version without the
+    /// `parentHash` field of `_meta.block`.
+    #[serde(rename = "parentHash", default)]
+    pub parent_hash: Option<BlockHash>,
     pub timestamp: u64,
 }
 
+impl PartialEq for Block {
+    fn eq(&self, other: &Self) -> bool {
+        (self.number, self.hash) == (other.number, other.hash)
+    }
+}
+
+impl Eq for Block {}
+
+impl PartialOrd for Block {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Block {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.number, self.hash).cmp(&(other.number, other.hash))
+    }
+}
+
 #[derive(Clone, Debug, Eq, PartialEq, Hash, Ord, PartialOrd)]
 pub enum UnresolvedBlock {
     WithHash(BlockHash),
     WithNumber(BlockNumber),
+    WithTimestamp(u64),
 }
 
 impl UnresolvedBlock {
+    /// Return true if the block may be the resolved block. A block produced at or before the
+    /// timestamp matches `WithTimestamp`, so the resolved block is the latest matching block.
     pub fn matches(&self, block: &Block) -> bool {
         match self {
             Self::WithHash(hash) => hash == &block.hash,
             Self::WithNumber(number) => number == &block.number,
+            Self::WithTimestamp(timestamp) => block.timestamp <= *timestamp,
         }
     }
 }
@@ -30,6 +60,7 @@ impl fmt::Display for UnresolvedBlock {
         match self {
             Self::WithHash(hash) => write!(f, "{hash}"),
             Self::WithNumber(number) => write!(f, "{number}"),
+            Self::WithTimestamp(timestamp) => write!(f, "timestamp {timestamp}"),
         }
     }
 }
@@ -40,6 +71,10 @@ pub enum BlockConstraint {
     Hash(BlockHash),
     Number(BlockNumber),
     NumberGTE(BlockNumber),
+    /// The latest block produced at or before the given unix timestamp (seconds).
+    Timestamp(u64),
+    /// Any block produced at or after the given unix timestamp (seconds).
+    TimestampGTE(u64),
 }
 
 impl BlockConstraint {
@@ -48,6 +83,7 @@ impl BlockConstraint {
             Self::Unconstrained => None,
             Self::Hash(h) => Some(UnresolvedBlock::WithHash(h)),
             Self::Number(n) | Self::NumberGTE(n) => Some(UnresolvedBlock::WithNumber(n)),
+            Self::Timestamp(t) | Self::TimestampGTE(t) => Some(UnresolvedBlock::WithTimestamp(t)),
         }
     }
 }
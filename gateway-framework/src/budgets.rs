@@ -1,6 +1,10 @@
-use std::time::Duration;
+use std::{collections::HashSet, time::Duration};
 
+use alloy_primitives::Address;
+use anyhow::ensure;
 use ordered_float::NotNan;
+use serde::{Deserialize, Serialize};
+use thegraph_core::types::SubgraphId;
 use tokio::{
     select, spawn,
     sync::{mpsc, watch},
@@ -12,41 +16,187 @@ use crate::metrics::METRICS;
 #[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
 pub struct USD(pub NotNan<f64>);
 
-pub struct Budgeter {
+/// Name of the budget of queries not matching any budget policy.
+pub const DEFAULT_POLICY: &str = "default";
+
+/// Budget policy, applying its own query fees target to the queries matching its key.
+#[derive(Clone, Debug, Deserialize)]
+pub struct BudgetPolicy {
+    /// Name of the policy, used to label its metrics
+    pub name: String,
+    #[serde(flatten)]
+    pub key: BudgetKey,
+    /// Target for indexer fees paid per request, in USD
+    pub query_fees_target: NotNan<f64>,
+}
+
+/// Key matched against queries by a [`BudgetPolicy`]. When policies with different kinds of keys
+/// match a query, the most specific kind takes precedence, in the order of the variants below.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BudgetKey {
+    ApiKey(String),
+    User(Address),
+    Subgraph(SubgraphId),
+    Chain(String),
+}
+
+impl BudgetKey {
+    fn precedence(&self) -> u8 {
+        match self {
+            Self::ApiKey(_) => 0,
+            Self::User(_) => 1,
+            Self::Subgraph(_) => 2,
+            Self::Chain(_) => 3,
+        }
+    }
+}
+
+/// Budget of the queries matching a policy, with its own controller of the minimum indexer fees.
+pub struct Budget {
+    pub name: String,
     pub feedback: mpsc::UnboundedSender<USD>,
     pub query_fees_target: USD,
     pub min_indexer_fees: watch::Receiver<USD>,
+    /// Live state of the controller of the minimum indexer fees.
+    pub controller: watch::Receiver<ControllerState>,
 }
 
-impl Budgeter {
-    pub fn new(query_fees_target: USD) -> Self {
+impl Budget {
+    fn new(name: String, query_fees_target: USD, config: ControllerConfig) -> Self {
         let (feedback_tx, feedback_rx) = mpsc::unbounded_channel();
         let (min_indexer_fees_tx, min_indexer_fees_rx) = watch::channel(query_fees_target);
-        Actor::create(feedback_rx, min_indexer_fees_tx, query_fees_target);
+        let controller = Controller::with_config(query_fees_target, config);
+        let (state_tx, state_rx) = watch::channel(controller.state().clone());
+        Actor::create(
+            name.clone(),
+            feedback_rx,
+            min_indexer_fees_tx,
+            state_tx,
+            controller,
+        );
         Self {
+            name,
             feedback: feedback_tx,
             query_fees_target,
             min_indexer_fees: min_indexer_fees_rx,
+            controller: state_rx,
         }
     }
 }
 
+pub struct Budgeter {
+    pub controller_config: ControllerConfig,
+    /// Budget of the queries not matching any policy.
+    pub default: Budget,
+    /// Policies, in order of precedence.
+    policies: Vec<(BudgetKey, Budget)>,
+}
+
+impl Budgeter {
+    pub fn new(query_fees_target: USD) -> anyhow::Result<Self> {
+        Self::with_policies(query_fees_target, vec![], ControllerConfig::default())
+    }
+
+    /// Policy names must be unique, since they label the metrics of their budgets, and query fees
+    /// targets must be positive.
+    pub fn with_policies(
+        query_fees_target: USD,
+        policies: Vec<BudgetPolicy>,
+        controller_config: ControllerConfig,
+    ) -> anyhow::Result<Self> {
+        ensure!(
+            *query_fees_target.0 > 0.0,
+            "query fees target ({}) must be positive",
+            query_fees_target.0
+        );
+        let mut names: HashSet<&str> = HashSet::from([DEFAULT_POLICY]);
+        for policy in &policies {
+            ensure!(
+                names.insert(&policy.name),
+                "duplicate budget policy name: {}",
+                policy.name
+            );
+            ensure!(
+                *policy.query_fees_target > 0.0,
+                "query fees target ({}) of budget policy {} must be positive",
+                policy.query_fees_target,
+                policy.name
+            );
+        }
+
+        let mut policies: Vec<(BudgetKey, Budget)> = policies
+            .into_iter()
+            .map(|policy| {
+                let budget = Budget::new(
+                    policy.name,
+                    USD(policy.query_fees_target),
+                    controller_config.clone(),
+                );
+                (policy.key, budget)
+            })
+            .collect();
+        policies.sort_by_key(|(key, _)| key.precedence());
+        let default = Budget::new(
+            DEFAULT_POLICY.to_string(),
+            query_fees_target,
+            controller_config.clone(),
+        );
+        Ok(Self {
+            controller_config,
+            default,
+            policies,
+        })
+    }
+
+    /// The budget of a query, from the most specific policy matching it.
+    pub fn budget(
+        &self,
+        api_key: &str,
+        user: &Address,
+        subgraphs: &[SubgraphId],
+        chain: &str,
+    ) -> &Budget {
+        self.policies
+            .iter()
+            .find(|(key, _)| match key {
+                BudgetKey::ApiKey(key) => key == api_key,
+                BudgetKey::User(address) => address == user,
+                BudgetKey::Subgraph(subgraph) => subgraphs.contains(subgraph),
+                BudgetKey::Chain(name) => name == chain,
+            })
+            .map(|(_, budget)| budget)
+            .unwrap_or(&self.default)
+    }
+
+    /// All budgets, starting with the default.
+    pub fn budgets(&self) -> impl Iterator<Item = &Budget> {
+        std::iter::once(&self.default).chain(self.policies.iter().map(|(_, budget)| budget))
+    }
+}
+
 struct Actor {
+    policy: String,
     feedback: mpsc::UnboundedReceiver<USD>,
     min_indexer_fees: watch::Sender<USD>,
+    state: watch::Sender<ControllerState>,
     controller: Controller,
 }
 
 impl Actor {
     fn create(
+        policy: String,
         feedback: mpsc::UnboundedReceiver<USD>,
         min_indexer_fees: watch::Sender<USD>,
-        query_fees_target: USD,
+        state: watch::Sender<ControllerState>,
+        controller: Controller,
     ) {
         let mut actor = Actor {
+            policy,
             feedback,
             min_indexer_fees,
-            controller: Controller::new(query_fees_target),
+            state,
+            controller,
         };
         let mut budget_timer = interval(Duration::from_secs(1));
         budget_timer.set_missed_tick_behavior(MissedTickBehavior::Skip);
@@ -69,42 +219,153 @@ impl Actor {
             return;
         }
         let min_indexer_fees = self.controller.control_variable();
-        tracing::debug!(?min_indexer_fees);
+        tracing::debug!(policy = self.policy, ?min_indexer_fees);
+        self.export_state();
         if let Err(min_indexer_fees_send_err) = self.min_indexer_fees.send(min_indexer_fees) {
             tracing::error!(%min_indexer_fees_send_err);
         };
     }
+
+    fn export_state(&self) {
+        let state = self.controller.state();
+        let policy = self.policy.as_str();
+        if policy == DEFAULT_POLICY {
+            METRICS.avg_query_fees.set(state.avg_query_fees);
+        }
+        METRICS
+            .budget_avg_query_fees
+            .with_label_values(&[policy])
+            .set(state.avg_query_fees);
+        METRICS
+            .budget_min_indexer_fees
+            .with_label_values(&[policy])
+            .set(state.min_indexer_fees);
+        METRICS
+            .budget_integral
+            .with_label_values(&[policy])
+            .set(state.integral);
+        for (frame, error) in state.error_history.iter().enumerate() {
+            METRICS
+                .budget_error_history
+                .with_label_values(&[policy, &frame.to_string()])
+                .set(*error);
+        }
+        self.state.send_replace(state.clone());
+    }
+}
+
+/// Settings of the [`Controller`].
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ControllerConfig {
+    /// Integral gain.
+    pub k_i: f64,
+    /// Lower bound of the minimum indexer fees, in USD.
+    pub min_usd: f64,
+    /// Upper bound of the minimum indexer fees, as a multiple of the query fees target.
+    pub max_target_ratio: f64,
+    /// Number of frames of the error history, each covering 4 times as long as the previous one.
+    pub history_frames: usize,
+    /// Decay of the error history per second, in thousandths.
+    pub history_decay: u16,
+}
+
+impl Default for ControllerConfig {
+    fn default() -> Self {
+        Self {
+            k_i: 0.2,
+            min_usd: 10e-6,
+            max_target_ratio: 1.0,
+            history_frames: 6,
+            history_decay: 4,
+        }
+    }
+}
+
+impl ControllerConfig {
+    /// Reject settings the [`Controller`] can't operate with.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        ensure!(
+            self.k_i.is_finite() && (self.k_i > 0.0),
+            "k_i ({}) must be positive",
+            self.k_i
+        );
+        ensure!(
+            self.min_usd.is_finite() && (self.min_usd >= 0.0),
+            "min_usd ({}) must not be negative",
+            self.min_usd
+        );
+        ensure!(
+            self.max_target_ratio.is_finite() && (self.max_target_ratio > 0.0),
+            "max_target_ratio ({}) must be positive",
+            self.max_target_ratio
+        );
+        ensure!(self.history_frames > 0, "history_frames must be at least 1");
+        ensure!(
+            self.history_decay < 1000,
+            "history_decay ({}) must be less than 1000",
+            self.history_decay
+        );
+        Ok(())
+    }
+}
+
+/// Live state of a [`Controller`], as of the last revision of the minimum indexer fees.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct ControllerState {
+    /// Average fees of the queries since the previous revision, in USD.
+    pub avg_query_fees: f64,
+    /// Relative error of the average query fees to the target, per frame of the error history.
+    pub error_history: Vec<f64>,
+    /// Sum of the error history.
+    pub integral: f64,
+    /// Minimum indexer fees, in USD.
+    pub min_indexer_fees: f64,
 }
 
-struct Controller {
+/// Controller of the minimum indexer fees, driving the average query fees to the target.
+pub struct Controller {
+    config: ControllerConfig,
     query_fees_target: USD,
     recent_fees: USD,
     recent_count: u64,
-    error_history: DecayBuffer<f64, 6, 4>,
+    error_history: DecayBuffer,
+    state: ControllerState,
 }
 
 impl Controller {
-    fn new(query_fees_target: USD) -> Self {
+    pub fn new(query_fees_target: USD) -> Self {
+        Self::with_config(query_fees_target, ControllerConfig::default())
+    }
+
+    pub fn with_config(query_fees_target: USD, config: ControllerConfig) -> Self {
+        let error_history = DecayBuffer::new(config.history_frames, config.history_decay);
         Self {
+            config,
             query_fees_target,
             recent_fees: USD(NotNan::default()),
             recent_count: 0,
-            error_history: DecayBuffer::default(),
+            error_history,
+            state: ControllerState {
+                min_indexer_fees: f64::from(query_fees_target.0),
+                ..Default::default()
+            },
         }
     }
 
-    fn add_recent_fees(&mut self, fees: USD) {
+    pub fn add_recent_fees(&mut self, fees: USD) {
         self.recent_fees = USD(self.recent_fees.0 + fees.0);
         self.recent_count += 1;
     }
 
-    fn control_variable(&mut self) -> USD {
+    /// The minimum indexer fees given the fees added since the last call, expected to be called
+    /// once per second.
+    pub fn control_variable(&mut self) -> USD {
         // See the following link if you're unfamiliar with PID controllers:
         // https://en.wikipedia.org/wiki/Proportional%E2%80%93integral%E2%80%93derivative_controller
         let target = f64::from(self.query_fees_target.0);
         let process_variable = f64::from(self.recent_fees.0) / self.recent_count.max(1) as f64;
         tracing::debug!(avg_fees = process_variable);
-        METRICS.avg_query_fees.set(process_variable);
 
         self.recent_fees = USD(NotNan::default());
         self.recent_count = 0;
@@ -113,72 +374,183 @@ impl Controller {
         *self.error_history.current_mut() += error;
 
         let i: f64 = self.error_history.frames().iter().sum();
-        let k_i = 0.2;
-        let control_variable = (i * k_i) * target;
+        let control_variable = (i * self.config.k_i) * target;
 
-        let min = NotNan::new(10e-6).unwrap_or_default();
-        let max = self.query_fees_target.0;
-        USD(NotNan::new(control_variable)
+        let min = NotNan::new(self.config.min_usd).unwrap_or_default();
+        let max = NotNan::new(target * self.config.max_target_ratio).unwrap_or_default();
+        let min_indexer_fees = NotNan::new(control_variable)
             .unwrap_or_default()
-            .clamp(min, max))
-    }
-}
+            .clamp(min, max.max(min));
 
-// TODO: the following is overkill now that it's only used here.
-
-#[derive(Clone, Debug)]
-struct DecayBuffer<T, const F: usize, const D: u16> {
-    frames: [T; F],
-}
+        self.state = ControllerState {
+            avg_query_fees: process_variable,
+            error_history: self.error_history.frames().to_vec(),
+            integral: i,
+            min_indexer_fees: f64::from(min_indexer_fees),
+        };
+        USD(min_indexer_fees)
+    }
 
-trait Decay {
-    fn decay(&mut self, prev: &Self, retain: f64, take: f64);
+    pub fn state(&self) -> &ControllerState {
+        &self.state
+    }
 }
 
-impl Decay for f64 {
-    fn decay(&mut self, prev: &Self, retain: f64, take: f64) {
-        *self = (*self * retain) + (prev * take);
-    }
+/// Buffer of values decaying into frames covering exponentially longer periods of time.
+#[derive(Clone, Debug)]
+struct DecayBuffer {
+    frames: Vec<f64>,
+    /// Decay per call to [`DecayBuffer::decay`], in thousandths.
+    decay: u16,
 }
 
-impl<T, const F: usize, const D: u16> Default for DecayBuffer<T, F, D>
-where
-    [T; F]: Default,
-{
-    fn default() -> Self {
-        debug_assert!(F > 0);
-        debug_assert!(D < 1000);
+impl DecayBuffer {
+    fn new(frames: usize, decay: u16) -> Self {
+        debug_assert!(frames > 0);
+        debug_assert!(decay < 1000);
         Self {
-            frames: Default::default(),
+            frames: vec![0.0; frames.max(1)],
+            decay,
         }
     }
-}
 
-impl<T, const F: usize, const D: u16> DecayBuffer<T, F, D> {
-    fn current_mut(&mut self) -> &mut T {
+    fn current_mut(&mut self) -> &mut f64 {
         &mut self.frames[0]
     }
 
-    fn frames(&self) -> &[T] {
+    fn frames(&self) -> &[f64] {
         &self.frames
     }
-}
 
-impl<T, const F: usize, const D: u16> DecayBuffer<T, F, D>
-where
-    T: Decay + Default,
-{
     fn decay(&mut self) {
         // BQN: (1-1e¯3×d)×((1-4⋆-↕f)×⊢)+(«4⋆-↕f)×⊢
-        // LLVM should be capable of constant folding & unrolling this loop nicely.
-        // https://rust.godbolt.org/z/K13dj78Ge
+        let decay = 1.0 - 1e-3 * self.decay as f64;
         for i in (1..self.frames.len()).rev() {
             let retain = 1.0 - 4_f64.powi(-(i as i32));
             let take = 4_f64.powi(-(i as i32 - 1));
-            let decay = 1.0 - 1e-3 * D as f64;
-            let (cur, prev) = self.frames[..=i].split_last_mut().unwrap();
-            cur.decay(prev.last().unwrap(), retain * decay, take * decay);
+            self.frames[i] =
+                (self.frames[i] * retain * decay) + (self.frames[i - 1] * take * decay);
         }
-        self.frames[0] = T::default();
+        self.frames[0] = 0.0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn most_specific_policy() {
+        //* Given
+        let subgraph: SubgraphId = "DZz4kDTdmzWLWsV373w2bSmoar3umKKH9y82SUKr5qmp"
+            .parse()
+            .unwrap();
+        let user = Address::with_last_byte(1);
+        let policy = |name: &str, key: BudgetKey| BudgetPolicy {
+            name: name.to_string(),
+            key,
+            query_fees_target: NotNan::new(1e-4).unwrap(),
+        };
+        let budgeter = Budgeter::with_policies(
+            USD(NotNan::new(2e-5).unwrap()),
+            vec![
+                policy("mainnet", BudgetKey::Chain("mainnet".to_string())),
+                policy("subgraph", BudgetKey::Subgraph(subgraph)),
+                policy("user", BudgetKey::User(user)),
+            ],
+            ControllerConfig::default(),
+        )
+        .unwrap();
+
+        //* When
+        let budget = |user: &Address, subgraphs: &[SubgraphId], chain: &str| {
+            budgeter.budget("key", user, subgraphs, chain).name.clone()
+        };
+
+        //* Then
+        assert_eq!(budget(&user, &[subgraph], "mainnet"), "user");
+        assert_eq!(budget(&Address::ZERO, &[subgraph], "mainnet"), "subgraph");
+        assert_eq!(budget(&Address::ZERO, &[], "mainnet"), "mainnet");
+        assert_eq!(budget(&Address::ZERO, &[], "arbitrum-one"), DEFAULT_POLICY);
+    }
+
+    #[tokio::test]
+    async fn invalid_policies() {
+        //* Given
+        let target = USD(NotNan::new(2e-5).unwrap());
+        let policy = |name: &str, query_fees_target: f64| BudgetPolicy {
+            name: name.to_string(),
+            key: BudgetKey::Chain(name.to_string()),
+            query_fees_target: NotNan::new(query_fees_target).unwrap(),
+        };
+        let budgeter = |policies: Vec<BudgetPolicy>| {
+            Budgeter::with_policies(target, policies, ControllerConfig::default())
+        };
+
+        //* Then
+        assert!(budgeter(vec![policy("a", 1e-4), policy("b", 1e-4)]).is_ok());
+        assert!(budgeter(vec![policy("a", 1e-4), policy("a", 2e-4)]).is_err());
+        assert!(budgeter(vec![policy(DEFAULT_POLICY, 1e-4)]).is_err());
+        assert!(budgeter(vec![policy("a", 0.0)]).is_err());
+        assert!(Budgeter::new(USD(NotNan::new(-1.0).unwrap())).is_err());
+    }
+
+    #[test]
+    fn controller_bounds() {
+        //* Given
+        let target = USD(NotNan::new(20e-6).unwrap());
+        let mut controller = Controller::with_config(
+            target,
+            ControllerConfig {
+                k_i: 1.0,
+                min_usd: 5e-6,
+                max_target_ratio: 2.0,
+                history_frames: 3,
+                history_decay: 0,
+            },
+        );
+
+        //* When
+        // Query fees far below the target drive the minimum indexer fees up to the upper bound.
+        let mut raised = USD::default();
+        for _ in 0..10 {
+            controller.add_recent_fees(USD::default());
+            raised = controller.control_variable();
+        }
+        let state = controller.state().clone();
+        // Query fees far above the target drive them down to the lower bound.
+        let mut lowered = USD::default();
+        for _ in 0..100 {
+            controller.add_recent_fees(USD(NotNan::new(1e-3).unwrap()));
+            lowered = controller.control_variable();
+        }
+
+        //* Then
+        assert_eq!(*raised.0, 40e-6);
+        assert_eq!(state.error_history.len(), 3);
+        assert_eq!(state.min_indexer_fees, 40e-6);
+        assert!(state.integral > 2.0);
+        assert_eq!(*lowered.0, 5e-6);
+    }
+
+    #[test]
+    fn controller_config_validation() {
+        //* Given
+        let config = |update: fn(&mut ControllerConfig)| {
+            let mut config = ControllerConfig::default();
+            update(&mut config);
+            config
+        };
+
+        //* Then
+        assert!(ControllerConfig::default().validate().is_ok());
+        assert!(config(|c| c.k_i = 0.0).validate().is_err());
+        assert!(config(|c| c.k_i = f64::NAN).validate().is_err());
+        assert!(config(|c| c.min_usd = -1.0).validate().is_err());
+        assert!(config(|c| c.max_target_ratio = f64::INFINITY)
+            .validate()
+            .is_err());
+        assert!(config(|c| c.history_frames = 0).validate().is_err());
+        assert!(config(|c| c.history_decay = 1000).validate().is_err());
     }
 }
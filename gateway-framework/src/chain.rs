@@ -3,15 +3,55 @@ use std::{
     iter,
 };
 
-use alloy_primitives::Address;
+use alloy_primitives::{Address, BlockHash, BlockNumber};
+use itertools::Itertools as _;
 
 use crate::blocks::{Block, UnresolvedBlock};
 
 #[derive(Default)]
-pub struct Chain(BTreeMap<Block, BTreeSet<Address>>);
+pub struct Chain {
+    blocks: BTreeMap<Block, BTreeSet<BlockSource>>,
+    /// Blocks invalidated by `resolve_forks`, which are no longer accepted from any source. Limited
+    /// to `MAX_LEN` entries, dropping the lowest blocks first.
+    orphaned: BTreeSet<(BlockNumber, BlockHash)>,
+}
 
 const MAX_LEN: usize = 512;
 const DEFAULT_BLOCKS_PER_MINUTE: u64 = 6;
+/// Consensus weight of a block reported by a chain RPC provider, relative to the weight of a block
+/// reported by a single indexer.
+const RPC_WEIGHT: usize = 3;
+
+/// The origin of a block report.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum BlockSource {
+    /// An indexer, reporting blocks via the `_gateway_probe_` field of query responses.
+    Indexer(Address),
+    /// A chain JSON-RPC provider, identified by its position in the chain's RPC config.
+    Rpc(usize),
+}
+
+impl BlockSource {
+    fn weight(&self) -> usize {
+        match self {
+            Self::Indexer(_) => 1,
+            Self::Rpc(_) => RPC_WEIGHT,
+        }
+    }
+}
+
+fn weight(sources: &BTreeSet<BlockSource>) -> usize {
+    sources.iter().map(BlockSource::weight).sum()
+}
+
+/// A fork resolved in favor of one of the competing blocks.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Reorg {
+    /// The block number where the chain forked.
+    pub number: BlockNumber,
+    /// The number of blocks invalidated, including the descendants of the orphaned blocks.
+    pub orphaned: usize,
+}
 
 impl Chain {
     pub fn latest(&self) -> Option<&Block> {
@@ -19,9 +59,31 @@ impl Chain {
     }
 
     pub fn find(&self, unresolved: &UnresolvedBlock) -> Option<&Block> {
+        if let UnresolvedBlock::WithTimestamp(timestamp) = unresolved {
+            // The latest block produced at or before the timestamp is only known if its successor
+            // is known, or if it is the chain head.
+            return match self.timestamp_bounds(*timestamp) {
+                (Some(before), Some(after)) if after.number == (before.number + 1) => Some(before),
+                (Some(before), None) => Some(before),
+                _ => None,
+            };
+        }
         self.consensus_blocks().find(|b| unresolved.matches(b))
     }
 
+    /// Return the consensus blocks surrounding the given timestamp: the latest block produced at
+    /// or before the timestamp, and the earliest block produced after it.
+    pub fn timestamp_bounds(&self, timestamp: u64) -> (Option<&Block>, Option<&Block>) {
+        let mut after = None;
+        for block in self.consensus_blocks() {
+            if block.timestamp <= timestamp {
+                return (Some(block), after);
+            }
+            after = Some(block);
+        }
+        (None, after)
+    }
+
     /// Return the average block production rate, based on the consensus blocks. The result will
     /// be greater than 0.
     pub fn blocks_per_minute(&self) -> u64 {
@@ -38,32 +100,138 @@ impl Chain {
         (bps * 60.0) as u64
     }
 
-    pub fn should_insert(&self, block: &Block, indexer: &Address) -> bool {
+    pub fn should_insert(&self, block: &Block, source: &BlockSource) -> bool {
+        if self.is_orphaned(block) {
+            return false;
+        }
+        // A report adding the parent hash of a known block is not redundant.
         let redundant = self
-            .0
-            .get(block)
-            .map(|indexers| indexers.contains(indexer))
+            .blocks
+            .get_key_value(block)
+            .map(|(known, sources)| {
+                sources.contains(source)
+                    && (known.parent_hash.is_some() || block.parent_hash.is_none())
+            })
             .unwrap_or(false);
-        let lowest_block = self.0.first_key_value().map(|(b, _)| b.number).unwrap_or(0);
-        let has_space = (self.0.len() < MAX_LEN) || (block.number > lowest_block);
+        let lowest_block = self
+            .blocks
+            .first_key_value()
+            .map(|(b, _)| b.number)
+            .unwrap_or(0);
+        let has_space = (self.blocks.len() < MAX_LEN) || (block.number > lowest_block);
         !redundant && has_space
     }
 
-    pub fn insert(&mut self, block: Block, indexer: Address) {
-        tracing::trace!(%indexer, ?block);
-        debug_assert!(self.should_insert(&block, &indexer));
-        if self.0.len() >= MAX_LEN {
-            self.evict();
+    /// Insert the block report. Reports of a known block are merged into its entry, keeping the
+    /// parent hash if any report includes it.
+    pub fn insert(&mut self, mut block: Block, source: BlockSource) {
+        tracing::trace!(?source, ?block);
+        debug_assert!(self.should_insert(&block, &source));
+        let mut sources = match self.blocks.remove_entry(&block) {
+            Some((known, sources)) => {
+                block.parent_hash = block.parent_hash.or(known.parent_hash);
+                sources
+            }
+            None => {
+                if self.blocks.len() >= MAX_LEN {
+                    self.evict();
+                }
+                BTreeSet::new()
+            }
+        };
+        sources.insert(source);
+        self.blocks.insert(block, sources);
+    }
+
+    /// Resolve competing blocks at the same number, using the parent hashes of their descendants.
+    /// At each fork, the block with the heaviest subtree (the block and its known descendants) wins
+    /// and the other blocks are invalidated along with their descendants. Ties on subtree weight
+    /// are broken by the weight of the blocks themselves. Unresolved forks are skipped by
+    /// `consensus_blocks`.
+    pub fn resolve_forks(&mut self) -> Vec<Reorg> {
+        let fork_numbers: Vec<BlockNumber> = self
+            .blocks
+            .keys()
+            .tuple_windows()
+            .filter(|(a, b)| a.number == b.number)
+            .map(|(a, _)| a.number)
+            .dedup()
+            .collect();
+        let mut reorgs = Vec::new();
+        for number in fork_numbers {
+            let forks: Vec<(Block, (usize, usize))> = self
+                .blocks
+                .iter()
+                .filter(|(block, _)| block.number == number)
+                .map(|(block, sources)| {
+                    let subtree_weight = self.subtree(block).map(|(_, s)| weight(s)).sum();
+                    (block.clone(), (subtree_weight, weight(sources)))
+                })
+                .collect();
+            if forks.len() < 2 {
+                continue;
+            }
+            let max_weight = forks.iter().map(|(_, w)| *w).max().unwrap();
+            if forks.iter().filter(|(_, w)| *w == max_weight).count() > 1 {
+                continue;
+            }
+            let orphaned_blocks: Vec<Block> = forks
+                .iter()
+                .filter(|(_, w)| *w != max_weight)
+                .flat_map(|(fork, _)| self.subtree(fork).map(|(b, _)| b.clone()))
+                .collect();
+            for block in &orphaned_blocks {
+                self.blocks.remove(block);
+                self.orphaned.insert((block.number, block.hash));
+            }
+            while self.orphaned.len() > MAX_LEN {
+                self.orphaned.pop_first();
+            }
+            reorgs.push(Reorg {
+                number,
+                orphaned: orphaned_blocks.len(),
+            });
         }
-        self.0.entry(block).or_default().insert(indexer);
+        reorgs
+    }
+
+    /// Return true if the block, or its parent, was invalidated by `resolve_forks`. Reports of
+    /// orphaned blocks may keep arriving from sources that have yet to see the reorg.
+    fn is_orphaned(&self, block: &Block) -> bool {
+        self.orphaned.contains(&(block.number, block.hash))
+            || block.parent_hash.is_some_and(|parent_hash| {
+                block.number > 0 && self.orphaned.contains(&(block.number - 1, parent_hash))
+            })
+    }
+
+    /// Return the given block and its known descendants.
+    fn subtree<'c>(
+        &'c self,
+        root: &'c Block,
+    ) -> impl Iterator<Item = (&'c Block, &'c BTreeSet<BlockSource>)> {
+        let mut hashes = BTreeSet::from([root.hash]);
+        self.blocks.range(root..).filter(move |(block, _)| {
+            if block.hash == root.hash {
+                return true;
+            }
+            if (block.number > root.number)
+                && block
+                    .parent_hash
+                    .is_some_and(|parent_hash| hashes.contains(&parent_hash))
+            {
+                hashes.insert(block.hash);
+                return true;
+            }
+            false
+        })
     }
 
     fn evict(&mut self) {
-        let min_block = match self.0.pop_first() {
+        let min_block = match self.blocks.pop_first() {
             Some((min_block, _)) => min_block,
             None => return,
         };
-        while let Some(entry) = self.0.first_entry() {
+        while let Some(entry) = self.blocks.first_entry() {
             debug_assert!(entry.key().number >= min_block.number);
             if entry.key().number > min_block.number {
                 break;
@@ -72,14 +240,15 @@ impl Chain {
         }
     }
 
-    /// Return blocks with simple majority consensus, starting from the latest block.
+    /// Return blocks with simple majority consensus, starting from the latest block. Reports from
+    /// RPC providers are weighted by `RPC_WEIGHT` against reports from indexers.
     pub fn consensus_blocks(&self) -> impl Iterator<Item = &Block> {
         struct ConsensusBlocks<Iter> {
             blocks: Iter,
         }
         impl<'c, Iter> Iterator for ConsensusBlocks<iter::Peekable<Iter>>
         where
-            Iter: Iterator<Item = (&'c Block, &'c BTreeSet<Address>)> + Clone,
+            Iter: Iterator<Item = (&'c Block, &'c BTreeSet<BlockSource>)> + Clone,
         {
             type Item = &'c Block;
             fn next(&mut self) -> Option<Self::Item> {
@@ -87,8 +256,8 @@ impl Chain {
                     let number = self.blocks.peek()?.0.number;
                     let forks = self.blocks.clone().take_while(|(b, _)| b.number == number);
                     let forks_len = forks.clone().count();
-                    let max_indexers = forks.clone().map(|(_, i)| i.len()).max().unwrap();
-                    let mut candidates = forks.clone().filter(|(_, i)| i.len() == max_indexers);
+                    let max_weight = forks.clone().map(|(_, s)| weight(s)).max().unwrap();
+                    let mut candidates = forks.clone().filter(|(_, s)| weight(s) == max_weight);
                     for _ in 0..forks_len {
                         self.blocks.next();
                     }
@@ -99,7 +268,7 @@ impl Chain {
             }
         }
         ConsensusBlocks {
-            blocks: self.0.iter().rev().peekable(),
+            blocks: self.blocks.iter().rev().peekable(),
         }
     }
 }
@@ -113,13 +282,14 @@ mod tests {
     };
     use toolshed::concat_bytes;
 
-    use super::{Block, Chain, MAX_LEN};
+    use super::{weight, Block, BlockSource, Chain, Reorg, UnresolvedBlock, MAX_LEN};
 
     #[test]
     fn chain() {
         let mut chain: Chain = Default::default();
-        let indexers: Vec<Address> = (1..=3)
-            .map(|n| Address::from(concat_bytes!(20, [&[0; 19], &[n]])))
+        let sources: Vec<BlockSource> = (1..=3)
+            .map(|n| BlockSource::Indexer(Address::from(concat_bytes!(20, [&[0; 19], &[n]]))))
+            .chain([BlockSource::Rpc(0)])
             .collect();
         let seed = thread_rng().next_u64();
         println!("seed: {seed}");
@@ -132,37 +302,202 @@ mod tests {
             let block = Block {
                 number: block_number,
                 hash: BlockHash::from(U256::from(timestamp)),
+                parent_hash: Some(BlockHash::from(U256::from(timestamp.saturating_sub(1)))),
                 timestamp,
             };
-            let indexer = *indexers.choose(&mut rng).unwrap();
-            if chain.should_insert(&block, &indexer) {
-                chain.insert(block, indexer);
+            let source = *sources.choose(&mut rng).unwrap();
+            if chain.should_insert(&block, &source) {
+                chain.insert(block, source);
+                chain.resolve_forks();
             }
         }
 
-        // println!("{:#?}", chain.0);
+        // println!("{:#?}", chain.blocks);
         // println!("{:#?}", chain.consensus_blocks().collect::<Vec<_>>());
 
-        assert!(chain.0.len() <= MAX_LEN, "chain len above max");
-        assert!(chain.consensus_blocks().count() <= chain.0.len());
+        assert!(chain.blocks.len() <= MAX_LEN, "chain len above max");
+        assert!(chain.consensus_blocks().count() <= chain.blocks.len());
         assert!(chain.blocks_per_minute() > 0);
-        let blocks = || chain.0.keys();
+        let blocks = || chain.blocks.keys();
         assert!(
             blocks().tuple_windows().all(|(a, b)| a.number <= b.number),
             "chain block numbers not monotonic, check ord impl"
         );
         for block in chain.consensus_blocks() {
-            let max_fork_indexers = chain
-                .0
+            let max_fork_weight = chain
+                .blocks
                 .iter()
                 .filter(|(block, _)| (block != block) && (block.number == block.number))
-                .map(|(_, indexers)| indexers.len())
+                .map(|(_, sources)| weight(sources))
                 .max()
                 .unwrap_or(0);
             assert!(
-                chain.0.get(block).unwrap().len() > max_fork_indexers,
+                weight(chain.blocks.get(block).unwrap()) > max_fork_weight,
                 "consensus block without majority consensus"
             );
         }
     }
+
+    #[test]
+    fn rpc_outweighs_single_indexer() {
+        let mut chain: Chain = Default::default();
+        let block = |hash: u64| Block {
+            number: 1,
+            hash: BlockHash::from(U256::from(hash)),
+            parent_hash: None,
+            timestamp: 1,
+        };
+        let indexer =
+            |n: u8| BlockSource::Indexer(Address::from(concat_bytes!(20, [&[0; 19], &[n]])));
+
+        chain.insert(block(1), indexer(1));
+        chain.insert(block(2), BlockSource::Rpc(0));
+        assert_eq!(chain.latest(), Some(&block(2)));
+
+        // A majority of indexers can still outvote the RPC provider.
+        for n in 2..=4 {
+            chain.insert(block(1), indexer(n));
+        }
+        assert_eq!(chain.latest(), Some(&block(1)));
+    }
+
+    #[test]
+    fn resolve_forks() {
+        let mut chain: Chain = Default::default();
+        let hash = |n: u64| BlockHash::from(U256::from(n));
+        let block = |number: u64, hash: BlockHash, parent_hash: BlockHash| Block {
+            number,
+            hash,
+            parent_hash: Some(parent_hash),
+            timestamp: number,
+        };
+        let indexer =
+            |n: u8| BlockSource::Indexer(Address::from(concat_bytes!(20, [&[0; 19], &[n]])));
+
+        // Competing blocks at 2, without any descendants to break the tie.
+        chain.insert(block(1, hash(1), hash(0)), indexer(1));
+        chain.insert(block(2, hash(2), hash(1)), indexer(1));
+        chain.insert(block(2, hash(20), hash(1)), indexer(2));
+        assert_eq!(chain.resolve_forks(), vec![]);
+        assert_eq!(chain.find(&UnresolvedBlock::WithNumber(2)), None);
+
+        // Descendants of both forks are reported, and the RPC provider's fork outweighs the other.
+        chain.insert(block(3, hash(30), hash(20)), indexer(2));
+        chain.insert(block(2, hash(2), hash(1)), BlockSource::Rpc(0));
+        chain.insert(block(3, hash(3), hash(2)), BlockSource::Rpc(0));
+        assert_eq!(
+            chain.resolve_forks(),
+            vec![Reorg {
+                number: 2,
+                orphaned: 2
+            }]
+        );
+        assert!(chain
+            .blocks
+            .keys()
+            .all(|b| ![hash(20), hash(30)].contains(&b.hash)));
+        assert_eq!(chain.latest().map(|b| b.hash), Some(hash(3)));
+        assert_eq!(
+            chain.find(&UnresolvedBlock::WithNumber(2)).map(|b| b.hash),
+            Some(hash(2))
+        );
+
+        // Late reports of the orphaned blocks, and of their descendants, are rejected.
+        assert!(!chain.should_insert(&block(2, hash(20), hash(1)), &indexer(3)));
+        assert!(!chain.should_insert(&block(3, hash(30), hash(20)), &indexer(3)));
+        assert!(!chain.should_insert(&block(4, hash(40), hash(30)), &indexer(3)));
+        assert!(chain.should_insert(&block(4, hash(4), hash(3)), &indexer(3)));
+        assert_eq!(chain.resolve_forks(), vec![]);
+    }
+
+    #[test]
+    fn merge_reports_with_and_without_parent_hash() {
+        //* Given
+        let mut chain: Chain = Default::default();
+        let hash = |n: u64| BlockHash::from(U256::from(n));
+        let block = |number: u64, hash: BlockHash, parent_hash: Option<BlockHash>| Block {
+            number,
+            hash,
+            parent_hash,
+            timestamp: number,
+        };
+        let indexer =
+            |n: u8| BlockSource::Indexer(Address::from(concat_bytes!(20, [&[0; 19], &[n]])));
+
+        //* When
+        // Indexers running graph-node versions with and without `parentHash` report the same block
+        // at 2, while a single indexer reports a competing block.
+        chain.insert(block(1, hash(1), Some(hash(0))), indexer(1));
+        chain.insert(block(2, hash(2), None), indexer(1));
+        chain.insert(block(2, hash(2), Some(hash(1))), indexer(2));
+        chain.insert(block(2, hash(20), Some(hash(1))), indexer(3));
+        chain.insert(block(3, hash(3), None), indexer(1));
+        chain.insert(block(3, hash(3), Some(hash(2))), indexer(2));
+        assert!(!chain.should_insert(&block(3, hash(3), None), &indexer(2)));
+        assert!(chain.should_insert(&block(3, hash(3), Some(hash(2))), &indexer(1)));
+        chain.insert(block(3, hash(3), Some(hash(2))), indexer(1));
+        let reorgs = chain.resolve_forks();
+
+        //* Then
+        assert_eq!(
+            reorgs,
+            vec![Reorg {
+                number: 2,
+                orphaned: 1
+            }]
+        );
+        assert_eq!(chain.blocks.len(), 3);
+        assert_eq!(
+            weight(chain.blocks.get(&block(2, hash(2), None)).unwrap()),
+            2
+        );
+        assert_eq!(
+            chain
+                .blocks
+                .keys()
+                .map(|b| (b.number, b.hash, b.parent_hash))
+                .collect::<Vec<_>>(),
+            vec![
+                (1, hash(1), Some(hash(0))),
+                (2, hash(2), Some(hash(1))),
+                (3, hash(3), Some(hash(2))),
+            ]
+        );
+        assert_eq!(chain.latest().map(|b| b.hash), Some(hash(3)));
+        assert_eq!(
+            chain.find(&UnresolvedBlock::WithNumber(2)).map(|b| b.hash),
+            Some(hash(2))
+        );
+    }
+
+    #[test]
+    fn timestamp_bounds() {
+        let mut chain: Chain = Default::default();
+        let block = |number: u64| Block {
+            number,
+            hash: BlockHash::from(U256::from(number)),
+            parent_hash: Some(BlockHash::from(U256::from(number - 1))),
+            timestamp: number * 12,
+        };
+        for number in [10, 11, 15] {
+            chain.insert(block(number), BlockSource::Rpc(0));
+        }
+
+        assert_eq!(chain.timestamp_bounds(100), (None, Some(&block(10))));
+        assert_eq!(
+            chain.timestamp_bounds(125),
+            (Some(&block(10)), Some(&block(11)))
+        );
+        assert_eq!(
+            chain.timestamp_bounds(132),
+            (Some(&block(11)), Some(&block(15)))
+        );
+        assert_eq!(chain.timestamp_bounds(180), (Some(&block(15)), None));
+
+        let find = |timestamp| chain.find(&UnresolvedBlock::WithTimestamp(timestamp));
+        assert_eq!(find(100), None);
+        assert_eq!(find(125), Some(&block(10)));
+        assert_eq!(find(132), None);
+        assert_eq!(find(180), Some(&block(15)));
+    }
 }
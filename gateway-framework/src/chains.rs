@@ -7,16 +7,24 @@ use alloy_primitives::Address;
 use parking_lot::{RwLock, RwLockReadGuard};
 use tokio::{
     select, spawn,
-    sync::mpsc,
+    sync::{mpsc, watch},
     time::{interval, MissedTickBehavior},
 };
+use url::Url;
 
-use crate::{blocks::Block, chain::Chain, metrics::METRICS};
+use crate::{
+    blocks::Block,
+    chain::{BlockSource, Chain, Reorg},
+    metrics::METRICS,
+};
+
+mod rpc;
 
 #[derive(Clone)]
 pub struct ChainReader {
     tx: mpsc::UnboundedSender<Msg>,
     chain: &'static RwLock<Chain>,
+    latest: watch::Receiver<Option<Block>>,
 }
 
 impl ChainReader {
@@ -24,8 +32,16 @@ impl ChainReader {
         self.chain.read()
     }
 
+    /// Watch the latest consensus block of the chain.
+    pub fn subscribe(&self) -> watch::Receiver<Option<Block>> {
+        self.latest.clone()
+    }
+
     pub fn notify(&self, block: Block, indexer: Address) {
-        let _ = self.tx.send(Msg { block, indexer });
+        let _ = self.tx.send(Msg {
+            block,
+            source: BlockSource::Indexer(indexer),
+        });
     }
 }
 
@@ -35,11 +51,26 @@ pub struct Chains {
 }
 
 impl Chains {
-    pub fn new(aliases: BTreeMap<String, String>) -> Self {
-        Self {
+    /// Create the chains cache. Each chain in `rpcs` gets its head tracked by polling (HTTP) or
+    /// subscribing to (WebSocket) the given JSON-RPC providers, in addition to the blocks reported
+    /// by indexers.
+    pub fn new(aliases: BTreeMap<String, String>, rpcs: BTreeMap<String, Vec<Url>>) -> Self {
+        let chains = Self {
             data: Default::default(),
             aliases,
+        };
+        for (chain_name, urls) in rpcs {
+            let chain = chains.chain(&chain_name);
+            for (index, url) in urls.into_iter().enumerate() {
+                rpc::spawn(
+                    chain_name.clone(),
+                    BlockSource::Rpc(index),
+                    url,
+                    chain.tx.clone(),
+                );
+            }
         }
+        chains
     }
 
     pub fn chain(&self, name: &str) -> ChainReader {
@@ -62,7 +93,7 @@ impl Chains {
 
 struct Msg {
     block: Block,
-    indexer: Address,
+    source: BlockSource,
 }
 
 struct Actor;
@@ -71,13 +102,22 @@ impl Actor {
     pub fn spawn(chain_name: String) -> ChainReader {
         let chain: &'static RwLock<Chain> = Box::leak(Box::default());
         let (tx, mut rx) = mpsc::unbounded_channel();
+        let (latest_tx, latest_rx) = watch::channel(None);
         spawn(async move {
             let mut msgs: Vec<Msg> = Default::default();
             let mut timer = interval(Duration::from_secs(1));
             timer.set_missed_tick_behavior(MissedTickBehavior::Delay);
             loop {
                 select! {
-                    _ = rx.recv_many(&mut msgs, 32) => Self::handle_msgs(chain, &mut msgs),
+                    _ = rx.recv_many(&mut msgs, 32) => {
+                        Self::handle_msgs(&chain_name, chain, &mut msgs);
+                        let latest = chain.read().latest().cloned();
+                        latest_tx.send_if_modified(|current| {
+                            let modified = *current != latest;
+                            *current = latest;
+                            modified
+                        });
+                    },
                     _ = timer.tick() => {
                         let blocks_per_minute = chain.read().blocks_per_minute();
                         METRICS
@@ -88,21 +128,29 @@ impl Actor {
                 }
             }
         });
-        ChainReader { tx, chain }
+        ChainReader {
+            tx,
+            chain,
+            latest: latest_rx,
+        }
     }
 
-    fn handle_msgs(chain: &RwLock<Chain>, msgs: &mut Vec<Msg>) {
+    fn handle_msgs(chain_name: &str, chain: &RwLock<Chain>, msgs: &mut Vec<Msg>) {
         {
             let reader = chain.read();
-            msgs.retain(|Msg { block, indexer }| reader.should_insert(block, indexer));
+            msgs.retain(|Msg { block, source }| reader.should_insert(block, source));
         }
         {
             let mut writer = chain.write();
-            for Msg { block, indexer } in msgs.drain(..) {
-                if writer.should_insert(&block, &indexer) {
-                    writer.insert(block, indexer);
+            for Msg { block, source } in msgs.drain(..) {
+                if writer.should_insert(&block, &source) {
+                    writer.insert(block, source);
                 }
             }
+            for Reorg { number, orphaned } in writer.resolve_forks() {
+                tracing::info!(chain = chain_name, number, orphaned, "reorg");
+                METRICS.chain_reorgs.with_label_values(&[chain_name]).inc();
+            }
         }
         debug_assert!(msgs.is_empty());
     }
@@ -0,0 +1,190 @@
+//! Chain head tracking via JSON-RPC providers. This keeps chain heads fresh for chains that have
+//! too little query traffic for indexer responses to do so.
+
+use std::time::Duration;
+
+use alloy_primitives::BlockHash;
+use anyhow::{anyhow, Context as _};
+use ethers::{
+    providers::{Http, Middleware as _, Provider, StreamExt as _, Ws},
+    types::{BlockNumber, H256},
+};
+use tokio::{
+    sync::mpsc,
+    time::{interval, sleep, MissedTickBehavior},
+};
+use url::Url;
+
+use super::Msg;
+use crate::{blocks::Block, chain::BlockSource};
+
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// Spawn a task feeding the blocks produced by the JSON-RPC provider at `url` into the chain
+/// actor. WebSocket providers are subscribed to for new heads, HTTP providers are polled for the
+/// latest block.
+pub(super) fn spawn(
+    chain_name: String,
+    source: BlockSource,
+    url: Url,
+    tx: mpsc::UnboundedSender<Msg>,
+) {
+    tokio::spawn(async move {
+        match url.scheme() {
+            "ws" | "wss" => loop {
+                if let Err(chain_rpc_err) = subscribe(&url, source, &tx).await {
+                    tracing::warn!(chain = chain_name, %chain_rpc_err);
+                }
+                if tx.is_closed() {
+                    return;
+                }
+                sleep(RECONNECT_DELAY).await;
+            },
+            _ => {
+                if let Err(chain_rpc_err) = poll(&chain_name, &url, source, &tx).await {
+                    tracing::error!(chain = chain_name, %chain_rpc_err);
+                }
+            }
+        }
+    });
+}
+
+async fn subscribe(
+    url: &Url,
+    source: BlockSource,
+    tx: &mpsc::UnboundedSender<Msg>,
+) -> anyhow::Result<()> {
+    let provider = Provider::<Ws>::connect(url.as_str()).await?;
+    let mut blocks = provider.subscribe_blocks().await?;
+    while let Some(block) = blocks.next().await {
+        let block = convert_block(block)?;
+        if tx.send(Msg { block, source }).is_err() {
+            return Ok(());
+        }
+    }
+    Err(anyhow!("block subscription closed"))
+}
+
+async fn poll(
+    chain_name: &str,
+    url: &Url,
+    source: BlockSource,
+    tx: &mpsc::UnboundedSender<Msg>,
+) -> anyhow::Result<()> {
+    let provider = Provider::<Http>::try_from(url.as_str())?;
+    let mut timer = interval(POLL_INTERVAL);
+    timer.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut latest: Option<Block> = None;
+    loop {
+        timer.tick().await;
+        let block = match provider
+            .get_block(BlockNumber::Latest)
+            .await
+            .map_err(anyhow::Error::from)
+            .and_then(|block| convert_block(block.context("missing latest block")?))
+        {
+            Ok(block) => block,
+            Err(chain_rpc_err) => {
+                tracing::warn!(chain = chain_name, %chain_rpc_err);
+                continue;
+            }
+        };
+        if latest.as_ref() == Some(&block) {
+            continue;
+        }
+        latest = Some(block.clone());
+        if tx.send(Msg { block, source }).is_err() {
+            return Ok(());
+        }
+    }
+}
+
+fn convert_block(block: ethers::types::Block<H256>) -> anyhow::Result<Block> {
+    let number = block.number.context("missing block number")?;
+    let hash = block.hash.context("missing block hash")?;
+    Ok(Block {
+        number: number.as_u64(),
+        hash: BlockHash::from(hash.0),
+        parent_hash: Some(BlockHash::from(block.parent_hash.0)),
+        timestamp: u64::try_from(block.timestamp).map_err(|_| anyhow!("invalid timestamp"))?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::BTreeMap,
+        sync::{
+            atomic::{AtomicU64, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use axum::{extract::State, routing::post, Json, Router};
+    use serde_json::{json, Value};
+    use tokio::{net::TcpListener, time::timeout};
+    use url::Url;
+
+    use crate::chains::Chains;
+
+    /// Serve a mock JSON-RPC provider, producing a new block for each request.
+    async fn mock_rpc_server() -> Url {
+        async fn handle(
+            State(head): State<Arc<AtomicU64>>,
+            Json(request): Json<Value>,
+        ) -> Json<Value> {
+            let number = head.fetch_add(1, Ordering::Relaxed) + 1;
+            Json(json!({
+                "jsonrpc": "2.0",
+                "id": request["id"],
+                "result": {
+                    "number": format!("{number:#x}"),
+                    "hash": format!("0x{number:064x}"),
+                    "parentHash": format!("0x{:064x}", number - 1),
+                    "timestamp": format!("{:#x}", 1_700_000_000 + (number * 12)),
+                },
+            }))
+        }
+
+        let router = Router::new()
+            .route("/", post(handle))
+            .with_state(Arc::new(AtomicU64::new(100)));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            axum::serve(listener, router.into_make_service())
+                .await
+                .unwrap();
+        });
+        format!("http://{addr}/").parse().unwrap()
+    }
+
+    #[tokio::test]
+    async fn track_chain_head_from_rpc() {
+        //* Given
+        let url = mock_rpc_server().await;
+        let chains = Chains::new(
+            BTreeMap::from([("homestead".to_string(), "mainnet".to_string())]),
+            BTreeMap::from([("homestead".to_string(), vec![url])]),
+        );
+
+        //* When
+        let chain = chains.chain("mainnet");
+        let head = timeout(Duration::from_secs(10), async {
+            loop {
+                if let Some(block) = chain.read().latest().cloned() {
+                    break block;
+                }
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        })
+        .await
+        .expect("timeout waiting for chain head");
+
+        //* Then
+        assert!(head.number > 100);
+        assert_eq!(head.timestamp, 1_700_000_000 + (head.number * 12));
+    }
+}
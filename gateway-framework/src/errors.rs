@@ -110,6 +110,10 @@ pub enum UnavailableReason {
     #[error("no fee")]
     NoFee,
 
+    /// The indexer's fee for the query is above the fee cap set by the gateway.
+    #[error("fee too high")]
+    FeeTooHigh,
+
     /// The indexer did not have a block required by the query.
     #[error("{}", .0.message())]
     MissingBlock(MissingBlockError),
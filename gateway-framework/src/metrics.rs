@@ -1,9 +1,10 @@
 use lazy_static::lazy_static;
 use prometheus::{
     core::{MetricVec, MetricVecBuilder},
-    register_gauge, register_histogram, register_histogram_vec, register_int_counter,
-    register_int_counter_vec, register_int_gauge_vec, Gauge, Histogram, HistogramTimer,
-    HistogramVec, IntCounter, IntCounterVec, IntGaugeVec,
+    register_counter, register_gauge, register_gauge_vec, register_histogram,
+    register_histogram_vec, register_int_counter, register_int_counter_vec, register_int_gauge,
+    register_int_gauge_vec, Counter, Gauge, GaugeVec, Histogram, HistogramTimer, HistogramVec,
+    IntCounter, IntCounterVec, IntGauge, IntGaugeVec,
 };
 
 lazy_static! {
@@ -13,11 +14,25 @@ lazy_static! {
 pub struct Metrics {
     pub client_query: ResponseMetrics,
     pub avg_query_fees: Gauge,
+    pub budget_avg_query_fees: GaugeVec,
+    pub budget_min_indexer_fees: GaugeVec,
+    pub budget_integral: GaugeVec,
+    pub budget_error_history: GaugeVec,
     pub indexer_query: ResponseMetricVecs,
     pub collect_receipts: ResponseMetrics,
     pub partial_voucher: ResponseMetrics,
     pub voucher: ResponseMetrics,
+    pub rav: ResponseMetrics,
     pub blocks_per_minute: IntGaugeVec,
+    pub chain_reorgs: IntCounterVec,
+    pub indexer_connections: IntCounter,
+    pub indexer_requests: IntCounterVec,
+    pub indexer_requests_cancelled: IntCounter,
+    pub indexer_fees_wasted: Counter,
+    pub indexer_fees_capped: IntCounterVec,
+    pub indexer_fee_anomalies: IntCounterVec,
+    pub legacy_receipt_pools: IntGauge,
+    pub legacy_receipt_pools_evicted: IntCounter,
 }
 
 impl Metrics {
@@ -29,6 +44,30 @@ impl Metrics {
                 "average indexer fees per query, in USD"
             )
             .unwrap(),
+            budget_avg_query_fees: register_gauge_vec!(
+                "gw_budget_avg_query_fees",
+                "average indexer fees per query of each budget policy, in USD",
+                &["policy"]
+            )
+            .unwrap(),
+            budget_min_indexer_fees: register_gauge_vec!(
+                "gw_budget_min_indexer_fees",
+                "minimum indexer fees per query of each budget policy, in USD",
+                &["policy"]
+            )
+            .unwrap(),
+            budget_integral: register_gauge_vec!(
+                "gw_budget_integral",
+                "integral term of the budget controller of each budget policy",
+                &["policy"]
+            )
+            .unwrap(),
+            budget_error_history: register_gauge_vec!(
+                "gw_budget_error_history",
+                "relative error of the query fees to the target, per frame of the error history",
+                &["policy", "frame"]
+            )
+            .unwrap(),
             indexer_query: ResponseMetricVecs::new(
                 "gw_indexer_query",
                 "indexer query",
@@ -40,12 +79,62 @@ impl Metrics {
             ),
             partial_voucher: ResponseMetrics::new("gw_partial_voucher", "partial-voucher request"),
             voucher: ResponseMetrics::new("gw_voucher", "requests for voucher"),
+            rav: ResponseMetrics::new("gw_rav", "requests for TAP receipt aggregate voucher"),
             blocks_per_minute: register_int_gauge_vec!(
                 "gw_blocks_per_minute",
                 "chain blocks per minute",
                 &["chain"]
             )
             .unwrap(),
+            chain_reorgs: register_int_counter_vec!(
+                "gw_chain_reorgs",
+                "chain forks resolved by orphaning blocks",
+                &["chain"]
+            )
+            .unwrap(),
+            indexer_connections: register_int_counter!(
+                "gw_indexer_connections",
+                "connections opened to indexers"
+            )
+            .unwrap(),
+            indexer_requests: register_int_counter_vec!(
+                "gw_indexer_requests",
+                "requests sent to indexer hosts",
+                &["host"]
+            )
+            .unwrap(),
+            indexer_requests_cancelled: register_int_counter!(
+                "gw_indexer_requests_cancelled",
+                "indexer requests cancelled after the client query was answered"
+            )
+            .unwrap(),
+            indexer_fees_wasted: register_counter!(
+                "gw_indexer_fees_wasted",
+                "fees of cancelled indexer requests, in USD"
+            )
+            .unwrap(),
+            indexer_fees_capped: register_int_counter_vec!(
+                "gw_indexer_fees_capped",
+                "candidates excluded for fees above the fee cap",
+                &["indexer"]
+            )
+            .unwrap(),
+            indexer_fee_anomalies: register_int_counter_vec!(
+                "gw_indexer_fee_anomalies",
+                "indexer fees detected far above those of peers on the same deployment",
+                &["indexer"]
+            )
+            .unwrap(),
+            legacy_receipt_pools: register_int_gauge!(
+                "gw_legacy_receipt_pools",
+                "legacy Scalar receipt pools held in memory"
+            )
+            .unwrap(),
+            legacy_receipt_pools_evicted: register_int_counter!(
+                "gw_legacy_receipt_pools_evicted",
+                "legacy Scalar receipt pools dropped for allocations closed on the network"
+            )
+            .unwrap(),
         }
     }
 }
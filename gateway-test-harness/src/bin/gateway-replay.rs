@@ -0,0 +1,117 @@
+//! `gateway-replay <requests file> [--gateway <url>] [--api-key <key>] [--rate <requests/s>]
+//! [--mock-indexers <count>]`
+//!
+//! Replay captured client requests, one JSON object per line with the fields `selector`, `query`,
+//! and optionally `variables`, against a gateway at a fixed rate. Reports latency, error classes,
+//! and fees.
+//!
+//! Without `--gateway`, the requests are replayed against a harness gateway backed by mock
+//! indexers, where every selector is allocated to by all mock indexers.
+
+use std::{collections::BTreeSet, env, path::PathBuf, time::Duration};
+
+use alloy_primitives::keccak256;
+use gateway_test_harness::{
+    replay::{self, Replay},
+    Harness, MockIndexer, MockIndexerConfig, API_KEY,
+};
+use thegraph_core::types::{DeploymentId, SubgraphId};
+use url::Url;
+
+#[tokio::main]
+async fn main() {
+    let mut args = env::args().skip(1);
+    let path: PathBuf = args
+        .next()
+        .expect("Missing argument for requests path")
+        .into();
+    let mut gateway: Option<Url> = None;
+    let mut api_key = API_KEY.to_string();
+    let mut rate = 10.0;
+    let mut mock_indexers = 3;
+    while let Some(flag) = args.next() {
+        let value = args
+            .next()
+            .unwrap_or_else(|| panic!("Missing value for {flag}"));
+        match flag.as_str() {
+            "--gateway" => gateway = Some(value.parse().expect("Invalid gateway URL")),
+            "--api-key" => api_key = value,
+            "--rate" => rate = value.parse().expect("Invalid rate"),
+            "--mock-indexers" => mock_indexers = value.parse().expect("Invalid indexer count"),
+            _ => panic!("Unexpected argument: {flag}"),
+        }
+    }
+    assert!(rate > 0.0, "Rate must be positive");
+
+    let requests = replay::read(&path).expect("Failed to read requests");
+    let selectors: BTreeSet<String> = requests.iter().map(|r| r.selector.clone()).collect();
+
+    // The mock indexers and harness must outlive the replay.
+    let mut mocks = None;
+    let gateway = match gateway {
+        Some(gateway) => gateway,
+        None => {
+            let (harness, indexers) = mock_gateway(&selectors, mock_indexers).await;
+            let gateway = harness.serve().await;
+            mocks = Some((harness, indexers));
+            gateway
+        }
+    };
+
+    eprintln!(
+        "replaying {} requests against {gateway} at {rate} requests/s",
+        requests.len()
+    );
+    let replay = Replay {
+        client: reqwest::Client::builder()
+            .timeout(Duration::from_secs(60))
+            .build()
+            .unwrap(),
+        gateway,
+        api_key,
+        rate,
+    };
+    let report = replay.run(requests).await;
+    drop(mocks);
+    print!("{report}");
+}
+
+/// Build a harness gateway where each selector resolves to a subgraph allocated to by all mock
+/// indexers. The subgraph ID of deployment selectors, and the deployment ID of subgraph selectors,
+/// are derived from the selector.
+async fn mock_gateway(selectors: &BTreeSet<String>, count: u8) -> (Harness, Vec<MockIndexer>) {
+    let mut indexers = Vec::new();
+    for seed in 1..=count {
+        let config = MockIndexerConfig {
+            latency: Duration::from_millis(10 * seed as u64),
+            fee_grt: 10e-6,
+            ..Default::default()
+        };
+        indexers.push(MockIndexer::spawn(seed, config).await);
+    }
+    let indexer_refs: Vec<&MockIndexer> = indexers.iter().collect();
+
+    let mut builder = Harness::builder();
+    for selector in selectors {
+        let digest = keccak256(selector);
+        let (subgraph, deployment) = match selector.parse::<DeploymentId>() {
+            Ok(deployment) => (
+                bs58::encode(digest).into_string().parse::<SubgraphId>(),
+                Ok(deployment),
+            ),
+            Err(_) => {
+                let cid = [&[0x12_u8, 0x20][..], digest.as_slice()].concat();
+                (
+                    selector.parse::<SubgraphId>(),
+                    bs58::encode(cid).into_string().parse::<DeploymentId>(),
+                )
+            }
+        };
+        let (Ok(subgraph), Ok(deployment)) = (subgraph, deployment) else {
+            eprintln!("skipping invalid selector: {selector}");
+            continue;
+        };
+        builder = builder.subgraph(&subgraph, &deployment, "mainnet", &indexer_refs);
+    }
+    (builder.build().await, indexers)
+}
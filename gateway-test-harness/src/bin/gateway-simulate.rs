@@ -0,0 +1,66 @@
+//! `gateway-simulate [--indexers <count>] [--seed <seed>] [--qps <queries/s>]
+//! [--duration <seconds>] [--grt-per-usd <rate>] [--selection-limits <list>] [--cutoffs <list>]
+//! [--budgets <list>]`
+//!
+//! Simulate indexer selection and budgeting offline, against a synthetic indexer population, for
+//! every combination of the comma-separated selection limits, seconds-behind cutoffs, and query
+//! fees targets (in USD). Prints one CSV row of fees, latency, and success rate per combination.
+
+use std::{env, str::FromStr};
+
+use gateway_test_harness::simulation::{self, Outcome, PopulationConfig, Workload};
+
+fn main() {
+    let mut args = env::args().skip(1);
+    let mut population_config = PopulationConfig::default();
+    let mut workload = Workload::default();
+    let mut seed = 1;
+    let mut selection_limits = vec![1, 2, 3];
+    let mut cutoffs = vec![60 * 30];
+    let mut budgets = vec![20e-6];
+    while let Some(flag) = args.next() {
+        let value = args
+            .next()
+            .unwrap_or_else(|| panic!("Missing value for {flag}"));
+        match flag.as_str() {
+            "--indexers" => population_config.indexers = parse(&flag, &value),
+            "--seed" => seed = parse(&flag, &value),
+            "--qps" => workload.queries_per_second = parse(&flag, &value),
+            "--duration" => workload.duration_secs = parse(&flag, &value),
+            "--grt-per-usd" => workload.grt_per_usd = parse(&flag, &value),
+            "--selection-limits" => selection_limits = parse_list(&flag, &value),
+            "--cutoffs" => cutoffs = parse_list(&flag, &value),
+            "--budgets" => budgets = parse_list(&flag, &value),
+            _ => panic!("Unexpected argument: {flag}"),
+        }
+    }
+    assert!(
+        selection_limits.iter().all(|l| (1..=5).contains(l)),
+        "Selection limits must be in 1..=5"
+    );
+
+    let population = simulation::population(&population_config, seed);
+    let outcomes = simulation::sweep(
+        &population,
+        &workload,
+        &selection_limits,
+        &cutoffs,
+        &budgets,
+        seed,
+    )
+    .expect("Simulation failed");
+    println!("{}", Outcome::CSV_HEADER);
+    for outcome in outcomes {
+        println!("{}", outcome.csv_row());
+    }
+}
+
+fn parse<T: FromStr>(flag: &str, value: &str) -> T {
+    value
+        .parse()
+        .unwrap_or_else(|_| panic!("Invalid value for {flag}: {value}"))
+}
+
+fn parse_list<T: FromStr>(flag: &str, value: &str) -> Vec<T> {
+    value.split(',').map(|v| parse(flag, v.trim())).collect()
+}
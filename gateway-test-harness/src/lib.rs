@@ -0,0 +1,328 @@
+//! Deterministic, offline test harness for the gateway.
+//!
+//! The harness runs in-process mock indexers and a mock network subgraph, and builds the full
+//! client query [`Context`] and router on top of them. Client-to-indexer flows can then be tested
+//! without any live endpoint:
+//!
+//! ```ignore
+//! let indexer = MockIndexer::spawn(1, MockIndexerConfig::default()).await;
+//! let harness = Harness::builder()
+//!     .subgraph(subgraph, deployment, "mainnet", &[&indexer])
+//!     .build()
+//!     .await;
+//! let (status, response) = harness.query_deployment(&deployment, "{ tokens { id } }").await;
+//! ```
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::{Ipv4Addr, SocketAddr},
+    sync::OnceLock,
+    time::Duration,
+};
+
+use alloy_primitives::{Address, U256};
+use alloy_sol_types::Eip712Domain;
+use axum::{
+    body::{self, Body},
+    http::{header, Method, Request, StatusCode},
+    Router,
+};
+use gateway_framework::{
+    auth::{api_keys::APIKey, AuthContext},
+    budgets::{Budgeter, USD},
+    chains::Chains,
+};
+use graph_gateway::{
+    block_constraints::DEFAULT_PARENT_HASH_MIN_VERSION,
+    client_query::{
+        self,
+        context::Context,
+        fee_monitor::{FeeLimits, FeeMonitor},
+        timeouts::IndexerTimeouts,
+    },
+    indexer_client::{IndexerClient, TransportConfig},
+    indexing_performance::IndexingPerformance,
+    network::{subgraph_client::Client as NetworkSubgraphClient, NetworkServiceBuilder},
+    receipts::ReceiptSigner,
+    reports,
+};
+use ordered_float::NotNan;
+use secp256k1::SecretKey;
+use semver::Version;
+use thegraph_core::{
+    client::Client as SubgraphClient,
+    types::{attestation, DeploymentId, SubgraphId},
+};
+use tokio::{
+    net::TcpListener,
+    sync::{mpsc, watch},
+};
+use tower::ServiceExt as _;
+use url::Url;
+
+pub use self::{
+    mock_indexer::{AttestationMode, MockIndexer, MockIndexerConfig},
+    mock_network_subgraph::MockNetworkSubgraph,
+};
+
+pub mod mock_indexer;
+pub mod mock_network_subgraph;
+pub mod replay;
+pub mod simulation;
+
+/// API key accepted by the harness gateway.
+pub const API_KEY: &str = "0123456789abcdef0123456789abcdef";
+
+/// Timeout for the initial network topology synchronization.
+const NETWORK_READY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The EIP-712 domain used by the harness gateway to verify attestations, and by the mock
+/// indexers to sign them.
+pub fn attestation_domain() -> &'static Eip712Domain {
+    static DOMAIN: OnceLock<Eip712Domain> = OnceLock::new();
+    DOMAIN.get_or_init(|| attestation::eip712_domain(U256::from(1337), Address::ZERO))
+}
+
+/// A gateway built on top of mock indexers and a mock network subgraph.
+pub struct Harness {
+    pub ctx: Context,
+    /// The gateway router, with the client query routes nested under `/api`.
+    pub router: Router,
+    pub network_subgraph: MockNetworkSubgraph,
+    /// Reports of the client requests handled by the gateway, in place of the Kafka reporter.
+    pub reports: mpsc::UnboundedReceiver<reports::ClientRequest>,
+}
+
+impl Harness {
+    pub fn builder() -> HarnessBuilder {
+        HarnessBuilder::default()
+    }
+
+    /// Send a client query for the given deployment through the gateway router, authorized with
+    /// [`API_KEY`]. Returns the response status and JSON body.
+    pub async fn query_deployment(
+        &self,
+        deployment: &DeploymentId,
+        query: &str,
+    ) -> (StatusCode, serde_json::Value) {
+        self.query(&format!("/api/deployments/id/{deployment}"), query)
+            .await
+    }
+
+    /// Send a client query for the given subgraph through the gateway router, authorized with
+    /// [`API_KEY`]. Returns the response status and JSON body.
+    pub async fn query_subgraph(
+        &self,
+        subgraph: &SubgraphId,
+        query: &str,
+    ) -> (StatusCode, serde_json::Value) {
+        self.query(&format!("/api/subgraphs/id/{subgraph}"), query)
+            .await
+    }
+
+    /// Request a query fee quote for the given subgraph through the gateway router, authorized with
+    /// [`API_KEY`]. Returns the response status and JSON body.
+    pub async fn quote_subgraph(
+        &self,
+        subgraph: &SubgraphId,
+        query: &str,
+    ) -> (StatusCode, serde_json::Value) {
+        self.query(&format!("/api/subgraphs/id/{subgraph}/quote"), query)
+            .await
+    }
+
+    /// Serve the gateway router on an ephemeral local port, returning its base URL.
+    pub async fn serve(&self) -> Url {
+        serve(self.router.clone()).await
+    }
+
+    async fn query(&self, path: &str, query: &str) -> (StatusCode, serde_json::Value) {
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri(path)
+            .header(header::CONTENT_TYPE, "application/json")
+            .header(header::AUTHORIZATION, format!("Bearer {API_KEY}"))
+            .body(Body::from(
+                serde_json::json!({ "query": query }).to_string(),
+            ))
+            .unwrap();
+        let response = self.router.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body = serde_json::from_slice(&body).unwrap_or_else(|_| {
+            serde_json::Value::String(String::from_utf8_lossy(&body).into_owned())
+        });
+        (status, body)
+    }
+}
+
+pub struct HarnessBuilder {
+    subgraphs: Vec<serde_json::Value>,
+    api_key: APIKey,
+    query_fees_target: f64,
+    grt_per_usd: f64,
+    indexer_timeouts: IndexerTimeouts,
+}
+
+impl Default for HarnessBuilder {
+    fn default() -> Self {
+        Self {
+            subgraphs: Vec::new(),
+            api_key: APIKey {
+                key: API_KEY.to_string(),
+                ..Default::default()
+            },
+            query_fees_target: 20e-6,
+            grt_per_usd: 1.0,
+            indexer_timeouts: IndexerTimeouts::default(),
+        }
+    }
+}
+
+impl HarnessBuilder {
+    /// Add a subgraph, with a single version for the given deployment, allocated to by each of the
+    /// mock indexers.
+    pub fn subgraph(
+        mut self,
+        id: &SubgraphId,
+        deployment: &DeploymentId,
+        chain: &str,
+        indexers: &[&MockIndexer],
+    ) -> Self {
+        let allocations: Vec<serde_json::Value> = indexers
+            .iter()
+            .map(|indexer| indexer.allocation_entity(deployment))
+            .collect();
+        self.subgraphs.push(serde_json::json!({
+            "id": id.to_string(),
+            "versions": [{
+                "version": 0,
+                "subgraphDeployment": {
+                    "ipfsHash": deployment.to_string(),
+                    "manifest": {
+                        "network": chain,
+                        "startBlock": "0",
+                    },
+                    "indexerAllocations": allocations,
+                },
+            }],
+        }));
+        self
+    }
+
+    /// Settings of the API key used by [`Harness::query_deployment`] and
+    /// [`Harness::query_subgraph`]. The key itself is always [`API_KEY`].
+    pub fn api_key(mut self, api_key: APIKey) -> Self {
+        self.api_key = APIKey {
+            key: API_KEY.to_string(),
+            ..api_key
+        };
+        self
+    }
+
+    pub fn query_fees_target(mut self, usd: f64) -> Self {
+        self.query_fees_target = usd;
+        self
+    }
+
+    pub fn grt_per_usd(mut self, grt_per_usd: f64) -> Self {
+        self.grt_per_usd = grt_per_usd;
+        self
+    }
+
+    pub fn indexer_timeouts(mut self, indexer_timeouts: IndexerTimeouts) -> Self {
+        self.indexer_timeouts = indexer_timeouts;
+        self
+    }
+
+    /// Spawn the mock network subgraph and build the gateway. This waits for the initial network
+    /// topology synchronization, so the mock indexers must already be running.
+    ///
+    /// # Panics
+    /// Panics if the network topology is not available within 10 seconds.
+    pub async fn build(self) -> Harness {
+        let network_subgraph = MockNetworkSubgraph::spawn(self.subgraphs).await;
+
+        let http_client = reqwest::Client::new();
+        let subgraph_client = NetworkSubgraphClient::new(
+            SubgraphClient::new(http_client.clone(), network_subgraph.url.clone()),
+            false,
+        );
+        let mut network = NetworkServiceBuilder::new(subgraph_client, http_client)
+            .with_indexer_min_indexer_service_version(Version::new(0, 0, 0))
+            .with_indexer_min_graph_node_version(Version::new(0, 0, 0))
+            .build()
+            .spawn();
+        let indexing_perf = IndexingPerformance::new(network.clone());
+        tokio::time::timeout(NETWORK_READY_TIMEOUT, network.wait_until_ready())
+            .await
+            .expect("network topology not ready");
+
+        let signer = SecretKey::from_slice(&[0xAA; 32]).unwrap();
+        let receipt_signer: &'static ReceiptSigner = Box::leak(Box::new(ReceiptSigner::new(
+            vec![signer.into()],
+            U256::from(1337),
+            Address::ZERO,
+            Some(signer),
+        )));
+        let query_fees_target = NotNan::new(self.query_fees_target).expect("invalid budget");
+        let budgeter: &'static Budgeter = Box::leak(Box::new(
+            Budgeter::new(USD(query_fees_target)).expect("invalid budget"),
+        ));
+        let (reporter, reports) = mpsc::unbounded_channel();
+
+        let ctx = Context {
+            indexer_client: IndexerClient::new(TransportConfig::default(), None)
+                .expect("failed to build indexer client"),
+            receipt_signer,
+            budgeter,
+            l2_gateway: None,
+            grt_per_usd: watch::channel(
+                NotNan::new(self.grt_per_usd).expect("invalid exchange rate"),
+            )
+            .1,
+            chains: Box::leak(Box::new(Chains::new(BTreeMap::new(), BTreeMap::new()))),
+            network,
+            indexing_perf,
+            indexer_timeouts: self.indexer_timeouts,
+            fee_monitor: Box::leak(Box::new(FeeMonitor::new(FeeLimits::default()))),
+            subscription_budget: USD(NotNan::new(1.0).unwrap()),
+            attestation_domain: attestation_domain(),
+            parent_hash_min_graph_node_version: DEFAULT_PARENT_HASH_MIN_VERSION,
+            reporter,
+        };
+
+        let auth = AuthContext {
+            payment_required: false,
+            api_keys: watch::channel(HashMap::from([(API_KEY.to_string(), self.api_key)])).1,
+            special_api_keys: Default::default(),
+        };
+        let router = Router::new().nest(
+            "/api",
+            client_query::router(ctx.clone(), auth, "harness".to_string()),
+        );
+
+        Harness {
+            ctx,
+            router,
+            network_subgraph,
+            reports,
+        }
+    }
+}
+
+/// Serve the router on an ephemeral local port, returning its base URL.
+async fn serve(router: Router) -> Url {
+    let listener = TcpListener::bind(SocketAddr::from((Ipv4Addr::LOCALHOST, 0)))
+        .await
+        .expect("failed to bind mock server");
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        axum::serve(listener, router.into_make_service())
+            .await
+            .expect("mock server failed");
+    });
+    format!("http://{addr}/").parse().unwrap()
+}
@@ -0,0 +1,313 @@
+//! In-process mock indexer, serving the indexer-service and graph-node endpoints queried by the
+//! gateway: `version/`, `status/`, `cost/`, and `subgraphs/id/<deployment>`.
+
+use std::{sync::Arc, time::Duration};
+
+use alloy_primitives::{keccak256, Address, BlockNumber, B256};
+use alloy_sol_types::{sol, SolStruct as _};
+use axum::{
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing, Json, Router,
+};
+use ethers::signers::{LocalWallet, Signer as _};
+use parking_lot::{Mutex, RwLock};
+use semver::Version;
+use serde_json::json;
+use thegraph_core::types::{Attestation, DeploymentId};
+use url::Url;
+
+use crate::attestation_domain;
+
+sol! {
+    struct Receipt {
+        bytes32 requestCID;
+        bytes32 responseCID;
+        bytes32 subgraphDeploymentID;
+    }
+}
+
+/// How the mock indexer attests query responses.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AttestationMode {
+    /// Signed with the allocation key of the deployment.
+    #[default]
+    Valid,
+    /// Signed with a key unrelated to the allocation.
+    Invalid,
+    /// No attestation.
+    Missing,
+}
+
+#[derive(Clone, Debug)]
+pub struct MockIndexerConfig {
+    /// Delay before responding to queries.
+    pub latency: Duration,
+    /// When set, queries fail with this HTTP status.
+    pub status: Option<StatusCode>,
+    /// The `data` field of query responses. The `_gateway_probe_` field is added when requested.
+    pub data: serde_json::Value,
+    /// GraphQL errors of query responses.
+    pub errors: Vec<String>,
+    pub attestation: AttestationMode,
+    /// Block reported in `_meta` probes and as the latest block of every indexing status.
+    pub block: BlockNumber,
+    /// Fee per query in GRT, served as a flat cost model. No cost model is served if zero.
+    pub fee_grt: f64,
+    pub indexer_service_version: Version,
+    pub graph_node_version: Version,
+}
+
+impl Default for MockIndexerConfig {
+    fn default() -> Self {
+        Self {
+            latency: Duration::ZERO,
+            status: None,
+            data: json!({}),
+            errors: Vec::new(),
+            attestation: AttestationMode::Valid,
+            block: 1_000,
+            fee_grt: 0.0,
+            indexer_service_version: Version::new(1, 0, 0),
+            graph_node_version: Version::new(0, 35, 0),
+        }
+    }
+}
+
+/// A query received by a mock indexer.
+#[derive(Clone, Debug)]
+pub struct MockRequest {
+    pub deployment: String,
+    /// The request body, as sent by the gateway.
+    pub body: String,
+    /// Whether the request carried a receipt header.
+    pub receipt: bool,
+}
+
+#[derive(Clone)]
+pub struct MockIndexer {
+    /// Address of the indexer, derived from its seed.
+    pub id: Address,
+    pub url: Url,
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    seed: u8,
+    config: RwLock<MockIndexerConfig>,
+    requests: Mutex<Vec<MockRequest>>,
+}
+
+impl MockIndexer {
+    /// Spawn a mock indexer on an ephemeral local port. The indexer address and allocation keys
+    /// are derived from the seed, which must be unique among the mock indexers of a test.
+    pub async fn spawn(seed: u8, config: MockIndexerConfig) -> Self {
+        let inner = Arc::new(Inner {
+            seed,
+            config: RwLock::new(config),
+            requests: Default::default(),
+        });
+        let router = Router::new()
+            .route("/version/", routing::get(handle_version))
+            .route("/status/", routing::post(handle_status))
+            .route("/cost/", routing::post(handle_cost))
+            .route("/subgraphs/id/:deployment", routing::post(handle_query))
+            .with_state(inner.clone());
+        let url = crate::serve(router).await;
+        Self {
+            id: Address::repeat_byte(seed),
+            url,
+            inner,
+        }
+    }
+
+    /// Update the configuration, which applies to subsequent requests.
+    pub fn update(&self, f: impl FnOnce(&mut MockIndexerConfig)) {
+        f(&mut self.inner.config.write());
+    }
+
+    /// The queries received so far.
+    pub fn requests(&self) -> Vec<MockRequest> {
+        self.inner.requests.lock().clone()
+    }
+
+    /// Address of the allocation of this indexer on the deployment.
+    pub fn allocation(&self, deployment: &DeploymentId) -> Address {
+        self.inner
+            .allocation_wallet(&deployment.to_string())
+            .address()
+            .0
+            .into()
+    }
+
+    /// The network subgraph `indexerAllocations` entity of this indexer on the deployment.
+    pub(crate) fn allocation_entity(&self, deployment: &DeploymentId) -> serde_json::Value {
+        json!({
+            "id": self.allocation(deployment).to_string(),
+            "allocatedTokens": "100000000000000000000000",
+            "indexer": {
+                "id": self.id.to_string(),
+                "url": self.url.to_string(),
+                "stakedTokens": "100000000000000000000000",
+            },
+        })
+    }
+}
+
+impl Inner {
+    fn allocation_wallet(&self, deployment: &str) -> LocalWallet {
+        let key = keccak256(format!("allocation-{}-{deployment}", self.seed));
+        LocalWallet::from_bytes(key.as_slice()).expect("invalid allocation key")
+    }
+
+    fn attestation(&self, deployment: &str, request: &str, response: &str) -> Attestation {
+        let wallet = match self.config.read().attestation {
+            AttestationMode::Invalid => {
+                LocalWallet::from_bytes(keccak256("invalid").as_slice()).unwrap()
+            }
+            _ => self.allocation_wallet(deployment),
+        };
+        let msg = Receipt {
+            requestCID: keccak256(request),
+            responseCID: keccak256(response),
+            subgraphDeploymentID: deployment_bytes(deployment),
+        };
+        let hash = msg.eip712_signing_hash(attestation_domain());
+        let signature: [u8; 65] = wallet
+            .sign_hash(hash.0.into())
+            .expect("failed to sign attestation")
+            .into();
+        Attestation {
+            request_cid: msg.requestCID,
+            response_cid: msg.responseCID,
+            deployment: msg.subgraphDeploymentID,
+            r: B256::from_slice(&signature[0..32]),
+            s: B256::from_slice(&signature[32..64]),
+            v: signature[64],
+        }
+    }
+}
+
+/// The 32-byte digest of an IPFS CIDv0 deployment ID, skipping the multihash prefix.
+fn deployment_bytes(deployment: &str) -> B256 {
+    let bytes = bs58::decode(deployment)
+        .into_vec()
+        .expect("invalid deployment ID");
+    B256::from_slice(&bytes[2..])
+}
+
+fn block_hash(number: BlockNumber) -> B256 {
+    keccak256(number.to_be_bytes())
+}
+
+/// Block timestamps of a chain producing a block every 12 seconds, so that the probes of all mock
+/// indexers agree on the timestamp of each block.
+fn block_timestamp(number: BlockNumber) -> u64 {
+    1_600_000_000 + (number * 12)
+}
+
+async fn handle_version(State(indexer): State<Arc<Inner>>) -> Json<serde_json::Value> {
+    let version = indexer.config.read().indexer_service_version.to_string();
+    Json(json!({ "version": version }))
+}
+
+async fn handle_status(State(indexer): State<Arc<Inner>>, body: String) -> Json<serde_json::Value> {
+    let request: serde_json::Value = serde_json::from_str(&body).unwrap_or_default();
+    let query = request["query"].as_str().unwrap_or_default();
+    let config = indexer.config.read().clone();
+    if query.contains("indexingStatuses") {
+        let statuses: Vec<serde_json::Value> = request["variables"]["deployments"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .map(|deployment| {
+                json!({
+                    "subgraph": deployment,
+                    "chains": [{
+                        "network": "mock",
+                        "latestBlock": { "number": config.block.to_string() },
+                        "earliestBlock": { "number": "0" },
+                    }],
+                })
+            })
+            .collect();
+        Json(json!({ "data": { "indexingStatuses": statuses } }))
+    } else if query.contains("publicProofsOfIndexing") {
+        Json(json!({ "data": { "publicProofsOfIndexing": [] } }))
+    } else {
+        let version = config.graph_node_version.to_string();
+        Json(json!({ "data": { "version": { "version": version } } }))
+    }
+}
+
+async fn handle_cost(State(indexer): State<Arc<Inner>>, body: String) -> Json<serde_json::Value> {
+    let request: serde_json::Value = serde_json::from_str(&body).unwrap_or_default();
+    let fee = indexer.config.read().fee_grt;
+    let models: Vec<serde_json::Value> = request["variables"]["deployments"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter(|_| fee > 0.0)
+        .map(|deployment| {
+            json!({
+                "deployment": deployment,
+                "model": format!("default => {fee};"),
+                "variables": null,
+            })
+        })
+        .collect();
+    Json(json!({ "data": { "costModels": models } }))
+}
+
+async fn handle_query(
+    State(indexer): State<Arc<Inner>>,
+    Path(deployment): Path<String>,
+    headers: HeaderMap,
+    body: String,
+) -> Response {
+    indexer.requests.lock().push(MockRequest {
+        deployment: deployment.clone(),
+        body: body.clone(),
+        receipt: headers.contains_key("scalar-receipt") || headers.contains_key("tap-receipt"),
+    });
+    let config = indexer.config.read().clone();
+    tokio::time::sleep(config.latency).await;
+    if let Some(status) = config.status {
+        return status.into_response();
+    }
+
+    let mut data = config.data;
+    if body.contains("_gateway_probe_") {
+        data["_gateway_probe_"] = json!({
+            "block": {
+                "number": config.block,
+                "hash": block_hash(config.block),
+                "parentHash": block_hash(config.block.saturating_sub(1)),
+                "timestamp": block_timestamp(config.block),
+            },
+        });
+    }
+    let mut response = json!({ "data": data });
+    if !config.errors.is_empty() {
+        let errors: Vec<serde_json::Value> = config
+            .errors
+            .iter()
+            .map(|message| json!({ "message": message }))
+            .collect();
+        response["errors"] = errors.into();
+    }
+    let response = response.to_string();
+
+    let attestation = match config.attestation {
+        AttestationMode::Missing => None,
+        AttestationMode::Valid | AttestationMode::Invalid => {
+            Some(indexer.attestation(&deployment, &body, &response))
+        }
+    };
+    Json(json!({
+        "graphQLResponse": response,
+        "attestation": attestation,
+    }))
+    .into_response()
+}
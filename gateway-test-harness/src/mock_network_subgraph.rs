@@ -0,0 +1,70 @@
+//! In-process mock of the network subgraph, serving the paginated subgraphs query of the
+//! network service.
+
+use std::sync::Arc;
+
+use alloy_primitives::keccak256;
+use axum::{extract::State, routing, Json, Router};
+use parking_lot::RwLock;
+use serde_json::json;
+use url::Url;
+
+/// Block number reported in the `_meta` field of responses.
+const BLOCK_NUMBER: u64 = 1_000;
+
+#[derive(Clone)]
+pub struct MockNetworkSubgraph {
+    pub url: Url,
+    subgraphs: Arc<RwLock<Vec<serde_json::Value>>>,
+}
+
+impl MockNetworkSubgraph {
+    /// Spawn the mock network subgraph on an ephemeral local port, serving the given `Subgraph`
+    /// entities.
+    pub async fn spawn(subgraphs: Vec<serde_json::Value>) -> Self {
+        let subgraphs = Arc::new(RwLock::new(subgraphs));
+        let router = Router::new()
+            .route("/", routing::post(handle_query))
+            .with_state(subgraphs.clone());
+        let url = crate::serve(router).await;
+        Self { url, subgraphs }
+    }
+
+    /// Replace the served `Subgraph` entities, picked up on the next network topology update.
+    pub fn set_subgraphs(&self, subgraphs: Vec<serde_json::Value>) {
+        *self.subgraphs.write() = subgraphs;
+    }
+}
+
+/// Respond with the page of subgraphs following the `$last` subgraph ID, in insertion order.
+async fn handle_query(
+    State(subgraphs): State<Arc<RwLock<Vec<serde_json::Value>>>>,
+    body: String,
+) -> Json<serde_json::Value> {
+    let request: serde_json::Value = serde_json::from_str(&body).unwrap_or_default();
+    let variables = &request["variables"];
+    let first = variables["first"].as_u64().unwrap_or(1000) as usize;
+    let last = variables["last"].as_str().unwrap_or_default();
+
+    let subgraphs = subgraphs.read();
+    let start = match last {
+        "" => 0,
+        last => subgraphs
+            .iter()
+            .position(|s| s["id"] == last)
+            .map(|index| index + 1)
+            .unwrap_or(subgraphs.len()),
+    };
+    let results: Vec<&serde_json::Value> = subgraphs.iter().skip(start).take(first).collect();
+    Json(json!({
+        "data": {
+            "meta": {
+                "block": {
+                    "number": BLOCK_NUMBER,
+                    "hash": keccak256(BLOCK_NUMBER.to_be_bytes()),
+                },
+            },
+            "results": results,
+        },
+    }))
+}
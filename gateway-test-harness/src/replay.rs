@@ -0,0 +1,270 @@
+//! Replay of captured client requests against a gateway, for load testing and benchmarking.
+
+use std::{
+    collections::BTreeMap,
+    fmt,
+    path::Path,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context as _;
+use serde::Deserialize;
+use thegraph_core::types::DeploymentId;
+use tokio::{task::JoinSet, time::MissedTickBehavior};
+use url::Url;
+
+/// A client request, as captured from gateway traffic. One JSON object per line.
+#[derive(Clone, Debug, Deserialize)]
+pub struct CapturedRequest {
+    /// Deployment ID or subgraph ID the query is sent to.
+    pub selector: String,
+    pub query: String,
+    #[serde(default)]
+    pub variables: Option<serde_json::Value>,
+}
+
+impl CapturedRequest {
+    /// Path of the request, relative to the gateway base URL.
+    fn path(&self) -> String {
+        match self.selector.parse::<DeploymentId>() {
+            Ok(_) => format!("api/deployments/id/{}", self.selector),
+            Err(_) => format!("api/subgraphs/id/{}", self.selector),
+        }
+    }
+}
+
+/// Read captured requests from a JSONL file, skipping empty lines.
+pub fn read(path: &Path) -> anyhow::Result<Vec<CapturedRequest>> {
+    let file = std::fs::read_to_string(path)?;
+    file.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(index, line)| {
+            serde_json::from_str(line)
+                .with_context(|| format!("invalid request on line {}", index + 1))
+        })
+        .collect()
+}
+
+pub struct Replay {
+    pub client: reqwest::Client,
+    /// Base URL of the gateway.
+    pub gateway: Url,
+    pub api_key: String,
+    /// Requests sent per second.
+    pub rate: f64,
+}
+
+impl Replay {
+    /// Send the requests at the configured rate, regardless of how long the gateway takes to
+    /// respond, and wait for all responses.
+    pub async fn run(&self, requests: Vec<CapturedRequest>) -> Report {
+        let mut interval = tokio::time::interval(Duration::from_secs_f64(1.0 / self.rate));
+        interval.set_missed_tick_behavior(MissedTickBehavior::Burst);
+        let api_key = Arc::new(format!("Bearer {}", self.api_key));
+        let mut tasks = JoinSet::new();
+        for request in requests {
+            interval.tick().await;
+            let client = self.client.clone();
+            let url = self.gateway.join(&request.path());
+            let api_key = api_key.clone();
+            tasks.spawn(async move {
+                let start = Instant::now();
+                let result = match url {
+                    Ok(url) => send(&client, url, &api_key, &request).await,
+                    Err(err) => Err(format!("invalid URL: {err}")),
+                };
+                (start.elapsed(), result)
+            });
+        }
+
+        let mut report = Report::default();
+        while let Some(result) = tasks.join_next().await {
+            let (latency, result) = result.expect("replay task panicked");
+            report.record(latency, result);
+        }
+        report
+    }
+}
+
+/// Send the request, returning the fee reported in the gateway extensions or the error class.
+async fn send(
+    client: &reqwest::Client,
+    url: Url,
+    api_key: &str,
+    request: &CapturedRequest,
+) -> Result<f64, String> {
+    let body = serde_json::json!({ "query": request.query, "variables": request.variables });
+    let response = client
+        .post(url)
+        .header("Authorization", api_key)
+        .header("graph-gateway-extensions", "true")
+        .json(&body)
+        .send()
+        .await
+        .map_err(|err| {
+            if err.is_timeout() {
+                "Timeout".to_string()
+            } else {
+                "Transport".to_string()
+            }
+        })?;
+    let status = response.status();
+    let text = response.text().await.map_err(|_| "Transport".to_string())?;
+    let payload: serde_json::Value = match serde_json::from_str(&text) {
+        Ok(payload) => payload,
+        Err(_) if !status.is_success() => return Err(format!("HTTP {}", status.as_u16())),
+        Err(_) => return Err("InvalidResponse".to_string()),
+    };
+    match payload.get("data") {
+        Some(data) if !data.is_null() => Ok(payload["extensions"]["gateway"]["fee_usd"]
+            .as_f64()
+            .unwrap_or(0.0)),
+        _ => {
+            let message = payload["errors"][0]["message"].as_str().unwrap_or_default();
+            Err(error_class(message).to_string())
+        }
+    }
+}
+
+/// Map a gateway error message to the name of the corresponding `Error` variant.
+pub fn error_class(message: &str) -> &'static str {
+    const CLASSES: [(&str, &str); 7] = [
+        ("internal error", "Internal"),
+        ("auth error", "Auth"),
+        ("block not found", "BlockNotFound"),
+        ("subgraph not found", "SubgraphNotFound"),
+        ("bad query", "BadQuery"),
+        ("no indexers found", "NoIndexers"),
+        ("bad indexers", "BadIndexers"),
+    ];
+    CLASSES
+        .iter()
+        .find(|(prefix, _)| message.starts_with(prefix))
+        .map(|(_, class)| *class)
+        .unwrap_or("Other")
+}
+
+/// Results of a replay.
+#[derive(Debug, Default)]
+pub struct Report {
+    /// Response latencies of all requests, including failed ones.
+    pub latencies: Vec<Duration>,
+    pub successes: u64,
+    /// Number of failed requests by error class.
+    pub errors: BTreeMap<String, u64>,
+    /// Sum of the fees reported for successful requests.
+    pub fees_usd: f64,
+}
+
+impl Report {
+    fn record(&mut self, latency: Duration, result: Result<f64, String>) {
+        self.latencies.push(latency);
+        match result {
+            Ok(fee_usd) => {
+                self.successes += 1;
+                self.fees_usd += fee_usd;
+            }
+            Err(class) => *self.errors.entry(class).or_default() += 1,
+        }
+    }
+
+    /// Latency at the given percentile, in `[0, 1]`.
+    pub fn latency_percentile(&self, p: f64) -> Option<Duration> {
+        let mut latencies = self.latencies.clone();
+        latencies.sort_unstable();
+        let index = ((latencies.len() as f64 * p).ceil() as usize).saturating_sub(1);
+        latencies.get(index).copied()
+    }
+
+    /// Number of requests per power-of-2 latency bucket, keyed by the bucket upper bound in
+    /// milliseconds.
+    pub fn latency_histogram(&self) -> BTreeMap<u64, u64> {
+        let mut histogram = BTreeMap::new();
+        for latency in &self.latencies {
+            let ms = (latency.as_millis() as u64).max(1);
+            *histogram.entry(ms.next_power_of_two()).or_default() += 1;
+        }
+        histogram
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let total = self.latencies.len() as u64;
+        writeln!(f, "requests: {total}, successes: {}", self.successes)?;
+        let fee_per_query = self.fees_usd / self.successes.max(1) as f64;
+        writeln!(
+            f,
+            "fees: {:.6} USD, {fee_per_query:.8} USD per successful query",
+            self.fees_usd,
+        )?;
+
+        writeln!(f, "latency:")?;
+        for (label, p) in [("p50", 0.5), ("p90", 0.9), ("p99", 0.99), ("max", 1.0)] {
+            if let Some(latency) = self.latency_percentile(p) {
+                writeln!(f, "  {label:<4} {:>8} ms", latency.as_millis())?;
+            }
+        }
+        let max_count = self.latency_histogram().into_values().max().unwrap_or(1);
+        for (bucket, count) in self.latency_histogram() {
+            let bar = "#".repeat(((count * 40) / max_count).max(1) as usize);
+            writeln!(f, "  <= {bucket:>6} ms {count:>8} {bar}")?;
+        }
+
+        if !self.errors.is_empty() {
+            writeln!(f, "errors:")?;
+            for (class, count) in &self.errors {
+                writeln!(f, "  {class:<16} {count:>8}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classify_errors() {
+        let tests = [
+            ("bad indexers: {0x00: BadResponse(500)}", "BadIndexers"),
+            ("no indexers found", "NoIndexers"),
+            ("auth error: missing bearer token", "Auth"),
+            ("subgraph not found: invalid id", "SubgraphNotFound"),
+            ("Too many requests, try again later", "Other"),
+        ];
+        for (message, expected) in tests {
+            assert_eq!(error_class(message), expected, "{message}");
+        }
+    }
+
+    #[test]
+    fn latency_stats() {
+        //* Given
+        let mut report = Report::default();
+        for ms in 1..=100 {
+            report.record(Duration::from_millis(ms), Ok(0.5));
+        }
+        report.record(Duration::from_millis(3), Err("NoIndexers".into()));
+
+        //* Then
+        assert_eq!(report.successes, 100);
+        assert_eq!(report.errors.get("NoIndexers"), Some(&1));
+        assert_eq!(report.fees_usd, 50.0);
+        assert_eq!(
+            report.latency_percentile(0.5),
+            Some(Duration::from_millis(50))
+        );
+        assert_eq!(
+            report.latency_percentile(1.0),
+            Some(Duration::from_millis(100))
+        );
+        let histogram = report.latency_histogram();
+        assert_eq!(histogram.get(&4), Some(&3));
+        assert_eq!(histogram.get(&128), Some(&36));
+        assert_eq!(histogram.values().sum::<u64>(), 101);
+    }
+}
@@ -0,0 +1,414 @@
+//! Offline simulation of indexer selection and budgeting.
+//!
+//! A synthetic population of indexers, each with its own latency, success rate, fee, and distance
+//! from chain head, is queried by a synthetic workload in simulated time. Each query goes through
+//! the same candidate list, selection, and fee logic as client queries, and the outcomes are fed
+//! back into the indexing performance snapshots and the budget controller.
+
+use std::{collections::HashMap, sync::Arc};
+
+use alloy_primitives::{Address, BlockNumber};
+use cost_model::{Context as AgoraContext, CostModel};
+use gateway_common::ptr::Ptr;
+use gateway_framework::budgets::{Controller, USD};
+use graph_gateway::{
+    block_constraints::BlockRequirements,
+    client_query::{build_candidates_list, indexer_request_fee, CandidateMetadata},
+    indexing_performance::Snapshot,
+    network::{internal::IndexingProgress, Indexer, Indexing, IndexingId},
+};
+use indexer_selection::{ArrayVec, Candidate, Normalized};
+use ordered_float::NotNan;
+use rand::{rngs::SmallRng, Rng as _, SeedableRng as _};
+use semver::Version;
+use thegraph_core::types::DeploymentId;
+
+const CHAIN_HEAD: BlockNumber = 20_000_000;
+const BLOCKS_PER_MINUTE: u64 = 5;
+/// Deployment queried by the workload.
+const DEPLOYMENT: &str = "QmZTy9EJHu8rfY9QbEk3z1epmmvh5XHhT2Wqhkfbyt8k9Z";
+
+/// Ranges the properties of each synthetic indexer are drawn from, uniformly.
+#[derive(Clone, Debug)]
+pub struct PopulationConfig {
+    pub indexers: usize,
+    /// Median response latency, in ms. Latencies are log-normally distributed around it.
+    pub median_latency_ms: (f64, f64),
+    /// Standard deviation of the log of the response latency.
+    pub latency_sigma: f64,
+    pub success_rate: (f64, f64),
+    /// Fee per query, in GRT.
+    pub fee_grt: (f64, f64),
+    pub blocks_behind: (u64, u64),
+}
+
+impl Default for PopulationConfig {
+    fn default() -> Self {
+        Self {
+            indexers: 20,
+            median_latency_ms: (50.0, 1_000.0),
+            latency_sigma: 0.5,
+            success_rate: (0.8, 1.0),
+            fee_grt: (0.0, 40e-6),
+            blocks_behind: (0, 600),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct SimIndexer {
+    pub id: Address,
+    pub median_latency_ms: f64,
+    pub latency_sigma: f64,
+    pub success_rate: f64,
+    pub fee_grt: f64,
+    pub blocks_behind: u64,
+}
+
+impl SimIndexer {
+    /// Sample the outcome of a request: success and latency in ms.
+    fn respond(&self, rng: &mut SmallRng) -> (bool, u16) {
+        // Box-Muller transform of uniform samples into a standard normal sample.
+        let z = (-2.0 * (1.0 - rng.gen::<f64>()).ln()).sqrt()
+            * (2.0 * std::f64::consts::PI * rng.gen::<f64>()).cos();
+        let latency_ms = self.median_latency_ms * (self.latency_sigma * z).exp();
+        let latency_ms = latency_ms.clamp(1.0, u16::MAX as f64) as u16;
+        (rng.gen_bool(self.success_rate), latency_ms)
+    }
+}
+
+/// Generate an indexer population from the seed.
+pub fn population(config: &PopulationConfig, seed: u64) -> Vec<SimIndexer> {
+    let mut rng = SmallRng::seed_from_u64(seed);
+    let mut sample = |(min, max): (f64, f64)| min + (max - min) * rng.gen::<f64>();
+    let indexers: Vec<(f64, f64, f64, f64)> = (0..config.indexers)
+        .map(|_| {
+            (
+                sample(config.median_latency_ms),
+                sample(config.success_rate),
+                sample(config.fee_grt),
+                sample((config.blocks_behind.0 as f64, config.blocks_behind.1 as f64)),
+            )
+        })
+        .collect();
+    indexers
+        .into_iter()
+        .enumerate()
+        .map(
+            |(index, (median_latency_ms, success_rate, fee_grt, blocks_behind))| SimIndexer {
+                id: Address::left_padding_from(&(index as u64 + 1).to_be_bytes()),
+                median_latency_ms,
+                latency_sigma: config.latency_sigma,
+                success_rate: success_rate.clamp(0.0, 1.0),
+                fee_grt,
+                blocks_behind: blocks_behind as u64,
+            },
+        )
+        .collect()
+}
+
+#[derive(Clone, Debug)]
+pub struct Workload {
+    pub queries_per_second: u32,
+    /// Simulated duration, in seconds.
+    pub duration_secs: u32,
+    pub query: String,
+    pub grt_per_usd: f64,
+}
+
+impl Default for Workload {
+    fn default() -> Self {
+        Self {
+            queries_per_second: 10,
+            duration_secs: 600,
+            query: "{ tokens(first: 10) { id } }".to_string(),
+            grt_per_usd: 1.0,
+        }
+    }
+}
+
+/// Parameters swept by the simulation.
+#[derive(Clone, Debug)]
+pub struct Params {
+    /// Maximum number of indexers selected per round, in `1..=5`.
+    pub selection_limit: usize,
+    pub seconds_behind_cutoff: u32,
+    pub query_fees_target_usd: f64,
+}
+
+#[derive(Clone, Debug)]
+pub struct Outcome {
+    pub params: Params,
+    pub queries: u64,
+    pub success_rate: f64,
+    /// Latency percentiles of successful queries, in ms.
+    pub latency_p50_ms: u64,
+    pub latency_p99_ms: u64,
+    pub avg_fees_usd: f64,
+    /// Minimum indexer fees set by the budget controller at the end of the simulation.
+    pub min_indexer_fees_usd: f64,
+}
+
+impl Outcome {
+    pub const CSV_HEADER: &'static str = "selection_limit,seconds_behind_cutoff,query_fees_target_usd,queries,success_rate,latency_p50_ms,latency_p99_ms,avg_fees_usd,min_indexer_fees_usd";
+
+    pub fn csv_row(&self) -> String {
+        format!(
+            "{},{},{},{},{:.4},{},{},{:.8},{:.8}",
+            self.params.selection_limit,
+            self.params.seconds_behind_cutoff,
+            self.params.query_fees_target_usd,
+            self.queries,
+            self.success_rate,
+            self.latency_p50_ms,
+            self.latency_p99_ms,
+            self.avg_fees_usd,
+            self.min_indexer_fees_usd,
+        )
+    }
+}
+
+/// Run the workload against the population. Each query is tried in rounds, like client queries,
+/// until an indexer succeeds or no candidates remain. Rounds last until the first success, or
+/// until the slowest failure.
+pub fn simulate(
+    population: &[SimIndexer],
+    workload: &Workload,
+    params: &Params,
+    seed: u64,
+) -> anyhow::Result<Outcome> {
+    let mut rng = SmallRng::seed_from_u64(seed);
+    let deployment: DeploymentId = DEPLOYMENT.parse()?;
+    let agora_context = AgoraContext::new(&workload.query, "")
+        .map_err(|err| anyhow::anyhow!("invalid query: {err}"))?;
+    let block_requirements = BlockRequirements {
+        range: None,
+        number_gte: None,
+        latest: true,
+        timestamps: Default::default(),
+    };
+    let indexings = indexings(population, deployment)?;
+    let mut snapshots: HashMap<(Address, DeploymentId), Snapshot> = population
+        .iter()
+        .map(|indexer| {
+            let snapshot = Snapshot {
+                latest_block: Some(CHAIN_HEAD - indexer.blocks_behind),
+                ..Default::default()
+            };
+            ((indexer.id, deployment), snapshot)
+        })
+        .collect();
+    let indexers: HashMap<Address, &SimIndexer> = population.iter().map(|i| (i.id, i)).collect();
+
+    let query_fees_target = USD(NotNan::new(params.query_fees_target_usd)?);
+    let mut controller = Controller::new(query_fees_target);
+    let mut min_indexer_fees = query_fees_target;
+    let one_grt = 1e18;
+    let budget = (params.query_fees_target_usd * workload.grt_per_usd * one_grt) as u128;
+
+    let mut queries = 0_u64;
+    let mut latencies: Vec<u64> = Vec::new();
+    let mut total_fees_usd = 0.0;
+    for _ in 0..workload.duration_secs {
+        for _ in 0..workload.queries_per_second {
+            let (mut candidates, _) = build_candidates_list(
+                &snapshots,
+                &agora_context,
+                budget,
+                CHAIN_HEAD,
+                BLOCKS_PER_MINUTE,
+                &block_requirements,
+                &[deployment],
+                indexings.clone(),
+                params.seconds_behind_cutoff,
+            );
+            let min_fees = *min_indexer_fees.0 * workload.grt_per_usd * one_grt;
+
+            let mut latency_ms = 0_u64;
+            let mut fees_grt = 0.0;
+            let mut success = false;
+            while !success {
+                let selections = select(&candidates, params.selection_limit);
+                if selections.is_empty() {
+                    break;
+                }
+                let mut first_success: Option<u16> = None;
+                let mut slowest: u16 = 0;
+                for (indexer, fee) in &selections {
+                    fees_grt += indexer_request_fee(*fee, budget, min_fees, selections.len())
+                        as f64
+                        / one_grt;
+                    let sim_indexer = indexers[indexer];
+                    let (ok, latency) = sim_indexer.respond(&mut rng);
+                    snapshots
+                        .get_mut(&(*indexer, deployment))
+                        .unwrap()
+                        .feedback(ok, latency, Some(CHAIN_HEAD - sim_indexer.blocks_behind));
+                    slowest = slowest.max(latency);
+                    if ok {
+                        first_success = Some(first_success.map_or(latency, |l| l.min(latency)));
+                    }
+                }
+                latency_ms += first_success.unwrap_or(slowest) as u64;
+                success = first_success.is_some();
+                candidates.retain(|c| !selections.iter().any(|(id, _)| *id == c.id));
+            }
+
+            queries += 1;
+            if success {
+                latencies.push(latency_ms);
+            }
+            let fees_usd = fees_grt / workload.grt_per_usd;
+            total_fees_usd += fees_usd;
+            controller.add_recent_fees(USD(NotNan::new(fees_usd)?));
+        }
+
+        for snapshot in snapshots.values_mut() {
+            snapshot.decay();
+        }
+        if workload.queries_per_second > 0 {
+            min_indexer_fees = controller.control_variable();
+        }
+    }
+
+    latencies.sort_unstable();
+    let percentile = |p: f64| -> u64 {
+        let index = ((latencies.len() as f64 * p).ceil() as usize).saturating_sub(1);
+        latencies.get(index).copied().unwrap_or_default()
+    };
+    Ok(Outcome {
+        params: params.clone(),
+        queries,
+        success_rate: latencies.len() as f64 / queries.max(1) as f64,
+        latency_p50_ms: percentile(0.5),
+        latency_p99_ms: percentile(0.99),
+        avg_fees_usd: total_fees_usd / queries.max(1) as f64,
+        min_indexer_fees_usd: *min_indexer_fees.0,
+    })
+}
+
+/// Simulate every combination of the swept parameters.
+pub fn sweep(
+    population: &[SimIndexer],
+    workload: &Workload,
+    selection_limits: &[usize],
+    seconds_behind_cutoffs: &[u32],
+    query_fees_targets_usd: &[f64],
+    seed: u64,
+) -> anyhow::Result<Vec<Outcome>> {
+    let mut outcomes = Vec::new();
+    for &selection_limit in selection_limits {
+        for &seconds_behind_cutoff in seconds_behind_cutoffs {
+            for &query_fees_target_usd in query_fees_targets_usd {
+                let params = Params {
+                    selection_limit,
+                    seconds_behind_cutoff,
+                    query_fees_target_usd,
+                };
+                outcomes.push(simulate(population, workload, &params, seed)?);
+            }
+        }
+    }
+    Ok(outcomes)
+}
+
+/// Select candidates with the given selection limit, returning their IDs and normalized fees.
+fn select(
+    candidates: &[Candidate<Address, CandidateMetadata>],
+    limit: usize,
+) -> Vec<(Address, Normalized)> {
+    fn collect<const L: usize>(
+        selections: ArrayVec<&Candidate<Address, CandidateMetadata>, L>,
+    ) -> Vec<(Address, Normalized)> {
+        selections.into_iter().map(|c| (c.id, c.fee)).collect()
+    }
+    if candidates.is_empty() {
+        return Vec::new();
+    }
+    match limit {
+        1 => collect::<1>(indexer_selection::select(candidates)),
+        2 => collect::<2>(indexer_selection::select(candidates)),
+        3 => collect::<3>(indexer_selection::select(candidates)),
+        4 => collect::<4>(indexer_selection::select(candidates)),
+        5 => collect::<5>(indexer_selection::select(candidates)),
+        _ => panic!("selection limit must be in 1..=5"),
+    }
+}
+
+fn indexings(
+    population: &[SimIndexer],
+    deployment: DeploymentId,
+) -> anyhow::Result<HashMap<IndexingId, Result<Indexing, graph_gateway::network::ResolutionError>>>
+{
+    population
+        .iter()
+        .enumerate()
+        .map(|(index, indexer)| {
+            let cost_model = match indexer.fee_grt {
+                fee if fee > 0.0 => Some(Ptr::new(
+                    CostModel::compile(&format!("default => {fee};"), "")
+                        .map_err(|err| anyhow::anyhow!("invalid cost model: {err:?}"))?,
+                )),
+                _ => None,
+            };
+            let id = IndexingId {
+                indexer: indexer.id,
+                deployment,
+            };
+            let indexing = Indexing {
+                id,
+                chain: "mainnet".to_string(),
+                largest_allocation: indexer.id,
+                total_allocated_tokens: 1,
+                indexer: Arc::new(Indexer {
+                    id: indexer.id,
+                    url: format!("http://indexer-{index}.example/").parse()?,
+                    indexer_service_version: Version::new(1, 0, 0),
+                    graph_node_version: Version::new(0, 35, 0),
+                    tap_support: true,
+                    staked_tokens: 100_000 * 10_u128.pow(18),
+                }),
+                progress: IndexingProgress {
+                    latest_block: CHAIN_HEAD - indexer.blocks_behind,
+                    min_block: None,
+                },
+                cost_model,
+            };
+            Ok((id, Ok(indexing)))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn simulation_is_deterministic() {
+        //* Given
+        let population = population(&PopulationConfig::default(), 1);
+        let workload = Workload {
+            duration_secs: 30,
+            ..Default::default()
+        };
+        let params = Params {
+            selection_limit: 3,
+            seconds_behind_cutoff: 60 * 30,
+            query_fees_target_usd: 20e-6,
+        };
+
+        //* When
+        let a = simulate(&population, &workload, &params, 1).unwrap();
+        let b = simulate(&population, &workload, &params, 1).unwrap();
+
+        //* Then
+        assert_eq!(a.queries, 300);
+        assert!(a.success_rate > 0.9, "{a:?}");
+        assert!(
+            a.avg_fees_usd <= 3.0 * params.query_fees_target_usd,
+            "{a:?}"
+        );
+        assert_eq!(a.success_rate, b.success_rate);
+        assert_eq!(a.latency_p99_ms, b.latency_p99_ms);
+    }
+}
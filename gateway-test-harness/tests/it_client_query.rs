@@ -0,0 +1,182 @@
+use std::time::Duration;
+
+use assert_matches::assert_matches;
+use axum::http::StatusCode;
+use gateway_test_harness::{AttestationMode, Harness, MockIndexer, MockIndexerConfig};
+use serde_json::json;
+use thegraph_core::types::{DeploymentId, SubgraphId};
+
+fn test_subgraph() -> SubgraphId {
+    "DZz4kDTdmzWLWsV373w2bSmoar3umKKH9y82SUKr5qmp"
+        .parse()
+        .unwrap()
+}
+
+fn test_deployment() -> DeploymentId {
+    "QmZTy9EJHu8rfY9QbEk3z1epmmvh5XHhT2Wqhkfbyt8k9Z"
+        .parse()
+        .unwrap()
+}
+
+#[tokio::test]
+async fn query_is_served_by_indexer() {
+    //* Given
+    let data = json!({ "tokens": [{ "id": "1" }] });
+    let indexer = MockIndexer::spawn(
+        1,
+        MockIndexerConfig {
+            data: data.clone(),
+            ..Default::default()
+        },
+    )
+    .await;
+    let mut harness = Harness::builder()
+        .subgraph(&test_subgraph(), &test_deployment(), "mainnet", &[&indexer])
+        .build()
+        .await;
+
+    //* When
+    let (status, response) = harness
+        .query_subgraph(&test_subgraph(), "{ tokens { id } }")
+        .await;
+
+    //* Then
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(response["data"], data, "{response}");
+
+    let requests = indexer.requests();
+    assert_eq!(requests.len(), 1);
+    assert_eq!(requests[0].deployment, test_deployment().to_string());
+    assert!(requests[0].receipt);
+
+    let report = tokio::time::timeout(Duration::from_secs(1), harness.reports.recv())
+        .await
+        .expect("missing client request report")
+        .unwrap();
+    assert_matches!(report.result, Ok(()));
+    assert_eq!(report.indexer_requests.len(), 1);
+    assert_eq!(report.indexer_requests[0].indexer, indexer.id);
+    assert_eq!(
+        report.indexer_requests[0].receipt.allocation(),
+        indexer.allocation(&test_deployment())
+    );
+}
+
+#[tokio::test]
+async fn failed_indexer_requests_are_retried_on_other_indexers() {
+    //* Given
+    let data = json!({ "tokens": [] });
+    let failing = MockIndexer::spawn(
+        1,
+        MockIndexerConfig {
+            status: Some(StatusCode::INTERNAL_SERVER_ERROR),
+            ..Default::default()
+        },
+    )
+    .await;
+    let healthy = MockIndexer::spawn(
+        2,
+        MockIndexerConfig {
+            data: data.clone(),
+            latency: Duration::from_millis(50),
+            ..Default::default()
+        },
+    )
+    .await;
+    let harness = Harness::builder()
+        .subgraph(
+            &test_subgraph(),
+            &test_deployment(),
+            "mainnet",
+            &[&failing, &healthy],
+        )
+        .build()
+        .await;
+
+    //* When
+    let (status, response) = harness
+        .query_deployment(&test_deployment(), "{ tokens { id } }")
+        .await;
+
+    //* Then
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(response["data"], data, "{response}");
+    assert_eq!(healthy.requests().len(), 1);
+}
+
+#[tokio::test]
+async fn responses_with_bad_attestations_are_rejected() {
+    //* Given
+    let indexer = MockIndexer::spawn(
+        1,
+        MockIndexerConfig {
+            data: json!({ "tokens": [] }),
+            attestation: AttestationMode::Invalid,
+            ..Default::default()
+        },
+    )
+    .await;
+    let harness = Harness::builder()
+        .subgraph(&test_subgraph(), &test_deployment(), "mainnet", &[&indexer])
+        .build()
+        .await;
+
+    //* When
+    let (_, response) = harness
+        .query_deployment(&test_deployment(), "{ tokens { id } }")
+        .await;
+
+    //* Then
+    assert!(response.get("data").is_none(), "{response}");
+    let message = response["errors"][0]["message"]
+        .as_str()
+        .unwrap_or_default();
+    assert!(message.contains("attestation"), "{response}");
+}
+
+#[tokio::test]
+async fn quotes_are_served_without_indexer_requests() {
+    //* Given
+    let cheap = MockIndexer::spawn(
+        1,
+        MockIndexerConfig {
+            fee_grt: 0.00001,
+            ..Default::default()
+        },
+    )
+    .await;
+    let expensive = MockIndexer::spawn(
+        2,
+        MockIndexerConfig {
+            fee_grt: 0.00002,
+            ..Default::default()
+        },
+    )
+    .await;
+    let harness = Harness::builder()
+        .subgraph(
+            &test_subgraph(),
+            &test_deployment(),
+            "mainnet",
+            &[&cheap, &expensive],
+        )
+        .build()
+        .await;
+
+    //* When
+    let (status, response) = harness
+        .quote_subgraph(&test_subgraph(), "{ tokens { id } }")
+        .await;
+
+    //* Then
+    assert_eq!(status, StatusCode::OK, "{response}");
+    assert_eq!(response["candidates"].as_array().unwrap().len(), 2);
+    let fee_min = response["fees"]["min"]["grt"].as_f64().unwrap();
+    let fee_max = response["fees"]["max"]["grt"].as_f64().unwrap();
+    assert!(fee_min > 0.0, "{response}");
+    assert!(fee_min <= fee_max, "{response}");
+    assert!(response["budget"]["usd"].as_f64().unwrap() > 0.0);
+
+    assert!(cheap.requests().is_empty());
+    assert!(expensive.requests().is_empty());
+}
@@ -0,0 +1,54 @@
+use gateway_test_harness::{
+    replay::{CapturedRequest, Replay},
+    Harness, MockIndexer, MockIndexerConfig, API_KEY,
+};
+use serde_json::json;
+use thegraph_core::types::{DeploymentId, SubgraphId};
+
+#[tokio::test]
+async fn replay_against_mock_indexers() {
+    //* Given
+    let subgraph: SubgraphId = "DZz4kDTdmzWLWsV373w2bSmoar3umKKH9y82SUKr5qmp"
+        .parse()
+        .unwrap();
+    let deployment: DeploymentId = "QmZTy9EJHu8rfY9QbEk3z1epmmvh5XHhT2Wqhkfbyt8k9Z"
+        .parse()
+        .unwrap();
+    let indexer = MockIndexer::spawn(
+        1,
+        MockIndexerConfig {
+            data: json!({ "tokens": [] }),
+            ..Default::default()
+        },
+    )
+    .await;
+    let harness = Harness::builder()
+        .subgraph(&subgraph, &deployment, "mainnet", &[&indexer])
+        .build()
+        .await;
+    let replay = Replay {
+        client: reqwest::Client::new(),
+        gateway: harness.serve().await,
+        api_key: API_KEY.to_string(),
+        rate: 100.0,
+    };
+    let request = |selector: String| CapturedRequest {
+        selector,
+        query: "{ tokens { id } }".to_string(),
+        variables: None,
+    };
+    let requests = vec![
+        request(subgraph.to_string()),
+        request(deployment.to_string()),
+        request("QmInvalid".to_string()),
+    ];
+
+    //* When
+    let report = replay.run(requests).await;
+
+    //* Then
+    assert_eq!(report.latencies.len(), 3);
+    assert_eq!(report.successes, 2);
+    assert_eq!(report.errors.get("SubgraphNotFound"), Some(&1));
+    assert_eq!(indexer.requests().len(), 2);
+}
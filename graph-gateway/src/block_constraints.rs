@@ -17,8 +17,39 @@ use graphql::{
     IntoStaticValue as _, StaticValue,
 };
 use itertools::Itertools as _;
+use semver::Version;
 use serde_json::{self, json};
 
+/// Default minimum graph-node version queried for the `parentHash` field of `_meta.block`, see
+/// `parent_hash_min_graph_node_version` in the config. Older versions fail the whole query on the
+/// unknown field.
+pub const DEFAULT_PARENT_HASH_MIN_VERSION: Version = Version::new(0, 35, 0);
+
+/// Return the `_meta.block` fields requested from an indexer running the given graph-node version,
+/// where `parentHash` is only requested from versions at or above `parent_hash_min_version`.
+pub fn probe_block_fields(
+    graph_node_version: &Version,
+    parent_hash_min_version: &Version,
+) -> &'static str {
+    common_probe_block_fields([graph_node_version], parent_hash_min_version)
+}
+
+/// Return the `_meta.block` fields supported by all the given graph-node versions, for requests
+/// that must be identical across indexers.
+pub fn common_probe_block_fields<'v>(
+    graph_node_versions: impl IntoIterator<Item = &'v Version>,
+    parent_hash_min_version: &Version,
+) -> &'static str {
+    if graph_node_versions
+        .into_iter()
+        .all(|version| version >= parent_hash_min_version)
+    {
+        "hash number timestamp parentHash"
+    } else {
+        "hash number timestamp"
+    }
+}
+
 #[derive(Debug)]
 pub struct BlockRequirements {
     /// required block range, for exact block constraints (`number` & `hash`)
@@ -27,18 +58,62 @@ pub struct BlockRequirements {
     pub number_gte: Option<BlockNumber>,
     /// does the query benefit from using the latest block (contains NumberGTE or Unconstrained)
     pub latest: bool,
+    /// block number constraints resolved for timestamp constraints (`timestamp` & `timestamp_gte`)
+    pub timestamps: BTreeMap<BlockConstraint, BlockConstraint>,
 }
 
+/// Resolve the block requirements of the query. The blocks for `timestamp` constraints must be
+/// given in `timestamp_blocks`, since they may not be resolvable using the chain cache (see
+/// `timestamp_constraints`). The blocks for `timestamp_gte` constraints are resolved using the
+/// chain cache.
 pub fn resolve_block_requirements(
     chain: &Chain,
     context: &Context,
     manifest_min_block: BlockNumber,
+    timestamp_blocks: &BTreeMap<u64, BlockNumber>,
 ) -> Result<BlockRequirements, Error> {
-    let constraints = block_constraints(context).unwrap_or_default();
+    let constraints = block_constraints(context)?;
+
+    let mut timestamps = BTreeMap::new();
+    for constraint in &constraints {
+        let resolved = match constraint {
+            BlockConstraint::Timestamp(timestamp) => timestamp_blocks
+                .get(timestamp)
+                .map(|number| BlockConstraint::Number(*number))
+                .ok_or(Error::BlockNotFound(UnresolvedBlock::WithTimestamp(
+                    *timestamp,
+                )))?,
+            BlockConstraint::TimestampGTE(timestamp) => {
+                check_timestamp(*timestamp)?;
+                // Any block at or after the earliest known block produced at or after the
+                // timestamp satisfies the constraint. If the chain head was produced before the
+                // timestamp, any block after the chain head does.
+                match chain.timestamp_bounds(timestamp.saturating_sub(1)) {
+                    (_, Some(after)) => BlockConstraint::NumberGTE(after.number),
+                    (Some(head), None) => BlockConstraint::NumberGTE(head.number + 1),
+                    (None, None) => {
+                        return Err(Error::BlockNotFound(UnresolvedBlock::WithTimestamp(
+                            *timestamp,
+                        )))
+                    }
+                }
+            }
+            _ => continue,
+        };
+        timestamps.insert(constraint.clone(), resolved);
+    }
+    let constraints: BTreeSet<BlockConstraint> = constraints
+        .into_iter()
+        .map(|c| timestamps.get(&c).cloned().unwrap_or(c))
+        .collect();
 
     let latest = constraints.iter().any(|c| match c {
-        BlockConstraint::Unconstrained | BlockConstraint::NumberGTE(_) => true,
-        BlockConstraint::Hash(_) | BlockConstraint::Number(_) => false,
+        BlockConstraint::Unconstrained
+        | BlockConstraint::NumberGTE(_)
+        | BlockConstraint::TimestampGTE(_) => true,
+        BlockConstraint::Hash(_) | BlockConstraint::Number(_) | BlockConstraint::Timestamp(_) => {
+            false
+        }
     });
     let number_gte = constraints
         .iter()
@@ -51,7 +126,10 @@ pub fn resolve_block_requirements(
     let exact_constraints: Vec<u64> = constraints
         .iter()
         .filter_map(|c| match c {
-            BlockConstraint::Unconstrained | BlockConstraint::NumberGTE(_) => None,
+            BlockConstraint::Unconstrained
+            | BlockConstraint::NumberGTE(_)
+            | BlockConstraint::Timestamp(_)
+            | BlockConstraint::TimestampGTE(_) => None,
             BlockConstraint::Number(number) => Some(*number),
             // resolving block hashes is not guaranteed
             BlockConstraint::Hash(hash) => chain
@@ -79,9 +157,33 @@ pub fn resolve_block_requirements(
         range: min_block.map(|min| (min, max_block.unwrap())),
         number_gte,
         latest,
+        timestamps,
     })
 }
 
+/// Return the timestamps of the `timestamp` constraints in the query.
+pub fn timestamp_constraints(context: &Context) -> Result<BTreeSet<u64>, Error> {
+    let timestamps = block_constraints(context)?
+        .into_iter()
+        .filter_map(|c| match c {
+            BlockConstraint::Timestamp(timestamp) => Some(timestamp),
+            _ => None,
+        })
+        .collect();
+    Ok(timestamps)
+}
+
+/// Reject timestamps that are in the future, since no block can be resolved for them yet.
+pub fn check_timestamp(timestamp: u64) -> Result<(), Error> {
+    let now = unix_timestamp() / 1_000;
+    if timestamp > now {
+        return Err(Error::BadQuery(anyhow!(
+            "requested block timestamp {timestamp}, after current time {now}"
+        )));
+    }
+    Ok(())
+}
+
 fn block_constraints(context: &Context) -> Result<BTreeSet<BlockConstraint>, Error> {
     let mut constraints = BTreeSet::new();
     let vars = &context.variables;
@@ -103,17 +205,16 @@ fn block_constraints(context: &Context) -> Result<BTreeSet<BlockConstraint>, Err
                     .collect();
                 (&query.selection_set, defaults)
             }
+            // Queries using GraphQL features not supported here are left unconstrained.
             OperationDefinition::Query(_)
             | OperationDefinition::Mutation(_)
-            | OperationDefinition::Subscription(_) => {
-                return Err(Error::BadQuery(anyhow!("unsupported GraphQL features")))
-            }
+            | OperationDefinition::Subscription(_) => return Ok(BTreeSet::new()),
         };
         for selection in &selection_set.items {
             let selection_field = match selection {
                 Selection::Field(field) => field,
                 Selection::FragmentSpread(_) | Selection::InlineFragment(_) => {
-                    return Err(Error::BadQuery(anyhow!("unsupported GraphQL features")))
+                    return Ok(BTreeSet::new())
                 }
             };
             let constraint = match selection_field
@@ -137,7 +238,8 @@ pub fn rewrite_query<'q>(
     ctx: &Context<'q>,
     requirements: &BlockRequirements,
     blocks_behind: u64,
-) -> String {
+    probe_fields: &str,
+) -> Result<String, Error> {
     let mut buf: String = Default::default();
     for fragment in &ctx.fragments {
         write!(&mut buf, "{}", fragment).unwrap();
@@ -163,102 +265,120 @@ pub fn rewrite_query<'q>(
             Some(block.clone())
         });
 
-        let serialize_field =
-            |buf: &mut String,
-             field: &Field<'q, &'q str>,
-             defaults: &BTreeMap<String, StaticValue>| {
-                buf.push_str("  ");
-                if let Some(alias) = field.alias {
-                    write!(buf, "{alias}: ").unwrap();
-                }
-                write!(buf, "{}", field.name).unwrap();
-                for directive in &field.directives {
-                    write!(buf, " {}", directive).unwrap();
-                }
-                buf.push_str("(block: ");
-                if let Some(constraint) = field
-                    .arguments
-                    .iter()
-                    .find(|(n, _)| *n == "block")
-                    .and_then(|(_, field)| field_constraint(&ctx.variables, defaults, field).ok())
-                {
-                    match (constraint, &latest_block) {
-                        (BlockConstraint::Hash(hash), _) => {
-                            write!(buf, "{{ hash: \"{hash}\" }}").unwrap();
-                        }
-                        (BlockConstraint::Number(number), _) => {
-                            match chain.find(&UnresolvedBlock::WithNumber(number)) {
-                                Some(Block { hash, .. }) => {
-                                    write!(buf, "{{ hash: \"{hash}\" }}").unwrap();
-                                }
-                                None => {
-                                    write!(buf, "{{ number: {number} }}").unwrap();
-                                }
-                            }
-                        }
-                        (_, Some(Block { hash, .. })) => {
-                            write!(buf, "{{ hash: \"{hash}\" }}",).unwrap();
-                        }
-                        (BlockConstraint::NumberGTE(number), None) => {
-                            write!(buf, "{{ number_gte: {number} }}").unwrap();
-                        }
-                        (BlockConstraint::Unconstrained, None) => {
-                            write!(buf, "null").unwrap();
-                        }
-                    };
-                } else if let Some(block) = &latest_block {
-                    write!(buf, "{{ hash: \"{}\" }}", block.hash).unwrap();
-                } else {
-                    buf.push_str("null");
-                }
-                for (name, value) in &field.arguments {
-                    if *name != "block" {
-                        write!(buf, ", {name}: {value}").unwrap();
+        let serialize_field = |buf: &mut String,
+                               field: &Field<'q, &'q str>,
+                               defaults: &BTreeMap<String, StaticValue>|
+         -> Result<(), Error> {
+            buf.push_str("  ");
+            if let Some(alias) = field.alias {
+                write!(buf, "{alias}: ").unwrap();
+            }
+            write!(buf, "{}", field.name).unwrap();
+            for directive in &field.directives {
+                write!(buf, " {}", directive).unwrap();
+            }
+            buf.push_str("(block: ");
+            if let Some(constraint) = field
+                .arguments
+                .iter()
+                .find(|(n, _)| *n == "block")
+                .and_then(|(_, field)| field_constraint(&ctx.variables, defaults, field).ok())
+            {
+                let constraint = requirements
+                    .timestamps
+                    .get(&constraint)
+                    .cloned()
+                    .unwrap_or(constraint);
+                match (constraint, &latest_block) {
+                    (BlockConstraint::Hash(hash), _) => {
+                        write!(buf, "{{ hash: \"{hash}\" }}").unwrap();
                     }
-                }
-                buf.push(')');
-                if !field.selection_set.items.is_empty() {
-                    buf.push_str(" {\n");
-                    for selection in &field.selection_set.items {
-                        match selection {
-                            Selection::Field(field) => {
-                                write!(buf, "    {}", field).unwrap();
-                            }
-                            Selection::FragmentSpread(spread) => {
-                                write!(buf, "    {}", spread).unwrap();
+                    (BlockConstraint::Number(number), _) => {
+                        match chain.find(&UnresolvedBlock::WithNumber(number)) {
+                            Some(Block { hash, .. }) => {
+                                write!(buf, "{{ hash: \"{hash}\" }}").unwrap();
                             }
-                            Selection::InlineFragment(fragment) => {
-                                write!(buf, "    {}", fragment).unwrap();
+                            None => {
+                                write!(buf, "{{ number: {number} }}").unwrap();
                             }
-                        };
+                        }
+                    }
+                    (_, Some(Block { hash, .. })) => {
+                        write!(buf, "{{ hash: \"{hash}\" }}",).unwrap();
+                    }
+                    (BlockConstraint::NumberGTE(number), None) => {
+                        write!(buf, "{{ number_gte: {number} }}").unwrap();
                     }
-                    buf.push_str("  }");
+                    (BlockConstraint::Unconstrained, None) => {
+                        write!(buf, "null").unwrap();
+                    }
+                    // Timestamp constraints are resolved by `resolve_block_requirements`,
+                    // unless the query uses GraphQL features it doesn't support.
+                    (BlockConstraint::Timestamp(_) | BlockConstraint::TimestampGTE(_), None) => {
+                        return Err(Error::BadQuery(anyhow!(
+                            "block timestamp constraints are not supported in queries using {}",
+                            unsupported_construct(ctx).unwrap_or("unsupported GraphQL features"),
+                        )));
+                    }
+                };
+            } else if let Some(block) = &latest_block {
+                write!(buf, "{{ hash: \"{}\" }}", block.hash).unwrap();
+            } else {
+                buf.push_str("null");
+            }
+            for (name, value) in &field.arguments {
+                if *name != "block" {
+                    write!(buf, ", {name}: {value}").unwrap();
                 }
-                buf.push('\n');
-            };
-        let serialize_selection_set =
-            |buf: &mut String,
-             selection_set: &SelectionSet<'q, &'q str>,
-             defaults: &BTreeMap<String, StaticValue>| {
-                buf.push_str("{\n");
-                for selection in &selection_set.items {
+            }
+            buf.push(')');
+            if !field.selection_set.items.is_empty() {
+                buf.push_str(" {\n");
+                for selection in &field.selection_set.items {
                     match selection {
-                        Selection::Field(field) => serialize_field(buf, field, defaults),
+                        Selection::Field(field) => {
+                            write!(buf, "    {}", field).unwrap();
+                        }
                         Selection::FragmentSpread(spread) => {
-                            write!(buf, "  {}", spread).unwrap();
+                            write!(buf, "    {}", spread).unwrap();
                         }
                         Selection::InlineFragment(fragment) => {
-                            write!(buf, "  {}", fragment).unwrap()
+                            write!(buf, "    {}", fragment).unwrap();
                         }
                     };
                 }
-                buf.push_str("  _gateway_probe_: _meta { block { hash number timestamp } }\n}\n");
-            };
+                buf.push_str("  }");
+            }
+            buf.push('\n');
+            Ok(())
+        };
+        let serialize_selection_set = |buf: &mut String,
+                                       selection_set: &SelectionSet<'q, &'q str>,
+                                       defaults: &BTreeMap<String, StaticValue>|
+         -> Result<(), Error> {
+            buf.push_str("{\n");
+            for selection in &selection_set.items {
+                match selection {
+                    Selection::Field(field) => serialize_field(buf, field, defaults)?,
+                    Selection::FragmentSpread(spread) => {
+                        write!(buf, "  {}", spread).unwrap();
+                    }
+                    Selection::InlineFragment(fragment) => write!(buf, "  {}", fragment).unwrap(),
+                };
+            }
+            writeln!(
+                buf,
+                "  _gateway_probe_: _meta {{ block {{ {} }} }}\n}}",
+                probe_fields,
+            )
+            .unwrap();
+            Ok(())
+        };
         let serialize_operation =
-            |buf: &mut String, operation: &OperationDefinition<'q, &'q str>| {
+            |buf: &mut String, operation: &OperationDefinition<'q, &'q str>| -> Result<(), Error> {
                 match operation {
                     OperationDefinition::SelectionSet(selection_set) => {
-                        serialize_selection_set(buf, selection_set, &BTreeMap::default());
+                        serialize_selection_set(buf, selection_set, &BTreeMap::default())?;
                     }
                     OperationDefinition::Query(query) => {
                         buf.push_str("query");
@@ -282,17 +402,42 @@ pub fn rewrite_query<'q>(
                                 Some((d.name.to_string(), d.default_value.as_ref()?.to_graphql()))
                             })
                             .collect::<BTreeMap<String, StaticValue>>();
-                        serialize_selection_set(buf, &query.selection_set, &defaults);
+                        serialize_selection_set(buf, &query.selection_set, &defaults)?;
                     }
                     OperationDefinition::Mutation(_) | OperationDefinition::Subscription(_) => (),
                 };
+                Ok(())
             };
         for operation in &ctx.operations {
-            serialize_operation(&mut buf, operation);
+            serialize_operation(&mut buf, operation)?;
         }
     }
 
-    serde_json::to_string(&json!({ "query": buf, "variables": ctx.variables })).unwrap()
+    Ok(serde_json::to_string(&json!({ "query": buf, "variables": ctx.variables })).unwrap())
+}
+
+/// Return the GraphQL construct used by the query for which `block_constraints` leaves the query
+/// unconstrained, if any.
+fn unsupported_construct(ctx: &Context<'_>) -> Option<&'static str> {
+    ctx.operations.iter().find_map(|operation| {
+        let selection_set = match operation {
+            OperationDefinition::SelectionSet(selection_set) => selection_set,
+            OperationDefinition::Query(query) if query.directives.is_empty() => {
+                &query.selection_set
+            }
+            OperationDefinition::Query(_) => return Some("query directives"),
+            OperationDefinition::Mutation(_) => return Some("mutations"),
+            OperationDefinition::Subscription(_) => return Some("subscriptions"),
+        };
+        selection_set
+            .items
+            .iter()
+            .find_map(|selection| match selection {
+                Selection::Field(_) => None,
+                Selection::FragmentSpread(_) => Some("top-level fragment spreads"),
+                Selection::InlineFragment(_) => Some("top-level inline fragments"),
+            })
+    })
 }
 
 fn contains_introspection(ctx: &Context<'_>) -> bool {
@@ -352,6 +497,14 @@ fn parse_constraint<'c, T: Text<'c>>(
                 n.map(BlockConstraint::NumberGTE)
                     .unwrap_or(BlockConstraint::Unconstrained)
             }),
+            ("timestamp", timestamp) => parse_number(timestamp, vars, defaults).map(|t| {
+                t.map(BlockConstraint::Timestamp)
+                    .unwrap_or(BlockConstraint::Unconstrained)
+            }),
+            ("timestamp_gte", timestamp) => parse_number(timestamp, vars, defaults).map(|t| {
+                t.map(BlockConstraint::TimestampGTE)
+                    .unwrap_or(BlockConstraint::Unconstrained)
+            }),
             _ => Err(anyhow!("unexpected block constraint: {}", k.as_ref())),
         },
     }
@@ -407,10 +560,12 @@ mod tests {
     use std::iter::FromIterator as _;
 
     use alloy_primitives::{hex, Address};
-    use gateway_framework::blocks::Block;
+    use gateway_framework::{blocks::Block, chain::BlockSource};
 
     use super::*;
 
+    const PROBE_FIELDS: &str = "hash number timestamp parentHash";
+
     #[test]
     fn tests() {
         use BlockConstraint::*;
@@ -456,6 +611,18 @@ mod tests {
                 "query($b: Block_height = {number_gte:0}) { a(block:$b) }",
                 Ok(vec![NumberGTE(0)]),
             ),
+            (
+                "{ a(block:{timestamp:1700000000}) b(block:{timestamp_gte:1700000012}) }",
+                Ok(vec![Timestamp(1700000000), TimestampGTE(1700000012)]),
+            ),
+            (
+                "query($t: Int) { a(block:{timestamp:$t}) }",
+                Ok(vec![Unconstrained]),
+            ),
+            (
+                "{ a(block:{timestamp:\"today\"}) }",
+                Err("bad query: malformed block number"),
+            ),
         ];
         for (query, expected) in tests {
             let context = Context::new(query, "").unwrap();
@@ -479,6 +646,70 @@ mod tests {
         }
     }
 
+    #[test]
+    fn probe_fields_by_graph_node_version() {
+        //* Given
+        let chain = Chain::default();
+        let context = Context::new("{ a }", "").unwrap();
+        let requirements =
+            resolve_block_requirements(&chain, &context, 0, &BTreeMap::new()).unwrap();
+
+        //* When
+        let query = |version: Version| {
+            let fields = probe_block_fields(&version, &DEFAULT_PARENT_HASH_MIN_VERSION);
+            let request = rewrite_query(&chain, &context, &requirements, 0, fields).unwrap();
+            let doc = serde_json::from_str::<serde_json::Value>(&request).unwrap();
+            doc["query"].as_str().unwrap().to_string()
+        };
+
+        //* Then
+        assert_eq!(
+            query(Version::new(0, 34, 1)),
+            "{\n  a(block: null)\n  _gateway_probe_: _meta { block { hash number timestamp } }\n}\n",
+        );
+        assert_eq!(
+            query(Version::new(0, 35, 0)),
+            "{\n  a(block: null)\n  _gateway_probe_: _meta { block { hash number timestamp parentHash } }\n}\n",
+        );
+        assert_eq!(
+            probe_block_fields(&Version::new(0, 35, 0), &Version::new(0, 36, 0)),
+            "hash number timestamp",
+        );
+    }
+
+    #[test]
+    fn common_probe_fields_for_mixed_graph_node_versions() {
+        //* Given
+        let chain = Chain::default();
+        let context = Context::new("{ a(block: { number: 10 }) }", "").unwrap();
+        let requirements =
+            resolve_block_requirements(&chain, &context, 0, &BTreeMap::new()).unwrap();
+        let versions = [Version::new(0, 34, 1), Version::new(0, 35, 0)];
+        let query = |fields: &str| rewrite_query(&chain, &context, &requirements, 0, fields);
+
+        //* When
+        let common_fields = common_probe_block_fields(&versions, &DEFAULT_PARENT_HASH_MIN_VERSION);
+        let per_version_queries: Vec<String> = versions
+            .iter()
+            .map(|version| {
+                query(probe_block_fields(
+                    version,
+                    &DEFAULT_PARENT_HASH_MIN_VERSION,
+                ))
+                .unwrap()
+            })
+            .collect();
+
+        //* Then
+        assert_eq!(common_fields, "hash number timestamp");
+        assert_ne!(per_version_queries[0], per_version_queries[1]);
+        assert_eq!(query(common_fields).unwrap(), per_version_queries[0]);
+        assert_eq!(
+            common_probe_block_fields(&versions[1..], &DEFAULT_PARENT_HASH_MIN_VERSION),
+            "hash number timestamp parentHash",
+        );
+    }
+
     #[test]
     fn query_rewrite() {
         let mut chain = Chain::default();
@@ -487,19 +718,21 @@ mod tests {
             Block {
                 hash: hex!("0000000000000000000000000000000000000000000000000000000000000000")
                     .into(),
+                parent_hash: None,
                 number: 123,
                 timestamp: now - 1,
             },
-            Address::default(),
+            BlockSource::Indexer(Address::default()),
         );
         chain.insert(
             Block {
                 hash: hex!("0000000000000000000000000000000000000000000000000000000000000001")
                     .into(),
+                parent_hash: None,
                 number: 124,
                 timestamp: now,
             },
-            Address::default(),
+            BlockSource::Indexer(Address::default()),
         );
 
         let tests = [
@@ -512,8 +745,9 @@ mod tests {
                     latest: true,
                     number_gte: None,
                     range: Some((123, 123)),
+                    timestamps: BTreeMap::new(),
                 },
-                "{\n  bundle0: bundle(block: { hash: \"0x0000000000000000000000000000000000000000000000000000000000000000\" }, id: \"1\") {\n    ethPriceUSD\n  }\n  bundle1: bundle(block: { hash: \"0x0000000000000000000000000000000000000000000000000000000000000001\" }, id: \"1\") {\n    ethPriceUSD\n  }\n  _gateway_probe_: _meta { block { hash number timestamp } }\n}\n",
+                "{\n  bundle0: bundle(block: { hash: \"0x0000000000000000000000000000000000000000000000000000000000000000\" }, id: \"1\") {\n    ethPriceUSD\n  }\n  bundle1: bundle(block: { hash: \"0x0000000000000000000000000000000000000000000000000000000000000001\" }, id: \"1\") {\n    ethPriceUSD\n  }\n  _gateway_probe_: _meta { block { hash number timestamp parentHash } }\n}\n",
             ),
             (
                 r#"{
@@ -523,8 +757,9 @@ mod tests {
                     latest: true,
                     number_gte: None,
                     range: Some((125, 125)),
+                    timestamps: BTreeMap::new(),
                 },
-                "{\n  bundle0: bundle(block: { number: 125 }, id: \"1\") {\n    ethPriceUSD\n  }\n  _gateway_probe_: _meta { block { hash number timestamp } }\n}\n",
+                "{\n  bundle0: bundle(block: { number: 125 }, id: \"1\") {\n    ethPriceUSD\n  }\n  _gateway_probe_: _meta { block { hash number timestamp parentHash } }\n}\n",
             ),
             (
                 r#"{ bundle(block:{number_gte:125}) { ethPriceUSD } }"#,
@@ -532,8 +767,9 @@ mod tests {
                     latest: true,
                     number_gte: Some(125),
                     range: None,
+                    timestamps: BTreeMap::new(),
                 },
-                "{\n  bundle(block: { number_gte: 125 }) {\n    ethPriceUSD\n  }\n  _gateway_probe_: _meta { block { hash number timestamp } }\n}\n",
+                "{\n  bundle(block: { number_gte: 125 }) {\n    ethPriceUSD\n  }\n  _gateway_probe_: _meta { block { hash number timestamp parentHash } }\n}\n",
             ),
             (
                 r#"query GetTopSales {
@@ -545,8 +781,9 @@ mod tests {
                     latest: true,
                     number_gte: None,
                     range: None,
+                    timestamps: BTreeMap::new(),
                 },
-                "query GetTopSales {\n  events(block: { hash: \"0x0000000000000000000000000000000000000000000000000000000000000001\" }, where: {type: \"Sale\"}, first: 1, orderBy: value, orderDirection: desc) {\n    type\n  }\n  _gateway_probe_: _meta { block { hash number timestamp } }\n}\n",
+                "query GetTopSales {\n  events(block: { hash: \"0x0000000000000000000000000000000000000000000000000000000000000001\" }, where: {type: \"Sale\"}, first: 1, orderBy: value, orderDirection: desc) {\n    type\n  }\n  _gateway_probe_: _meta { block { hash number timestamp parentHash } }\n}\n",
             ),
             (
                 r#"
@@ -564,14 +801,16 @@ mod tests {
                     latest: true,
                     number_gte: None,
                     range: None,
+                    timestamps: BTreeMap::new(),
                 },
-                "fragment Foo on Delegation {\n  id\n}\n{\n  delegations(block: { hash: \"0x0000000000000000000000000000000000000000000000000000000000000001\" }, first: 1) {\n    delegator\n    ...Foo\n  }\n  _gateway_probe_: _meta { block { hash number timestamp } }\n}\n",
+                "fragment Foo on Delegation {\n  id\n}\n{\n  delegations(block: { hash: \"0x0000000000000000000000000000000000000000000000000000000000000001\" }, first: 1) {\n    delegator\n    ...Foo\n  }\n  _gateway_probe_: _meta { block { hash number timestamp parentHash } }\n}\n",
             ),
         ];
 
         for (client_query, requirements, expected_indexer_query) in tests {
             let context = Context::new(client_query, "").unwrap();
-            let indexer_request = rewrite_query(&chain, &context, &requirements, 0);
+            let indexer_request =
+                rewrite_query(&chain, &context, &requirements, 0, PROBE_FIELDS).unwrap();
             let doc = serde_json::from_str::<serde_json::Value>(&indexer_request).unwrap();
             let doc = doc
                 .as_object()
@@ -582,4 +821,209 @@ mod tests {
             assert_eq!(doc, expected_indexer_query);
         }
     }
+
+    #[test]
+    fn query_rewrite_skips_unresolved_forks() {
+        let mut chain = Chain::default();
+        let now = unix_timestamp() / 1_000;
+        let block = |number: BlockNumber, hash: u8, parent_hash: u8| Block {
+            hash: BlockHash::with_last_byte(hash),
+            parent_hash: Some(BlockHash::with_last_byte(parent_hash)),
+            number,
+            timestamp: now,
+        };
+        // Competing blocks at 124, without consensus on either of them.
+        chain.insert(block(123, 1, 0), BlockSource::Indexer(Address::default()));
+        chain.insert(block(124, 2, 1), BlockSource::Indexer(Address::default()));
+        chain.insert(
+            block(124, 3, 1),
+            BlockSource::Indexer(Address::repeat_byte(1)),
+        );
+        assert!(chain.resolve_forks().is_empty());
+
+        let tests = [
+            (
+                r#"{ bundle(block:{number:124}) { ethPriceUSD } }"#,
+                BlockRequirements {
+                    latest: false,
+                    number_gte: None,
+                    range: Some((124, 124)),
+                    timestamps: BTreeMap::new(),
+                },
+                "{\n  bundle(block: { number: 124 }) {\n    ethPriceUSD\n  }\n  _gateway_probe_: _meta { block { hash number timestamp parentHash } }\n}\n",
+            ),
+            (
+                r#"{ bundle { ethPriceUSD } }"#,
+                BlockRequirements {
+                    latest: true,
+                    number_gte: None,
+                    range: None,
+                    timestamps: BTreeMap::new(),
+                },
+                "{\n  bundle(block: { hash: \"0x0000000000000000000000000000000000000000000000000000000000000001\" }) {\n    ethPriceUSD\n  }\n  _gateway_probe_: _meta { block { hash number timestamp parentHash } }\n}\n",
+            ),
+        ];
+        for (client_query, requirements, expected_indexer_query) in tests {
+            let context = Context::new(client_query, "").unwrap();
+            let indexer_request =
+                rewrite_query(&chain, &context, &requirements, 0, PROBE_FIELDS).unwrap();
+            let doc = serde_json::from_str::<serde_json::Value>(&indexer_request).unwrap();
+            let doc = doc
+                .as_object()
+                .and_then(|o| o.get("query")?.as_str())
+                .unwrap();
+            assert_eq!(doc, expected_indexer_query);
+        }
+    }
+
+    #[test]
+    fn timestamp_requirements() {
+        let mut chain = Chain::default();
+        let now = unix_timestamp() / 1_000;
+        for number in 120..=124 {
+            chain.insert(
+                Block {
+                    hash: BlockHash::with_last_byte(number as u8),
+                    parent_hash: Some(BlockHash::with_last_byte(number as u8 - 1)),
+                    number,
+                    timestamp: now - ((124 - number) * 12),
+                },
+                BlockSource::Indexer(Address::default()),
+            );
+        }
+
+        //* Given
+        let context = Context::new(
+            &format!(
+                "{{ a(block:{{timestamp:{}}}) {{ id }} b(block:{{timestamp_gte:{}}}) {{ id }} }}",
+                now - 30,
+                now - 30,
+            ),
+            "",
+        )
+        .unwrap();
+        let timestamp_blocks = BTreeMap::from([(now - 30, 121)]);
+
+        //* When
+        let requirements =
+            resolve_block_requirements(&chain, &context, 0, &timestamp_blocks).unwrap();
+        let indexer_request =
+            rewrite_query(&chain, &context, &requirements, 0, PROBE_FIELDS).unwrap();
+
+        //* Then
+        assert_eq!(
+            timestamp_constraints(&context).unwrap(),
+            BTreeSet::from([now - 30])
+        );
+        assert_eq!(requirements.range, Some((121, 121)));
+        assert_eq!(requirements.number_gte, Some(122));
+        assert!(requirements.latest);
+        let doc = serde_json::from_str::<serde_json::Value>(&indexer_request).unwrap();
+        let doc = doc
+            .as_object()
+            .and_then(|o| o.get("query")?.as_str())
+            .unwrap();
+        assert_eq!(
+            doc,
+            "{\n  a(block: { hash: \"0x0000000000000000000000000000000000000000000000000000000000000079\" }) {\n    id\n  }\n  b(block: { hash: \"0x000000000000000000000000000000000000000000000000000000000000007c\" }) {\n    id\n  }\n  _gateway_probe_: _meta { block { hash number timestamp parentHash } }\n}\n",
+        );
+    }
+
+    #[test]
+    fn timestamp_requirements_out_of_range() {
+        let chain = Chain::default();
+        let now = unix_timestamp() / 1_000;
+
+        let future = Context::new(
+            &format!("{{ a(block:{{timestamp_gte:{}}}) }}", now + 60),
+            "",
+        )
+        .unwrap();
+        let err = resolve_block_requirements(&chain, &future, 0, &BTreeMap::new()).unwrap_err();
+        assert!(matches!(err, Error::BadQuery(_)));
+
+        let unresolved =
+            Context::new(&format!("{{ a(block:{{timestamp:{}}}) }}", now - 60), "").unwrap();
+        let err = resolve_block_requirements(&chain, &unresolved, 0, &BTreeMap::new()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!("block not found: timestamp {}", now - 60)
+        );
+    }
+
+    #[test]
+    fn timestamp_gte_after_chain_head() {
+        let mut chain = Chain::default();
+        let now = unix_timestamp() / 1_000;
+        chain.insert(
+            Block {
+                hash: BlockHash::with_last_byte(2),
+                parent_hash: Some(BlockHash::with_last_byte(1)),
+                number: 124,
+                timestamp: now - 60,
+            },
+            BlockSource::Indexer(Address::default()),
+        );
+
+        let context = Context::new(
+            &format!("{{ a(block:{{timestamp_gte:{}}}) }}", now - 10),
+            "",
+        )
+        .unwrap();
+        let requirements =
+            resolve_block_requirements(&chain, &context, 0, &BTreeMap::new()).unwrap();
+        assert_eq!(requirements.number_gte, Some(125));
+    }
+
+    #[test]
+    fn timestamp_requirements_malformed() {
+        let chain = Chain::default();
+        let now = unix_timestamp() / 1_000;
+
+        // A malformed block argument must not hide the timestamp constraints of other fields.
+        let malformed = Context::new(
+            &format!(
+                "{{ a(block:{{timestamp:{}}}) b(block:{{number:\"x\"}}) }}",
+                now - 60
+            ),
+            "",
+        )
+        .unwrap();
+        assert!(matches!(
+            timestamp_constraints(&malformed),
+            Err(Error::BadQuery(_))
+        ));
+        let err = resolve_block_requirements(&chain, &malformed, 0, &BTreeMap::new()).unwrap_err();
+        assert!(matches!(err, Error::BadQuery(_)));
+
+        // Timestamps are left unresolved for queries using unsupported GraphQL features, which are
+        // rejected naming the unsupported feature.
+        let unsupported = |query: &str| {
+            let context = Context::new(query, "").unwrap();
+            let requirements =
+                resolve_block_requirements(&chain, &context, 0, &BTreeMap::new()).unwrap();
+            match rewrite_query(&chain, &context, &requirements, 0, PROBE_FIELDS) {
+                Err(Error::BadQuery(err)) => err.to_string(),
+                result => panic!("unexpected result: {result:?}"),
+            }
+        };
+        assert!(unsupported(&format!(
+            "{{ ...F a(block:{{timestamp:{}}}) }} fragment F on Query {{ b }}",
+            now - 60
+        ))
+        .contains("top-level fragment spreads"));
+        assert!(unsupported(&format!(
+            "query @skip(if: false) {{ a(block:{{timestamp_gte:{}}}) }}",
+            now - 60
+        ))
+        .contains("query directives"));
+
+        // Queries using unsupported GraphQL features without timestamp constraints are still
+        // accepted.
+        let context =
+            Context::new("{ ...F a(block:{number:1}) } fragment F on Query { b }", "").unwrap();
+        let requirements =
+            resolve_block_requirements(&chain, &context, 0, &BTreeMap::new()).unwrap();
+        assert!(rewrite_query(&chain, &context, &requirements, 0, PROBE_FIELDS).is_ok());
+    }
 }
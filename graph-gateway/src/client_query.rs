@@ -9,17 +9,20 @@ use anyhow::anyhow;
 use axum::{
     body::Bytes,
     extract::{OriginalUri, State},
-    http::{HeaderMap, Response, StatusCode},
-    Extension,
+    http::{self, HeaderMap, Response, StatusCode},
+    middleware, routing, Extension, Router,
 };
 use cost_model::{Context as AgoraContext, CostModel};
 use custom_debug::CustomDebug;
 use gateway_common::{http_ext::HttpBuilderExt as _, ptr::Ptr};
 use gateway_framework::{
-    auth::AuthSettings,
+    auth::{AuthContext, AuthSettings},
     budgets::USD,
     errors::{Error, IndexerError, IndexerErrors, MissingBlockError, UnavailableReason},
-    http::middleware::RequestId,
+    http::middleware::{
+        legacy_auth_adapter, RequestId, RequestTracingLayer, RequireAuthorizationLayer,
+        SetRequestIdLayer,
+    },
     metrics::{with_metric, METRICS},
 };
 use headers::ContentType;
@@ -28,40 +31,123 @@ use num_traits::cast::ToPrimitive as _;
 use ordered_float::NotNan;
 use prost::bytes::Buf;
 use rand::{thread_rng, Rng as _};
+use semver::Version;
 use serde::Deserialize;
 use serde_json::value::RawValue;
 use thegraph_core::types::{DeploymentId, SubgraphId};
-use tokio::sync::mpsc;
+use tokio::{
+    sync::{mpsc, oneshot},
+    task::AbortHandle,
+};
+use tower_http::cors::{self, CorsLayer};
 use tracing::{info_span, Instrument as _};
 use url::Url;
 
 use self::{
     attestation_header::GraphAttestation, context::Context, l2_forwarding::forward_request_to_l2,
     query_selector::QuerySelector, query_settings::QuerySettings,
+    response_extensions::GatewayExtensions,
 };
 use crate::{
-    block_constraints::{resolve_block_requirements, rewrite_query, BlockRequirements},
+    block_constraints::{
+        common_probe_block_fields, probe_block_fields, resolve_block_requirements, rewrite_query,
+        timestamp_constraints, BlockRequirements,
+    },
     indexer_client::IndexerResponse,
     indexing_performance,
     network::{self, DeploymentError, Indexing, IndexingId, ResolvedSubgraphInfo, SubgraphError},
-    receipts::ReceiptStatus,
+    query_complexity,
+    receipts::{Receipt, ReceiptStatus},
     reports,
 };
 
 mod attestation_header;
+mod block_timestamps;
 pub mod context;
+mod cross_check;
+pub mod fee_monitor;
 mod l2_forwarding;
 mod query_selector;
 mod query_settings;
-
-const SELECTION_LIMIT: usize = 3;
-
-#[derive(Debug, Deserialize)]
+mod quote;
+mod response_extensions;
+pub mod subscriptions;
+pub mod timeouts;
+
+/// Maximum number of indexers selected per round.
+pub const SELECTION_LIMIT: usize = 3;
+/// Candidates further behind chain head are excluded from queries for the latest block, unless no
+/// candidate is within this cutoff.
+pub const SECONDS_BEHIND_CUTOFF: u32 = 60 * 30;
+
+#[derive(Clone, Debug, Deserialize)]
 pub struct QueryBody {
     pub query: String,
     pub variables: Option<Box<RawValue>>,
 }
 
+/// Router for client queries and subscriptions, with the authorization and request tracing layers
+/// applied. The gateway nests it under `/api`.
+pub fn router(ctx: Context, auth: AuthContext, gateway_id: String) -> Router {
+    Router::new()
+        .route(
+            "/deployments/id/:deployment_id",
+            routing::post(handle_query).get(subscriptions::handle_subscriptions),
+        )
+        .route(
+            "/:api_key/deployments/id/:deployment_id",
+            routing::post(handle_query).get(subscriptions::handle_subscriptions),
+        )
+        .route(
+            "/subgraphs/id/:subgraph_id",
+            routing::post(handle_query).get(subscriptions::handle_subscriptions),
+        )
+        .route(
+            "/:api_key/subgraphs/id/:subgraph_id",
+            routing::post(handle_query).get(subscriptions::handle_subscriptions),
+        )
+        .route(
+            "/deployments/id/:deployment_id/quote",
+            routing::post(quote::handle_quote),
+        )
+        .route(
+            "/:api_key/deployments/id/:deployment_id/quote",
+            routing::post(quote::handle_quote),
+        )
+        .route(
+            "/subgraphs/id/:subgraph_id/quote",
+            routing::post(quote::handle_quote),
+        )
+        .route(
+            "/:api_key/subgraphs/id/:subgraph_id/quote",
+            routing::post(quote::handle_quote),
+        )
+        .with_state(ctx)
+        .layer(
+            // ServiceBuilder works by composing all layers into one such that they run top to
+            // bottom, and then the response would bubble back up through the layers in reverse
+            tower::ServiceBuilder::new()
+                .layer(
+                    CorsLayer::new()
+                        .allow_origin(cors::Any)
+                        .allow_headers(cors::Any)
+                        .allow_methods([
+                            http::Method::OPTIONS,
+                            http::Method::GET,
+                            http::Method::POST,
+                        ]),
+                )
+                // Set up the query tracing span
+                .layer(RequestTracingLayer)
+                // Set the query ID on the request
+                .layer(SetRequestIdLayer::new(gateway_id))
+                // Handle legacy in-path auth, and convert it into a header
+                .layer(middleware::from_fn(legacy_auth_adapter))
+                // Require the query to be authorized
+                .layer(RequireAuthorizationLayer::new(auth)),
+        )
+}
+
 #[allow(clippy::too_many_arguments)]
 pub async fn handle_query(
     State(ctx): State<Context>,
@@ -74,6 +160,7 @@ pub async fn handle_query(
     payload: Bytes,
 ) -> Result<Response<String>, Error> {
     let start_time = Instant::now();
+    let response_extensions = auth.response_extensions || response_extensions::requested(&headers);
 
     // Check if the query selector is authorized by the auth token and
     // resolve the subgraph deployments for the query.
@@ -99,24 +186,12 @@ pub async fn handle_query(
         serde_json::from_reader(payload.reader()).map_err(|err| Error::BadQuery(err.into()))?;
 
     // Calculate the budget for the query
-    let grt_per_usd = *ctx.grt_per_usd.borrow();
-    let one_grt = NotNan::new(1e18).unwrap();
-    let budget = {
-        let mut budget = *(ctx.budgeter.query_fees_target.0 * grt_per_usd * one_grt) as u128;
-        if let Some(Extension(QuerySettings {
-            budget_usd: Some(user_budget_usd),
-        })) = query_settings
-        {
-            // Security: Consumers can and will set their budget to unreasonably high values.
-            // This `.min` prevents the budget from being set far beyond what it would be
-            // automatically. The reason this is important is that sometimes queries are
-            // subsidized, and we would be at-risk to allow arbitrarily high values.
-            let max_budget = budget * 10;
-
-            budget = (*(user_budget_usd * grt_per_usd * one_grt) as u128).min(max_budget);
-        }
-        budget
-    };
+    let budget = query_budget(
+        &ctx,
+        &auth,
+        &subgraph,
+        query_settings.as_ref().map(|Extension(settings)| settings),
+    );
 
     let (tx, mut rx) = mpsc::channel(1);
     tokio::spawn(
@@ -128,7 +203,9 @@ pub async fn handle_query(
             subgraph,
             budget,
             client_request,
+            response_extensions,
             tx,
+            None,
         )
         .in_current_span(),
     );
@@ -160,6 +237,36 @@ pub async fn handle_query(
     )
 }
 
+/// Calculate the budget for a query, in GRT wei, from the budget policy matching the query and the
+/// user's query settings.
+fn query_budget(
+    ctx: &Context,
+    auth: &AuthSettings,
+    subgraph: &ResolvedSubgraphInfo,
+    query_settings: Option<&QuerySettings>,
+) -> u128 {
+    let grt_per_usd = *ctx.grt_per_usd.borrow();
+    let one_grt = NotNan::new(1e18).unwrap();
+    let query_fees_target = ctx
+        .budgeter
+        .budget(&auth.key, &auth.user, &subgraph.subgraphs, &subgraph.chain)
+        .query_fees_target;
+    let mut budget = *(query_fees_target.0 * grt_per_usd * one_grt) as u128;
+    if let Some(QuerySettings {
+        budget_usd: Some(user_budget_usd),
+    }) = query_settings
+    {
+        // Security: Consumers can and will set their budget to unreasonably high values.
+        // This `.min` prevents the budget from being set far beyond what it would be
+        // automatically. The reason this is important is that sometimes queries are
+        // subsidized, and we would be at-risk to allow arbitrarily high values.
+        let max_budget = budget * 10;
+
+        budget = (*(*user_budget_usd * grt_per_usd * one_grt) as u128).min(max_budget);
+    }
+    budget
+}
+
 /// Error type for the `resolve_subgraph_info` function.
 #[derive(Debug, thiserror::Error)]
 enum ResolutionError {
@@ -232,10 +339,15 @@ async fn run_indexer_queries(
     subgraph: ResolvedSubgraphInfo,
     budget: u128,
     client_request: QueryBody,
+    response_extensions: bool,
     client_response: mpsc::Sender<Result<IndexerResponse, Error>>,
+    fees: Option<oneshot::Sender<USD>>,
 ) {
     let one_grt = NotNan::new(1e18).unwrap();
     let grt_per_usd = *ctx.grt_per_usd.borrow();
+    let budget_policy =
+        ctx.budgeter
+            .budget(&auth.key, &auth.user, &subgraph.subgraphs, &subgraph.chain);
 
     // Create the Agora context from the query and variables
     let variables = client_request
@@ -256,8 +368,47 @@ async fn run_indexer_queries(
         }
     };
 
+    // Reject expensive queries before any fees are spent on them.
+    if let Some(query_limits) = &auth.query_limits {
+        if let Err(err) = query_complexity::check(&agora_context, query_limits) {
+            client_response.try_send(Err(err)).unwrap();
+            return;
+        }
+    }
+
     // Get the chain information for the resolved subgraph
     let chain = ctx.chains.chain(&subgraph.chain);
+
+    // Resolve the blocks for `timestamp` constraints, which may require probing indexers when the
+    // blocks are not in the chain cache.
+    let timestamps = match timestamp_constraints(&agora_context) {
+        Ok(timestamps) => timestamps,
+        Err(err) => {
+            client_response.try_send(Err(err)).unwrap();
+            return;
+        }
+    };
+    let mut probe_fees: u128 = 0;
+    let timestamp_blocks = match block_timestamps::resolve_timestamps(
+        &ctx,
+        &chain,
+        &subgraph,
+        timestamps,
+        &mut probe_fees,
+    )
+    .await
+    {
+        Ok(timestamp_blocks) => timestamp_blocks,
+        Err(err) => {
+            client_response.try_send(Err(err)).unwrap();
+            let probe_fees_usd = (probe_fees as f64 * 1e-18) / *grt_per_usd;
+            let _ = budget_policy
+                .feedback
+                .send(USD(NotNan::new(probe_fees_usd).unwrap()));
+            return;
+        }
+    };
+
     let (chain_head, blocks_per_minute, block_requirements) = {
         let chain_reader = chain.read();
 
@@ -273,16 +424,18 @@ async fn run_indexer_queries(
         // Get the estimated blocks per minute for the chain
         let blocks_per_minute = chain_reader.blocks_per_minute();
 
-        let block_requirements =
-            match resolve_block_requirements(&chain_reader, &agora_context, subgraph.start_block) {
-                Ok(block_requirements) => block_requirements,
-                Err(err) => {
-                    client_response
-                        .try_send(Err(Error::BadQuery(anyhow!("{err}"))))
-                        .unwrap();
-                    return;
-                }
-            };
+        let block_requirements = match resolve_block_requirements(
+            &chain_reader,
+            &agora_context,
+            subgraph.start_block,
+            &timestamp_blocks,
+        ) {
+            Ok(block_requirements) => block_requirements,
+            Err(err) => {
+                client_response.try_send(Err(err)).unwrap();
+                return;
+            }
+        };
 
         (chain_head, blocks_per_minute, block_requirements)
     };
@@ -292,7 +445,7 @@ async fn run_indexer_queries(
 
     // Candidate selection preparation
     let (mut candidates, errors) = build_candidates_list(
-        &ctx,
+        &ctx.indexing_perf.latest(),
         &agora_context,
         budget,
         chain_head,
@@ -300,8 +453,10 @@ async fn run_indexer_queries(
         &block_requirements,
         &subgraph.versions,
         subgraph.indexings,
+        SECONDS_BEHIND_CUTOFF,
     );
     indexer_errors.extend(errors);
+    indexer_errors.extend(ctx.fee_monitor.apply(&mut candidates, budget, *grt_per_usd));
 
     if tracing::enabled!(tracing::Level::TRACE) {
         tracing::trace!(client_query = client_request.query, variables);
@@ -312,165 +467,363 @@ async fn run_indexer_queries(
         tracing::debug!(?candidates);
     }
 
+    // Cross-checking only applies to block-pinned queries, since responses for the latest block
+    // may legitimately differ between indexers.
+    let cross_check = auth.cross_check.filter(|_| !block_requirements.latest);
+    // Hedging sends to a single indexer at a time, which would defeat cross-checking.
+    let hedge_percentile = ctx
+        .indexer_timeouts
+        .hedge_percentile
+        .filter(|_| cross_check.is_none());
+    // Cross-checked responses are compared by the request CID of their attestations, so every
+    // indexer is sent the same query, with the `_gateway_probe_` fields supported by all candidates.
+    let cross_check_probe_fields = cross_check.map(|_| {
+        common_probe_block_fields(
+            candidates.iter().map(|c| &c.data.graph_node_version),
+            &ctx.parent_hash_min_graph_node_version,
+        )
+    });
+    let prepare_response = |report: &reports::IndexerRequest| -> IndexerResponse {
+        let mut response = report.result.as_ref().unwrap().clone();
+        if response_extensions {
+            let extensions = GatewayExtensions {
+                block: response.probe_block.as_ref().map(Into::into),
+                indexer: report.indexer,
+                deployment: report.deployment.to_string(),
+                fee_usd: (report.receipt.grt_value() as f64 * 1e-18) / *grt_per_usd,
+                request_id: request_id.clone(),
+            };
+            response.client_response = extensions.insert_into(&response.client_response);
+        }
+        response
+    };
+
     let mut indexer_requests: Vec<reports::IndexerRequest> = Default::default();
-    let mut indexer_request_rewrites: BTreeMap<u32, String> = Default::default();
+    // Rewritten queries, by seconds behind and `_gateway_probe_` block fields.
+    let mut indexer_request_rewrites: BTreeMap<(u32, &'static str), String> = Default::default();
     let mut client_response_time: Option<Duration> = None;
 
+    // Reports from all selection rounds are received on the same channel, so that requests still in
+    // flight when the next round starts are accounted for.
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let mut tx = Some(tx);
+    let mut in_flight: HashMap<Address, InFlight> = HashMap::new();
+    let mut round_deadline = Instant::now();
+    // Deadline for outstanding requests once the client query is answered.
+    let mut grace_deadline: Option<Instant> = None;
+    let mut cancelled_fees_grt = 0.0;
+    // Hedges sent, and hedges allowed, in the current round when hedging.
+    let mut hedged_round: (usize, usize) = (0, 0);
+
     // If a client query cannot be handled by the available indexers, we should give a reason for
     // all the available indexers in the `bad indexers` response.
-    while !candidates.is_empty()
-        && (Instant::now().duration_since(start_time) < Duration::from_secs(60))
-    {
-        let selections: ArrayVec<_, SELECTION_LIMIT> = indexer_selection::select(&candidates);
-        if selections.is_empty() {
-            // Candidates that would never be selected should be filtered out for improved errors.
-            tracing::error!("no candidates selected");
-            break;
-        }
-
-        let (tx, mut rx) = mpsc::channel(SELECTION_LIMIT);
-        let min_fee = *ctx.budgeter.min_indexer_fees.borrow();
-        for &selection in &selections {
-            let indexer = selection.id;
-            let deployment = selection.data.deployment;
-            let largest_allocation = selection.data.largest_allocation;
-            let url = selection.data.url.clone();
-            let seconds_behind = selection.seconds_behind;
-            let legacy_scalar = !selection.data.tap_support;
-            let subgraph_chain = subgraph.chain.clone();
-
-            // over-pay indexers to hit target
-            let min_fee = *(min_fee.0 * grt_per_usd * one_grt) / selections.len() as f64;
-            let indexer_fee = selection.fee.as_f64() * budget as f64;
-            let fee = indexer_fee.max(min_fee) as u128;
-            let receipt = match if legacy_scalar {
-                ctx.receipt_signer
-                    .create_legacy_receipt(largest_allocation, fee)
-            } else {
-                ctx.receipt_signer.create_receipt(largest_allocation, fee)
-            } {
-                Ok(receipt) => receipt,
-                Err(err) => {
-                    tracing::error!(%indexer, %deployment, error=?err, "failed to create receipt");
-                    continue;
-                }
-            };
-            debug_assert!(fee == receipt.grt_value());
-
-            let blocks_behind = blocks_behind(seconds_behind, blocks_per_minute);
-            let indexer_query = match indexer_request_rewrites.get(&seconds_behind) {
-                Some(indexer_query) => indexer_query.clone(),
-                None => {
-                    let chain = chain.read();
-                    let indexer_query =
-                        rewrite_query(&chain, &agora_context, &block_requirements, blocks_behind);
-                    if selections
-                        .iter()
-                        .filter(|s| s.seconds_behind == seconds_behind)
-                        .count()
-                        > 1
-                    {
-                        indexer_request_rewrites.insert(seconds_behind, indexer_query.clone());
+    loop {
+        let satisfied = client_response_time.is_some()
+            || cross_check.as_ref().is_some_and(|cross_check| {
+                indexer_requests.iter().filter(|r| r.result.is_ok()).count()
+                    >= cross_check.responses
+            });
+        // Start the next round once all requests from the previous round have completed, or are
+        // overdue.
+        if let Some(round_tx) = tx
+            .as_ref()
+            .filter(|_| !satisfied && (in_flight.is_empty() || (Instant::now() >= round_deadline)))
+        {
+            let mut selections: ArrayVec<_, SELECTION_LIMIT> =
+                if candidates.is_empty() || (start_time.elapsed() >= Duration::from_secs(60)) {
+                    ArrayVec::new()
+                } else {
+                    let selections = indexer_selection::select(&candidates);
+                    if selections.is_empty() {
+                        // Candidates that would never be selected should be filtered out for
+                        // improved errors.
+                        tracing::error!("no candidates selected");
                     }
-                    indexer_query
+                    selections
+                };
+            // The minimum indexer fees are split across the requests of a round. When hedging, the
+            // selections of a round are sent one at a time, as hedges.
+            let round_requests = match hedge_percentile {
+                Some(_) => {
+                    if in_flight.is_empty() || (hedged_round.0 >= hedged_round.1) {
+                        hedged_round = (0, selections.len());
+                    }
+                    hedged_round.0 += 1;
+                    selections.truncate(1);
+                    hedged_round.1
                 }
+                None => selections.len(),
             };
-            let indexer_client = ctx.indexer_client.clone();
-            let tx = tx.clone();
-            tokio::spawn(
-                async move {
-                    let start_time = Instant::now();
-                    let result = indexer_client
-                        .query_indexer(
-                            &deployment,
-                            &url,
-                            &receipt,
-                            ctx.attestation_domain,
-                            &indexer_query,
+            if selections.is_empty() {
+                tx = None;
+            } else {
+                let (timeouts, round_duration) = {
+                    let perf_snapshots = ctx.indexing_perf.latest();
+                    let snapshot = |s: &&Candidate<Address, CandidateMetadata>| {
+                        perf_snapshots.get(&(s.id, s.data.deployment))
+                    };
+                    let timeouts: ArrayVec<Duration, SELECTION_LIMIT> = selections
+                        .iter()
+                        .map(|s| ctx.indexer_timeouts.timeout(snapshot(s)))
+                        .collect();
+                    // When hedging, the next hedge is sent once the single selection is slower than
+                    // expected.
+                    let round_duration = match hedge_percentile {
+                        Some(percentile) if hedged_round.0 < hedged_round.1 => ctx
+                            .indexer_timeouts
+                            .hedge_delay(percentile, snapshot(&selections[0])),
+                        _ => timeouts.iter().max().copied().unwrap_or_default(),
+                    };
+                    (timeouts, round_duration)
+                };
+                round_deadline = Instant::now() + round_duration;
+
+                let min_fee = *(budget_policy.min_indexer_fees.borrow().0 * grt_per_usd * one_grt);
+                let probe_fields = |s: &Candidate<Address, CandidateMetadata>| {
+                    cross_check_probe_fields.unwrap_or_else(|| {
+                        probe_block_fields(
+                            &s.data.graph_node_version,
+                            &ctx.parent_hash_min_graph_node_version,
                         )
-                        .in_current_span()
-                        .await;
-                    let response_time_ms =
-                        Instant::now().duration_since(start_time).as_millis() as u16;
-                    let report = reports::IndexerRequest {
-                        indexer,
-                        deployment,
-                        largest_allocation,
-                        url: url.to_string(),
-                        receipt,
-                        subgraph_chain,
-                        result,
-                        response_time_ms,
-                        seconds_behind,
-                        blocks_behind,
-                        request: indexer_query,
+                    })
+                };
+                for (&selection, &timeout) in selections.iter().zip(&timeouts) {
+                    let indexer = selection.id;
+                    let deployment = selection.data.deployment;
+                    let largest_allocation = selection.data.largest_allocation;
+                    let url = selection.data.url.clone();
+                    let seconds_behind = selection.seconds_behind;
+                    let legacy_scalar = !selection.data.tap_support;
+                    let subgraph_chain = subgraph.chain.clone();
+
+                    let blocks_behind = blocks_behind(seconds_behind, blocks_per_minute);
+                    let rewrite_key = (seconds_behind, probe_fields(selection));
+                    let indexer_query = match indexer_request_rewrites.get(&rewrite_key) {
+                        Some(indexer_query) => indexer_query.clone(),
+                        None => {
+                            let chain = chain.read();
+                            let indexer_query = match rewrite_query(
+                                &chain,
+                                &agora_context,
+                                &block_requirements,
+                                blocks_behind,
+                                rewrite_key.1,
+                            ) {
+                                Ok(indexer_query) => indexer_query,
+                                // The query can't be rewritten for any indexer, so this fails in
+                                // the first round, before any request is sent.
+                                Err(err) => {
+                                    let _ = client_response.try_send(Err(err));
+                                    return;
+                                }
+                            };
+                            if selections
+                                .iter()
+                                .filter(|s| (s.seconds_behind, probe_fields(s)) == rewrite_key)
+                                .count()
+                                > 1
+                            {
+                                indexer_request_rewrites.insert(rewrite_key, indexer_query.clone());
+                            }
+                            indexer_query
+                        }
+                    };
+
+                    let fee = indexer_request_fee(selection.fee, budget, min_fee, round_requests);
+                    let receipt = if legacy_scalar {
+                        ctx.receipt_signer
+                            .create_legacy_receipt(largest_allocation, fee)
+                    } else {
+                        ctx.receipt_signer
+                            .create_receipt(largest_allocation, fee)
+                            .await
                     };
-                    tx.try_send(report).unwrap();
+                    let receipt = match receipt {
+                        Ok(receipt) => receipt,
+                        Err(err) => {
+                            tracing::error!(%indexer, %deployment, error=?err, "failed to create receipt");
+                            continue;
+                        }
+                    };
+                    debug_assert!(fee == receipt.grt_value());
+
+                    let indexer_client = ctx.indexer_client.clone();
+                    let tx = round_tx.clone();
+                    let in_flight_receipt = receipt.clone();
+                    let task = tokio::spawn(
+                        async move {
+                            let start_time = Instant::now();
+                            let result = tokio::time::timeout(
+                                timeout,
+                                indexer_client.query_indexer(
+                                    &deployment,
+                                    &url,
+                                    &receipt,
+                                    ctx.attestation_domain,
+                                    &indexer_query,
+                                ),
+                            )
+                            .in_current_span()
+                            .await
+                            .unwrap_or(Err(IndexerError::Timeout));
+                            let response_time_ms =
+                                Instant::now().duration_since(start_time).as_millis() as u16;
+                            let report = reports::IndexerRequest {
+                                indexer,
+                                deployment,
+                                largest_allocation,
+                                url: url.to_string(),
+                                receipt,
+                                subgraph_chain,
+                                result,
+                                response_time_ms,
+                                seconds_behind,
+                                blocks_behind,
+                                request: indexer_query,
+                            };
+                            let _ = tx.send(report);
+                        }
+                        .instrument(info_span!("indexer_request", ?indexer)),
+                    );
+                    in_flight.insert(
+                        indexer,
+                        InFlight {
+                            task: task.abort_handle(),
+                            largest_allocation,
+                            receipt: in_flight_receipt,
+                        },
+                    );
                 }
-                .instrument(info_span!("indexer_request", ?indexer)),
-            );
+
+                let selected_indexers: ArrayVec<Address, SELECTION_LIMIT> =
+                    selections.into_iter().map(|s| s.id).collect();
+                candidates.retain(|c| !selected_indexers.contains(&c.id));
+                continue;
+            }
+        }
+        if satisfied && grace_deadline.is_none() {
+            tx = None;
+            grace_deadline = Some(Instant::now() + ctx.indexer_timeouts.straggler_grace);
+        }
+        if in_flight.is_empty() {
+            break;
         }
-        drop(tx);
 
-        while let Some(report) = rx.recv().await {
-            match report.result.as_ref() {
-                Ok(response) if client_response_time.is_none() => {
-                    let _ = client_response.try_send(Ok(response.clone()));
-                    client_response_time = Some(Instant::now().duration_since(start_time));
-                }
-                Ok(_) => (),
-                Err(err) => {
-                    indexer_errors.insert(report.indexer, err.clone());
+        let deadline = match &tx {
+            Some(_) => Some(round_deadline),
+            None => grace_deadline,
+        };
+        let report = match deadline {
+            Some(deadline) => match tokio::time::timeout_at(deadline.into(), rx.recv()).await {
+                Ok(report) => report,
+                // All requests of the round are overdue.
+                Err(_) if tx.is_some() => continue,
+                // The grace period for stragglers has elapsed.
+                Err(_) => {
+                    cancelled_fees_grt = cancel_stragglers(&ctx, &mut in_flight);
+                    break;
                 }
+            },
+            None => rx.recv().await,
+        };
+        let report = match report {
+            Some(report) => report,
+            None => break,
+        };
+        in_flight.remove(&report.indexer);
+
+        match report.result.as_ref() {
+            Ok(_) if client_response_time.is_none() && cross_check.is_none() => {
+                let _ = client_response.try_send(Ok(prepare_response(&report)));
+                client_response_time = Some(Instant::now().duration_since(start_time));
             }
+            Ok(_) => (),
+            Err(err) => {
+                indexer_errors.insert(report.indexer, err.clone());
+            }
+        }
 
-            let receipt_status = match &report.result {
-                Ok(_) => ReceiptStatus::Success,
-                Err(IndexerError::Timeout) => ReceiptStatus::Unknown,
-                Err(_) => ReceiptStatus::Failure,
-            };
-            ctx.receipt_signer.record_receipt(
-                &report.largest_allocation,
-                &report.receipt,
-                receipt_status,
-            );
+        let receipt_status = match &report.result {
+            Ok(_) => ReceiptStatus::Success,
+            Err(IndexerError::Timeout) => ReceiptStatus::Unknown,
+            Err(_) => ReceiptStatus::Failure,
+        };
+        ctx.receipt_signer.record_receipt(
+            &report.largest_allocation,
+            &report.receipt,
+            receipt_status,
+        );
 
-            indexer_requests.push(report);
-        }
+        indexer_requests.push(report);
 
-        if client_response_time.is_some() {
-            break;
+        if let (Some(cross_check), None) = (&cross_check, client_response_time) {
+            if let Some(index) = cross_check::quorum(&indexer_requests, cross_check.quorum) {
+                let response = prepare_response(&indexer_requests[index]);
+                let _ = client_response.try_send(Ok(response));
+                client_response_time = Some(Instant::now().duration_since(start_time));
+            }
+        }
+    }
+    if cross_check.is_some() && client_response_time.is_none() {
+        for indexer_request in indexer_requests.iter().filter(|r| r.result.is_ok()) {
+            indexer_errors.insert(
+                indexer_request.indexer,
+                IndexerError::BadResponse("cross-check quorum not reached".to_string()),
+            );
         }
-
-        let selected_indexers: ArrayVec<Address, SELECTION_LIMIT> =
-            selections.into_iter().map(|s| s.id).collect();
-        candidates.retain(|c| !selected_indexers.contains(&c.id));
     }
     tracing::info!(?indexer_errors);
 
+    let result = if client_response_time.is_some() {
+        Ok(())
+    } else {
+        Err(Error::BadIndexers(indexer_errors.clone()))
+    };
     let client_response_time = match client_response_time {
         Some(client_response_time) => client_response_time,
         // Send fallback error to use when no indexers are successful.
         None => {
-            let _ = client_response.try_send(Err(Error::BadIndexers(indexer_errors.clone())));
+            let _ = client_response.try_send(Err(Error::BadIndexers(indexer_errors)));
             Instant::now().duration_since(start_time)
         }
     };
 
-    let result = if indexer_requests.iter().any(|r| r.result.is_ok()) {
-        Ok(())
-    } else {
-        Err(Error::BadIndexers(indexer_errors))
-    };
-
     let total_fees_grt: f64 = indexer_requests
         .iter()
         .map(|i| i.receipt.grt_value() as f64 * 1e-18)
-        .sum();
+        .sum::<f64>()
+        + cancelled_fees_grt
+        + (probe_fees as f64 * 1e-18);
     let total_fees_usd = USD(NotNan::new(total_fees_grt / *grt_per_usd).unwrap());
-    let _ = ctx.budgeter.feedback.send(total_fees_usd);
+    METRICS
+        .indexer_fees_wasted
+        .inc_by(cancelled_fees_grt / *grt_per_usd);
+    let _ = budget_policy.feedback.send(total_fees_usd);
+    if let Some(fees) = fees {
+        let _ = fees.send(total_fees_usd);
+    }
+
+    // Cross-checked responses that diverge from the response agreed on by other indexers are treated
+    // as failures.
+    let (divergent, dispute_evidence) = match &cross_check {
+        Some(_) => (
+            cross_check::divergent(&indexer_requests),
+            cross_check::dispute_evidence(&indexer_requests),
+        ),
+        None => (vec![], vec![]),
+    };
+    if let Some(store) = &ctx.indexer_client.dispute_evidence {
+        for evidence in &dispute_evidence {
+            store.record(evidence.into());
+        }
+    }
 
-    for indexer_request in &indexer_requests {
+    for (index, indexer_request) in indexer_requests.iter().enumerate() {
+        let divergent = divergent.contains(&index);
+        if divergent {
+            ctx.indexing_perf
+                .divergence(indexer_request.indexer, indexer_request.deployment);
+        }
         let latest_block = match &indexer_request.result {
             Ok(response) => response.probe_block.as_ref().map(|b| b.number),
             Err(IndexerError::Unavailable(UnavailableReason::MissingBlock(err))) => err.latest,
@@ -479,7 +832,7 @@ async fn run_indexer_queries(
         ctx.indexing_perf.feedback(
             indexer_request.indexer,
             indexer_request.deployment,
-            indexer_request.result.is_ok(),
+            indexer_request.result.is_ok() && !divergent,
             indexer_request.response_time_ms,
             latest_block,
         );
@@ -540,23 +893,49 @@ async fn run_indexer_queries(
         user_address: auth.user,
         grt_per_usd,
         indexer_requests,
+        dispute_evidence,
     });
 }
 
+/// An indexer request awaiting its report.
+struct InFlight {
+    task: AbortHandle,
+    largest_allocation: Address,
+    receipt: Receipt,
+}
+
+/// Cancel the outstanding indexer requests, returning their total fees in GRT. The outcome of the
+/// cancelled requests is unknown, since the indexer may have served them regardless.
+fn cancel_stragglers(ctx: &Context, in_flight: &mut HashMap<Address, InFlight>) -> f64 {
+    let mut fees_grt = 0.0;
+    for (_, request) in in_flight.drain() {
+        request.task.abort();
+        ctx.receipt_signer.record_receipt(
+            &request.largest_allocation,
+            &request.receipt,
+            ReceiptStatus::Unknown,
+        );
+        METRICS.indexer_requests_cancelled.inc();
+        fees_grt += request.receipt.grt_value() as f64 * 1e-18;
+    }
+    fees_grt
+}
+
 #[derive(CustomDebug)]
-struct CandidateMetadata {
+pub struct CandidateMetadata {
     deployment: DeploymentId,
     #[debug(with = std::fmt::Display::fmt)]
     url: Url,
     largest_allocation: Address,
     tap_support: bool,
+    graph_node_version: Version,
 }
 
 /// Given a list of indexings, build a list of candidates that are within the required block range
 /// and have the required performance.
 #[allow(clippy::too_many_arguments)]
-fn build_candidates_list(
-    ctx: &Context,
+pub fn build_candidates_list(
+    perf_snapshots: &HashMap<(Address, DeploymentId), indexing_performance::Snapshot>,
     context: &AgoraContext,
     budget: u128,
     chain_head: BlockNumber,
@@ -564,6 +943,7 @@ fn build_candidates_list(
     block_requirements: &BlockRequirements,
     subgraph_versions: &[DeploymentId],
     indexings: HashMap<IndexingId, Result<Indexing, network::ResolutionError>>,
+    seconds_behind_cutoff: u32,
 ) -> (
     Vec<Candidate<Address, CandidateMetadata>>,
     BTreeMap<Address, IndexerError>,
@@ -583,9 +963,6 @@ fn build_candidates_list(
         })
         .unwrap_or(&subgraph_versions[0]);
 
-    // Lock the indexing performance and get access to the latest performance snapshots
-    let perf_snapshots = ctx.indexing_perf.latest();
-
     for (indexing_id, indexing) in indexings {
         // If the indexer is not available, register an error and continue to the next indexer
         let indexing = match indexing {
@@ -604,8 +981,15 @@ fn build_candidates_list(
         // If the indexer is not available, register an error and continue to the next indexer
         let perf = match perf_snapshots
             .get(&(indexing_id.indexer, indexing_id.deployment))
-            .and_then(|snapshot| perf(snapshot, block_requirements, chain_head, blocks_per_minute))
-        {
+            .and_then(|snapshot| {
+                perf(
+                    snapshot,
+                    block_requirements,
+                    chain_head,
+                    blocks_per_minute,
+                    seconds_behind_cutoff,
+                )
+            }) {
             Some(perf) => perf,
             None => {
                 candidates_errors.insert(
@@ -673,6 +1057,7 @@ fn build_candidates_list(
                 url: indexing.indexer.url.clone(),
                 largest_allocation: indexing.largest_allocation,
                 tap_support: indexing.indexer.tap_support,
+                graph_node_version: indexing.indexer.graph_node_version.clone(),
             },
             perf: perf.response,
             fee,
@@ -682,7 +1067,6 @@ fn build_candidates_list(
         });
     }
 
-    let seconds_behind_cutoff = 60 * 30;
     if block_requirements.latest
         && candidates_list
             .iter()
@@ -714,6 +1098,7 @@ fn perf(
     block_requirements: &BlockRequirements,
     chain_head: BlockNumber,
     blocks_per_minute: u64,
+    seconds_behind_cutoff: u32,
 ) -> Option<Perf> {
     let latest_block = snapshot.latest_block?;
     let seconds_behind = if !block_requirements.latest || (blocks_per_minute == 0) {
@@ -724,10 +1109,16 @@ fn perf(
     };
 
     let mut response = snapshot.response.expected_performance();
+    // Divergent responses are already counted as failures, but they are penalized further since
+    // they may be incorrect responses attested to by the indexer.
+    if snapshot.divergences > 0.0 {
+        let success_rate = response.success_rate.as_f64() / (1.0 + snapshot.divergences);
+        response.success_rate = Normalized::new(success_rate).unwrap_or(Normalized::ZERO);
+    }
     // Since our gateway is specialized for frontends, add an additional penalty for candidates
     // far behind chain head. This compensates for the impacts of information decay and the sharp
     // dropoff of our `seconds_behind` curve.
-    if seconds_behind > (60 * 30) {
+    if seconds_behind > seconds_behind_cutoff {
         response.success_rate = Normalized::ZERO;
     }
 
@@ -742,6 +1133,20 @@ fn blocks_behind(seconds_behind: u32, blocks_per_minute: u64) -> u64 {
     ((seconds_behind as f64 / 60.0) * blocks_per_minute as f64) as u64
 }
 
+/// Fee paid to a selected indexer, in GRT wei. Indexers are over-paid when the selections would
+/// otherwise pay less than the minimum indexer fees (`min_fees`, in GRT wei) set by the budgeter, to
+/// hit the query fees target.
+pub fn indexer_request_fee(
+    fee: Normalized,
+    budget: u128,
+    min_fees: f64,
+    selections: usize,
+) -> u128 {
+    let min_fee = min_fees / selections as f64;
+    let indexer_fee = fee.as_f64() * budget as f64;
+    indexer_fee.max(min_fee) as u128
+}
+
 /// Estimate the fee for an indexer based on the cost model and the query context.
 ///
 /// If the cost model is not available, the fee is assumed to be zero.
@@ -0,0 +1,236 @@
+//! Resolution of `timestamp` block constraints to block numbers. Timestamps are resolved using the
+//! chain cache when possible. Otherwise, the block is found by a binary search over `_meta` probes
+//! sent to the indexers of the subgraph.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    time::{Duration, Instant},
+};
+
+use alloy_primitives::BlockNumber;
+use anyhow::anyhow;
+use cost_model::Context as AgoraContext;
+use gateway_framework::{
+    blocks::{Block, UnresolvedBlock},
+    chains::ChainReader,
+    errors::{Error, IndexerError, UnavailableReason},
+};
+use serde_json::json;
+
+use super::{context::Context, indexer_fee};
+use crate::{
+    block_constraints::{check_timestamp, probe_block_fields},
+    network::{Indexing, ResolvedSubgraphInfo},
+    receipts::ReceiptStatus,
+};
+
+/// Maximum number of probes sent to resolve a single timestamp.
+const MAX_PROBES: usize = 32;
+/// Maximum time spent probing indexers to resolve a single timestamp.
+const SEARCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Resolve the block numbers for the given timestamps, where each block number is the latest
+/// block produced at or before the timestamp. The fees of the probes sent, in GRT wei, are added to
+/// `fees`.
+pub async fn resolve_timestamps(
+    ctx: &Context,
+    chain: &ChainReader,
+    subgraph: &ResolvedSubgraphInfo,
+    timestamps: BTreeSet<u64>,
+    fees: &mut u128,
+) -> Result<BTreeMap<u64, BlockNumber>, Error> {
+    let mut blocks = BTreeMap::new();
+    for timestamp in timestamps {
+        check_timestamp(timestamp)?;
+        let (before, after) = {
+            let chain = chain.read();
+            let (before, after) = chain.timestamp_bounds(timestamp);
+            (before.cloned(), after.cloned())
+        };
+        let number = match (before, after) {
+            (Some(before), Some(after)) if after.number == (before.number + 1) => before.number,
+            // The timestamp is after the chain head, so the chain head is the latest block we know
+            // of that satisfies the constraint.
+            (Some(before), None) => before.number,
+            (before, Some(after)) => search(ctx, subgraph, timestamp, before, after, fees).await?,
+            (None, None) => {
+                return Err(Error::BlockNotFound(UnresolvedBlock::WithTimestamp(
+                    timestamp,
+                )))
+            }
+        };
+        blocks.insert(timestamp, number);
+    }
+    Ok(blocks)
+}
+
+/// Estimate the block numbers for the given timestamps using only the chain cache, without probing
+/// indexers. Each block number is the latest cached block produced at or before the timestamp,
+/// which may be earlier than the block `resolve_timestamps` would find.
+pub fn estimate_timestamps(
+    chain: &ChainReader,
+    timestamps: BTreeSet<u64>,
+) -> Result<BTreeMap<u64, BlockNumber>, Error> {
+    let chain = chain.read();
+    let mut blocks = BTreeMap::new();
+    for timestamp in timestamps {
+        check_timestamp(timestamp)?;
+        let (before, _) = chain.timestamp_bounds(timestamp);
+        let number = before.map(|b| b.number).ok_or(Error::BlockNotFound(
+            UnresolvedBlock::WithTimestamp(timestamp),
+        ))?;
+        blocks.insert(timestamp, number);
+    }
+    Ok(blocks)
+}
+
+/// Binary search for the latest block produced at or before `timestamp`, given the latest known
+/// block produced at or before the timestamp (if any) and a known block produced after it.
+///
+/// The probed blocks are not reported to the chain cache, since they are historical blocks which
+/// would otherwise be taken into account for the consensus at the chain head. Each probe is
+/// subject to the indexer's request timeout, and the whole search to `SEARCH_TIMEOUT`.
+async fn search(
+    ctx: &Context,
+    subgraph: &ResolvedSubgraphInfo,
+    timestamp: u64,
+    before: Option<Block>,
+    after: Block,
+    fees: &mut u128,
+) -> Result<BlockNumber, Error> {
+    let not_found = || Error::BlockNotFound(UnresolvedBlock::WithTimestamp(timestamp));
+
+    let mut lower = before
+        .as_ref()
+        .map(|b| b.number)
+        .unwrap_or(subgraph.start_block);
+    let mut lower_verified = before.is_some();
+    let mut upper = after.number;
+    let before_start_block = |block: BlockNumber, block_timestamp: u64| {
+        Error::BadQuery(anyhow!(
+            "requested block timestamp {timestamp}, before block {block} produced at {block_timestamp} (manifest `startBlock` {})",
+            subgraph.start_block,
+        ))
+    };
+    if lower >= upper {
+        return Err(before_start_block(after.number, after.timestamp));
+    }
+
+    // Prefer the indexers that have indexed the most blocks of the subgraph.
+    let mut indexings: Vec<&Indexing> = subgraph
+        .indexings
+        .values()
+        .filter_map(|indexing| indexing.as_ref().ok())
+        .filter(|indexing| indexing.progress.latest_block >= upper.saturating_sub(1))
+        .collect();
+    indexings.sort_by_key(|indexing| std::cmp::Reverse(indexing.progress.latest_block));
+    let mut indexings = indexings.into_iter();
+    let mut indexing = indexings.next().ok_or_else(not_found)?;
+
+    let deadline = Instant::now() + SEARCH_TIMEOUT;
+    let mut probes = 0;
+    while (upper - lower) > 1 || !lower_verified {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if (probes >= MAX_PROBES) || remaining.is_zero() {
+            return Err(not_found());
+        }
+        probes += 1;
+
+        let number = if (upper - lower) > 1 {
+            lower + ((upper - lower) / 2)
+        } else {
+            lower
+        };
+        let block = match probe(ctx, indexing, number, remaining, fees).await {
+            Ok(block) => block,
+            Err(probe_err) => {
+                tracing::warn!(indexer = ?indexing.id.indexer, number, %probe_err);
+                indexing = indexings.next().ok_or_else(not_found)?;
+                continue;
+            }
+        };
+
+        if block.timestamp <= timestamp {
+            lower = number;
+            lower_verified = true;
+        } else if number == lower {
+            return Err(before_start_block(block.number, block.timestamp));
+        } else {
+            upper = number;
+        }
+    }
+    Ok(lower)
+}
+
+/// Request the block at the given number from the indexer, within the indexer's request timeout
+/// and at most `max_timeout`. The receipt and the indexer's performance are recorded as for other
+/// indexer requests.
+async fn probe(
+    ctx: &Context,
+    indexing: &Indexing,
+    number: BlockNumber,
+    max_timeout: Duration,
+    fees: &mut u128,
+) -> Result<Block, IndexerError> {
+    let fields = probe_block_fields(
+        &indexing.indexer.graph_node_version,
+        &ctx.parent_hash_min_graph_node_version,
+    );
+    let query = format!(
+        "{{ _gateway_probe_: _meta(block: {{ number: {number} }}) {{ block {{ {fields} }} }} }}"
+    );
+    let fee = AgoraContext::new(&query, "")
+        .ok()
+        .and_then(|context| indexer_fee(&context, &indexing.cost_model))
+        .ok_or(IndexerError::Unavailable(UnavailableReason::NoFee))?;
+
+    let allocation = indexing.largest_allocation;
+    let receipt = if indexing.indexer.tap_support {
+        ctx.receipt_signer.create_receipt(allocation, fee).await
+    } else {
+        ctx.receipt_signer.create_legacy_receipt(allocation, fee)
+    }
+    .map_err(|_| IndexerError::Internal("failed to create receipt"))?;
+    *fees += receipt.grt_value();
+
+    let timeout = {
+        let perf_snapshots = ctx.indexing_perf.latest();
+        let snapshot = perf_snapshots.get(&(indexing.id.indexer, indexing.id.deployment));
+        ctx.indexer_timeouts.timeout(snapshot).min(max_timeout)
+    };
+    let start_time = Instant::now();
+    let result = tokio::time::timeout(
+        timeout,
+        ctx.indexer_client.query_indexer(
+            &indexing.id.deployment,
+            &indexing.indexer.url,
+            &receipt,
+            ctx.attestation_domain,
+            &json!({ "query": query }).to_string(),
+        ),
+    )
+    .await
+    .unwrap_or(Err(IndexerError::Timeout));
+    let receipt_status = match &result {
+        Ok(_) => ReceiptStatus::Success,
+        Err(IndexerError::Timeout) => ReceiptStatus::Unknown,
+        Err(_) => ReceiptStatus::Failure,
+    };
+    ctx.receipt_signer
+        .record_receipt(&allocation, &receipt, receipt_status);
+    let latest_block = match &result {
+        Err(IndexerError::Unavailable(UnavailableReason::MissingBlock(err))) => err.latest,
+        _ => None,
+    };
+    ctx.indexing_perf.feedback(
+        indexing.id.indexer,
+        indexing.id.deployment,
+        result.is_ok(),
+        start_time.elapsed().as_millis() as u16,
+        latest_block,
+    );
+
+    result?
+        .probe_block
+        .ok_or_else(|| IndexerError::BadResponse("missing probe block".to_string()))
+}
@@ -1,9 +1,14 @@
 use alloy_sol_types::Eip712Domain;
-use gateway_framework::{budgets::Budgeter, chains::Chains};
+use gateway_framework::{
+    budgets::{Budgeter, USD},
+    chains::Chains,
+};
 use ordered_float::NotNan;
+use semver::Version;
 use tokio::sync::{mpsc, watch};
 use url::Url;
 
+use super::{fee_monitor::FeeMonitor, timeouts::IndexerTimeouts};
 use crate::{
     indexer_client::IndexerClient, indexing_performance::IndexingPerformance,
     network::NetworkService, receipts::ReceiptSigner, reports,
@@ -19,6 +24,12 @@ pub struct Context {
     pub chains: &'static Chains,
     pub network: NetworkService,
     pub indexing_perf: IndexingPerformance,
+    pub indexer_timeouts: IndexerTimeouts,
+    pub fee_monitor: &'static FeeMonitor,
+    /// Maximum indexer fees spent on the subscriptions of a single WebSocket connection.
+    pub subscription_budget: USD,
     pub attestation_domain: &'static Eip712Domain,
+    /// Minimum graph-node version queried for the `parentHash` field of `_meta.block`.
+    pub parent_hash_min_graph_node_version: Version,
     pub reporter: mpsc::UnboundedSender<reports::ClientRequest>,
 }
@@ -0,0 +1,178 @@
+//! Comparison of attested responses from multiple indexers to the same request.
+
+use std::collections::BTreeMap;
+
+use alloy_primitives::B256;
+use itertools::Itertools as _;
+
+use crate::reports::{AttestedResponse, DisputeEvidence, IndexerRequest};
+
+/// Indices of the successful indexer requests, grouped by the response CID of their attestation,
+/// grouped by the request CID of their attestation.
+fn responses(indexer_requests: &[IndexerRequest]) -> BTreeMap<B256, BTreeMap<B256, Vec<usize>>> {
+    let mut requests: BTreeMap<B256, BTreeMap<B256, Vec<usize>>> = BTreeMap::new();
+    for (index, indexer_request) in indexer_requests.iter().enumerate() {
+        let attestation = match &indexer_request.result {
+            Ok(response) => match &response.attestation {
+                Some(attestation) => attestation,
+                None => continue,
+            },
+            Err(_) => continue,
+        };
+        requests
+            .entry(attestation.request_cid)
+            .or_default()
+            .entry(attestation.response_cid)
+            .or_default()
+            .push(index);
+    }
+    requests
+}
+
+/// Return the index of a response that at least `quorum` indexers agree on.
+pub fn quorum(indexer_requests: &[IndexerRequest], quorum: usize) -> Option<usize> {
+    responses(indexer_requests)
+        .into_values()
+        .flat_map(|responses| responses.into_values())
+        .find(|indices| indices.len() >= quorum)
+        .map(|indices| indices[0])
+}
+
+/// Return the indices of the responses that diverge from the response agreed on by the most
+/// indexers for the same request. Responses are not considered divergent when there is no single
+/// response agreed on by the most indexers.
+pub fn divergent(indexer_requests: &[IndexerRequest]) -> Vec<usize> {
+    let mut divergent = Vec::new();
+    for responses in responses(indexer_requests).into_values() {
+        let mut groups: Vec<Vec<usize>> = responses.into_values().collect();
+        groups.sort_by_key(|indices| std::cmp::Reverse(indices.len()));
+        match groups.as_slice() {
+            [majority, next, ..] if majority.len() > next.len() => {
+                divergent.extend(groups[1..].iter().flatten());
+            }
+            _ => (),
+        }
+    }
+    divergent
+}
+
+/// Return evidence of conflicting attestations, pairing a response agreed on by the most indexers
+/// with each of the other responses to the same request.
+pub fn dispute_evidence(indexer_requests: &[IndexerRequest]) -> Vec<DisputeEvidence> {
+    let attested = |index: usize| -> AttestedResponse {
+        let indexer_request = &indexer_requests[index];
+        let response = indexer_request.result.as_ref().unwrap();
+        AttestedResponse {
+            indexer: indexer_request.indexer,
+            allocation: indexer_request.receipt.allocation(),
+            response: response.original_response.clone(),
+            attestation: response.attestation.clone().unwrap(),
+        }
+    };
+    let mut evidence = Vec::new();
+    for responses in responses(indexer_requests).into_values() {
+        let representatives: Vec<usize> = responses
+            .into_values()
+            .sorted_by_key(|indices| std::cmp::Reverse(indices.len()))
+            .map(|indices| indices[0])
+            .collect();
+        let (first, others) = match representatives.split_first() {
+            Some(split) => split,
+            None => continue,
+        };
+        for other in others {
+            evidence.push(DisputeEvidence {
+                deployment: indexer_requests[*first].deployment,
+                request: indexer_requests[*first].request.clone(),
+                attestations: [attested(*first), attested(*other)],
+            });
+        }
+    }
+    evidence
+}
+
+#[cfg(test)]
+mod tests {
+    use alloy_primitives::Address;
+    use gateway_framework::{auth::CrossCheck, errors::IndexerError};
+    use thegraph_core::types::Attestation;
+
+    use super::*;
+    use crate::{indexer_client::IndexerResponse, receipts::Receipt};
+
+    fn indexer_request(indexer: u8, request_cid: u8, response_cid: u8) -> IndexerRequest {
+        let response = IndexerResponse {
+            original_response: format!("{{\"data\":{{\"n\":{response_cid}}}}}"),
+            attestation: Some(Attestation {
+                request_cid: B256::with_last_byte(request_cid),
+                response_cid: B256::with_last_byte(response_cid),
+                deployment: B256::ZERO,
+                r: B256::ZERO,
+                s: B256::ZERO,
+                v: 27,
+            }),
+            client_response: String::new(),
+            errors: vec![],
+            probe_block: None,
+        };
+        IndexerRequest {
+            indexer: Address::with_last_byte(indexer),
+            deployment: "QmaqcZxm6gcgWhWpQ88YKDm1keJDMpNxNGwtEDvjrjjNKh"
+                .parse()
+                .unwrap(),
+            largest_allocation: Address::with_last_byte(indexer),
+            url: String::new(),
+            receipt: Receipt::Legacy(0, [indexer; 32].to_vec()),
+            subgraph_chain: "mainnet".to_string(),
+            result: Ok(response),
+            response_time_ms: 0,
+            seconds_behind: 0,
+            blocks_behind: 0,
+            request: String::new(),
+        }
+    }
+
+    #[test]
+    fn cross_check_responses() {
+        //* Given
+        let mut indexer_requests = vec![
+            indexer_request(1, 1, 1),
+            indexer_request(2, 1, 2),
+            indexer_request(3, 1, 1),
+        ];
+        indexer_requests.push(IndexerRequest {
+            result: Err(IndexerError::Timeout),
+            ..indexer_request(4, 1, 2)
+        });
+
+        //* Then
+        assert_eq!(quorum(&indexer_requests[..2], 2), None);
+        assert_eq!(quorum(&indexer_requests, 2), Some(0));
+        assert_eq!(quorum(&indexer_requests, 3), None);
+        assert_eq!(divergent(&indexer_requests[..2]), Vec::<usize>::new());
+        assert_eq!(divergent(&indexer_requests), vec![1]);
+
+        let evidence = dispute_evidence(&indexer_requests);
+        assert_eq!(evidence.len(), 1);
+        assert_eq!(
+            evidence[0].attestations.each_ref().map(|a| a.indexer),
+            [Address::with_last_byte(1), Address::with_last_byte(2)]
+        );
+    }
+
+    #[test]
+    fn responses_to_different_requests_do_not_conflict() {
+        let indexer_requests = vec![indexer_request(1, 1, 1), indexer_request(2, 2, 2)];
+        assert_eq!(quorum(&indexer_requests, 2), None);
+        assert!(divergent(&indexer_requests).is_empty());
+        assert!(dispute_evidence(&indexer_requests).is_empty());
+    }
+
+    #[test]
+    fn reject_invalid_settings() {
+        let parse = |settings: &str| serde_json::from_str::<CrossCheck>(settings);
+        assert!(parse(r#"{"responses":3,"quorum":2}"#).is_ok());
+        assert!(parse(r#"{"responses":3,"quorum":0}"#).is_err());
+        assert!(parse(r#"{"responses":2,"quorum":3}"#).is_err());
+    }
+}
@@ -0,0 +1,329 @@
+//! Per-indexer fee caps, and detection of indexers whose fees jump far above those of their peers
+//! on the same deployment.
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::Arc,
+};
+
+use alloy_primitives::Address;
+use gateway_framework::{
+    errors::{IndexerError, UnavailableReason},
+    metrics::METRICS,
+};
+use indexer_selection::{Candidate, Normalized};
+use parking_lot::{Mutex, RwLock};
+use serde::Deserialize;
+use thegraph_core::types::DeploymentId;
+
+use super::CandidateMetadata;
+use crate::network::IndexingId;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AnomalyAction {
+    /// Only report the anomaly, via metrics and logs.
+    #[default]
+    Alert,
+    /// Also penalize the indexer in selection until its fees come back in line with its peers.
+    Deprioritize,
+}
+
+#[derive(Clone, Debug)]
+pub struct FeeLimits {
+    /// Maximum fee per indexer request, in USD.
+    pub max_usd: Option<f64>,
+    /// Maximum fee per request to specific indexers, in USD. These override `max_usd`.
+    pub indexer_max_usd: HashMap<Address, f64>,
+    /// An indexer's fees are anomalous when their moving average exceeds the median fee of its
+    /// peers on the same deployment by this factor.
+    pub anomaly_ratio: f64,
+    pub anomaly_action: AnomalyAction,
+}
+
+impl Default for FeeLimits {
+    fn default() -> Self {
+        Self {
+            max_usd: None,
+            indexer_max_usd: HashMap::new(),
+            anomaly_ratio: 5.0,
+            anomaly_action: AnomalyAction::Alert,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+struct RelativeFee {
+    /// Moving average of the indexer's fee divided by the median fee of its peers.
+    average: Option<f64>,
+    anomalous: bool,
+}
+
+pub struct FeeMonitor {
+    limits: FeeLimits,
+    /// Relative fees of indexers, sharded by deployment so that queries only contend with queries
+    /// of the same deployment.
+    relative_fees: RwLock<HashMap<DeploymentId, Arc<Mutex<HashMap<Address, RelativeFee>>>>>,
+}
+
+impl FeeMonitor {
+    /// Weight of each new observation in the moving average of relative fees.
+    const SMOOTHING: f64 = 0.1;
+    /// Minimum number of peers with a non-zero fee required to compare fees against.
+    const MIN_PEERS: usize = 2;
+
+    pub fn new(limits: FeeLimits) -> Self {
+        Self {
+            limits,
+            relative_fees: RwLock::default(),
+        }
+    }
+
+    /// Remove the candidates with fees above their cap, returning an error for each of them, and
+    /// update the relative fees of the candidates. `budget` is the budget the candidate fees are
+    /// normalized to, in GRT wei.
+    pub fn apply(
+        &self,
+        candidates: &mut Vec<Candidate<Address, CandidateMetadata>>,
+        budget: u128,
+        grt_per_usd: f64,
+    ) -> BTreeMap<Address, IndexerError> {
+        let fees = fees(candidates, budget);
+        for (index, candidate) in candidates.iter_mut().enumerate() {
+            let peers: Vec<f64> = fees
+                .iter()
+                .enumerate()
+                .filter(|(peer, fee)| (*peer != index) && (**fee > 0.0))
+                .map(|(_, fee)| *fee)
+                .collect();
+            let shard = self.shard(&candidate.data.deployment);
+            let mut shard = shard.lock();
+            let relative_fee = shard.entry(candidate.id).or_default();
+            if peers.len() >= Self::MIN_PEERS {
+                let relative = fees[index] / median(peers);
+                self.record(candidate, relative_fee, relative);
+            }
+            let anomalous = relative_fee.anomalous;
+            drop(shard);
+            if anomalous && (self.limits.anomaly_action == AnomalyAction::Deprioritize) {
+                candidate.perf.success_rate = Normalized::ZERO;
+            }
+        }
+
+        self.cap(candidates, budget, grt_per_usd)
+    }
+
+    /// Remove the candidates with fees above their cap, returning an error for each of them. Unlike
+    /// `apply`, this leaves the relative fees of the candidates untouched.
+    pub fn cap(
+        &self,
+        candidates: &mut Vec<Candidate<Address, CandidateMetadata>>,
+        budget: u128,
+        grt_per_usd: f64,
+    ) -> BTreeMap<Address, IndexerError> {
+        let fees = fees(candidates, budget);
+        let mut errors = BTreeMap::new();
+        let one_grt = 1e18;
+        let mut index = 0;
+        candidates.retain(|candidate| {
+            let fee = fees[index];
+            index += 1;
+            let cap = self
+                .limits
+                .indexer_max_usd
+                .get(&candidate.id)
+                .or(self.limits.max_usd.as_ref());
+            match cap {
+                Some(cap_usd) if fee > (cap_usd * grt_per_usd * one_grt) => {
+                    METRICS
+                        .indexer_fees_capped
+                        .with_label_values(&[&candidate.id.to_string()])
+                        .inc();
+                    errors.insert(
+                        candidate.id,
+                        IndexerError::Unavailable(UnavailableReason::FeeTooHigh),
+                    );
+                    false
+                }
+                _ => true,
+            }
+        });
+        errors
+    }
+
+    /// Drop the relative fees of indexings that are no longer on the network.
+    pub fn retain_indexings(&self, indexings: &HashSet<IndexingId>) {
+        self.relative_fees.write().retain(|deployment, shard| {
+            let mut shard = shard.lock();
+            shard.retain(|indexer, _| {
+                indexings.contains(&IndexingId {
+                    indexer: *indexer,
+                    deployment: *deployment,
+                })
+            });
+            !shard.is_empty()
+        });
+    }
+
+    fn shard(&self, deployment: &DeploymentId) -> Arc<Mutex<HashMap<Address, RelativeFee>>> {
+        if let Some(shard) = self.relative_fees.read().get(deployment) {
+            return shard.clone();
+        }
+        self.relative_fees
+            .write()
+            .entry(*deployment)
+            .or_default()
+            .clone()
+    }
+
+    fn record(
+        &self,
+        candidate: &Candidate<Address, CandidateMetadata>,
+        state: &mut RelativeFee,
+        relative: f64,
+    ) {
+        let average = match state.average {
+            Some(average) => average + Self::SMOOTHING * (relative - average),
+            None => relative,
+        };
+        state.average = Some(average);
+
+        let anomalous = average > self.limits.anomaly_ratio;
+        if anomalous && !state.anomalous {
+            METRICS
+                .indexer_fee_anomalies
+                .with_label_values(&[&candidate.id.to_string()])
+                .inc();
+            tracing::warn!(
+                indexer = %candidate.id,
+                deployment = %candidate.data.deployment,
+                relative_fee = average,
+                action = ?self.limits.anomaly_action,
+                "indexer fees far above peers",
+            );
+        }
+        state.anomalous = anomalous;
+    }
+}
+
+/// Candidate fees, in GRT wei.
+fn fees(candidates: &[Candidate<Address, CandidateMetadata>], budget: u128) -> Vec<f64> {
+    candidates
+        .iter()
+        .map(|c| c.fee.as_f64() * budget as f64)
+        .collect()
+}
+
+fn median(mut values: Vec<f64>) -> f64 {
+    values.sort_by(|a, b| a.total_cmp(b));
+    let mid = values.len() / 2;
+    if values.len() % 2 == 0 {
+        (values[mid - 1] + values[mid]) / 2.0
+    } else {
+        values[mid]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn candidate(id: u8, fee: f64) -> Candidate<Address, CandidateMetadata> {
+        let mut perf = indexer_selection::Performance::default().expected_performance();
+        perf.success_rate = Normalized::ONE;
+        Candidate {
+            id: Address::with_last_byte(id),
+            data: CandidateMetadata {
+                deployment: "QmaqcZxm6gcgWhWpQ88YKDm1keJDMpNxNGwtEDvjrjjNKh"
+                    .parse()
+                    .unwrap(),
+                url: "http://localhost".parse().unwrap(),
+                largest_allocation: Address::with_last_byte(id),
+                tap_support: true,
+                graph_node_version: semver::Version::new(0, 35, 0),
+            },
+            perf,
+            fee: Normalized::new(fee).unwrap(),
+            seconds_behind: 0,
+            slashable_grt: 100_000,
+            zero_allocation: false,
+        }
+    }
+
+    #[test]
+    fn cap_fees() {
+        //* Given
+        let monitor = FeeMonitor::new(FeeLimits {
+            max_usd: Some(2e-5),
+            indexer_max_usd: HashMap::from([(Address::with_last_byte(2), 1e-5)]),
+            ..Default::default()
+        });
+        // Budget of 1e-4 USD, at 1 GRT/USD
+        let budget = 100_000_000_000_000;
+        let mut candidates = vec![candidate(1, 0.15), candidate(2, 0.15), candidate(3, 0.25)];
+
+        //* When
+        let errors = monitor.apply(&mut candidates, budget, 1.0);
+
+        //* Then
+        let remaining: Vec<Address> = candidates.iter().map(|c| c.id).collect();
+        assert_eq!(remaining, vec![Address::with_last_byte(1)]);
+        assert_eq!(errors.len(), 2);
+        assert!(matches!(
+            errors.get(&Address::with_last_byte(3)),
+            Some(IndexerError::Unavailable(UnavailableReason::FeeTooHigh))
+        ));
+    }
+
+    #[test]
+    fn deprioritize_anomalous_fees() {
+        //* Given
+        let monitor = FeeMonitor::new(FeeLimits {
+            anomaly_action: AnomalyAction::Deprioritize,
+            ..Default::default()
+        });
+        let budget = 100_000_000_000_000;
+        let query = |outlier_fee: f64| {
+            let mut candidates = vec![
+                candidate(1, 0.01),
+                candidate(2, 0.01),
+                candidate(3, 0.01),
+                candidate(4, outlier_fee),
+            ];
+            monitor.apply(&mut candidates, budget, 1.0);
+            candidates[3].perf.success_rate.as_f64()
+        };
+
+        //* When
+        let in_line = query(0.012);
+        let jumped = (0..20).map(|_| query(1.0)).last().unwrap();
+        let recovered = (0..50).map(|_| query(0.01)).last().unwrap();
+
+        //* Then
+        assert!(in_line > 0.0);
+        assert_eq!(jumped, 0.0);
+        assert!(recovered > 0.0);
+    }
+
+    #[test]
+    fn evict_indexings_leaving_the_network() {
+        //* Given
+        let monitor = FeeMonitor::new(FeeLimits::default());
+        let budget = 100_000_000_000_000;
+        let mut candidates = vec![candidate(1, 0.01), candidate(2, 0.01), candidate(3, 0.01)];
+        monitor.apply(&mut candidates, budget, 1.0);
+        let deployment = candidates[0].data.deployment;
+
+        //* When
+        monitor.retain_indexings(&HashSet::from([IndexingId {
+            indexer: Address::with_last_byte(1),
+            deployment,
+        }]));
+        let remaining: Vec<Address> = monitor.shard(&deployment).lock().keys().copied().collect();
+        monitor.retain_indexings(&HashSet::new());
+
+        //* Then
+        assert_eq!(remaining, vec![Address::with_last_byte(1)]);
+        assert!(monitor.relative_fees.read().is_empty());
+    }
+}
@@ -0,0 +1,156 @@
+//! Query fee quotes. A quote runs the candidate selection preparation of a client query, without
+//! sending any indexer requests, and returns the fees the query is expected to pay.
+//!
+//! The blocks for `timestamp` constraints are estimated from the chain cache only, since resolving
+//! them exactly may require probing indexers.
+
+use anyhow::anyhow;
+use axum::{body::Bytes, extract::State, Extension};
+use cost_model::Context as AgoraContext;
+use gateway_framework::{
+    auth::AuthSettings,
+    errors::{Error, IndexerErrors},
+    json::{json_response, JsonResponse},
+};
+use ordered_float::NotNan;
+use prost::bytes::Buf;
+use serde_json::json;
+
+use super::{
+    block_timestamps, build_candidates_list, context::Context, indexer_request_fee, query_budget,
+    query_selector::QuerySelector, query_settings::QuerySettings, resolve_subgraph_info, QueryBody,
+    ResolutionError, SECONDS_BEHIND_CUTOFF, SELECTION_LIMIT,
+};
+use crate::{
+    block_constraints::{resolve_block_requirements, timestamp_constraints},
+    query_complexity,
+};
+
+/// Return the expected fees of a client query across the current candidates, along with the
+/// effective budget for the API key. Fee ranges are for the first round of indexer selection:
+/// the minimum is the fee of the cheapest candidate selected alone, and the maximum is the sum of
+/// the fees of the most expensive candidates selected together.
+pub async fn handle_quote(
+    State(ctx): State<Context>,
+    Extension(auth): Extension<AuthSettings>,
+    query_settings: Option<Extension<QuerySettings>>,
+    selector: QuerySelector,
+    payload: Bytes,
+) -> Result<JsonResponse, Error> {
+    let subgraph = match resolve_subgraph_info(&ctx, &auth, selector).await? {
+        Err(ResolutionError::TransferredToL2 { .. }) => {
+            return Err(Error::SubgraphNotFound(anyhow!("transferred to l2")));
+        }
+        Ok(info) => info,
+    };
+
+    let client_request: QueryBody =
+        serde_json::from_reader(payload.reader()).map_err(|err| Error::BadQuery(err.into()))?;
+
+    let grt_per_usd = *ctx.grt_per_usd.borrow();
+    let one_grt = NotNan::new(1e18).unwrap();
+    let budget = query_budget(
+        &ctx,
+        &auth,
+        &subgraph,
+        query_settings.as_ref().map(|Extension(settings)| settings),
+    );
+    let budget_policy =
+        ctx.budgeter
+            .budget(&auth.key, &auth.user, &subgraph.subgraphs, &subgraph.chain);
+
+    let variables = client_request
+        .variables
+        .as_ref()
+        .map(ToString::to_string)
+        .unwrap_or_default();
+    let agora_context = AgoraContext::new(&client_request.query, &variables)
+        .map_err(|err| Error::BadQuery(anyhow!("{err}")))?;
+    if let Some(query_limits) = &auth.query_limits {
+        query_complexity::check(&agora_context, query_limits)?;
+    }
+
+    let chain = ctx.chains.chain(&subgraph.chain);
+    let timestamp_blocks =
+        block_timestamps::estimate_timestamps(&chain, timestamp_constraints(&agora_context)?)?;
+    let (chain_head, blocks_per_minute, block_requirements) = {
+        let chain_reader = chain.read();
+        let chain_head = chain_reader.latest().map(|b| b.number).unwrap_or_else(|| {
+            subgraph
+                .latest_reported_block()
+                .unwrap_or(subgraph.start_block)
+        });
+        let blocks_per_minute = chain_reader.blocks_per_minute();
+        let block_requirements = resolve_block_requirements(
+            &chain_reader,
+            &agora_context,
+            subgraph.start_block,
+            &timestamp_blocks,
+        )?;
+        (chain_head, blocks_per_minute, block_requirements)
+    };
+
+    let mut indexer_errors = IndexerErrors::default();
+    let (mut candidates, errors) = build_candidates_list(
+        &ctx.indexing_perf.latest(),
+        &agora_context,
+        budget,
+        chain_head,
+        blocks_per_minute,
+        &block_requirements,
+        &subgraph.versions,
+        subgraph.indexings,
+        SECONDS_BEHIND_CUTOFF,
+    );
+    indexer_errors.extend(errors);
+    // Quotes must not affect the fee anomaly detection of client queries.
+    indexer_errors.extend(ctx.fee_monitor.cap(&mut candidates, budget, *grt_per_usd));
+
+    let to_usd = |grt_wei: f64| (grt_wei / *one_grt) / *grt_per_usd;
+    let amount = |grt_wei: f64| json!({ "usd": to_usd(grt_wei), "grt": grt_wei / *one_grt });
+
+    let min_fees = *(budget_policy.min_indexer_fees.borrow().0 * grt_per_usd * one_grt);
+    let selections = candidates.len().min(SELECTION_LIMIT);
+    let mut fees: Vec<u128> = candidates
+        .iter()
+        .map(|c| indexer_request_fee(c.fee, budget, min_fees, selections))
+        .collect();
+    fees.sort_unstable();
+    let fees_min = candidates
+        .iter()
+        .map(|c| indexer_request_fee(c.fee, budget, min_fees, 1))
+        .min();
+    let fees_max: Option<u128> = (selections > 0).then(|| fees.iter().rev().take(selections).sum());
+
+    let candidates: Vec<serde_json::Value> = candidates
+        .iter()
+        .map(|c| {
+            let fee = indexer_request_fee(c.fee, budget, min_fees, selections) as f64;
+            json!({
+                "indexer": c.id,
+                "deployment": c.data.deployment.to_string(),
+                "fee_usd": to_usd(fee),
+                "fee_grt": fee / *one_grt,
+            })
+        })
+        .collect();
+    let errors: serde_json::Map<String, serde_json::Value> = indexer_errors
+        .iter()
+        .map(|(indexer, err)| (indexer.to_string(), err.to_string().into()))
+        .collect();
+
+    Ok(json_response(
+        [],
+        json!({
+            "budget": amount(budget as f64),
+            "budget_policy": budget_policy.name,
+            "min_indexer_fees": amount(min_fees),
+            "fees": {
+                "min": fees_min.map(|fee| amount(fee as f64)),
+                "max": fees_max.map(|fee| amount(fee as f64)),
+            },
+            "candidates": candidates,
+            "errors": errors,
+        }),
+    ))
+}
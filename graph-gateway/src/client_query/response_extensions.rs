@@ -0,0 +1,122 @@
+use alloy_primitives::{Address, BlockHash, BlockNumber};
+use axum::http::{HeaderMap, HeaderName};
+use gateway_framework::blocks::Block;
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+static GRAPH_GATEWAY_EXTENSIONS_HEADER_NAME: HeaderName =
+    HeaderName::from_static("graph-gateway-extensions");
+
+/// Returns true if the client requested the gateway extensions by setting the
+/// `graph-gateway-extensions: true` header.
+pub fn requested(headers: &HeaderMap) -> bool {
+    headers
+        .get(&GRAPH_GATEWAY_EXTENSIONS_HEADER_NAME)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.eq_ignore_ascii_case("true"))
+        .unwrap_or(false)
+}
+
+/// Gateway metadata for a client response, returned under `extensions.gateway`.
+#[derive(Debug, Serialize)]
+pub struct GatewayExtensions {
+    /// The block the indexer responded at, if the indexer reported it.
+    pub block: Option<BlockExtension>,
+    pub indexer: Address,
+    pub deployment: String,
+    pub fee_usd: f64,
+    pub request_id: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BlockExtension {
+    pub number: BlockNumber,
+    pub hash: BlockHash,
+    pub timestamp: u64,
+}
+
+impl From<&Block> for BlockExtension {
+    fn from(block: &Block) -> Self {
+        Self {
+            number: block.number,
+            hash: block.hash,
+            timestamp: block.timestamp,
+        }
+    }
+}
+
+impl GatewayExtensions {
+    /// Add the gateway extensions to the client response, keeping any other extensions returned
+    /// by the indexer.
+    pub fn insert_into(&self, client_response: &str) -> String {
+        let mut response: Map<String, Value> = match serde_json::from_str(client_response) {
+            Ok(response) => response,
+            Err(_) => return client_response.to_string(),
+        };
+        let extensions = response
+            .entry("extensions")
+            .or_insert_with(|| Value::Object(Map::new()));
+        if let Value::Object(extensions) = extensions {
+            extensions.insert("gateway".to_string(), serde_json::to_value(self).unwrap());
+        }
+        serde_json::to_string(&response).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderValue;
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn requested_by_header() {
+        let mut headers = HeaderMap::new();
+        assert!(!requested(&headers));
+        headers.insert(
+            &GRAPH_GATEWAY_EXTENSIONS_HEADER_NAME,
+            HeaderValue::from_static("true"),
+        );
+        assert!(requested(&headers));
+    }
+
+    #[test]
+    fn insert_gateway_extensions() {
+        //* Given
+        let extensions = GatewayExtensions {
+            block: Some(BlockExtension {
+                number: 123,
+                hash: BlockHash::with_last_byte(1),
+                timestamp: 1_700_000_000,
+            }),
+            indexer: Address::with_last_byte(2),
+            deployment: "QmaqcZxm6gcgWhWpQ88YKDm1keJDMpNxNGwtEDvjrjjNKh".to_string(),
+            fee_usd: 0.0001,
+            request_id: "req".to_string(),
+        };
+        let client_response = r#"{"data":{"a":1},"extensions":{"other":true}}"#;
+
+        //* When
+        let response = extensions.insert_into(client_response);
+
+        //* Then
+        let response: Value = serde_json::from_str(&response).unwrap();
+        assert_eq!(response["data"], json!({ "a": 1 }));
+        assert_eq!(response["extensions"]["other"], json!(true));
+        assert_eq!(
+            response["extensions"]["gateway"],
+            json!({
+                "block": {
+                    "number": 123,
+                    "hash": "0x0000000000000000000000000000000000000000000000000000000000000001",
+                    "timestamp": 1_700_000_000,
+                },
+                "indexer": "0x0000000000000000000000000000000000000002",
+                "deployment": "QmaqcZxm6gcgWhWpQ88YKDm1keJDMpNxNGwtEDvjrjjNKh",
+                "fee_usd": 0.0001,
+                "request_id": "req",
+            })
+        );
+    }
+}
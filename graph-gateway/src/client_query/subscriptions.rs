@@ -0,0 +1,410 @@
+//! GraphQL subscriptions over WebSockets, using the `graphql-transport-ws` protocol.
+//!
+//! Subscriptions are executed as queries each time the chain of the subgraph has a new consensus
+//! block, and results are pushed to the client only when they change. Each execution goes through
+//! the same indexer selection, receipts, and reporting as client queries, and the fees spent are
+//! accounted for against the budget of the connection.
+
+use std::{borrow::Cow, collections::HashMap, time::Instant};
+
+use anyhow::{anyhow, bail};
+use axum::{
+    extract::{
+        ws::{CloseFrame, Message, WebSocket, WebSocketUpgrade},
+        State,
+    },
+    response::Response,
+    Extension,
+};
+use gateway_framework::{
+    auth::AuthSettings, blocks::Block, budgets::USD, errors::Error, http::middleware::RequestId,
+};
+use graphql::graphql_parser::query::{
+    parse_query, Definition, Document, OperationDefinition, Query,
+};
+use ordered_float::NotNan;
+use serde::Deserialize;
+use serde_json::{json, value::RawValue};
+use tokio::sync::{mpsc, oneshot, watch};
+use tracing::Instrument as _;
+
+use super::{
+    query_selector::QuerySelector, resolve_subgraph_info, run_indexer_queries, Context, QueryBody,
+    ResolutionError,
+};
+use crate::{indexer_client::IndexerResponse, network::ResolvedSubgraphInfo};
+
+const PROTOCOL: &str = "graphql-transport-ws";
+
+pub async fn handle_subscriptions(
+    State(ctx): State<Context>,
+    Extension(auth): Extension<AuthSettings>,
+    Extension(RequestId(request_id)): Extension<RequestId>,
+    selector: QuerySelector,
+    ws: WebSocketUpgrade,
+) -> Result<Response, Error> {
+    let subgraph = resolve(&ctx, &auth, &selector).await?;
+    let blocks = ctx.chains.chain(&subgraph.chain).subscribe();
+    let connection = Connection {
+        ctx,
+        auth,
+        request_id,
+        selector,
+        initialized: false,
+        subscriptions: HashMap::new(),
+        executions: 0,
+        spent: USD::default(),
+    };
+    Ok(ws
+        .protocols([PROTOCOL])
+        .on_upgrade(move |socket| connection.serve(socket, blocks).in_current_span()))
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ClientMessage {
+    ConnectionInit,
+    Ping,
+    Pong,
+    Subscribe {
+        id: String,
+        payload: SubscribePayload,
+    },
+    Complete {
+        id: String,
+    },
+}
+
+#[derive(Deserialize)]
+struct SubscribePayload {
+    query: String,
+    #[serde(default)]
+    variables: Option<serde_json::Value>,
+}
+
+enum Event {
+    Response { id: String, response: String },
+    Fees(USD),
+}
+
+struct Subscription {
+    query: QueryBody,
+    last_response: Option<String>,
+    executing: bool,
+    /// A new block arrived during the current execution.
+    stale: bool,
+}
+
+struct Connection {
+    ctx: Context,
+    auth: AuthSettings,
+    request_id: String,
+    selector: QuerySelector,
+    initialized: bool,
+    subscriptions: HashMap<String, Subscription>,
+    executions: u64,
+    spent: USD,
+}
+
+impl Connection {
+    async fn serve(mut self, mut socket: WebSocket, mut blocks: watch::Receiver<Option<Block>>) {
+        let (events_tx, mut events) = mpsc::unbounded_channel();
+        blocks.borrow_and_update();
+        loop {
+            let result = tokio::select! {
+                msg = socket.recv() => match msg {
+                    Some(Ok(msg)) => self.handle_message(&mut socket, msg, &events_tx).await,
+                    Some(Err(_)) | None => return,
+                },
+                changed = blocks.changed() => match changed {
+                    Ok(()) => self.execute_all(&events_tx),
+                    Err(_) => return,
+                },
+                Some(event) = events.recv() => self.handle_event(&mut socket, event, &events_tx).await,
+            };
+            if let Err(close_frame) = result {
+                let _ = socket.send(Message::Close(Some(close_frame))).await;
+                return;
+            }
+        }
+    }
+
+    async fn handle_message(
+        &mut self,
+        socket: &mut WebSocket,
+        msg: Message,
+        events: &mpsc::UnboundedSender<Event>,
+    ) -> Result<(), CloseFrame<'static>> {
+        let msg = match msg {
+            Message::Text(text) => text,
+            Message::Binary(_) => return Err(close(4400, "Binary messages are not supported")),
+            Message::Close(_) => return Err(close(1000, "Normal Closure")),
+            Message::Ping(_) | Message::Pong(_) => return Ok(()),
+        };
+        let msg: ClientMessage =
+            serde_json::from_str(&msg).map_err(|_| close(4400, "Invalid message received"))?;
+        match msg {
+            ClientMessage::ConnectionInit if self.initialized => {
+                Err(close(4429, "Too many initialisation requests"))
+            }
+            ClientMessage::ConnectionInit => {
+                self.initialized = true;
+                send(socket, json!({ "type": "connection_ack" }).to_string()).await
+            }
+            ClientMessage::Ping => send(socket, json!({ "type": "pong" }).to_string()).await,
+            ClientMessage::Pong => Ok(()),
+            ClientMessage::Subscribe { .. } if !self.initialized => {
+                Err(close(4401, "Unauthorized"))
+            }
+            ClientMessage::Subscribe { id, .. } if self.subscriptions.contains_key(&id) => {
+                Err(close(4409, format!("Subscriber for {id} already exists")))
+            }
+            ClientMessage::Subscribe { id, payload } => match to_query(payload) {
+                Ok(query) => {
+                    let subscription = Subscription {
+                        query,
+                        last_response: None,
+                        executing: false,
+                        stale: false,
+                    };
+                    self.subscriptions.insert(id.clone(), subscription);
+                    self.execute(&id, events)
+                }
+                Err(err) => {
+                    let msg = json!({
+                        "id": id,
+                        "type": "error",
+                        "payload": [{ "message": err.to_string() }],
+                    });
+                    send(socket, msg.to_string()).await
+                }
+            },
+            ClientMessage::Complete { id } => {
+                self.subscriptions.remove(&id);
+                Ok(())
+            }
+        }
+    }
+
+    async fn handle_event(
+        &mut self,
+        socket: &mut WebSocket,
+        event: Event,
+        events: &mpsc::UnboundedSender<Event>,
+    ) -> Result<(), CloseFrame<'static>> {
+        match event {
+            Event::Fees(fees) => {
+                self.spent = USD(self.spent.0 + fees.0);
+                self.check_budget()
+            }
+            Event::Response { id, response } => {
+                // The subscription may have been completed during the execution.
+                let subscription = match self.subscriptions.get_mut(&id) {
+                    Some(subscription) => subscription,
+                    None => return Ok(()),
+                };
+                subscription.executing = false;
+                let changed = subscription.last_response.as_ref() != Some(&response);
+                if changed {
+                    let msg = format!(
+                        r#"{{"id":{},"type":"next","payload":{response}}}"#,
+                        serde_json::to_string(&id).unwrap()
+                    );
+                    subscription.last_response = Some(response);
+                    send(socket, msg).await?;
+                }
+                if std::mem::take(&mut subscription.stale) {
+                    self.execute(&id, events)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn check_budget(&self) -> Result<(), CloseFrame<'static>> {
+        if self.spent.0 >= self.ctx.subscription_budget.0 {
+            return Err(close(4403, "Subscription budget exhausted"));
+        }
+        Ok(())
+    }
+
+    fn execute_all(
+        &mut self,
+        events: &mpsc::UnboundedSender<Event>,
+    ) -> Result<(), CloseFrame<'static>> {
+        let ids: Vec<String> = self.subscriptions.keys().cloned().collect();
+        for id in ids {
+            self.execute(&id, events)?;
+        }
+        Ok(())
+    }
+
+    fn execute(
+        &mut self,
+        id: &str,
+        events: &mpsc::UnboundedSender<Event>,
+    ) -> Result<(), CloseFrame<'static>> {
+        self.check_budget()?;
+        let subscription = match self.subscriptions.get_mut(id) {
+            Some(subscription) => subscription,
+            None => return Ok(()),
+        };
+        if subscription.executing {
+            subscription.stale = true;
+            return Ok(());
+        }
+        subscription.executing = true;
+        self.executions += 1;
+
+        let ctx = self.ctx.clone();
+        let auth = self.auth.clone();
+        let request_id = format!("{}-{}", self.request_id, self.executions);
+        let selector = self.selector.clone();
+        let query = subscription.query.clone();
+        let id = id.to_string();
+        let events = events.clone();
+        tokio::spawn(
+            async move {
+                let (fees_tx, fees_rx) = oneshot::channel();
+                let response = match execute(&ctx, auth, request_id, &selector, query, fees_tx)
+                    .await
+                {
+                    Ok(response) => response.client_response,
+                    Err(err) => json!({ "errors": [{ "message": err.to_string() }] }).to_string(),
+                };
+                let _ = events.send(Event::Response { id, response });
+                if let Ok(fees) = fees_rx.await {
+                    let _ = events.send(Event::Fees(fees));
+                }
+            }
+            .in_current_span(),
+        );
+        Ok(())
+    }
+}
+
+async fn resolve(
+    ctx: &Context,
+    auth: &AuthSettings,
+    selector: &QuerySelector,
+) -> Result<ResolvedSubgraphInfo, Error> {
+    match resolve_subgraph_info(ctx, auth, selector.clone()).await? {
+        Ok(subgraph) => Ok(subgraph),
+        Err(ResolutionError::TransferredToL2 { .. }) => {
+            Err(Error::SubgraphNotFound(anyhow!("transferred to l2")))
+        }
+    }
+}
+
+/// Execute the subscription query once, against the latest blocks.
+async fn execute(
+    ctx: &Context,
+    auth: AuthSettings,
+    request_id: String,
+    selector: &QuerySelector,
+    query: QueryBody,
+    fees: oneshot::Sender<USD>,
+) -> Result<IndexerResponse, Error> {
+    let subgraph = resolve(ctx, &auth, selector).await?;
+    let grt_per_usd = *ctx.grt_per_usd.borrow();
+    let one_grt = NotNan::new(1e18).unwrap();
+    let query_fees_target = ctx
+        .budgeter
+        .budget(&auth.key, &auth.user, &subgraph.subgraphs, &subgraph.chain)
+        .query_fees_target;
+    let budget = *(query_fees_target.0 * grt_per_usd * one_grt) as u128;
+
+    let (tx, mut rx) = mpsc::channel(1);
+    tokio::spawn(
+        run_indexer_queries(
+            ctx.clone(),
+            request_id,
+            auth,
+            Instant::now(),
+            subgraph,
+            budget,
+            query,
+            false,
+            tx,
+            Some(fees),
+        )
+        .in_current_span(),
+    );
+    rx.recv().await.unwrap()
+}
+
+/// Convert the subscription document into a query document.
+fn to_query(payload: SubscribePayload) -> anyhow::Result<QueryBody> {
+    let document = parse_query::<&str>(&payload.query)?;
+    let mut definitions = Vec::with_capacity(document.definitions.len());
+    for definition in document.definitions {
+        definitions.push(match definition {
+            Definition::Operation(OperationDefinition::Subscription(subscription)) => {
+                Definition::Operation(OperationDefinition::Query(Query {
+                    position: subscription.position,
+                    name: subscription.name,
+                    variable_definitions: subscription.variable_definitions,
+                    directives: subscription.directives,
+                    selection_set: subscription.selection_set,
+                }))
+            }
+            Definition::Operation(OperationDefinition::Mutation(_)) => {
+                bail!("mutations are not supported")
+            }
+            definition => definition,
+        });
+    }
+    let variables = payload
+        .variables
+        .map(|variables| RawValue::from_string(variables.to_string()))
+        .transpose()?;
+    Ok(QueryBody {
+        query: Document { definitions }.to_string(),
+        variables,
+    })
+}
+
+async fn send(socket: &mut WebSocket, msg: String) -> Result<(), CloseFrame<'static>> {
+    socket
+        .send(Message::Text(msg))
+        .await
+        .map_err(|_| close(1011, "Internal Error"))
+}
+
+fn close(code: u16, reason: impl Into<Cow<'static, str>>) -> CloseFrame<'static> {
+    CloseFrame {
+        code,
+        reason: reason.into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn subscription_to_query() {
+        //* Given
+        let payload = SubscribePayload {
+            query: "subscription Tokens($n: Int) { tokens(first: $n) { id } }".to_string(),
+            variables: Some(json!({ "n": 3 })),
+        };
+
+        //* When
+        let query = to_query(payload).unwrap();
+
+        //* Then
+        let document = parse_query::<&str>(&query.query).unwrap();
+        assert!(matches!(
+            document.definitions.as_slice(),
+            [Definition::Operation(OperationDefinition::Query(q))] if q.name == Some("Tokens")
+        ));
+        assert_eq!(query.variables.unwrap().get(), r#"{"n":3}"#);
+
+        let mutation = SubscribePayload {
+            query: "mutation { a }".to_string(),
+            variables: None,
+        };
+        assert!(to_query(mutation).is_err());
+    }
+}
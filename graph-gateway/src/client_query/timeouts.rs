@@ -0,0 +1,121 @@
+//! Per-indexer request timeouts, derived from the latency of recent responses.
+
+use std::time::Duration;
+
+use crate::indexing_performance::Snapshot;
+
+#[derive(Clone, Debug)]
+pub struct IndexerTimeouts {
+    /// Latency percentile the timeout is derived from.
+    pub percentile: f64,
+    /// Factor applied to the latency percentile.
+    pub factor: f64,
+    /// Lower bound of the timeout.
+    pub min: Duration,
+    /// Upper bound of the timeout, also used for indexers without enough latency history.
+    pub max: Duration,
+    /// When set, requests are hedged: only the top candidate is queried at first, and the next
+    /// candidate is queried only if no response arrives within this latency percentile.
+    pub hedge_percentile: Option<f64>,
+    /// Time to wait for outstanding requests once the client query is answered, after which they
+    /// are cancelled.
+    pub straggler_grace: Duration,
+}
+
+impl Default for IndexerTimeouts {
+    fn default() -> Self {
+        Self {
+            percentile: 0.99,
+            factor: 2.0,
+            min: Duration::from_secs(2),
+            max: Duration::from_secs(20),
+            hedge_percentile: None,
+            straggler_grace: Duration::from_secs(1),
+        }
+    }
+}
+
+impl IndexerTimeouts {
+    pub fn timeout(&self, snapshot: Option<&Snapshot>) -> Duration {
+        let latency_ms = match snapshot.and_then(|s| s.latency.percentile(self.percentile)) {
+            Some(latency_ms) => latency_ms,
+            None => return self.max,
+        };
+        // Timeouts that aren't representable, such as those overflowing due to a large factor,
+        // fall back to the upper bound.
+        Duration::try_from_secs_f64(latency_ms as f64 * self.factor * 1e-3)
+            .unwrap_or(self.max)
+            .min(self.max)
+            .max(self.min)
+    }
+
+    /// Delay after which a request to the indexer is hedged by a request to the next candidate.
+    /// Falls back to the minimum timeout for indexers without enough latency history.
+    pub fn hedge_delay(&self, percentile: f64, snapshot: Option<&Snapshot>) -> Duration {
+        match snapshot.and_then(|s| s.latency.percentile(percentile)) {
+            Some(latency_ms) => {
+                Duration::from_millis(latency_ms as u64).min(self.timeout(snapshot))
+            }
+            None => self.min,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timeout_from_latency() {
+        //* Given
+        let timeouts = IndexerTimeouts::default();
+        let snapshot = |latency_ms: u16| {
+            let mut snapshot = Snapshot::default();
+            for _ in 0..100 {
+                snapshot.latency.record(latency_ms);
+            }
+            snapshot
+        };
+
+        //* Then
+        assert_eq!(timeouts.timeout(None), timeouts.max);
+        assert_eq!(timeouts.timeout(Some(&Snapshot::default())), timeouts.max);
+        assert_eq!(timeouts.timeout(Some(&snapshot(10))), timeouts.min);
+        assert_eq!(timeouts.timeout(Some(&snapshot(60_000))), timeouts.max);
+        let timeout = timeouts.timeout(Some(&snapshot(1_500)));
+        assert!((timeout > timeouts.min) && (timeout <= Duration::from_millis(2 * 2_048)));
+    }
+
+    #[test]
+    fn timeout_overflow() {
+        //* Given
+        let timeouts = IndexerTimeouts {
+            factor: f64::MAX,
+            ..Default::default()
+        };
+        let mut snapshot = Snapshot::default();
+        for _ in 0..100 {
+            snapshot.latency.record(1_000);
+        }
+
+        //* Then
+        assert_eq!(timeouts.timeout(Some(&snapshot)), timeouts.max);
+        assert!(timeouts.hedge_delay(0.9, Some(&snapshot)) <= timeouts.max);
+    }
+
+    #[test]
+    fn hedge_delay_from_latency() {
+        //* Given
+        let timeouts = IndexerTimeouts::default();
+        let mut snapshot = Snapshot::default();
+        for _ in 0..100 {
+            snapshot.latency.record(300);
+        }
+
+        //* Then
+        assert_eq!(timeouts.hedge_delay(0.9, None), timeouts.min);
+        let delay = timeouts.hedge_delay(0.9, Some(&snapshot));
+        assert!((Duration::from_millis(256)..=Duration::from_millis(512)).contains(&delay));
+        assert!(delay < timeouts.timeout(Some(&snapshot)));
+    }
+}
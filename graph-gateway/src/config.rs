@@ -4,15 +4,30 @@ use std::{
     collections::{BTreeMap, HashSet},
     fmt::{self, Display},
     path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
 };
 
 use alloy_primitives::{Address, BlockNumber, U256};
-use anyhow::Context;
+use anyhow::{ensure, Context};
 use custom_debug::CustomDebug;
 use gateway_framework::{
     auth::api_keys::APIKey,
+    budgets::{BudgetPolicy, ControllerConfig},
     config::{Hidden, HiddenSecretKey},
 };
+use graph_gateway::{
+    block_constraints,
+    client_query::{
+        fee_monitor::{AnomalyAction, FeeLimits},
+        timeouts::IndexerTimeouts,
+    },
+    indexer_client::TransportConfig,
+    receipts::{
+        backend::{DaemonAddr, DaemonSigner},
+        keys, ledger,
+    },
+};
 use ipnetwork::IpNetwork;
 use ordered_float::NotNan;
 use secp256k1::SecretKey;
@@ -33,9 +48,26 @@ pub struct Config {
     /// indexer-selection imperfections.
     #[serde(default)]
     pub bad_indexers: Vec<Address>,
+    /// Settings of the controllers of the minimum indexer fees, driving the average query fees of
+    /// each budget to its target
+    #[serde(default)]
+    pub budget_controller: ControllerConfig,
+    /// Budget policies, applying their own query fees target to the queries of an API key, user,
+    /// subgraph or chain. Queries matching no policy use `query_fees_target`.
+    #[serde(default)]
+    pub budget_policies: Vec<BudgetPolicy>,
     /// Chain aliases
     #[serde(default)]
     pub chain_aliases: BTreeMap<String, String>,
+    /// JSON-RPC providers used to track chain heads independently of indexer responses, keyed by
+    /// chain name. WebSocket URLs are subscribed to for new blocks, HTTP URLs are polled.
+    #[serde(default)]
+    #[serde_as(as = "BTreeMap<_, Vec<DisplayFromStr>>")]
+    pub chain_rpcs: BTreeMap<String, Vec<Hidden<Url>>>,
+    /// File to append evidence of failed and conflicting attestations to. See the
+    /// `export-disputes` subcommand for exporting this evidence as dispute packages.
+    #[serde(default)]
+    pub dispute_evidence: Option<PathBuf>,
     /// Ethereum RPC provider, or fixed exchange rate for testing
     pub exchange_rate_provider: ExchangeRateProvider,
     /// The Gateway unique identifier. This ID is used to identify the Gateway in the network
@@ -46,6 +78,15 @@ pub struct Config {
     pub gateway_id: Option<String>,
     /// Graph network environment identifier, inserted into Kafka messages
     pub graph_env_id: String,
+    /// Caps on indexer fees, and detection of fees far above those of peers
+    #[serde(default)]
+    pub indexer_fees: IndexerFeesConfig,
+    /// Per-indexer request timeouts, derived from the latency of recent responses
+    #[serde(default)]
+    pub indexer_timeouts: IndexerTimeoutsConfig,
+    /// HTTP transport settings for indexer requests
+    #[serde(default)]
+    pub indexer_transport: IndexerTransport,
     /// File path of CSV containing rows of `IpNetwork,Country`
     pub ip_blocker_db: Option<PathBuf>,
     /// IP rate limit in requests per second
@@ -69,6 +110,11 @@ pub struct Config {
     #[debug(with = Display::fmt)]
     #[serde_as(as = "DisplayFromStr")]
     pub network_subgraph: Url,
+    /// Minimum graph-node version queried for the `parentHash` field of `_meta.block`, which is
+    /// used to resolve chain forks (default: 0.35.0)
+    #[serde(default = "default_parent_hash_min_graph_node_version")]
+    #[serde_as(as = "DisplayFromStr")]
+    pub parent_hash_min_graph_node_version: Version,
     /// Check payment state of client (disable for testnets)
     pub payment_required: bool,
     /// POI blocklist
@@ -85,6 +131,21 @@ pub struct Config {
     pub query_fees_target: NotNan<f64>,
     /// Scalar TAP config (receipt signing)
     pub scalar: Scalar,
+    /// Maximum indexer fees spent on the subscriptions of a single WebSocket connection, in USD
+    /// (default: 1.0)
+    #[serde(
+        default = "default_subscription_budget_usd",
+        deserialize_with = "deserialize_not_nan_f64"
+    )]
+    pub subscription_budget_usd: NotNan<f64>,
+}
+
+fn default_parent_hash_min_graph_node_version() -> Version {
+    block_constraints::DEFAULT_PARENT_HASH_MIN_VERSION
+}
+
+fn default_subscription_budget_usd() -> NotNan<f64> {
+    NotNan::new(1.0).unwrap()
 }
 
 /// Deserialize a `NotNan<f64>` from a `f64` and return an error if the value is NaN.
@@ -147,6 +208,188 @@ pub enum ExchangeRateProvider {
     Fixed(#[serde(deserialize_with = "deserialize_not_nan_f64")] NotNan<f64>),
 }
 
+/// Caps on indexer fees, and detection of indexers whose fees jump far above those of their peers
+/// on the same deployment.
+///
+/// See [`Config`]'s [`indexer_fees`](struct.Config.html#structfield.indexer_fees).
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct IndexerFeesConfig {
+    /// Action taken on indexers with anomalous fees: `alert` (metrics and logs only), or
+    /// `deprioritize`
+    pub anomaly_action: AnomalyAction,
+    /// Fees are anomalous when their moving average exceeds the median fee of peers by this factor
+    pub anomaly_ratio: f64,
+    /// Maximum fee per request to specific indexers, in USD. These override `max_usd`.
+    pub indexer_max_usd: BTreeMap<Address, f64>,
+    /// Maximum fee per indexer request, in USD. Indexers with higher fees are not queried.
+    pub max_usd: Option<f64>,
+}
+
+impl Default for IndexerFeesConfig {
+    fn default() -> Self {
+        let defaults = FeeLimits::default();
+        Self {
+            anomaly_action: defaults.anomaly_action,
+            anomaly_ratio: defaults.anomaly_ratio,
+            indexer_max_usd: defaults.indexer_max_usd.into_iter().collect(),
+            max_usd: defaults.max_usd,
+        }
+    }
+}
+
+impl IndexerFeesConfig {
+    fn validate(&self) -> anyhow::Result<()> {
+        ensure!(
+            self.anomaly_ratio > 0.0,
+            "indexer_fees.anomaly_ratio ({}) must be positive",
+            self.anomaly_ratio
+        );
+        Ok(())
+    }
+}
+
+impl From<IndexerFeesConfig> for FeeLimits {
+    fn from(from: IndexerFeesConfig) -> Self {
+        Self {
+            max_usd: from.max_usd,
+            indexer_max_usd: from.indexer_max_usd.into_iter().collect(),
+            anomaly_ratio: from.anomaly_ratio,
+            anomaly_action: from.anomaly_action,
+        }
+    }
+}
+
+/// Per-indexer request timeouts. Each indexer request times out after the indexer's latency
+/// percentile multiplied by the factor, clamped to the given bounds (in milliseconds).
+///
+/// See [`Config`]'s [`indexer_timeouts`](struct.Config.html#structfield.indexer_timeouts).
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct IndexerTimeoutsConfig {
+    /// Factor applied to the latency percentile
+    pub factor: f64,
+    /// Enables hedged requests: the next candidate is only queried if the top candidate has not
+    /// responded within this latency percentile, in the range [0, 1]
+    pub hedge_percentile: Option<f64>,
+    /// Maximum timeout, also used for indexers without enough latency history
+    pub max_ms: u64,
+    /// Minimum timeout
+    pub min_ms: u64,
+    /// Latency percentile, in the range [0, 1]
+    pub percentile: f64,
+    /// Time to wait for outstanding indexer requests once the client query is answered, after
+    /// which they are cancelled
+    pub straggler_grace_ms: u64,
+}
+
+impl Default for IndexerTimeoutsConfig {
+    fn default() -> Self {
+        let defaults = IndexerTimeouts::default();
+        Self {
+            factor: defaults.factor,
+            hedge_percentile: defaults.hedge_percentile,
+            max_ms: defaults.max.as_millis() as u64,
+            min_ms: defaults.min.as_millis() as u64,
+            percentile: defaults.percentile,
+            straggler_grace_ms: defaults.straggler_grace.as_millis() as u64,
+        }
+    }
+}
+
+impl IndexerTimeoutsConfig {
+    fn validate(&self) -> anyhow::Result<()> {
+        ensure!(
+            (0.0..=1.0).contains(&self.percentile),
+            "indexer_timeouts.percentile ({}) must be in the range [0, 1]",
+            self.percentile
+        );
+        if let Some(hedge_percentile) = self.hedge_percentile {
+            ensure!(
+                (0.0..=1.0).contains(&hedge_percentile),
+                "indexer_timeouts.hedge_percentile ({hedge_percentile}) must be in the range [0, 1]"
+            );
+        }
+        ensure!(
+            self.factor.is_finite() && (self.factor > 0.0),
+            "indexer_timeouts.factor ({}) must be positive and finite",
+            self.factor
+        );
+        ensure!(self.max_ms > 0, "indexer_timeouts.max_ms must be positive");
+        ensure!(
+            self.min_ms <= self.max_ms,
+            "indexer_timeouts.min_ms ({}) exceeds max_ms ({})",
+            self.min_ms,
+            self.max_ms
+        );
+        Ok(())
+    }
+}
+
+impl From<IndexerTimeoutsConfig> for IndexerTimeouts {
+    fn from(from: IndexerTimeoutsConfig) -> Self {
+        Self {
+            percentile: from.percentile,
+            factor: from.factor,
+            min: Duration::from_millis(from.min_ms),
+            max: Duration::from_millis(from.max_ms),
+            hedge_percentile: from.hedge_percentile,
+            straggler_grace: Duration::from_millis(from.straggler_grace_ms),
+        }
+    }
+}
+
+/// HTTP transport settings for indexer requests. All durations are in milliseconds.
+///
+/// See [`Config`]'s [`indexer_transport`](struct.Config.html#structfield.indexer_transport).
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct IndexerTransport {
+    /// Timeout for establishing a connection to an indexer
+    pub connect_timeout_ms: u64,
+    /// Hosts that support HTTP/2 over cleartext, to be queried with HTTP/2 prior knowledge
+    pub http2_prior_knowledge: Vec<String>,
+    /// Maximum number of concurrent requests (and connections) to a single host
+    pub max_connections_per_host: Option<usize>,
+    /// Time after which idle connections are closed
+    pub pool_idle_timeout_ms: u64,
+    /// Maximum number of idle connections kept per host
+    pub pool_max_idle_per_host: usize,
+    /// Interval of TCP keepalive probes
+    pub tcp_keepalive_ms: u64,
+    /// Timeout for indexer requests
+    pub timeout_ms: u64,
+}
+
+impl Default for IndexerTransport {
+    fn default() -> Self {
+        let defaults = TransportConfig::default();
+        Self {
+            connect_timeout_ms: defaults.connect_timeout.as_millis() as u64,
+            http2_prior_knowledge: defaults.http2_prior_knowledge,
+            max_connections_per_host: defaults.max_connections_per_host,
+            pool_idle_timeout_ms: defaults.pool_idle_timeout.as_millis() as u64,
+            pool_max_idle_per_host: defaults.pool_max_idle_per_host,
+            tcp_keepalive_ms: defaults.tcp_keepalive.as_millis() as u64,
+            timeout_ms: defaults.timeout.as_millis() as u64,
+        }
+    }
+}
+
+impl From<IndexerTransport> for TransportConfig {
+    fn from(from: IndexerTransport) -> Self {
+        Self {
+            timeout: Duration::from_millis(from.timeout_ms),
+            connect_timeout: Duration::from_millis(from.connect_timeout_ms),
+            max_connections_per_host: from.max_connections_per_host,
+            http2_prior_knowledge: from.http2_prior_knowledge,
+            tcp_keepalive: Duration::from_millis(from.tcp_keepalive_ms),
+            pool_idle_timeout: Duration::from_millis(from.pool_idle_timeout_ms),
+            pool_max_idle_per_host: from.pool_max_idle_per_host,
+        }
+    }
+}
+
 /// Kafka configuration.
 ///
 /// See [`Config`]'s [`kafka`](struct.Config.html#structfield.kafka).
@@ -191,14 +434,108 @@ impl From<KafkaConfig> for rdkafka::config::ClientConfig {
 pub struct Scalar {
     /// Scalar TAP verifier contract chain
     pub chain_id: U256,
-    /// Secret key for legacy voucher signing
+    /// Secret key for legacy receipt and voucher signing. This key is always held in-process, even
+    /// when `signers` uses signing daemons, since the `receipts` crate signs legacy receipts and
+    /// vouchers from the secret key itself. Legacy receipts and vouchers are disabled when neither
+    /// this nor `signer` is set.
     #[serde_as(as = "Option<HiddenSecretKey>")]
     pub legacy_signer: Option<Hidden<SecretKey>>,
-    /// Secret key for voucher signing
-    #[serde_as(as = "HiddenSecretKey")]
-    pub signer: Hidden<SecretKey>,
+    /// Days for which TAP receipts not aggregated into a RAV are kept in the receipt ledger once
+    /// their allocation is closed, so that the indexer can still request a final RAV (default: 30)
+    #[serde(default = "default_pending_receipt_retention_days")]
+    pub pending_receipt_retention_days: u64,
+    /// File to record issued TAP receipts, and their status, to. See the `export-receipts`
+    /// subcommand for exporting the receipt totals per allocation.
+    #[serde(default)]
+    pub receipt_ledger: Option<PathBuf>,
+    /// Secret key for voucher signing. Also used for legacy voucher signing when
+    /// `legacy_signer` is not set.
+    #[serde(default)]
+    #[serde_as(as = "Option<HiddenSecretKey>")]
+    pub signer: Option<Hidden<SecretKey>>,
+    /// Signer keys for TAP receipts, with activation and expiry times for key rotation. These
+    /// replace `signer` for TAP receipts when set.
+    #[serde(default)]
+    pub signers: Vec<SignerKey>,
     /// Scalar TAP verifier contract address
     pub verifier: Address,
+    /// Addresses allowed to request legacy vouchers for any allocation, in addition to the
+    /// allocation keys
+    #[serde(default)]
+    pub voucher_allowlist: Vec<Address>,
+    /// File to record the receipt ID ranges of issued legacy vouchers to, so that receipts can't
+    /// be collected again after a restart. Ranges are only kept in memory when unset.
+    #[serde(default)]
+    pub voucher_ranges: Option<PathBuf>,
+}
+
+fn default_pending_receipt_retention_days() -> u64 {
+    ledger::DEFAULT_PENDING_RECEIPT_RETENTION.as_secs() / (24 * 60 * 60)
+}
+
+impl Scalar {
+    /// The TAP signer keys.
+    pub fn signer_keys(&self) -> anyhow::Result<Vec<keys::SignerKey>> {
+        if self.signers.is_empty() {
+            let signer = self
+                .signer
+                .as_ref()
+                .context("missing scalar.signer or scalar.signers")?;
+            return Ok(vec![signer.0.into()]);
+        }
+        self.signers.iter().map(SignerKey::load).collect()
+    }
+}
+
+/// TAP signer key.
+///
+/// See [`Scalar`]'s [`signers`](struct.Scalar.html#structfield.signers).
+#[derive(Debug, Deserialize)]
+pub struct SignerKey {
+    /// Unix timestamp, in seconds, from which the key is used to sign new receipts
+    #[serde(default)]
+    pub active_from: Option<u64>,
+    /// Unix timestamp, in seconds, from which the key is no longer used to sign new receipts.
+    /// Receipts signed with the key are still accepted for aggregation.
+    #[serde(default)]
+    pub expires_at: Option<u64>,
+    #[serde(flatten)]
+    pub source: SignerKeySource,
+}
+
+#[serde_as]
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SignerKeySource {
+    /// Secret key
+    SecretKey(#[serde_as(as = "HiddenSecretKey")] Hidden<SecretKey>),
+    /// File containing the hex-encoded secret key
+    File(PathBuf),
+    /// Remote signing service at a `host:port` address, signing with the key of the given address
+    Remote { addr: String, address: Address },
+    /// Local signing daemon listening on the Unix socket at the given path, signing with the key
+    /// of the given address
+    UnixSocket { path: PathBuf, address: Address },
+}
+
+impl SignerKey {
+    fn load(&self) -> anyhow::Result<keys::SignerKey> {
+        let key = match &self.source {
+            SignerKeySource::SecretKey(secret_key) => secret_key.0.into(),
+            SignerKeySource::File(path) => keys::load_key_file(path)?,
+            SignerKeySource::Remote { addr, address } => keys::SignerKey::new(Arc::new(
+                DaemonSigner::new(DaemonAddr::Tcp(addr.clone()), *address),
+            )),
+            SignerKeySource::UnixSocket { path, address } => keys::SignerKey::new(Arc::new(
+                DaemonSigner::new(DaemonAddr::Unix(path.clone()), *address),
+            )),
+        };
+        Ok(keys::SignerKey {
+            active_from: self.active_from,
+            expires_at: self.expires_at,
+            ..key
+        })
+    }
 }
 
 /// Proof of indexing info for the POI blocklist.
@@ -226,7 +563,14 @@ impl From<ProofOfIndexingInfo> for ((DeploymentId, BlockNumber), ProofOfIndexing
 /// Load the configuration from a JSON file.
 pub fn load_from_file(path: &Path) -> Result<Config, Error> {
     let config_content = std::fs::read_to_string(path)?;
-    let config = serde_json::from_str(&config_content)?;
+    let config: Config = serde_json::from_str(&config_content)?;
+    config.indexer_fees.validate().map_err(Error::Invalid)?;
+    config.indexer_timeouts.validate().map_err(Error::Invalid)?;
+    config
+        .budget_controller
+        .validate()
+        .context("budget_controller")
+        .map_err(Error::Invalid)?;
     Ok(config)
 }
 
@@ -251,4 +595,8 @@ pub enum Error {
     /// An error occurred while deserializing the configuration.
     #[error("failed to deserialize configuration: {0}")]
     Deserialize(#[from] serde_json::Error),
+
+    /// The configuration is deserialized, but contains invalid settings.
+    #[error("invalid configuration: {0:#}")]
+    Invalid(anyhow::Error),
 }
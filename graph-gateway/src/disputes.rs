@@ -0,0 +1,278 @@
+//! Local store of evidence for failed and conflicting attestations, and export of that evidence
+//! as dispute packages for the DisputeManager contract.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufRead as _, BufReader, Write as _},
+    path::Path,
+    thread,
+};
+
+use alloy_primitives::Address;
+use anyhow::Context as _;
+use gateway_common::time::unix_timestamp;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use thegraph_core::types::Attestation;
+use tokio::sync::mpsc;
+use toolshed::concat_bytes;
+
+use crate::reports;
+
+/// Evidence of a bad attestation, or of conflicting attestations for the same request.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Evidence {
+    /// Unix timestamp, in milliseconds, of when the evidence was recorded.
+    pub timestamp: u64,
+    pub request: String,
+    /// A single attestation that failed verification, or two conflicting attestations.
+    pub attestations: Vec<AttestedResponse>,
+    /// The reason the attestation failed verification, if any.
+    pub error: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AttestedResponse {
+    pub allocation: Address,
+    pub response: String,
+    pub attestation: Attestation,
+}
+
+impl From<&reports::DisputeEvidence> for Evidence {
+    fn from(evidence: &reports::DisputeEvidence) -> Self {
+        Self {
+            timestamp: unix_timestamp(),
+            request: evidence.request.clone(),
+            attestations: evidence
+                .attestations
+                .iter()
+                .map(|a| AttestedResponse {
+                    allocation: a.allocation,
+                    response: a.response.clone(),
+                    attestation: a.attestation.clone(),
+                })
+                .collect(),
+            error: None,
+        }
+    }
+}
+
+/// Filter for the evidence exported as dispute packages.
+#[derive(Debug, Default)]
+pub struct Filter {
+    pub allocation: Option<Address>,
+    /// Minimum unix timestamp, in seconds.
+    pub from: Option<u64>,
+    /// Maximum unix timestamp, in seconds.
+    pub to: Option<u64>,
+}
+
+impl Filter {
+    fn matches(&self, evidence: &Evidence) -> bool {
+        let timestamp = evidence.timestamp / 1_000;
+        self.allocation.map_or(true, |allocation| {
+            evidence
+                .attestations
+                .iter()
+                .any(|a| a.allocation == allocation)
+        }) && self.from.map_or(true, |from| timestamp >= from)
+            && self.to.map_or(true, |to| timestamp <= to)
+    }
+}
+
+/// Append-only store of evidence, as newline-delimited JSON.
+#[derive(Clone)]
+pub struct EvidenceStore {
+    tx: mpsc::UnboundedSender<Evidence>,
+}
+
+impl EvidenceStore {
+    /// Open the evidence file for appending, creating it if necessary. Evidence is written from a
+    /// dedicated thread, so that recording it never blocks query handling.
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("failed to open {}", path.display()))?;
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        thread::spawn(move || {
+            while let Some(evidence) = rx.blocking_recv() {
+                if let Err(dispute_evidence_err) = append(&mut file, &evidence) {
+                    tracing::error!(%dispute_evidence_err);
+                }
+            }
+        });
+        Ok(Self { tx })
+    }
+
+    pub fn record(&self, evidence: Evidence) {
+        let _ = self.tx.send(evidence);
+    }
+}
+
+fn append(file: &mut File, evidence: &Evidence) -> anyhow::Result<()> {
+    let mut line = serde_json::to_vec(evidence)?;
+    line.push(b'\n');
+    file.write_all(&line)?;
+    file.flush()?;
+    Ok(())
+}
+
+/// Read all evidence from the evidence file.
+pub fn read(path: &Path) -> anyhow::Result<Vec<Evidence>> {
+    let file = File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+    let mut evidence = Vec::new();
+    for (index, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let entry = serde_json::from_str(&line)
+            .with_context(|| format!("invalid evidence on line {}", index + 1))?;
+        evidence.push(entry);
+    }
+    Ok(evidence)
+}
+
+/// Build dispute packages for the evidence matching the filter. Each package contains the
+/// attestation data expected by the DisputeManager: one attestation for `createQueryDispute`, or
+/// two conflicting attestations for `createQueryDisputeConflict`.
+pub fn dispute_packages(evidence: &[Evidence], filter: &Filter) -> serde_json::Value {
+    let packages: Vec<serde_json::Value> = evidence
+        .iter()
+        .filter(|evidence| filter.matches(evidence))
+        .map(|evidence| {
+            let kind = match evidence.attestations.len() {
+                1 => "query",
+                _ => "conflict",
+            };
+            json!({
+                "type": kind,
+                "timestamp": evidence.timestamp / 1_000,
+                "allocations": evidence.attestations.iter().map(|a| a.allocation).collect::<Vec<_>>(),
+                "attestation_data": evidence.attestations.iter().map(|a| attestation_data(&a.attestation)).collect::<Vec<_>>(),
+                "request": evidence.request,
+                "responses": evidence.attestations.iter().map(|a| a.response.as_str()).collect::<Vec<_>>(),
+                "error": evidence.error,
+            })
+        })
+        .collect();
+    serde_json::Value::Array(packages)
+}
+
+/// Encode the attestation as expected by the DisputeManager:
+/// `requestCID ‖ responseCID ‖ subgraphDeploymentID ‖ r ‖ s ‖ v` (161 bytes).
+fn attestation_data(attestation: &Attestation) -> String {
+    let data = concat_bytes!(
+        161,
+        [
+            &attestation.request_cid.0,
+            &attestation.response_cid.0,
+            &attestation.deployment.0,
+            &attestation.r.0,
+            &attestation.s.0,
+            &[attestation.v],
+        ]
+    );
+    format!("0x{}", hex::encode(data))
+}
+
+#[cfg(test)]
+mod tests {
+    use alloy_primitives::B256;
+
+    use super::*;
+
+    fn evidence(timestamp: u64, allocations: &[u8]) -> Evidence {
+        Evidence {
+            timestamp,
+            request: "{\"query\":\"{ a }\"}".to_string(),
+            attestations: allocations
+                .iter()
+                .map(|allocation| AttestedResponse {
+                    allocation: Address::with_last_byte(*allocation),
+                    response: format!("{{\"data\":{{\"a\":{allocation}}}}}"),
+                    attestation: Attestation {
+                        request_cid: B256::with_last_byte(1),
+                        response_cid: B256::with_last_byte(*allocation),
+                        deployment: B256::with_last_byte(3),
+                        r: B256::with_last_byte(4),
+                        s: B256::with_last_byte(5),
+                        v: 27,
+                    },
+                })
+                .collect(),
+            error: None,
+        }
+    }
+
+    #[test]
+    fn store_and_read_evidence() {
+        //* Given
+        let path = std::env::temp_dir().join(format!(
+            "gateway-dispute-evidence-{}.jsonl",
+            rand::random::<u64>()
+        ));
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .unwrap();
+
+        //* When
+        append(&mut file, &evidence(1_000, &[1])).unwrap();
+        append(&mut file, &evidence(2_000, &[1, 2])).unwrap();
+        let evidence = read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        //* Then
+        assert_eq!(evidence.len(), 2);
+        assert_eq!(evidence[1].attestations.len(), 2);
+        assert_eq!(
+            evidence[1].attestations[1].attestation.response_cid,
+            B256::with_last_byte(2)
+        );
+    }
+
+    #[test]
+    fn export_dispute_packages() {
+        //* Given
+        let evidence = [
+            evidence(1_000_000, &[1]),
+            evidence(2_000_000, &[1, 2]),
+            evidence(3_000_000, &[3]),
+        ];
+
+        //* When
+        let by_allocation = dispute_packages(
+            &evidence,
+            &Filter {
+                allocation: Some(Address::with_last_byte(1)),
+                ..Default::default()
+            },
+        );
+        let by_time = dispute_packages(
+            &evidence,
+            &Filter {
+                from: Some(1_500),
+                to: Some(3_000),
+                ..Default::default()
+            },
+        );
+
+        //* Then
+        let by_allocation = by_allocation.as_array().unwrap();
+        assert_eq!(by_allocation.len(), 2);
+        assert_eq!(by_allocation[0]["type"], "query");
+        assert_eq!(by_allocation[1]["type"], "conflict");
+        let attestation_data = by_allocation[0]["attestation_data"][0].as_str().unwrap();
+        assert_eq!(attestation_data.len(), 2 + (161 * 2));
+        assert!(attestation_data.ends_with("1b"));
+
+        let by_time = by_time.as_array().unwrap();
+        assert_eq!(by_time.len(), 2);
+        assert_eq!(by_time[0]["timestamp"], 2_000);
+        assert_eq!(by_time[1]["timestamp"], 3_000);
+    }
+}
@@ -1,21 +1,36 @@
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+
 use alloy_primitives::{BlockHash, BlockNumber};
 use alloy_sol_types::Eip712Domain;
+use gateway_common::time::unix_timestamp;
 use gateway_framework::{
     blocks::Block,
     errors::{
         IndexerError::{self, *},
         MissingBlockError, UnavailableReason,
     },
+    metrics::{with_metric, METRICS},
 };
+use parking_lot::Mutex;
 use serde::{Deserialize, Serialize};
 use thegraph_core::types::{
     attestation::{self, Attestation},
     DeploymentId,
 };
 use thegraph_graphql_http::http::response::{Error as GQLError, ResponseBody as GQLResponseBody};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
 use url::Url;
 
-use crate::{receipts::Receipt, unattestable_errors::miscategorized_unattestable};
+use crate::{
+    disputes::{self, EvidenceStore},
+    receipts::Receipt,
+    unattestable_errors::miscategorized_unattestable,
+};
 
 #[derive(Clone, Debug)]
 pub struct IndexerResponse {
@@ -26,12 +41,75 @@ pub struct IndexerResponse {
     pub probe_block: Option<Block>,
 }
 
+/// Settings for the HTTP transport used to query indexers.
+#[derive(Clone, Debug)]
+pub struct TransportConfig {
+    /// Timeout for an indexer request, from sending the request to receiving the response headers.
+    pub timeout: Duration,
+    /// Timeout for establishing a connection to an indexer.
+    pub connect_timeout: Duration,
+    /// Maximum number of concurrent requests, and therefore connections, to a single host.
+    /// Requests beyond this limit wait for a request to the same host to complete.
+    pub max_connections_per_host: Option<usize>,
+    /// Hosts known to support HTTP/2 over cleartext, which are queried with HTTP/2 prior
+    /// knowledge. HTTP/2 is negotiated via ALPN for all HTTPS hosts.
+    pub http2_prior_knowledge: Vec<String>,
+    /// Interval of TCP keepalive probes on idle connections.
+    pub tcp_keepalive: Duration,
+    /// Time after which idle connections are closed.
+    pub pool_idle_timeout: Duration,
+    /// Maximum number of idle connections kept per host.
+    pub pool_max_idle_per_host: usize,
+}
+
+impl Default for TransportConfig {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(20),
+            connect_timeout: Duration::from_secs(5),
+            max_connections_per_host: None,
+            http2_prior_knowledge: vec![],
+            tcp_keepalive: Duration::from_secs(30),
+            pool_idle_timeout: Duration::from_secs(90),
+            pool_max_idle_per_host: 32,
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct IndexerClient {
     pub client: reqwest::Client,
+    /// Client for the hosts queried with HTTP/2 prior knowledge.
+    http2_client: reqwest::Client,
+    http2_hosts: Arc<Vec<String>>,
+    host_limits: Option<HostLimits>,
+    /// Store for the evidence of attestations that fail verification.
+    pub dispute_evidence: Option<EvidenceStore>,
 }
 
 impl IndexerClient {
+    pub fn new(
+        transport: TransportConfig,
+        dispute_evidence: Option<EvidenceStore>,
+    ) -> reqwest::Result<Self> {
+        let builder = || {
+            reqwest::Client::builder()
+                .timeout(transport.timeout)
+                .connect_timeout(transport.connect_timeout)
+                .tcp_keepalive(transport.tcp_keepalive)
+                .pool_idle_timeout(transport.pool_idle_timeout)
+                .pool_max_idle_per_host(transport.pool_max_idle_per_host)
+                .connector_layer(ConnectionCounter)
+        };
+        Ok(Self {
+            client: builder().build()?,
+            http2_client: builder().http2_prior_knowledge().build()?,
+            http2_hosts: Arc::new(transport.http2_prior_knowledge),
+            host_limits: transport.max_connections_per_host.map(HostLimits::new),
+            dispute_evidence,
+        })
+    }
+
     pub async fn query_indexer(
         &self,
         deployment: &DeploymentId,
@@ -43,9 +121,21 @@ impl IndexerClient {
         let url = url
             .join(&format!("subgraphs/id/{:?}", deployment))
             .map_err(|_| Unavailable(UnavailableReason::Internal("bad indexer url")))?;
+        let host = url.host_str().unwrap_or_default().to_string();
+
+        // Held until the response body has been read, since the connection is in use until then.
+        let _permit = match &self.host_limits {
+            Some(host_limits) => Some(host_limits.acquire(&host).await),
+            None => None,
+        };
+        with_metric(&METRICS.indexer_requests, &[&host], |c| c.inc());
+        let client = if self.http2_hosts.contains(&host) {
+            &self.http2_client
+        } else {
+            &self.client
+        };
 
-        let result = self
-            .client
+        let result = client
             .post(url)
             .header("Content-Type", "application/json")
             .header(receipt.header_name(), receipt.serialize())
@@ -118,6 +208,18 @@ impl IndexerClient {
                     query,
                     &original_response,
                 ) {
+                    if let Some(dispute_evidence) = &self.dispute_evidence {
+                        dispute_evidence.record(disputes::Evidence {
+                            timestamp: unix_timestamp(),
+                            request: query.to_string(),
+                            attestations: vec![disputes::AttestedResponse {
+                                allocation,
+                                response: original_response.clone(),
+                                attestation: attestation.clone(),
+                            }],
+                            error: Some(err.to_string()),
+                        });
+                    }
                     return Err(BadResponse(format!("bad attestation: {err}")));
                 }
             }
@@ -148,6 +250,66 @@ impl IndexerClient {
     }
 }
 
+/// Limits on the number of concurrent requests to each host.
+#[derive(Clone)]
+struct HostLimits {
+    limit: usize,
+    hosts: Arc<Mutex<HashMap<String, Arc<Semaphore>>>>,
+}
+
+impl HostLimits {
+    fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            hosts: Default::default(),
+        }
+    }
+
+    async fn acquire(&self, host: &str) -> OwnedSemaphorePermit {
+        let semaphore = self
+            .hosts
+            .lock()
+            .entry(host.to_string())
+            .or_insert_with(|| Arc::new(Semaphore::new(self.limit)))
+            .clone();
+        semaphore.acquire_owned().await.unwrap()
+    }
+}
+
+/// Connector layer that counts the connections opened to indexers. A new connection is only opened
+/// when the connection pool has no idle connection to the host. So the rate of connection reuse is
+/// `1 - gw_indexer_connections / sum(gw_indexer_requests)`.
+#[derive(Clone)]
+struct ConnectionCounter;
+
+impl<S> tower::Layer<S> for ConnectionCounter {
+    type Service = CountConnections<S>;
+    fn layer(&self, connector: S) -> Self::Service {
+        CountConnections(connector)
+    }
+}
+
+#[derive(Clone)]
+struct CountConnections<S>(S);
+
+impl<S, R> tower::Service<R> for CountConnections<S>
+where
+    S: tower::Service<R>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = S::Future;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.0.poll_ready(cx)
+    }
+
+    fn call(&mut self, dst: R) -> Self::Future {
+        METRICS.indexer_connections.inc();
+        self.0.call(dst)
+    }
+}
+
 fn rewrite_response(
     response: &str,
 ) -> Result<(String, Vec<GQLError>, Option<Block>), IndexerError> {
@@ -166,6 +328,8 @@ fn rewrite_response(
     struct MaybeBlock {
         number: BlockNumber,
         hash: BlockHash,
+        #[serde(rename = "parentHash")]
+        parent_hash: Option<BlockHash>,
         timestamp: Option<u64>,
     }
     let mut payload: GQLResponseBody<ProbedData> =
@@ -185,6 +349,7 @@ fn rewrite_response(
             Some(Block {
                 number: meta.block.number,
                 hash: meta.block.hash,
+                parent_hash: meta.block.parent_hash,
                 timestamp: meta.block.timestamp?,
             })
         });
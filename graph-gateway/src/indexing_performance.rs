@@ -11,20 +11,102 @@ use crate::network::NetworkService;
 pub struct Snapshot {
     pub response: indexer_selection::Performance,
     pub latest_block: Option<BlockNumber>,
+    /// Latency of successful responses.
+    pub latency: LatencyHistogram,
+    /// Decaying number of responses that diverged from the response agreed on by other indexers.
+    pub divergences: f64,
+}
+
+impl Snapshot {
+    /// Record the outcome of a request to the indexer.
+    pub fn feedback(&mut self, success: bool, latency_ms: u16, latest_block: Option<BlockNumber>) {
+        self.response.feedback(success, latency_ms);
+        if success {
+            self.latency.record(latency_ms);
+        }
+        self.latest_block = match (self.latest_block, latest_block) {
+            (None, block) => block,
+            (Some(a), Some(b)) if b > a => Some(b),
+            (Some(a), _) => Some(a),
+        };
+    }
+
+    /// Decay the performance information, expected to be called once per second.
+    pub fn decay(&mut self) {
+        self.response.decay();
+        self.latency.decay();
+        self.divergences *= LatencyHistogram::RETAIN;
+    }
+}
+
+/// Decaying histogram of response latencies, with buckets doubling in size. Bucket `i` counts
+/// latencies in `[2^(i-1), 2^i)` ms.
+#[derive(Clone, Default)]
+pub struct LatencyHistogram {
+    buckets: [f64; 17],
+}
+
+impl LatencyHistogram {
+    /// Fraction of the counts retained on each decay, which happens once per second.
+    const RETAIN: f64 = 0.995;
+    /// Minimum (decayed) number of samples required to estimate percentiles.
+    const MIN_SAMPLES: f64 = 10.0;
+
+    pub fn record(&mut self, latency_ms: u16) {
+        let bucket = (u16::BITS - latency_ms.leading_zeros()) as usize;
+        self.buckets[bucket] += 1.0;
+    }
+
+    fn decay(&mut self) {
+        for count in &mut self.buckets {
+            *count *= Self::RETAIN;
+        }
+    }
+
+    /// Estimate the latency percentile, in ms, by interpolating within the bucket containing it.
+    /// Returns `None` when there aren't enough samples.
+    pub fn percentile(&self, p: f64) -> Option<u32> {
+        let total: f64 = self.buckets.iter().sum();
+        if total < Self::MIN_SAMPLES {
+            return None;
+        }
+        let target = total * p.clamp(0.0, 1.0);
+        let mut cumulative = 0.0;
+        for (bucket, count) in self.buckets.iter().enumerate() {
+            if (*count > 0.0) && (cumulative + count >= target) {
+                let lower = if bucket == 0 {
+                    0.0
+                } else {
+                    (1_u32 << (bucket - 1)) as f64
+                };
+                let upper = (1_u32 << bucket) as f64;
+                let fraction = (target - cumulative) / count;
+                return Some((lower + (upper - lower) * fraction).ceil() as u32);
+            }
+            cumulative += count;
+        }
+        None
+    }
 }
 
 #[derive(Clone)]
 pub struct IndexingPerformance {
     data: &'static DoubleBuffer,
-    msgs: mpsc::UnboundedSender<Feedback>,
+    msgs: mpsc::UnboundedSender<Msg>,
 }
 
-struct Feedback {
-    indexer: Address,
-    deployment: DeploymentId,
-    success: bool,
-    latency_ms: u16,
-    latest_block: Option<BlockNumber>,
+enum Msg {
+    Feedback {
+        indexer: Address,
+        deployment: DeploymentId,
+        success: bool,
+        latency_ms: u16,
+        latest_block: Option<BlockNumber>,
+    },
+    Divergence {
+        indexer: Address,
+        deployment: DeploymentId,
+    },
 }
 
 impl IndexingPerformance {
@@ -57,7 +139,7 @@ impl IndexingPerformance {
         latest_block: Option<BlockNumber>,
     ) {
         self.msgs
-            .send(Feedback {
+            .send(Msg::Feedback {
                 indexer,
                 deployment,
                 success,
@@ -66,6 +148,17 @@ impl IndexingPerformance {
             })
             .unwrap();
     }
+
+    /// Record a response from the indexer that diverged from the response agreed on by other
+    /// indexers for the same request.
+    pub fn divergence(&self, indexer: Address, deployment: DeploymentId) {
+        self.msgs
+            .send(Msg::Divergence {
+                indexer,
+                deployment,
+            })
+            .unwrap();
+    }
 }
 
 #[derive(Default)]
@@ -78,7 +171,7 @@ struct Actor {
 impl Actor {
     fn spawn(
         data: &'static DoubleBuffer,
-        mut messages: mpsc::UnboundedReceiver<Feedback>,
+        mut messages: mpsc::UnboundedReceiver<Msg>,
         mut network: NetworkService,
     ) {
         let mut actor = Self { data };
@@ -100,29 +193,37 @@ impl Actor {
     fn decay(&mut self) {
         for unlocked in &self.data.0 {
             for snapshot in unlocked.write().values_mut() {
-                snapshot.response.decay();
+                snapshot.decay();
             }
         }
     }
 
-    fn handle_msgs(&mut self, msgs: &mut Vec<Feedback>) {
+    fn handle_msgs(&mut self, msgs: &mut Vec<Msg>) {
         for unlocked in &self.data.0 {
             let mut locked = unlocked.write();
-            for Feedback {
-                indexer,
-                deployment,
-                success,
-                latency_ms,
-                latest_block,
-            } in msgs.drain(..)
-            {
-                let snapshot = locked.entry((indexer, deployment)).or_default();
-                snapshot.response.feedback(success, latency_ms);
-                snapshot.latest_block = match (snapshot.latest_block, latest_block) {
-                    (None, block) => block,
-                    (Some(a), Some(b)) if b > a => Some(b),
-                    (Some(a), _) => Some(a),
-                };
+            for msg in msgs.drain(..) {
+                match msg {
+                    Msg::Feedback {
+                        indexer,
+                        deployment,
+                        success,
+                        latency_ms,
+                        latest_block,
+                    } => {
+                        locked.entry((indexer, deployment)).or_default().feedback(
+                            success,
+                            latency_ms,
+                            latest_block,
+                        );
+                    }
+                    Msg::Divergence {
+                        indexer,
+                        deployment,
+                    } => {
+                        let snapshot = locked.entry((indexer, deployment)).or_default();
+                        snapshot.divergences += 1.0;
+                    }
+                }
             }
         }
         debug_assert!(msgs.is_empty());
@@ -143,3 +244,27 @@ impl Actor {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::LatencyHistogram;
+
+    #[test]
+    fn latency_percentiles() {
+        //* Given
+        let mut histogram = LatencyHistogram::default();
+        assert_eq!(histogram.percentile(0.99), None);
+
+        //* When
+        for _ in 0..99 {
+            histogram.record(100);
+        }
+        histogram.record(3_000);
+
+        //* Then
+        // 100 ms falls in the [64, 128) bucket, and 3000 ms falls in the [2048, 4096) bucket.
+        assert!((64..=128).contains(&histogram.percentile(0.5).unwrap()));
+        assert!((64..=128).contains(&histogram.percentile(0.99).unwrap()));
+        assert!((2_048..=4_096).contains(&histogram.percentile(1.0).unwrap()));
+    }
+}
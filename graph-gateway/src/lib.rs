@@ -1,9 +1,11 @@
 pub mod block_constraints;
 pub mod client_query;
+pub mod disputes;
 pub mod indexer_client;
 pub mod indexers;
 pub mod indexing_performance;
 pub mod network;
+pub mod query_complexity;
 pub mod receipts;
 pub mod reports;
 pub mod subgraph_studio;
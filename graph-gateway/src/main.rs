@@ -13,7 +13,7 @@ use alloy_sol_types::Eip712Domain;
 use axum::{
     body::Body,
     extract::{ConnectInfo, DefaultBodyLimit, State},
-    http::{self, status::StatusCode, Request},
+    http::{status::StatusCode, Request},
     middleware,
     middleware::Next,
     response::Response,
@@ -24,24 +24,21 @@ use gateway_framework::{
     auth::AuthContext,
     budgets::{Budgeter, USD},
     chains::Chains,
-    exchange_rate,
-    http::middleware::{
-        legacy_auth_adapter, RequestTracingLayer, RequireAuthorizationLayer, SetRequestIdLayer,
-    },
-    json, logging,
+    exchange_rate, json, logging,
 };
 use graph_gateway::{
-    client_query::{self, context::Context},
+    client_query::{self, context::Context, fee_monitor::FeeMonitor},
+    disputes,
     indexer_client::IndexerClient,
     indexing_performance::IndexingPerformance,
     network::{
         subgraph_client::Client as NetworkSubgraphClient, NetworkService, NetworkServiceBuilder,
     },
-    receipts::ReceiptSigner,
-    reports, subgraph_studio, vouchers,
+    receipts::{self, ledger::ReceiptLedger, ReceiptSigner},
+    reports, subgraph_studio,
+    vouchers::{self, ranges::VoucherRanges, Vouchers},
 };
 use prometheus::{self, Encoder as _};
-use secp256k1::SecretKey;
 use semver::Version;
 use serde_json::json;
 use simple_rate_limiter::RateLimiter;
@@ -52,7 +49,6 @@ use tokio::{
     sync::watch,
     time::{interval, MissedTickBehavior},
 };
-use tower_http::cors::{self, CorsLayer};
 use uuid::Uuid;
 
 mod config;
@@ -62,6 +58,15 @@ static ALLOC: snmalloc_rs::SnMalloc = snmalloc_rs::SnMalloc;
 
 #[tokio::main]
 async fn main() {
+    if env::args().nth(1).as_deref() == Some("export-disputes") {
+        export_disputes(env::args().skip(2).collect());
+        return;
+    }
+    if env::args().nth(1).as_deref() == Some("export-receipts") {
+        export_receipts(env::args().skip(2).collect());
+        return;
+    }
+
     let conf_path = env::args()
         .nth(1)
         .expect("Missing argument for config path")
@@ -120,25 +125,80 @@ async fn main() {
     let indexing_perf = IndexingPerformance::new(network.clone());
     network.wait_until_ready().await;
 
-    let legacy_signer: &'static SecretKey = Box::leak(Box::new(
+    // Legacy receipts and vouchers are signed from the secret key itself, so their key can't be
+    // held by a signing daemon.
+    let legacy_signer = conf
+        .scalar
+        .legacy_signer
+        .as_ref()
+        .or(conf.scalar.signer.as_ref())
+        .map(|s| s.0);
+    if legacy_signer.is_none() {
+        tracing::warn!(
+            "scalar.legacy_signer is not set, so legacy receipts and vouchers are disabled"
+        );
+    }
+    let mut receipt_signer = ReceiptSigner::new(
         conf.scalar
-            .legacy_signer
-            .map(|s| s.0)
-            .unwrap_or(conf.scalar.signer.0),
-    ));
-    let receipt_signer: &'static ReceiptSigner = Box::leak(Box::new(ReceiptSigner::new(
-        conf.scalar.signer.0,
+            .signer_keys()
+            .expect("Failed to load signer keys"),
         conf.scalar.chain_id,
         conf.scalar.verifier,
         legacy_signer,
+    );
+    if let Some(path) = &conf.scalar.receipt_ledger {
+        receipt_signer = receipt_signer.with_ledger(
+            ReceiptLedger::open(path)
+                .expect("Failed to open receipt ledger")
+                .with_retention(Duration::from_secs(
+                    conf.scalar.pending_receipt_retention_days * 24 * 60 * 60,
+                )),
+        );
+    }
+    let receipt_signer: &'static ReceiptSigner = Box::leak(Box::new(receipt_signer));
+    let legacy_vouchers: &'static Vouchers = Box::leak(Box::new(Vouchers::new(
+        legacy_signer,
+        conf.scalar.voucher_allowlist.iter().copied().collect(),
+        match &conf.scalar.voucher_ranges {
+            Some(path) => VoucherRanges::open(path).expect("Failed to open voucher ranges"),
+            None => {
+                tracing::warn!(
+                    "scalar.voucher_ranges is not set, so vouchered receipt ranges are only kept \
+                     in memory, and receipts can be collected again after a restart"
+                );
+                VoucherRanges::default()
+            }
+        },
     )));
 
+    let fee_monitor: &'static FeeMonitor =
+        Box::leak(Box::new(FeeMonitor::new(conf.indexer_fees.into())));
+
+    // Drop the receipt state of allocations that are closed on the network, and the fee history of
+    // indexings that left it
+    {
+        let mut network = network.clone();
+        tokio::spawn(async move {
+            loop {
+                network.changed().await;
+                receipt_signer.retain_allocations(&network.allocations());
+                fee_monitor.retain_indexings(&network.indexing_progress().into_keys().collect());
+            }
+        });
+    }
+
     // Initialize the auth service
     let auth_service =
         init_auth_service(http_client.clone(), conf.api_keys, conf.payment_required).await;
 
-    let budgeter: &'static Budgeter =
-        Box::leak(Box::new(Budgeter::new(USD(conf.query_fees_target))));
+    let budgeter: &'static Budgeter = Box::leak(Box::new(
+        Budgeter::with_policies(
+            USD(conf.query_fees_target),
+            conf.budget_policies,
+            conf.budget_controller,
+        )
+        .expect("Failed to create budgets"),
+    ));
 
     let reporter = reports::Reporter::create(
         conf.graph_env_id,
@@ -146,22 +206,37 @@ async fn main() {
         "gateway_client_query_results",
         "gateway_indexer_attempts",
         "gateway_attestations",
+        "gateway_dispute_evidence",
         conf.kafka,
     )
     .unwrap();
 
     let ctx = Context {
-        indexer_client: IndexerClient {
-            client: http_client.clone(),
-        },
+        indexer_client: IndexerClient::new(
+            conf.indexer_transport.into(),
+            conf.dispute_evidence.as_deref().map(|path| {
+                disputes::EvidenceStore::open(path).expect("Failed to open dispute evidence")
+            }),
+        )
+        .expect("Failed to build indexer client"),
         receipt_signer,
         budgeter,
         l2_gateway: conf.l2_gateway,
-        chains: Box::leak(Box::new(Chains::new(conf.chain_aliases))),
+        chains: Box::leak(Box::new(Chains::new(
+            conf.chain_aliases,
+            conf.chain_rpcs
+                .into_iter()
+                .map(|(chain, urls)| (chain, urls.into_iter().map(|url| url.0).collect()))
+                .collect(),
+        ))),
         grt_per_usd,
         indexing_perf,
+        indexer_timeouts: conf.indexer_timeouts.into(),
+        fee_monitor,
+        subscription_budget: USD(conf.subscription_budget_usd),
         network,
         attestation_domain,
+        parent_hash_min_graph_node_version: conf.parent_hash_min_graph_node_version,
         reporter,
     };
 
@@ -198,44 +273,6 @@ async fn main() {
         }
     });
 
-    let api = Router::new()
-        .route(
-            "/deployments/id/:deployment_id",
-            routing::post(client_query::handle_query),
-        )
-        .route(
-            "/:api_key/deployments/id/:deployment_id",
-            routing::post(client_query::handle_query),
-        )
-        .route(
-            "/subgraphs/id/:subgraph_id",
-            routing::post(client_query::handle_query),
-        )
-        .route(
-            "/:api_key/subgraphs/id/:subgraph_id",
-            routing::post(client_query::handle_query),
-        )
-        .with_state(ctx)
-        .layer(
-            // ServiceBuilder works by composing all layers into one such that they run top to
-            // bottom, and then the response would bubble back up through the layers in reverse
-            tower::ServiceBuilder::new()
-                .layer(
-                    CorsLayer::new()
-                        .allow_origin(cors::Any)
-                        .allow_headers(cors::Any)
-                        .allow_methods([http::Method::OPTIONS, http::Method::POST]),
-                )
-                // Set up the query tracing span
-                .layer(RequestTracingLayer)
-                // Set the query ID on the request
-                .layer(SetRequestIdLayer::new(gateway_id))
-                // Handle legacy in-path auth, and convert it into a header
-                .layer(middleware::from_fn(legacy_auth_adapter))
-                // Require the query to be authorized
-                .layer(RequireAuthorizationLayer::new(auth_service)),
-        );
-
     let router = Router::new()
         .route("/", routing::get(|| async { "Ready to roll!" }))
         // This path is required by NGINX ingress controller
@@ -243,24 +280,27 @@ async fn main() {
         .route(
             "/collect-receipts",
             routing::post(vouchers::handle_collect_receipts)
-                .with_state(legacy_signer)
+                .with_state(legacy_vouchers)
                 .layer(DefaultBodyLimit::max(3_000_000)),
         )
         .route(
             "/partial-voucher",
             routing::post(vouchers::handle_partial_voucher)
-                .with_state(legacy_signer)
+                .with_state(legacy_vouchers)
                 .layer(DefaultBodyLimit::max(3_000_000)),
         )
         .route(
             "/voucher",
-            routing::post(vouchers::handle_voucher).with_state(legacy_signer),
+            routing::post(vouchers::handle_voucher).with_state(legacy_vouchers),
         )
         .route(
-            "/budget",
-            routing::get(|| async { budgeter.query_fees_target.0.to_string() }),
+            "/rav",
+            routing::post(vouchers::handle_rav)
+                .with_state(receipt_signer)
+                .layer(DefaultBodyLimit::max(3_000_000)),
         )
-        .nest("/api", api)
+        .route("/budget", routing::get(handle_budget).with_state(budgeter))
+        .nest("/api", client_query::router(ctx, auth_service, gateway_id))
         .layer(middleware::from_fn_with_state(rate_limiter, ip_rate_limit));
 
     let app_listener = TcpListener::bind(SocketAddr::new(
@@ -281,6 +321,60 @@ async fn main() {
     tracing::warn!("shutdown");
 }
 
+/// `graph-gateway export-disputes <evidence file> [--allocation <address>] [--from <unix seconds>]
+/// [--to <unix seconds>]`
+///
+/// Print the dispute packages for the recorded evidence as JSON.
+fn export_disputes(args: Vec<String>) {
+    let mut args = args.into_iter();
+    let path: PathBuf = args
+        .next()
+        .expect("Missing argument for dispute evidence path")
+        .into();
+    let mut filter = disputes::Filter::default();
+    while let Some(flag) = args.next() {
+        let value = args
+            .next()
+            .unwrap_or_else(|| panic!("Missing value for {flag}"));
+        match flag.as_str() {
+            "--allocation" => filter.allocation = Some(value.parse().expect("Invalid allocation")),
+            "--from" => filter.from = Some(value.parse().expect("Invalid --from timestamp")),
+            "--to" => filter.to = Some(value.parse().expect("Invalid --to timestamp")),
+            _ => panic!("Unexpected argument: {flag}"),
+        }
+    }
+    let evidence = disputes::read(&path).expect("Failed to read dispute evidence");
+    let packages = disputes::dispute_packages(&evidence, &filter);
+    println!("{}", serde_json::to_string_pretty(&packages).unwrap());
+}
+
+/// `graph-gateway export-receipts <receipt ledger file> [--allocation <address>] [--receipts]`
+///
+/// Print the TAP receipt totals per allocation as JSON, along with the issued receipts if
+/// `--receipts` is given.
+fn export_receipts(args: Vec<String>) {
+    let mut args = args.into_iter();
+    let path: PathBuf = args
+        .next()
+        .expect("Missing argument for receipt ledger path")
+        .into();
+    let mut allocation = None;
+    let mut include_receipts = false;
+    while let Some(flag) = args.next() {
+        match flag.as_str() {
+            "--allocation" => {
+                let value = args.next().expect("Missing value for --allocation");
+                allocation = Some(value.parse().expect("Invalid allocation"));
+            }
+            "--receipts" => include_receipts = true,
+            _ => panic!("Unexpected argument: {flag}"),
+        }
+    }
+    let entries = receipts::ledger::read(&path).expect("Failed to read receipt ledger");
+    let export = receipts::ledger::export(&entries, allocation, include_receipts);
+    println!("{}", serde_json::to_string_pretty(&export).unwrap());
+}
+
 async fn await_shutdown_signals() {
     #[cfg(unix)]
     let sigint = async {
@@ -321,6 +415,29 @@ async fn ip_rate_limit(
     Ok(next.run(req).await)
 }
 
+/// Live state of the budget controllers, one per budget policy.
+async fn handle_budget(State(budgeter): State<&'static Budgeter>) -> json::JsonResponse {
+    let budgets: Vec<serde_json::Value> = budgeter
+        .budgets()
+        .map(|budget| {
+            json!({
+                "policy": budget.name,
+                "query_fees_target": *budget.query_fees_target.0,
+                "min_indexer_fees": *budget.min_indexer_fees.borrow().0,
+                "controller": &*budget.controller.borrow(),
+            })
+        })
+        .collect();
+    json::json_response(
+        [],
+        json!({
+            "query_fees_target": *budgeter.default.query_fees_target.0,
+            "controller": budgeter.controller_config,
+            "budgets": budgets,
+        }),
+    )
+}
+
 async fn handle_metrics() -> impl axum::response::IntoResponse {
     let encoder = prometheus::TextEncoder::new();
     let metric_families = prometheus::gather();
@@ -1,4 +1,7 @@
-use std::{collections::HashMap, time::Duration};
+use std::{
+    collections::{HashMap, HashSet},
+    time::Duration,
+};
 
 use alloy_primitives::Address;
 use thegraph_core::types::{DeploymentId, SubgraphId};
@@ -28,6 +31,7 @@ pub async fn fetch_update(
         indexers_info,
         network.subgraphs.clone(),
         network.deployments.clone(),
+        network.allocations.clone(),
     )
 }
 
@@ -35,6 +39,7 @@ pub struct PreprocessedNetworkInfo {
     subgraphs: HashMap<SubgraphId, Result<SubgraphInfo, SubgraphError>>,
     deployments: HashMap<DeploymentId, Result<DeploymentInfo, DeploymentError>>,
     indexers: HashMap<Address, IndexerRawInfo>,
+    allocations: HashSet<Address>,
 }
 
 /// Fetch the subgraphs information from the graph network subgraph and performs pre-processing
@@ -55,6 +60,7 @@ pub async fn fetch_and_preprocess_subgraph_info(
     anyhow::ensure!(!data.is_empty(), "empty subgraph response");
 
     // Pre-process (validate and convert) the fetched subgraphs information
+    let allocations = pre_processing::into_allocations(data.iter());
     let indexers = pre_processing::into_internal_indexers_raw_info(data.iter());
     let subgraphs = pre_processing::into_internal_subgraphs_raw_info(data.into_iter());
     let deployments = pre_processing::into_internal_deployments_raw_info(subgraphs.values());
@@ -66,6 +72,7 @@ pub async fn fetch_and_preprocess_subgraph_info(
         subgraphs,
         deployments,
         indexers,
+        allocations,
     })
 }
 
@@ -1,4 +1,4 @@
-use std::collections::{hash_map::Entry, HashMap};
+use std::collections::{hash_map::Entry, HashMap, HashSet};
 
 use alloy_primitives::Address;
 use anyhow::anyhow;
@@ -15,6 +15,17 @@ use crate::network::{
     subgraph_client::types::SubgraphVersion,
 };
 
+/// Collect the IDs of all allocations, including those of the subgraphs, deployments and indexers
+/// filtered out as invalid by the rest of the pre-processing.
+pub fn into_allocations<'a>(
+    data: impl Iterator<Item = &'a subgraph_client::types::Subgraph>,
+) -> HashSet<Address> {
+    data.flat_map(|subgraph| &subgraph.versions)
+        .flat_map(|version| &version.subgraph_deployment.allocations)
+        .map(|allocation| allocation.id)
+        .collect()
+}
+
 pub fn into_internal_indexers_raw_info<'a>(
     data: impl Iterator<Item = &'a subgraph_client::types::Subgraph>,
 ) -> HashMap<Address, IndexerRawInfo> {
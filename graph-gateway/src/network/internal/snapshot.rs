@@ -161,6 +161,8 @@ pub struct NetworkTopologySnapshot {
     pub subgraphs: HashMap<SubgraphId, Result<Subgraph, SubgraphError>>,
     /// Deployments network topology table.
     pub deployments: HashMap<DeploymentId, Result<Deployment, DeploymentError>>,
+    /// All active allocations on the network, regardless of whether their indexings resolved.
+    pub allocations: HashSet<Address>,
 }
 
 /// Construct the [`NetworkTopologySnapshot`] from the indexers and subgraphs information.
@@ -168,6 +170,7 @@ pub fn new_from(
     indexers_info: HashMap<Address, Result<ResolvedIndexerInfo, IndexerInfoResolutionError>>,
     subgraphs_info: HashMap<SubgraphId, Result<SubgraphInfo, SubgraphError>>,
     deployments_info: HashMap<DeploymentId, Result<DeploymentInfo, DeploymentError>>,
+    allocations: HashSet<Address>,
 ) -> NetworkTopologySnapshot {
     // Construct the indexers table
     let indexers = indexers_info
@@ -221,6 +224,7 @@ pub fn new_from(
     NetworkTopologySnapshot {
         deployments,
         subgraphs,
+        allocations,
     }
 }
 
@@ -38,6 +38,81 @@ fn network_data(data: serde_json::Value) -> Vec<SubgraphData> {
     serde_json::from_value(data).expect("deserialization failed")
 }
 
+#[test]
+fn allocations_data_pre_processing() {
+    init_test_tracing();
+
+    //* Given
+    // A deployment with two allocations of the same indexer, the smaller one not being the
+    // largest allocation of the indexing, and an allocation of an indexer with an invalid URL.
+    let data = network_data(json!([
+      {
+        "id": "21dvLGCdpj4TNQXt7azhjc2sZhj2j5fWXuYCYG6z3mjP",
+        "idOnL2": null,
+        "versions": [
+          {
+            "version": 0,
+            "subgraphDeployment": {
+              "ipfsHash": "QmaiXMTFDFPRKoXQceXwzuFYhAYDkUXHLmBVxLUQs4ZKsN",
+              "manifest": {
+                "network": "gnosis",
+                "startBlock": "25313137"
+              },
+              "transferredToL2": false,
+              "indexerAllocations": [
+                {
+                  "id": "0x8de241c35f8bc02ae9ad635e273372dd083f6520",
+                  "allocatedTokens": "2000000000000000000",
+                  "indexer": {
+                    "id": "0xedca8740873152ff30a2696add66d1ab41882beb",
+                    "stakedTokens": "1581895764461196487409847",
+                    "url": "https://arbitrum.graph.pinax.network/"
+                  }
+                },
+                {
+                  "id": "0xf29f2d086abf0b92cf119575d000b45e331a4df7",
+                  "allocatedTokens": "1000000000000000000",
+                  "indexer": {
+                    "id": "0xedca8740873152ff30a2696add66d1ab41882beb",
+                    "stakedTokens": "1581895764461196487409847",
+                    "url": "https://arbitrum.graph.pinax.network/"
+                  }
+                },
+                {
+                  "id": "0xcc3f326bdbfcb6fc730e04d859e6103f31cd691c",
+                  "allocatedTokens": "0",
+                  "indexer": {
+                    "id": "0xbdfb5ee5a2abf4fc7bb1bd1221067aef7f9de491",
+                    "stakedTokens": "100000000000000000000000",
+                    "url": null
+                  }
+                }
+              ],
+            },
+          }
+        ]
+      }
+    ]));
+
+    //* When
+    let allocations = pre_processing::into_allocations(data.iter());
+    let indexers = pre_processing::into_internal_indexers_raw_info(data.iter());
+
+    //* Then
+    let largest = parse_address("0x8de241c35f8bc02ae9ad635e273372dd083f6520");
+    let smaller = parse_address("0xf29f2d086abf0b92cf119575d000b45e331a4df7");
+    let invalid_indexer = parse_address("0xcc3f326bdbfcb6fc730e04d859e6103f31cd691c");
+    assert_eq!(
+        allocations,
+        [largest, smaller, invalid_indexer].into_iter().collect()
+    );
+    // Only the largest allocation is tracked by the indexing.
+    let indexing = &indexers[&parse_address("0xedca8740873152ff30a2696add66d1ab41882beb")]
+        .indexings[&parse_deployment_id("QmaiXMTFDFPRKoXQceXwzuFYhAYDkUXHLmBVxLUQs4ZKsN")];
+    assert_eq!(indexing.largest_allocation, largest);
+    assert_eq!(indexers.len(), 1);
+}
+
 #[test]
 fn indexers_data_pre_processing() {
     init_test_tracing();
@@ -181,6 +181,13 @@ impl NetworkService {
             .flat_map(|(id, indexing)| indexing.iter().map(|i| (*id, i.progress.latest_block)))
             .collect()
     }
+
+    /// Get all active allocations on the network. Unlike the indexings, this includes the
+    /// allocations that aren't the largest of their indexing, and those of indexings that failed
+    /// to resolve.
+    pub fn allocations(&self) -> HashSet<Address> {
+        self.network.borrow().allocations.clone()
+    }
 }
 
 /// The [`NetworkService`] builder.
@@ -0,0 +1,327 @@
+//! Static analysis of the complexity of client queries, used to reject expensive queries before
+//! they are sent to indexers.
+
+use std::collections::BTreeMap;
+
+use anyhow::anyhow;
+use cost_model::Context;
+use gateway_framework::{auth::QueryLimits, errors::Error};
+use graphql::{
+    graphql_parser::query::{Field, OperationDefinition, Selection, SelectionSet, Text, Value},
+    IntoStaticValue as _, StaticValue,
+};
+
+/// Number of entities assumed for collection fields without a `first` argument, matching the
+/// graph-node default.
+const DEFAULT_FIRST: u64 = 100;
+/// Arguments that are only accepted by collection fields.
+const COLLECTION_ARGUMENTS: [&str; 5] = ["first", "skip", "where", "orderBy", "orderDirection"];
+/// Maximum number of fields visited, after expanding fragments, before the query is rejected.
+const MAX_VISITED_FIELDS: usize = 100_000;
+/// Maximum nesting depth of fields, after expanding fragments, before the query is rejected. This
+/// bounds the recursion of the analysis regardless of the configured query limits.
+const MAX_DEPTH: usize = 256;
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Complexity {
+    /// Maximum nesting depth of fields.
+    pub depth: usize,
+    /// Maximum number of fields in a single selection set.
+    pub breadth: usize,
+    /// Estimated number of entities returned. Fields with collection arguments are assumed to
+    /// return `first` entities, and all other fields with a selection set a single entity.
+    pub entities: u64,
+}
+
+/// Reject the query if its complexity exceeds any of the limits.
+pub fn check(context: &Context, limits: &QueryLimits) -> Result<(), Error> {
+    let complexity = complexity(context)?;
+    let exceeded = |name: &str, value: u64, limit: Option<u64>| match limit {
+        Some(limit) if value > limit => Err(Error::BadQuery(anyhow!(
+            "query {name} of {value} exceeds the limit of {limit}"
+        ))),
+        _ => Ok(()),
+    };
+    exceeded(
+        "depth",
+        complexity.depth as u64,
+        limits.max_depth.map(|l| l as u64),
+    )?;
+    exceeded(
+        "breadth",
+        complexity.breadth as u64,
+        limits.max_breadth.map(|l| l as u64),
+    )?;
+    exceeded("entity estimate", complexity.entities, limits.max_entities)?;
+    Ok(())
+}
+
+pub fn complexity(context: &Context) -> Result<Complexity, Error> {
+    let mut analyzer = Analyzer {
+        context,
+        defaults: BTreeMap::new(),
+        fragments: Vec::new(),
+        visited: 0,
+        complexity: Complexity::default(),
+    };
+    for operation in &context.operations {
+        let selection_set = match operation {
+            OperationDefinition::SelectionSet(selection_set) => {
+                analyzer.defaults = BTreeMap::new();
+                selection_set
+            }
+            OperationDefinition::Query(query) => {
+                analyzer.defaults = query
+                    .variable_definitions
+                    .iter()
+                    .filter(|d| !context.variables.0.contains_key(d.name))
+                    .filter_map(|d| {
+                        Some((d.name.to_string(), d.default_value.as_ref()?.to_graphql()))
+                    })
+                    .collect();
+                &query.selection_set
+            }
+            OperationDefinition::Mutation(_) | OperationDefinition::Subscription(_) => continue,
+        };
+        analyzer.selection_set(selection_set, 1, 1)?;
+    }
+    Ok(analyzer.complexity)
+}
+
+struct Analyzer<'c, 'q> {
+    context: &'c Context<'q>,
+    defaults: BTreeMap<String, StaticValue>,
+    /// Names of the fragments being expanded, including those enclosing the current selection set,
+    /// to detect cycles.
+    fragments: Vec<&'q str>,
+    visited: usize,
+    complexity: Complexity,
+}
+
+impl<'c, 'q> Analyzer<'c, 'q> {
+    fn selection_set(
+        &mut self,
+        selection_set: &'c SelectionSet<'q, &'q str>,
+        depth: usize,
+        multiplier: u64,
+    ) -> Result<(), Error> {
+        if depth > MAX_DEPTH {
+            return Err(Error::BadQuery(anyhow!("query too deep")));
+        }
+        let mut fields = Vec::new();
+        self.fields(selection_set, &mut fields)?;
+        self.complexity.breadth = self.complexity.breadth.max(fields.len());
+        self.complexity.depth = self.complexity.depth.max(depth);
+        for (field, fragments) in fields {
+            if field.selection_set.items.is_empty() {
+                continue;
+            }
+            let entities = multiplier.saturating_mul(self.entities(field));
+            self.complexity.entities = self.complexity.entities.saturating_add(entities);
+            // The fragments the field was expanded from remain active for its selection set, so
+            // that cycles through nested fields are detected.
+            let outer = std::mem::replace(&mut self.fragments, fragments);
+            self.selection_set(&field.selection_set, depth + 1, entities)?;
+            self.fragments = outer;
+        }
+        Ok(())
+    }
+
+    /// Collect the fields of the selection set, expanding fragments. Each field is paired with the
+    /// names of the active fragments it was expanded from.
+    fn fields(
+        &mut self,
+        selection_set: &'c SelectionSet<'q, &'q str>,
+        fields: &mut Vec<(&'c Field<'q, &'q str>, Vec<&'q str>)>,
+    ) -> Result<(), Error> {
+        for selection in &selection_set.items {
+            match selection {
+                Selection::Field(field) => {
+                    self.visited += 1;
+                    if self.visited > MAX_VISITED_FIELDS {
+                        return Err(Error::BadQuery(anyhow!("query too complex")));
+                    }
+                    fields.push((field, self.fragments.clone()));
+                }
+                Selection::InlineFragment(fragment) => {
+                    self.fields(&fragment.selection_set, fields)?;
+                }
+                Selection::FragmentSpread(spread) => {
+                    let name = spread.fragment_name;
+                    if self.fragments.contains(&name) {
+                        return Err(Error::BadQuery(anyhow!("fragment cycle at {name}")));
+                    }
+                    let fragment = self
+                        .context
+                        .fragments
+                        .iter()
+                        .find(|f| f.name == name)
+                        .ok_or_else(|| Error::BadQuery(anyhow!("unknown fragment {name}")))?;
+                    self.fragments.push(name);
+                    self.fields(&fragment.selection_set, fields)?;
+                    self.fragments.pop();
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Estimated number of entities returned by the field, for each entity of its parent.
+    fn entities(&self, field: &Field<'q, &'q str>) -> u64 {
+        let mut collection = false;
+        for (name, value) in &field.arguments {
+            if *name == "first" {
+                if let Some(first) = self.int(value) {
+                    return first.max(0) as u64;
+                }
+            }
+            collection |= COLLECTION_ARGUMENTS.contains(name);
+        }
+        if collection {
+            DEFAULT_FIRST
+        } else {
+            1
+        }
+    }
+
+    fn int(&self, value: &Value<'q, &'q str>) -> Option<i64> {
+        match value {
+            Value::Variable(name) => self
+                .context
+                .variables
+                .get(name)
+                .or_else(|| self.defaults.get(*name))
+                .and_then(int),
+            value => int(value),
+        }
+    }
+}
+
+fn int<'a, T: Text<'a>>(value: &Value<'a, T>) -> Option<i64> {
+    match value {
+        Value::Int(n) => n.as_i64(),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn complexity(query: &str, variables: &str) -> Complexity {
+        let context = Context::new(query, variables).unwrap();
+        super::complexity(&context).unwrap()
+    }
+
+    #[test]
+    fn query_complexity() {
+        let tests = [
+            (
+                "{ a }",
+                "",
+                Complexity {
+                    depth: 1,
+                    breadth: 1,
+                    entities: 0,
+                },
+            ),
+            (
+                "{ a(id: \"1\") { b c } }",
+                "",
+                Complexity {
+                    depth: 2,
+                    breadth: 2,
+                    entities: 1,
+                },
+            ),
+            (
+                "{ a(first: 10) { b(where: { x: 1 }) { c } } }",
+                "",
+                Complexity {
+                    depth: 3,
+                    breadth: 1,
+                    entities: 10 + (10 * 100),
+                },
+            ),
+            (
+                "query q($n: Int = 5) { a(first: $n) { ...F } } fragment F on A { b c d }",
+                "",
+                Complexity {
+                    depth: 2,
+                    breadth: 3,
+                    entities: 5,
+                },
+            ),
+            (
+                "query q($n: Int) { a(first: $n) { b } }",
+                r#"{"n": 1000}"#,
+                Complexity {
+                    depth: 2,
+                    breadth: 1,
+                    entities: 1000,
+                },
+            ),
+        ];
+        for (query, variables, expected) in tests {
+            assert_eq!(complexity(query, variables), expected, "{query}");
+        }
+    }
+
+    #[test]
+    fn query_limits() {
+        //* Given
+        let context = Context::new("{ a(first: 1000) { b { c } } }", "").unwrap();
+        let limits = |max_depth, max_entities| QueryLimits {
+            max_depth,
+            max_breadth: None,
+            max_entities,
+        };
+
+        //* Then
+        assert!(check(&context, &QueryLimits::default()).is_ok());
+        assert!(check(&context, &limits(Some(3), Some(2_000))).is_ok());
+        assert!(matches!(
+            check(&context, &limits(Some(2), None)),
+            Err(Error::BadQuery(_))
+        ));
+        assert!(matches!(
+            check(&context, &limits(None, Some(1_000))),
+            Err(Error::BadQuery(_))
+        ));
+    }
+
+    #[test]
+    fn fragment_cycles_are_rejected() {
+        let cycles = [
+            "{ a { ...F } } fragment F on A { ...F }",
+            "{ a { ...F } } fragment F on A { b { ...F } }",
+            "{ a { ...F } } fragment F on A { b { ...G } } fragment G on B { c { d { ...F } } }",
+        ];
+        for query in cycles {
+            let context = Context::new(query, "").unwrap();
+            assert!(
+                matches!(super::complexity(&context), Err(Error::BadQuery(_))),
+                "{query}"
+            );
+        }
+
+        // Fragments spread more than once, without a cycle, are accepted.
+        let context =
+            Context::new("{ a { ...F } b { c { ...F } } } fragment F on A { d }", "").unwrap();
+        assert!(super::complexity(&context).is_ok());
+    }
+
+    #[test]
+    fn deep_queries_are_rejected() {
+        let query = format!(
+            "{}{}",
+            "{ a ".repeat(MAX_DEPTH + 1),
+            "}".repeat(MAX_DEPTH + 1)
+        );
+        let context = Context::new(&query, "").unwrap();
+        assert!(matches!(
+            super::complexity(&context),
+            Err(Error::BadQuery(_))
+        ));
+    }
+}
@@ -1,14 +1,28 @@
-use std::{collections::HashMap, sync::Arc, time::SystemTime};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
 
 use alloy_primitives::{Address, U256};
-use alloy_sol_types::Eip712Domain;
-use ethers::{core::k256::ecdsa::SigningKey, signers::Wallet};
+use alloy_sol_types::{Eip712Domain, SolStruct};
+use gateway_common::time::unix_timestamp;
+use gateway_framework::metrics::METRICS;
 use parking_lot::{Mutex, RwLock};
 use rand::RngCore;
 pub use receipts::QueryStatus as ReceiptStatus;
 use receipts::ReceiptPool;
 use secp256k1::SecretKey;
-use tap_core::{receipt::Receipt as TapReceipt, signed_message::EIP712SignedMessage};
+use tap_core::{
+    rav::ReceiptAggregateVoucher, receipt::Receipt as TapReceipt,
+    signed_message::EIP712SignedMessage,
+};
+
+use self::{keys::SignerKey, ledger::ReceiptLedger};
+
+pub mod backend;
+pub mod keys;
+pub mod ledger;
 
 /// A receipt for an indexer request.
 #[derive(Debug, Clone)]
@@ -55,17 +69,15 @@ impl Receipt {
 
 /// Scalar TAP signer.
 struct TapSigner {
-    signer: Wallet<SigningKey>,
+    keys: Vec<SignerKey>,
     domain: Eip712Domain,
 }
 
 impl TapSigner {
     /// Creates a new `TapSigner`.
-    fn new(signer: SecretKey, chain_id: U256, verifying_contract: Address) -> Self {
-        let signer = Wallet::from_bytes(signer.as_ref()).expect("failed to prepare receipt wallet");
-
+    fn new(keys: Vec<SignerKey>, chain_id: U256, verifying_contract: Address) -> Self {
         Self {
-            signer,
+            keys,
             domain: Eip712Domain {
                 name: Some("TAP".into()),
                 version: Some("1".into()),
@@ -77,7 +89,7 @@ impl TapSigner {
     }
 
     /// Creates a new receipt for the given allocation and fee.
-    fn create_receipt(
+    async fn create_receipt(
         &self,
         allocation: Address,
         fee: u128,
@@ -100,32 +112,111 @@ impl TapSigner {
             nonce,
             value: fee,
         };
-        let signed = EIP712SignedMessage::new(&self.domain, receipt, &self.signer)
-            .map_err(|e| anyhow::anyhow!("failed to sign receipt: {:?}", e))?;
+        self.sign(receipt)
+            .await
+            .map_err(|e| anyhow::anyhow!("failed to sign receipt: {:?}", e))
+    }
+
+    /// Signs the message with the active key. Among the keys active at the current time, the
+    /// most recently activated one is used.
+    async fn sign<M: SolStruct>(&self, message: M) -> anyhow::Result<EIP712SignedMessage<M>> {
+        let now = unix_timestamp() / 1_000;
+        let key = self
+            .keys
+            .iter()
+            .filter(|key| key.is_active(now))
+            .max_by_key(|key| key.active_from)
+            .ok_or_else(|| anyhow::anyhow!("no active signer key"))?;
+        let digest = message.eip712_signing_hash(&self.domain);
+        let signature = key.signer.sign_digest(digest).await?;
+        Ok(EIP712SignedMessage { message, signature })
+    }
+
+    /// Returns true if the signer address belongs to a key that has become active. Expired keys
+    /// are still accepted.
+    fn is_accepted_signer(&self, signer: Address) -> bool {
+        let now = unix_timestamp() / 1_000;
+        self.keys
+            .iter()
+            .any(|key| key.is_activated(now) && key.signer.address() == signer)
+    }
+
+    /// Aggregates the receipts, and the previous RAV if any, into a new RAV for the allocation.
+    /// The receipts and the previous RAV must have been signed by this signer.
+    async fn aggregate_receipts(
+        &self,
+        allocation: Address,
+        receipts: &[EIP712SignedMessage<TapReceipt>],
+        previous_rav: Option<EIP712SignedMessage<ReceiptAggregateVoucher>>,
+    ) -> anyhow::Result<EIP712SignedMessage<ReceiptAggregateVoucher>> {
+        for receipt in receipts {
+            if Address::from(receipt.message.allocation_id.0) != allocation {
+                anyhow::bail!("receipt for another allocation");
+            }
+            let signer = receipt
+                .recover_signer(&self.domain)
+                .map_err(|e| anyhow::anyhow!("invalid receipt signature: {:?}", e))?;
+            if !self.is_accepted_signer(Address::from(signer.0)) {
+                anyhow::bail!("receipt not signed by the gateway");
+            }
+        }
+        if let Some(previous_rav) = &previous_rav {
+            if Address::from(previous_rav.message.allocation_id.0) != allocation {
+                anyhow::bail!("previous RAV for another allocation");
+            }
+            let signer = previous_rav
+                .recover_signer(&self.domain)
+                .map_err(|e| anyhow::anyhow!("invalid previous RAV signature: {:?}", e))?;
+            if !self.is_accepted_signer(Address::from(signer.0)) {
+                anyhow::bail!("previous RAV not signed by the gateway");
+            }
+        }
 
-        Ok(signed)
+        let allocation_id = match receipts.first() {
+            Some(receipt) => receipt.message.allocation_id,
+            None => anyhow::bail!("no receipts"),
+        };
+        let rav =
+            ReceiptAggregateVoucher::aggregate_receipts(allocation_id, receipts, previous_rav)
+                .map_err(|e| anyhow::anyhow!("failed to aggregate receipts: {:?}", e))?;
+        self.sign(rav)
+            .await
+            .map_err(|e| anyhow::anyhow!("failed to sign RAV: {:?}", e))
     }
 }
 
-/// Legacy Scalar signer.
+/// Time for which the receipt pool of an allocation is kept after the allocation disappears from
+/// the network, so that receipts of outstanding requests can still be released.
+const LEGACY_POOL_GRACE_PERIOD: Duration = Duration::from_secs(60 * 60);
+
+/// Legacy Scalar signer. The `receipts` crate signs receipts in `ReceiptPool::commit` from the
+/// secret key itself, rather than from a digest, so they can't be signed through a
+/// `ReceiptSigningBackend`. Without a secret key, legacy receipts can't be created.
 struct LegacySigner {
-    secret_key: &'static SecretKey,
-    // Note: We are holding on to receipt pools indefinitely. This is acceptable, since the memory
-    // cost is minor and the typical duration of an allocation is 28 days.
+    secret_key: Option<SecretKey>,
     receipt_pools: RwLock<HashMap<Address, Arc<Mutex<ReceiptPool>>>>,
+    /// Allocations with a receipt pool that are missing from the network, and when they were
+    /// first found missing.
+    closed_allocations: Mutex<HashMap<Address, Instant>>,
 }
 
 impl LegacySigner {
     /// Creates a new `LegacySigner`.
-    fn new(secret_key: &'static SecretKey) -> Self {
+    fn new(secret_key: Option<SecretKey>) -> Self {
         Self {
             secret_key,
             receipt_pools: RwLock::default(),
+            closed_allocations: Mutex::default(),
         }
     }
 
     /// Creates a new receipt for the given allocation and fee.
     fn create_receipt(&self, allocation: Address, fee: u128) -> anyhow::Result<(u128, Vec<u8>)> {
+        let secret_key = self
+            .secret_key
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("legacy receipts require scalar.legacy_signer"))?;
+
         // Get the pool for the allocation
         let receipt_pool = self.receipt_pools.read().get(&allocation).cloned();
 
@@ -133,14 +224,15 @@ impl LegacySigner {
         let receipt = match receipt_pool {
             Some(pool) => {
                 let mut pool = pool.lock();
-                pool.commit(self.secret_key, fee.into())
+                pool.commit(secret_key, fee.into())
             }
             None => {
                 let mut pool = ReceiptPool::new(allocation.0 .0);
-                let receipt = pool.commit(self.secret_key, fee.into());
+                let receipt = pool.commit(secret_key, fee.into());
 
                 let mut write_guard = self.receipt_pools.write();
                 write_guard.insert(allocation, Arc::new(Mutex::new(pool)));
+                METRICS.legacy_receipt_pools.set(write_guard.len() as i64);
 
                 receipt
             }
@@ -157,31 +249,82 @@ impl LegacySigner {
             legacy_pool.lock().release(receipt, status);
         };
     }
+
+    /// Drop the receipt pools of allocations that have been missing from the given set of all
+    /// network allocations for longer than the grace period. Returns the number of pools dropped.
+    fn retain_pools(&self, allocations: &HashSet<Address>, now: Instant) -> usize {
+        let mut closed_allocations = self.closed_allocations.lock();
+        let mut receipt_pools = self.receipt_pools.write();
+
+        closed_allocations.retain(|allocation, _| {
+            receipt_pools.contains_key(allocation) && !allocations.contains(allocation)
+        });
+        for allocation in receipt_pools.keys() {
+            if !allocations.contains(allocation) {
+                closed_allocations.entry(*allocation).or_insert(now);
+            }
+        }
+
+        let expired: Vec<Address> = closed_allocations
+            .iter()
+            .filter(|(_, since)| now.duration_since(**since) >= LEGACY_POOL_GRACE_PERIOD)
+            .map(|(allocation, _)| *allocation)
+            .collect();
+        for allocation in &expired {
+            receipt_pools.remove(allocation);
+            closed_allocations.remove(allocation);
+        }
+
+        METRICS.legacy_receipt_pools.set(receipt_pools.len() as i64);
+        METRICS
+            .legacy_receipt_pools_evicted
+            .inc_by(expired.len() as u64);
+        expired.len()
+    }
 }
 
 /// ReceiptSigner is responsible for creating receipts for indexing requests.
 pub struct ReceiptSigner {
     tap: TapSigner,
     legacy: LegacySigner,
+    ledger: Option<ReceiptLedger>,
 }
 
 impl ReceiptSigner {
-    /// Creates a new `ReceiptSigner`.
+    /// Creates a new `ReceiptSigner`. TAP receipts are signed with the active key among `signers`.
+    /// Legacy receipts are signed with the `legacy_signer` secret key, held in-process, and can't
+    /// be created without it.
     pub fn new(
-        signer: SecretKey,
+        signers: Vec<SignerKey>,
         chain_id: U256,
         verifier: Address,
-        legacy_signer: &'static SecretKey,
+        legacy_signer: Option<SecretKey>,
     ) -> Self {
         Self {
-            tap: TapSigner::new(signer, chain_id, verifier),
+            tap: TapSigner::new(signers, chain_id, verifier),
             legacy: LegacySigner::new(legacy_signer),
+            ledger: None,
         }
     }
 
+    /// Record issued TAP receipts, and their status, in the given ledger.
+    pub fn with_ledger(mut self, ledger: ReceiptLedger) -> Self {
+        self.ledger = Some(ledger);
+        self
+    }
+
+    /// The ledger of issued TAP receipts, if any.
+    pub fn ledger(&self) -> Option<&ReceiptLedger> {
+        self.ledger.as_ref()
+    }
+
     /// Creates a new Scalar TAP receipt for the given allocation and fee.
-    pub fn create_receipt(&self, allocation: Address, fee: u128) -> anyhow::Result<Receipt> {
-        self.tap.create_receipt(allocation, fee).map(Receipt::TAP)
+    pub async fn create_receipt(&self, allocation: Address, fee: u128) -> anyhow::Result<Receipt> {
+        let receipt = self.tap.create_receipt(allocation, fee).await?;
+        if let Some(ledger) = &self.ledger {
+            ledger.record_issued(allocation, &receipt);
+        }
+        Ok(Receipt::TAP(receipt))
     }
 
     /// Creates a new Scalar legacy receipt for the given allocation and fee.
@@ -191,10 +334,52 @@ impl ReceiptSigner {
             .map(|(fee, receipt)| Receipt::Legacy(fee, receipt))
     }
 
+    /// Drop the legacy receipt pools of allocations that are no longer on the network, once they
+    /// have been missing for longer than a grace period, and the pending TAP receipts in the
+    /// ledger of those allocations, once they expire.
+    pub fn retain_allocations(&self, allocations: &HashSet<Address>) {
+        let evicted = self.legacy.retain_pools(allocations, Instant::now());
+        if evicted > 0 {
+            tracing::debug!(
+                evicted,
+                "dropped legacy receipt pools of closed allocations"
+            );
+        }
+        if let Some(ledger) = &self.ledger {
+            ledger.retain_allocations(allocations, unix_timestamp() * 1_000_000);
+        }
+    }
+
+    /// Aggregates TAP receipts, signed by this signer for the allocation, into a RAV. The receipts
+    /// are checked against the receipt ledger, so that no receipt is aggregated twice.
+    pub async fn aggregate_receipts(
+        &self,
+        allocation: Address,
+        receipts: &[EIP712SignedMessage<TapReceipt>],
+        previous_rav: Option<EIP712SignedMessage<ReceiptAggregateVoucher>>,
+    ) -> anyhow::Result<EIP712SignedMessage<ReceiptAggregateVoucher>> {
+        let ledger = self
+            .ledger
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("receipt ledger not configured"))?;
+        let previous = previous_rav.as_ref().map(|rav| rav.message.clone());
+        let rav = self
+            .tap
+            .aggregate_receipts(allocation, receipts, previous_rav)
+            .await?;
+        ledger.record_aggregation(allocation, receipts, previous.as_ref(), &rav.message)?;
+        Ok(rav)
+    }
+
     /// Record the receipt status and release it from the pool.
     pub fn record_receipt(&self, allocation: &Address, receipt: &Receipt, status: ReceiptStatus) {
-        if let Receipt::Legacy(_, receipt) = receipt {
-            self.legacy.record_receipt(allocation, receipt, status);
+        match receipt {
+            Receipt::Legacy(_, receipt) => self.legacy.record_receipt(allocation, receipt, status),
+            Receipt::TAP(receipt) => {
+                if let Some(ledger) = &self.ledger {
+                    ledger.record_status(*allocation, receipt, status);
+                }
+            }
         }
     }
 }
@@ -214,11 +399,9 @@ mod tests {
         #[test]
         fn create_receipt() {
             //* Given
-            let secret_key = Box::leak(Box::new(
-                SecretKey::from_slice(&[0xcd; 32]).expect("invalid secret key"),
-            ));
+            let secret_key = SecretKey::from_slice(&[0xcd; 32]).expect("invalid secret key");
 
-            let signer = LegacySigner::new(secret_key);
+            let signer = LegacySigner::new(Some(secret_key));
 
             // let indexer = parse_address("0xbdfb5ee5a2abf4fc7bb1bd1221067aef7f9de491");
             // let deployment = parse_deployment_id("QmaqcZxm6gcgWhWpQ88YKDm1keJDMpNxNGwtEDvjrjjNKh");
@@ -235,14 +418,27 @@ mod tests {
             assert!(!receipt.1.is_empty());
         }
 
+        #[test]
+        fn create_receipt_without_secret_key() {
+            //* Given
+            let signer = LegacySigner::new(None);
+
+            let largest_allocation = parse_address("0x89b23fea4e46d40e8a4c6cca723e2a03fdd4bec2");
+
+            //* When
+            let res = signer.create_receipt(largest_allocation, 1000);
+
+            //* Then
+            assert!(res.is_err());
+            assert!(signer.receipt_pools.read().is_empty());
+        }
+
         #[test]
         fn create_receipt_with_preexisting_pool() {
             //* Given
-            let secret_key = Box::leak(Box::new(
-                SecretKey::from_slice(&[0xcd; 32]).expect("invalid secret key"),
-            ));
+            let secret_key = SecretKey::from_slice(&[0xcd; 32]).expect("invalid secret key");
 
-            let signer = LegacySigner::new(secret_key);
+            let signer = LegacySigner::new(Some(secret_key));
 
             let largest_allocation = parse_address("0x89b23fea4e46d40e8a4c6cca723e2a03fdd4bec2");
             let fee = 1000;
@@ -259,17 +455,75 @@ mod tests {
             assert_eq!(receipt.0, fee);
             assert!(!receipt.1.is_empty());
         }
+
+        #[test]
+        fn drop_pools_of_closed_allocations() {
+            //* Given
+            let secret_key = SecretKey::from_slice(&[0xcd; 32]).expect("invalid secret key");
+
+            let signer = LegacySigner::new(Some(secret_key));
+
+            let open = parse_address("0x89b23fea4e46d40e8a4c6cca723e2a03fdd4bec2");
+            let closed = parse_address("0x177b557b12f22bb17a9d73dcc994d978dd6f5f89");
+            signer.create_receipt(open, 1000).unwrap();
+            signer.create_receipt(closed, 1000).unwrap();
+            let allocations = HashSet::from([open]);
+            let t0 = Instant::now();
+
+            //* When
+            let evicted_at_close = signer.retain_pools(&allocations, t0);
+            let evicted_in_grace_period =
+                signer.retain_pools(&allocations, t0 + LEGACY_POOL_GRACE_PERIOD / 2);
+            let evicted_after_grace_period =
+                signer.retain_pools(&allocations, t0 + LEGACY_POOL_GRACE_PERIOD);
+
+            //* Then
+            assert_eq!(evicted_at_close, 0);
+            assert_eq!(evicted_in_grace_period, 0);
+            assert_eq!(evicted_after_grace_period, 1);
+            let pools = signer.receipt_pools.read();
+            assert!(pools.contains_key(&open));
+            assert!(!pools.contains_key(&closed));
+            assert!(signer.closed_allocations.lock().is_empty());
+        }
+
+        #[test]
+        fn keep_pools_of_open_allocations() {
+            //* Given
+            let secret_key = SecretKey::from_slice(&[0xcd; 32]).expect("invalid secret key");
+
+            let signer = LegacySigner::new(Some(secret_key));
+
+            // Two open allocations of the same indexing, the smaller one no longer being the
+            // largest allocation of the indexing, which receives new receipts.
+            let largest = parse_address("0x89b23fea4e46d40e8a4c6cca723e2a03fdd4bec2");
+            let smaller = parse_address("0x177b557b12f22bb17a9d73dcc994d978dd6f5f89");
+            signer.create_receipt(smaller, 1000).unwrap();
+            signer.create_receipt(largest, 1000).unwrap();
+            let allocations = HashSet::from([largest, smaller]);
+            let t0 = Instant::now();
+
+            //* When
+            let evicted = signer.retain_pools(&allocations, t0)
+                + signer.retain_pools(&allocations, t0 + (LEGACY_POOL_GRACE_PERIOD * 2));
+
+            //* Then
+            assert_eq!(evicted, 0);
+            let pools = signer.receipt_pools.read();
+            assert!(pools.contains_key(&largest));
+            assert!(pools.contains_key(&smaller));
+        }
     }
 
     mod tap {
         use super::*;
 
-        #[test]
-        fn create_receipt() {
+        #[tokio::test]
+        async fn create_receipt() {
             //* Given
             let secret_key = SecretKey::from_slice(&[0xcd; 32]).expect("invalid secret key");
             let signer = TapSigner::new(
-                secret_key,
+                vec![secret_key.into()],
                 1.try_into().expect("invalid chain id"),
                 parse_address("0x177b557b12f22bb17a9d73dcc994d978dd6f5f89"),
             );
@@ -278,28 +532,153 @@ mod tests {
             let fee = 1000;
 
             //* When
-            let res = signer.create_receipt(allocation, fee);
+            let res = signer.create_receipt(allocation, fee).await;
 
             //* Then
             let receipt = res.expect("failed to create tap receipt");
 
             assert_eq!(receipt.message.value, fee);
         }
+
+        #[tokio::test]
+        async fn rotate_keys() {
+            //* Given
+            let now = unix_timestamp() / 1_000;
+            let key = |byte: u8, active_from: u64, expires_at: Option<u64>| SignerKey {
+                active_from: Some(active_from),
+                expires_at,
+                ..SecretKey::from_slice(&[byte; 32]).unwrap().into()
+            };
+            let (old_key, new_key, next_key) = (
+                key(0x01, now - 100, Some(now - 10)),
+                key(0x02, now - 20, None),
+                key(0x03, now + 100, None),
+            );
+            let signer_with = |keys: Vec<SignerKey>| {
+                TapSigner::new(
+                    keys,
+                    1.try_into().expect("invalid chain id"),
+                    parse_address("0x177b557b12f22bb17a9d73dcc994d978dd6f5f89"),
+                )
+            };
+            let signer = signer_with(vec![old_key.clone(), new_key.clone(), next_key.clone()]);
+            let allocation = parse_address("0x89b23fea4e46d40e8a4c6cca723e2a03fdd4bec2");
+            let signed_by = |key: &SignerKey| {
+                let key = SignerKey {
+                    active_from: None,
+                    expires_at: None,
+                    ..key.clone()
+                };
+                async move {
+                    signer_with(vec![key])
+                        .create_receipt(allocation, 10)
+                        .await
+                        .expect("failed to create tap receipt")
+                }
+            };
+
+            //* When
+            let receipt = signer
+                .create_receipt(allocation, 10)
+                .await
+                .expect("failed to create tap receipt");
+            let rav_from_old_key = signer
+                .aggregate_receipts(allocation, &[signed_by(&old_key).await], None)
+                .await;
+            let rav_from_next_key = signer
+                .aggregate_receipts(allocation, &[signed_by(&next_key).await], None)
+                .await;
+
+            //* Then
+            let receipt_signer = receipt.recover_signer(&signer.domain).unwrap();
+            assert_eq!(Address::from(receipt_signer.0), new_key.signer.address());
+            let rav = rav_from_old_key.expect("failed to aggregate receipts");
+            let rav_signer = rav.recover_signer(&signer.domain).unwrap();
+            assert_eq!(Address::from(rav_signer.0), new_key.signer.address());
+            assert!(rav_from_next_key.is_err());
+        }
+
+        #[tokio::test]
+        async fn aggregate_receipts() {
+            //* Given
+            let ledger_path = std::env::temp_dir().join(format!(
+                "gateway-receipt-ledger-{}.jsonl",
+                rand::random::<u64>()
+            ));
+            let new_signer = |key: u8| {
+                ReceiptSigner::new(
+                    vec![SecretKey::from_slice(&[key; 32])
+                        .expect("invalid secret key")
+                        .into()],
+                    1.try_into().expect("invalid chain id"),
+                    parse_address("0x177b557b12f22bb17a9d73dcc994d978dd6f5f89"),
+                    Some(SecretKey::from_slice(&[key; 32]).unwrap()),
+                )
+            };
+            let signer = new_signer(0xcd)
+                .with_ledger(ReceiptLedger::open(&ledger_path).expect("failed to open ledger"));
+            let other_signer = new_signer(0xab);
+
+            let allocation = parse_address("0x89b23fea4e46d40e8a4c6cca723e2a03fdd4bec2");
+            async fn tap_receipt(
+                signer: &ReceiptSigner,
+                allocation: Address,
+                fee: u128,
+            ) -> EIP712SignedMessage<TapReceipt> {
+                match signer
+                    .create_receipt(allocation, fee)
+                    .await
+                    .expect("failed to create tap receipt")
+                {
+                    Receipt::TAP(receipt) => receipt,
+                    Receipt::Legacy(_, _) => unreachable!(),
+                }
+            }
+            let receipts = vec![
+                tap_receipt(&signer, allocation, 10).await,
+                tap_receipt(&signer, allocation, 20).await,
+            ];
+
+            //* When
+            let rav = signer.aggregate_receipts(allocation, &receipts, None).await;
+            let double_counted = signer.aggregate_receipts(allocation, &receipts, None).await;
+            let next_receipts = vec![tap_receipt(&signer, allocation, 5).await];
+            let next_rav = signer
+                .aggregate_receipts(allocation, &next_receipts, rav.as_ref().ok().cloned())
+                .await;
+            let foreign = signer
+                .aggregate_receipts(
+                    allocation,
+                    &[tap_receipt(&other_signer, allocation, 1).await],
+                    next_rav.as_ref().ok().cloned(),
+                )
+                .await;
+            std::fs::remove_file(&ledger_path).unwrap();
+
+            //* Then
+            let rav = rav.expect("failed to aggregate receipts");
+            assert_eq!(rav.message.value_aggregate, 30);
+            assert!(double_counted.is_err());
+            let next_rav = next_rav.expect("failed to aggregate receipts");
+            assert_eq!(next_rav.message.value_aggregate, 35);
+            assert!(foreign.is_err());
+            let totals = signer.ledger().unwrap().allocation_totals(&allocation);
+            assert_eq!(totals.value, 35);
+            assert_eq!(totals.aggregated_value, 35);
+        }
     }
 
     #[test]
     fn create_legacy_receipt() {
         //* Given
         let tap_secret_key = SecretKey::from_slice(&[0xcd; 32]).expect("invalid secret key");
-        let legacy_secret_key = Box::leak(Box::new(
-            SecretKey::from_slice(&[0xcd; 32]).expect("invalid secret key"),
-        ));
+        let legacy_secret_key = SecretKey::from_slice(&[0xcd; 32]).expect("invalid secret key");
 
         let signer = ReceiptSigner::new(
-            tap_secret_key,
+            vec![tap_secret_key.into()],
             1.try_into().expect("invalid chain id"),
             parse_address("0x177b557b12f22bb17a9d73dcc994d978dd6f5f89"),
-            legacy_secret_key,
+            Some(legacy_secret_key),
         );
 
         let largest_allocation = parse_address("0x89b23fea4e46d40e8a4c6cca723e2a03fdd4bec2");
@@ -313,26 +692,24 @@ mod tests {
         assert!(matches!(receipt, Receipt::Legacy(_, _)));
     }
 
-    #[test]
-    fn create_tap_receipt() {
+    #[tokio::test]
+    async fn create_tap_receipt() {
         //* Given
         let tap_secret_key = SecretKey::from_slice(&[0xcd; 32]).expect("invalid secret key");
-        let legacy_secret_key = Box::leak(Box::new(
-            SecretKey::from_slice(&[0xcd; 32]).expect("invalid secret key"),
-        ));
+        let legacy_secret_key = SecretKey::from_slice(&[0xcd; 32]).expect("invalid secret key");
 
         let signer = ReceiptSigner::new(
-            tap_secret_key,
+            vec![tap_secret_key.into()],
             1.try_into().expect("invalid chain id"),
             parse_address("0x177b557b12f22bb17a9d73dcc994d978dd6f5f89"),
-            legacy_secret_key,
+            Some(legacy_secret_key),
         );
 
         let largest_allocation = parse_address("0x89b23fea4e46d40e8a4c6cca723e2a03fdd4bec2");
         let fee = 1000;
 
         //* When
-        let res = signer.create_receipt(largest_allocation, fee);
+        let res = signer.create_receipt(largest_allocation, fee).await;
 
         //* Then
         let receipt = res.expect("failed to create tap receipt");
@@ -0,0 +1,276 @@
+//! Signing backends for receipts, RAVs and vouchers.
+//!
+//! A [`ReceiptSigningBackend`] only signs EIP-712 digests, so the key may live outside the gateway
+//! process: either in a local signing daemon reached over a Unix socket, or in a remote signing
+//! service reached over TCP. Both speak the same newline-delimited JSON protocol:
+//! `{"digest":"0x…"}` requests, answered by `{"signature":"0x…"}` responses containing the
+//! 65-byte signature `r ‖ s ‖ v`.
+//!
+//! Legacy Scalar receipts and vouchers are not signed through a backend: the `receipts` crate only
+//! signs them from the secret key itself (`ReceiptPool::commit`, `receipts_to_voucher`,
+//! `receipts_to_partial_voucher` and `combine_partial_vouchers`), without exposing the digest. Their
+//! key, `scalar.legacy_signer`, lives in the gateway process when set, and legacy receipts and
+//! vouchers are disabled otherwise.
+
+use std::{path::PathBuf, time::Duration};
+
+use alloy_primitives::{Address, B256};
+use anyhow::Context as _;
+use ethers::{
+    core::{k256::ecdsa::SigningKey, types::Signature},
+    signers::{Signer as _, Wallet},
+};
+use futures::future::{self, BoxFuture};
+use parking_lot::Mutex;
+use secp256k1::SecretKey;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncBufReadExt as _, AsyncRead, AsyncWrite, AsyncWriteExt as _, BufReader},
+    net::{TcpStream, UnixStream},
+    sync::Semaphore,
+};
+
+/// Timeout for requests to a signing daemon, including connecting to it.
+const DAEMON_TIMEOUT: Duration = Duration::from_secs(5);
+/// Maximum number of connections to a signing daemon. Each connection serves one request at a
+/// time, so this bounds the number of concurrent signing requests.
+const DAEMON_MAX_CONNECTIONS: usize = 16;
+
+/// Sign-only interface to a signer key.
+pub trait ReceiptSigningBackend: Send + Sync {
+    /// Address of the key.
+    fn address(&self) -> Address;
+
+    /// Sign the EIP-712 digest.
+    fn sign_digest(&self, digest: B256) -> BoxFuture<'_, anyhow::Result<Signature>>;
+}
+
+/// Backend holding the key in-process.
+pub struct InProcessSigner {
+    wallet: Wallet<SigningKey>,
+}
+
+impl InProcessSigner {
+    pub fn new(secret_key: SecretKey) -> Self {
+        let wallet = Wallet::from_bytes(secret_key.as_ref()).expect("failed to prepare wallet");
+        Self { wallet }
+    }
+
+    fn sign(&self, digest: B256) -> anyhow::Result<Signature> {
+        self.wallet
+            .sign_hash(digest.0.into())
+            .map_err(|err| anyhow::anyhow!("failed to sign digest: {err}"))
+    }
+}
+
+impl ReceiptSigningBackend for InProcessSigner {
+    fn address(&self) -> Address {
+        Address::from(self.wallet.address().0)
+    }
+
+    fn sign_digest(&self, digest: B256) -> BoxFuture<'_, anyhow::Result<Signature>> {
+        Box::pin(future::ready(self.sign(digest)))
+    }
+}
+
+/// Address of a signing daemon.
+#[derive(Clone, Debug)]
+pub enum DaemonAddr {
+    /// Local signing daemon, listening on a Unix socket
+    Unix(PathBuf),
+    /// Remote signing service, at a `host:port` address
+    Tcp(String),
+}
+
+trait Connection: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Connection for T {}
+
+#[derive(Serialize)]
+struct SignRequest {
+    digest: B256,
+}
+
+#[derive(Deserialize)]
+struct SignResponse {
+    /// `r ‖ s ‖ v`, hex-encoded.
+    signature: String,
+}
+
+/// Backend sending digests to a signing daemon. Signatures are checked against the expected
+/// signer address.
+pub struct DaemonSigner {
+    addr: DaemonAddr,
+    address: Address,
+    /// Connections not serving a request, reused by later requests.
+    idle: Mutex<Vec<BufReader<Box<dyn Connection>>>>,
+    /// Permits to hold a connection, bounding the number of connections.
+    permits: Semaphore,
+}
+
+impl DaemonSigner {
+    pub fn new(addr: DaemonAddr, address: Address) -> Self {
+        Self {
+            addr,
+            address,
+            idle: Mutex::default(),
+            permits: Semaphore::new(DAEMON_MAX_CONNECTIONS),
+        }
+    }
+
+    async fn connect(&self) -> anyhow::Result<BufReader<Box<dyn Connection>>> {
+        let connection: Box<dyn Connection> = match &self.addr {
+            DaemonAddr::Unix(path) => {
+                let stream = UnixStream::connect(path).await.with_context(|| {
+                    format!("failed to connect to signing daemon {}", path.display())
+                })?;
+                Box::new(stream)
+            }
+            DaemonAddr::Tcp(addr) => {
+                let stream = TcpStream::connect(addr)
+                    .await
+                    .with_context(|| format!("failed to connect to remote signer {addr}"))?;
+                stream.set_nodelay(true)?;
+                Box::new(stream)
+            }
+        };
+        Ok(BufReader::new(connection))
+    }
+
+    async fn request(
+        connection: &mut BufReader<Box<dyn Connection>>,
+        digest: B256,
+    ) -> anyhow::Result<SignResponse> {
+        let mut request = serde_json::to_vec(&SignRequest { digest })?;
+        request.push(b'\n');
+        connection.get_mut().write_all(&request).await?;
+        let mut response = String::new();
+        if connection.read_line(&mut response).await? == 0 {
+            anyhow::bail!("signing daemon closed the connection");
+        }
+        Ok(serde_json::from_str(&response)?)
+    }
+
+    async fn sign(&self, digest: B256) -> anyhow::Result<Signature> {
+        let _permit = self.permits.acquire().await?;
+        let idle = self.idle.lock().pop();
+        let response = tokio::time::timeout(DAEMON_TIMEOUT, async {
+            let mut connection = match idle {
+                Some(connection) => connection,
+                None => self.connect().await?,
+            };
+            let response = Self::request(&mut connection, digest).await?;
+            // Only reuse the connection after a complete response.
+            self.idle.lock().push(connection);
+            anyhow::Ok(response)
+        })
+        .await
+        .map_err(|_| anyhow::anyhow!("signing daemon timed out"))??;
+
+        let bytes = hex::decode(response.signature.trim_start_matches("0x"))
+            .context("invalid signature")?;
+        let signature = Signature::try_from(bytes.as_slice()).context("invalid signature")?;
+        let signer = signature.recover(digest.0).context("invalid signature")?;
+        if Address::from(signer.0) != self.address {
+            anyhow::bail!("signing daemon signed with unexpected key {signer:?}");
+        }
+        Ok(signature)
+    }
+}
+
+impl ReceiptSigningBackend for DaemonSigner {
+    fn address(&self) -> Address {
+        self.address
+    }
+
+    fn sign_digest(&self, digest: B256) -> BoxFuture<'_, anyhow::Result<Signature>> {
+        Box::pin(self.sign(digest))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead as _, BufReader, Read, Write},
+        net::TcpListener,
+        os::unix::net::UnixListener,
+        sync::Arc,
+    };
+
+    use super::*;
+
+    fn backend() -> InProcessSigner {
+        InProcessSigner::new(SecretKey::from_slice(&[0xcd; 32]).unwrap())
+    }
+
+    /// Serve signing requests from a connection, like a signing daemon would.
+    fn serve(reader: impl Read, mut writer: impl Write, backend: Arc<InProcessSigner>) {
+        for line in BufReader::new(reader).lines() {
+            let request: serde_json::Value = serde_json::from_str(&line.unwrap()).unwrap();
+            let digest: B256 = request["digest"].as_str().unwrap().parse().unwrap();
+            let signature: [u8; 65] = backend.sign(digest).unwrap().into();
+            let response = serde_json::json!({
+                "signature": format!("0x{}", hex::encode(signature))
+            });
+            writeln!(writer, "{response}").unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn unix_socket_daemon() {
+        //* Given
+        let backend = Arc::new(backend());
+        let path =
+            std::env::temp_dir().join(format!("gateway-signer-{}.sock", rand::random::<u64>()));
+        let listener = UnixListener::bind(&path).unwrap();
+        let daemon_backend = backend.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = stream.unwrap();
+                let writer = stream.try_clone().unwrap();
+                let daemon_backend = daemon_backend.clone();
+                std::thread::spawn(move || serve(stream, writer, daemon_backend));
+            }
+        });
+        let signer = DaemonSigner::new(DaemonAddr::Unix(path.clone()), backend.address());
+        let impostor = DaemonSigner::new(DaemonAddr::Unix(path.clone()), Address::ZERO);
+
+        //* When
+        let digest = B256::repeat_byte(0x42);
+        let first = signer.sign_digest(digest).await;
+        let concurrent = futures::future::join_all(
+            (0..(2 * DAEMON_MAX_CONNECTIONS as u8))
+                .map(|i| signer.sign_digest(B256::repeat_byte(i))),
+        )
+        .await;
+        let unexpected_key = impostor.sign_digest(digest).await;
+        std::fs::remove_file(&path).unwrap();
+
+        //* Then
+        assert_eq!(first.unwrap(), backend.sign(digest).unwrap());
+        assert!(concurrent.iter().all(Result::is_ok));
+        assert!(signer.idle.lock().len() <= DAEMON_MAX_CONNECTIONS);
+        assert!(unexpected_key.is_err());
+    }
+
+    #[tokio::test]
+    async fn tcp_remote_signer() {
+        //* Given
+        let backend = Arc::new(backend());
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let daemon_backend = backend.clone();
+        std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let writer = stream.try_clone().unwrap();
+            serve(stream, writer, daemon_backend);
+        });
+        let signer = DaemonSigner::new(DaemonAddr::Tcp(addr), backend.address());
+
+        //* When
+        let digest = B256::repeat_byte(0x42);
+        let signature = signer.sign_digest(digest).await;
+
+        //* Then
+        assert_eq!(signature.unwrap(), backend.sign(digest).unwrap());
+    }
+}
@@ -0,0 +1,76 @@
+//! TAP signer keys, with activation and expiry times for key rotation.
+
+use std::{path::Path, sync::Arc};
+
+use anyhow::Context as _;
+use secp256k1::SecretKey;
+
+use super::backend::{InProcessSigner, ReceiptSigningBackend};
+
+/// A signer key, used to sign new receipts from `active_from` until `expires_at`. Receipts and
+/// RAVs signed by a key are accepted once the key is active, including after it expires.
+#[derive(Clone)]
+pub struct SignerKey {
+    pub signer: Arc<dyn ReceiptSigningBackend>,
+    /// Unix timestamp, in seconds, from which the key is used.
+    pub active_from: Option<u64>,
+    /// Unix timestamp, in seconds, from which the key is no longer used for new signatures.
+    pub expires_at: Option<u64>,
+}
+
+impl SignerKey {
+    /// A key that is always active.
+    pub fn new(signer: Arc<dyn ReceiptSigningBackend>) -> Self {
+        Self {
+            signer,
+            active_from: None,
+            expires_at: None,
+        }
+    }
+
+    /// Returns true if the key has become active by the given unix timestamp, in seconds.
+    pub fn is_activated(&self, now: u64) -> bool {
+        self.active_from.map_or(true, |t| t <= now)
+    }
+
+    /// Returns true if the key is used for new signatures at the given unix timestamp, in seconds.
+    pub fn is_active(&self, now: u64) -> bool {
+        self.is_activated(now) && self.expires_at.map_or(true, |t| now < t)
+    }
+}
+
+impl From<SecretKey> for SignerKey {
+    fn from(secret_key: SecretKey) -> Self {
+        Self::new(Arc::new(InProcessSigner::new(secret_key)))
+    }
+}
+
+/// Load a secret key from a file containing it as hex, optionally prefixed with `0x`.
+pub fn load_key_file(path: &Path) -> anyhow::Result<SignerKey> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read {}", path.display()))?;
+    let contents = contents.trim();
+    let bytes = hex::decode(contents.strip_prefix("0x").unwrap_or(contents))
+        .context("invalid secret key")?;
+    let secret_key = SecretKey::from_slice(&bytes).context("invalid secret key")?;
+    Ok(SignerKey::from(secret_key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn key_activation() {
+        let key = SignerKey {
+            active_from: Some(100),
+            expires_at: Some(200),
+            ..SignerKey::from(SecretKey::from_slice(&[0xcd; 32]).unwrap())
+        };
+        assert!(!key.is_activated(99));
+        assert!(!key.is_active(99));
+        assert!(key.is_active(100));
+        assert!(!key.is_active(200));
+        assert!(key.is_activated(200));
+    }
+}
@@ -0,0 +1,694 @@
+//! Local ledger of issued TAP receipts, and accounting of the value outstanding per allocation.
+//!
+//! The ledger is an append-only file of newline-delimited JSON entries. It is replayed when
+//! opened, so the totals, and the receipts not yet aggregated into a RAV, survive restarts.
+//!
+//! The ledger is compacted when opened, and periodically once enough entries have been appended:
+//! the file is replaced by a snapshot of each allocation. So the issued receipts that were
+//! aggregated into a RAV, or that expired after their allocation was closed on the network, are
+//! only listed by the `export-receipts` subcommand until the next compaction.
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fs::{self, File, OpenOptions},
+    io::{BufRead as _, BufReader, Write as _},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+    thread,
+    time::Duration,
+};
+
+use alloy_primitives::Address;
+use anyhow::Context as _;
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, DisplayFromStr};
+use tap_core::{
+    rav::ReceiptAggregateVoucher, receipt::Receipt as TapReceipt,
+    signed_message::EIP712SignedMessage,
+};
+use tokio::sync::mpsc;
+
+use super::ReceiptStatus;
+
+/// Number of entries appended to the ledger after which it is compacted.
+const COMPACTION_THRESHOLD: usize = 100_000;
+/// Default time for which receipts not aggregated into a RAV are kept once their allocation is
+/// closed, so that the indexer can still request a final RAV.
+pub const DEFAULT_PENDING_RECEIPT_RETENTION: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+#[serde_as]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Entry {
+    /// A receipt was signed and sent to the indexer.
+    Issued {
+        allocation: Address,
+        receipt: EIP712SignedMessage<TapReceipt>,
+    },
+    /// The outcome of the indexer request paid for by the receipt with the given nonce.
+    Recorded {
+        allocation: Address,
+        nonce: u64,
+        #[serde_as(as = "DisplayFromStr")]
+        value: u128,
+        status: Status,
+    },
+    /// A RAV was signed for the allocation, aggregating all receipts up to `timestamp_ns`.
+    Aggregated {
+        allocation: Address,
+        timestamp_ns: u64,
+        #[serde_as(as = "DisplayFromStr")]
+        value_aggregate: u128,
+    },
+    /// The state of the allocation when the ledger was compacted, replacing all previous entries
+    /// for the allocation.
+    Snapshot {
+        allocation: Address,
+        totals: AllocationTotals,
+        /// Timestamp of the latest RAV, aggregating `totals.aggregated_value`.
+        latest_rav_timestamp_ns: Option<u64>,
+        pending: Vec<PendingReceipt>,
+    },
+}
+
+impl Entry {
+    pub fn allocation(&self) -> Address {
+        match self {
+            Entry::Issued { allocation, .. }
+            | Entry::Recorded { allocation, .. }
+            | Entry::Aggregated { allocation, .. }
+            | Entry::Snapshot { allocation, .. } => *allocation,
+        }
+    }
+}
+
+/// A receipt issued since the latest RAV of its allocation.
+#[serde_as]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PendingReceipt {
+    nonce: u64,
+    timestamp_ns: u64,
+    #[serde_as(as = "DisplayFromStr")]
+    value: u128,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    Success,
+    Failure,
+    Unknown,
+}
+
+impl From<ReceiptStatus> for Status {
+    fn from(status: ReceiptStatus) -> Self {
+        match status {
+            ReceiptStatus::Success => Self::Success,
+            ReceiptStatus::Failure => Self::Failure,
+            ReceiptStatus::Unknown => Self::Unknown,
+        }
+    }
+}
+
+/// Receipt totals for an allocation, with values in GRT wei. All issued receipts are owed to the
+/// indexer, regardless of the outcome of the request.
+#[serde_as]
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AllocationTotals {
+    pub receipts: u64,
+    /// Value of all issued receipts.
+    #[serde_as(as = "DisplayFromStr")]
+    pub value: u128,
+    #[serde_as(as = "DisplayFromStr")]
+    pub success_value: u128,
+    #[serde_as(as = "DisplayFromStr")]
+    pub failure_value: u128,
+    #[serde_as(as = "DisplayFromStr")]
+    pub unknown_value: u128,
+    /// Value aggregated into the latest RAV.
+    #[serde_as(as = "DisplayFromStr")]
+    pub aggregated_value: u128,
+}
+
+impl AllocationTotals {
+    fn add(&mut self, entry: &Entry) {
+        match entry {
+            Entry::Issued { receipt, .. } => {
+                self.receipts += 1;
+                self.value += receipt.message.value;
+            }
+            Entry::Recorded { value, status, .. } => match status {
+                Status::Success => self.success_value += value,
+                Status::Failure => self.failure_value += value,
+                Status::Unknown => self.unknown_value += value,
+            },
+            Entry::Aggregated {
+                value_aggregate, ..
+            } => self.aggregated_value = *value_aggregate,
+            Entry::Snapshot { totals, .. } => *self = totals.clone(),
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum AggregationError {
+    #[error("previous RAV doesn't match the latest RAV for the allocation")]
+    PreviousRavMismatch,
+    #[error("receipt not issued, or already aggregated (nonce {0})")]
+    UnknownReceipt(u64),
+    #[error("duplicate receipt (nonce {0})")]
+    DuplicateReceipt(u64),
+}
+
+#[derive(Default)]
+struct AllocationState {
+    totals: AllocationTotals,
+    /// Timestamp and value aggregate of the latest RAV.
+    latest_rav: Option<(u64, u128)>,
+    /// Timestamp and value of the receipts issued since the latest RAV, by nonce.
+    pending: HashMap<u64, (u64, u128)>,
+}
+
+impl AllocationState {
+    fn apply(&mut self, entry: &Entry) {
+        self.totals.add(entry);
+        match entry {
+            Entry::Issued { receipt, .. } => {
+                let receipt = &receipt.message;
+                self.pending
+                    .insert(receipt.nonce, (receipt.timestamp_ns, receipt.value));
+            }
+            Entry::Recorded { .. } => (),
+            Entry::Aggregated {
+                timestamp_ns,
+                value_aggregate,
+                ..
+            } => {
+                self.latest_rav = Some((*timestamp_ns, *value_aggregate));
+                self.pending.retain(|_, (t, _)| t > timestamp_ns);
+            }
+            Entry::Snapshot {
+                totals,
+                latest_rav_timestamp_ns,
+                pending,
+                ..
+            } => {
+                self.latest_rav = latest_rav_timestamp_ns.map(|t| (t, totals.aggregated_value));
+                self.pending = pending
+                    .iter()
+                    .map(|r| (r.nonce, (r.timestamp_ns, r.value)))
+                    .collect();
+            }
+        }
+    }
+
+    fn snapshot(&self, allocation: Address) -> Entry {
+        Entry::Snapshot {
+            allocation,
+            totals: self.totals.clone(),
+            latest_rav_timestamp_ns: self.latest_rav.map(|(t, _)| t),
+            pending: self
+                .pending
+                .iter()
+                .map(|(nonce, (timestamp_ns, value))| PendingReceipt {
+                    nonce: *nonce,
+                    timestamp_ns: *timestamp_ns,
+                    value: *value,
+                })
+                .collect(),
+        }
+    }
+}
+
+enum Msg {
+    Append(Entry),
+    /// Replace the ledger file with the given entries.
+    Compact(Vec<Entry>),
+}
+
+/// Compute the receipt totals per allocation.
+pub fn totals<'e>(
+    entries: impl IntoIterator<Item = &'e Entry>,
+) -> BTreeMap<Address, AllocationTotals> {
+    let mut totals: BTreeMap<Address, AllocationTotals> = BTreeMap::new();
+    for entry in entries {
+        totals.entry(entry.allocation()).or_default().add(entry);
+    }
+    totals
+}
+
+/// Append-only ledger of issued TAP receipts, with the receipt totals per allocation, and the
+/// receipts not yet aggregated into a RAV, kept in memory.
+pub struct ReceiptLedger {
+    /// Sent to while holding the `state` lock, so that the order of the entries in the file
+    /// matches the order in which they are applied to `state`.
+    tx: mpsc::UnboundedSender<Msg>,
+    state: RwLock<HashMap<Address, AllocationState>>,
+    /// Number of entries appended since the latest compaction.
+    appended: AtomicUsize,
+    /// Time for which pending receipts of closed allocations are kept.
+    retention: Duration,
+}
+
+impl ReceiptLedger {
+    /// Open the ledger file, creating it if necessary, and replay it to restore the in-memory
+    /// state. The ledger is then compacted. Entries are written from a dedicated thread, so that
+    /// recording them never blocks query handling.
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let mut state: HashMap<Address, AllocationState> = HashMap::new();
+        if path.exists() {
+            truncate_partial_entry(path)?;
+            for_each_entry(path, |entry| {
+                state.entry(entry.allocation()).or_default().apply(&entry);
+            })?;
+        }
+        let snapshot: Vec<Entry> = state
+            .iter()
+            .map(|(allocation, state)| state.snapshot(*allocation))
+            .collect();
+        let mut file = write_snapshot(path, &snapshot)?;
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let path = path.to_path_buf();
+        thread::spawn(move || {
+            while let Some(msg) = rx.blocking_recv() {
+                let result = match msg {
+                    Msg::Append(entry) => append(&mut file, &entry),
+                    Msg::Compact(snapshot) => {
+                        write_snapshot(&path, &snapshot).map(|compacted| file = compacted)
+                    }
+                };
+                if let Err(receipt_ledger_err) = result {
+                    tracing::error!(%receipt_ledger_err);
+                }
+            }
+        });
+        Ok(Self {
+            tx,
+            state: RwLock::new(state),
+            appended: AtomicUsize::new(0),
+            retention: DEFAULT_PENDING_RECEIPT_RETENTION,
+        })
+    }
+
+    /// Keep the receipts not aggregated into a RAV for the given time once their allocation is
+    /// closed, instead of [`DEFAULT_PENDING_RECEIPT_RETENTION`].
+    pub fn with_retention(mut self, retention: Duration) -> Self {
+        self.retention = retention;
+        self
+    }
+
+    /// Drop the pending receipts older than the retention of allocations missing from the given
+    /// set of network allocations, and compact the ledger once enough entries have been appended
+    /// since the latest compaction. Pending receipts of allocations on the network are only
+    /// dropped once aggregated into a RAV. `now_ns` is the current Unix time, in nanoseconds.
+    pub fn retain_allocations(&self, allocations: &HashSet<Address>, now_ns: u64) {
+        let expiry_ns = now_ns.saturating_sub(self.retention.as_nanos() as u64);
+        let mut state = self.state.write();
+        let mut dropped = 0;
+        for (allocation, state) in state.iter_mut() {
+            if allocations.contains(allocation) {
+                continue;
+            }
+            let pending = state.pending.len();
+            state
+                .pending
+                .retain(|_, (timestamp_ns, _)| *timestamp_ns > expiry_ns);
+            dropped += pending - state.pending.len();
+        }
+        if dropped > 0 {
+            tracing::warn!(
+                dropped,
+                "dropped expired pending receipts of closed allocations"
+            );
+        }
+        if (dropped == 0) && (self.appended.load(Ordering::Relaxed) < COMPACTION_THRESHOLD) {
+            return;
+        }
+        let snapshot = state
+            .iter()
+            .map(|(allocation, state)| state.snapshot(*allocation))
+            .collect();
+        self.appended.store(0, Ordering::Relaxed);
+        let _ = self.tx.send(Msg::Compact(snapshot));
+    }
+
+    pub fn record_issued(&self, allocation: Address, receipt: &EIP712SignedMessage<TapReceipt>) {
+        self.record(Entry::Issued {
+            allocation,
+            receipt: receipt.clone(),
+        });
+    }
+
+    pub fn record_status(
+        &self,
+        allocation: Address,
+        receipt: &EIP712SignedMessage<TapReceipt>,
+        status: ReceiptStatus,
+    ) {
+        self.record(Entry::Recorded {
+            allocation,
+            nonce: receipt.message.nonce,
+            value: receipt.message.value,
+            status: status.into(),
+        });
+    }
+
+    /// Record a RAV signed for the allocation, after checking that it doesn't double count any
+    /// receipts: the previous RAV must be the latest RAV signed for the allocation, and every
+    /// receipt must have been issued since then, and be included only once.
+    pub fn record_aggregation(
+        &self,
+        allocation: Address,
+        receipts: &[EIP712SignedMessage<TapReceipt>],
+        previous_rav: Option<&ReceiptAggregateVoucher>,
+        rav: &ReceiptAggregateVoucher,
+    ) -> Result<(), AggregationError> {
+        let mut state = self.state.write();
+        let state = state.entry(allocation).or_default();
+        let previous_rav = previous_rav.map(|rav| (rav.timestamp_ns, rav.value_aggregate));
+        if previous_rav != state.latest_rav {
+            return Err(AggregationError::PreviousRavMismatch);
+        }
+        let mut nonces = HashSet::new();
+        for receipt in receipts {
+            let receipt = &receipt.message;
+            if !nonces.insert(receipt.nonce) {
+                return Err(AggregationError::DuplicateReceipt(receipt.nonce));
+            }
+            if state.pending.get(&receipt.nonce) != Some(&(receipt.timestamp_ns, receipt.value)) {
+                return Err(AggregationError::UnknownReceipt(receipt.nonce));
+            }
+        }
+        let entry = Entry::Aggregated {
+            allocation,
+            timestamp_ns: rav.timestamp_ns,
+            value_aggregate: rav.value_aggregate,
+        };
+        state.apply(&entry);
+        self.appended.fetch_add(1, Ordering::Relaxed);
+        let _ = self.tx.send(Msg::Append(entry));
+        Ok(())
+    }
+
+    fn record(&self, entry: Entry) {
+        let mut state = self.state.write();
+        state.entry(entry.allocation()).or_default().apply(&entry);
+        self.appended.fetch_add(1, Ordering::Relaxed);
+        let _ = self.tx.send(Msg::Append(entry));
+    }
+
+    /// Receipt totals for the allocation.
+    pub fn allocation_totals(&self, allocation: &Address) -> AllocationTotals {
+        self.state
+            .read()
+            .get(allocation)
+            .map(|state| state.totals.clone())
+            .unwrap_or_default()
+    }
+
+    /// Receipt totals for all allocations.
+    pub fn totals(&self) -> BTreeMap<Address, AllocationTotals> {
+        self.state
+            .read()
+            .iter()
+            .map(|(allocation, state)| (*allocation, state.totals.clone()))
+            .collect()
+    }
+}
+
+fn append(file: &mut File, entry: &Entry) -> anyhow::Result<()> {
+    let mut line = serde_json::to_vec(entry)?;
+    line.push(b'\n');
+    file.write_all(&line)?;
+    file.flush()?;
+    Ok(())
+}
+
+/// Replace the ledger file with the given entries, returning the new file opened for appending.
+/// The entries are written to a temporary file first, so that the ledger is never left partially
+/// written.
+fn write_snapshot(path: &Path, entries: &[Entry]) -> anyhow::Result<File> {
+    let mut tmp_path = PathBuf::from(path);
+    tmp_path.as_mut_os_string().push(".tmp");
+    let mut tmp = File::create(&tmp_path)
+        .with_context(|| format!("failed to create {}", tmp_path.display()))?;
+    for entry in entries {
+        append(&mut tmp, entry)?;
+    }
+    tmp.sync_all()?;
+    fs::rename(&tmp_path, path).with_context(|| format!("failed to replace {}", path.display()))?;
+    OpenOptions::new()
+        .append(true)
+        .open(path)
+        .with_context(|| format!("failed to open {}", path.display()))
+}
+
+/// Call `f` with each entry of the ledger file, in order.
+fn for_each_entry(path: &Path, mut f: impl FnMut(Entry)) -> anyhow::Result<()> {
+    let file = File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+    for (index, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let entry = serde_json::from_str(&line)
+            .with_context(|| format!("invalid entry on line {}", index + 1))?;
+        f(entry);
+    }
+    Ok(())
+}
+
+/// Read all entries from the ledger file.
+pub fn read(path: &Path) -> anyhow::Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+    for_each_entry(path, |entry| entries.push(entry))?;
+    Ok(entries)
+}
+
+/// Remove a trailing partial entry, left by a write interrupted by a crash, from a file of
+/// newline-delimited entries.
+pub(crate) fn truncate_partial_entry(path: &Path) -> anyhow::Result<()> {
+    let contents = std::fs::read(path)?;
+    let len = contents
+        .iter()
+        .rposition(|b| *b == b'\n')
+        .map(|index| index + 1)
+        .unwrap_or(0);
+    if len < contents.len() {
+        tracing::warn!(path = %path.display(), "truncating partial entry");
+        OpenOptions::new()
+            .write(true)
+            .open(path)?
+            .set_len(len as u64)?;
+    }
+    Ok(())
+}
+
+/// Export the receipt totals per allocation, along with the issued receipts of each allocation
+/// when `receipts` is set.
+pub fn export(entries: &[Entry], allocation: Option<Address>, receipts: bool) -> serde_json::Value {
+    let entries: Vec<&Entry> = entries
+        .iter()
+        .filter(|entry| allocation.map_or(true, |a| entry.allocation() == a))
+        .collect();
+    let export: BTreeMap<Address, serde_json::Value> = totals(entries.iter().copied())
+        .into_iter()
+        .map(|(allocation, totals)| {
+            let mut value = serde_json::to_value(totals).unwrap();
+            if receipts {
+                value["receipts_issued"] = entries
+                    .iter()
+                    .filter_map(|entry| match entry {
+                        Entry::Issued {
+                            allocation: a,
+                            receipt,
+                        } if *a == allocation => Some(serde_json::to_value(receipt).unwrap()),
+                        _ => None,
+                    })
+                    .collect();
+            }
+            (allocation, value)
+        })
+        .collect();
+    serde_json::to_value(export).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::receipts::TapSigner;
+
+    fn receipt(allocation: Address, value: u128) -> EIP712SignedMessage<TapReceipt> {
+        let signer = TapSigner::new(
+            vec![secp256k1::SecretKey::from_slice(&[0xcd; 32])
+                .unwrap()
+                .into()],
+            1.try_into().unwrap(),
+            Address::with_last_byte(0xff),
+        );
+        futures::executor::block_on(signer.create_receipt(allocation, value)).unwrap()
+    }
+
+    #[test]
+    fn totals_survive_restart() {
+        //* Given
+        let path = std::env::temp_dir().join(format!(
+            "gateway-receipt-ledger-{}.jsonl",
+            rand::random::<u64>()
+        ));
+        let allocation_a = Address::with_last_byte(1);
+        let allocation_b = Address::with_last_byte(2);
+        let (a1, a2, b1) = (
+            receipt(allocation_a, 10),
+            receipt(allocation_a, 20),
+            receipt(allocation_b, 5),
+        );
+
+        let ledger = ReceiptLedger::open(&path).unwrap();
+        ledger.record_issued(allocation_a, &a1);
+        ledger.record_issued(allocation_a, &a2);
+        ledger.record_issued(allocation_b, &b1);
+        ledger.record_status(allocation_a, &a1, ReceiptStatus::Success);
+        ledger.record_status(allocation_a, &a2, ReceiptStatus::Failure);
+        let expected = ledger.totals();
+        drop(ledger);
+
+        //* When
+        // The writer thread exits once the ledger is dropped and all entries are written.
+        let mut entries = read(&path).unwrap();
+        for _ in 0..100 {
+            if entries.len() == 5 {
+                break;
+            }
+            thread::sleep(std::time::Duration::from_millis(10));
+            entries = read(&path).unwrap();
+        }
+        // Simulate a write interrupted by a crash.
+        OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(b"{\"type\":\"iss")
+            .unwrap();
+        let reopened = ReceiptLedger::open(&path).unwrap();
+        let reread = read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        //* Then
+        assert_eq!(
+            reopened.allocation_totals(&allocation_a),
+            AllocationTotals {
+                receipts: 2,
+                value: 30,
+                success_value: 10,
+                failure_value: 20,
+                unknown_value: 0,
+                aggregated_value: 0,
+            }
+        );
+        assert_eq!(reopened.allocation_totals(&allocation_b).value, 5);
+        assert_eq!(reopened.totals(), expected);
+        // The reopened ledger is compacted to a snapshot per allocation.
+        assert_eq!(reread.len(), 2);
+        assert!(reread
+            .iter()
+            .all(|entry| matches!(entry, Entry::Snapshot { .. })));
+        assert_eq!(totals(&reread), expected);
+
+        let export = export(&entries, Some(allocation_a), true);
+        let export = export.as_object().unwrap();
+        assert_eq!(export.len(), 1);
+        let totals = &export[&allocation_a.to_string()];
+        assert_eq!(totals["value"], "30");
+        assert_eq!(totals["receipts_issued"].as_array().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn drop_pending_receipts_of_closed_allocations() {
+        //* Given
+        let path = std::env::temp_dir().join(format!(
+            "gateway-receipt-ledger-{}.jsonl",
+            rand::random::<u64>()
+        ));
+        let open = Address::with_last_byte(1);
+        let closed = Address::with_last_byte(2);
+        let (open_receipt, closed_receipt) = (receipt(open, 10), receipt(closed, 20));
+        let retention = Duration::from_secs(60);
+        let ledger = ReceiptLedger::open(&path)
+            .unwrap()
+            .with_retention(retention);
+        ledger.record_issued(open, &open_receipt);
+        ledger.record_issued(closed, &closed_receipt);
+        let allocations = HashSet::from([open]);
+        let issued_ns = closed_receipt.message.timestamp_ns;
+        let expired_ns = issued_ns + retention.as_nanos() as u64;
+
+        //* When
+        ledger.retain_allocations(&allocations, expired_ns - 1);
+        let pending_within_retention = ledger.state.read()[&closed].pending.len();
+        ledger.retain_allocations(&allocations, expired_ns);
+        let receipts = [closed_receipt.clone()];
+        let rav = ReceiptAggregateVoucher::aggregate_receipts(closed, &receipts, None).unwrap();
+        let aggregation = ledger.record_aggregation(closed, &receipts, None, &rav);
+        drop(ledger);
+
+        // The pending receipts are dropped from the ledger file by a compaction.
+        let mut entries = read(&path).unwrap();
+        for _ in 0..100 {
+            if (entries.len() == 2)
+                && entries
+                    .iter()
+                    .all(|entry| matches!(entry, Entry::Snapshot { .. }))
+            {
+                break;
+            }
+            thread::sleep(std::time::Duration::from_millis(10));
+            entries = read(&path).unwrap();
+        }
+        let reopened = ReceiptLedger::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        //* Then
+        assert_eq!(pending_within_retention, 1);
+        assert!(matches!(
+            aggregation,
+            Err(AggregationError::UnknownReceipt(_))
+        ));
+        let state = reopened.state.read();
+        assert_eq!(state[&open].pending.len(), 1);
+        assert!(state[&closed].pending.is_empty());
+        assert_eq!(state[&closed].totals.value, 20);
+    }
+
+    #[test]
+    fn keep_pending_receipts_of_open_allocations() {
+        //* Given
+        let path = std::env::temp_dir().join(format!(
+            "gateway-receipt-ledger-{}.jsonl",
+            rand::random::<u64>()
+        ));
+        // An open allocation that isn't the largest allocation of its indexing, which is only
+        // listed among all the allocations of the network.
+        let largest = Address::with_last_byte(1);
+        let smaller = Address::with_last_byte(2);
+        let smaller_receipt = receipt(smaller, 10);
+        let ledger = ReceiptLedger::open(&path).unwrap();
+        ledger.record_issued(smaller, &smaller_receipt);
+        let allocations = HashSet::from([largest, smaller]);
+
+        //* When
+        // Long after the retention of closed allocations.
+        let now_ns =
+            smaller_receipt.message.timestamp_ns + (10 * ledger.retention.as_nanos() as u64);
+        ledger.retain_allocations(&allocations, now_ns);
+        let receipts = [smaller_receipt];
+        let rav = ReceiptAggregateVoucher::aggregate_receipts(smaller, &receipts, None).unwrap();
+        let aggregation = ledger.record_aggregation(smaller, &receipts, None, &rav);
+        drop(ledger);
+        std::fs::remove_file(&path).unwrap();
+
+        //* Then
+        assert!(aggregation.is_ok());
+    }
+}
@@ -5,7 +5,7 @@ use gateway_framework::errors;
 use ordered_float::NotNan;
 use prost::Message;
 use serde_json::json;
-use thegraph_core::types::DeploymentId;
+use thegraph_core::types::{Attestation, DeploymentId};
 use tokio::sync::mpsc;
 use toolshed::concat_bytes;
 
@@ -19,6 +19,7 @@ pub struct ClientRequest {
     pub user_address: Address,
     pub grt_per_usd: NotNan<f64>,
     pub indexer_requests: Vec<IndexerRequest>,
+    pub dispute_evidence: Vec<DisputeEvidence>,
 }
 
 pub struct IndexerRequest {
@@ -35,12 +36,27 @@ pub struct IndexerRequest {
     pub request: String,
 }
 
+/// Conflicting attestations from indexers responding to the same request.
+pub struct DisputeEvidence {
+    pub deployment: DeploymentId,
+    pub request: String,
+    pub attestations: [AttestedResponse; 2],
+}
+
+pub struct AttestedResponse {
+    pub indexer: Address,
+    pub allocation: Address,
+    pub response: String,
+    pub attestation: Attestation,
+}
+
 pub struct Reporter {
     pub graph_env: String,
     pub budget: String,
     pub client_request_topic: &'static str,
     pub indexer_request_topic: &'static str,
     pub attestation_topic: &'static str,
+    pub dispute_evidence_topic: &'static str,
     pub write_buf: Vec<u8>,
     pub kafka_producer: rdkafka::producer::ThreadedProducer<
         rdkafka::producer::DefaultProducerContext,
@@ -55,6 +71,7 @@ impl Reporter {
         client_request_topic: &'static str,
         indexer_request_topic: &'static str,
         attestation_topic: &'static str,
+        dispute_evidence_topic: &'static str,
         kafka_config: impl Into<rdkafka::ClientConfig>,
     ) -> anyhow::Result<mpsc::UnboundedSender<ClientRequest>> {
         let kafka_producer = kafka_config
@@ -67,6 +84,7 @@ impl Reporter {
             client_request_topic,
             indexer_request_topic,
             attestation_topic,
+            dispute_evidence_topic,
             write_buf: Default::default(),
             kafka_producer,
         };
@@ -247,20 +265,12 @@ impl Reporter {
                 .ok()
                 .and_then(|r| Some((r.original_response, r.attestation?)))
             {
-                const MAX_PAYLOAD_BYTES: usize = 10_000;
-                AttestationProtobuf {
-                    request: Some(indexer_request.request).filter(|r| r.len() <= MAX_PAYLOAD_BYTES),
-                    response: Some(original_response).filter(|r| r.len() <= MAX_PAYLOAD_BYTES),
-                    allocation: indexer_request.receipt.allocation().0 .0.into(),
-                    subgraph_deployment: attestation.deployment.0.into(),
-                    request_cid: attestation.request_cid.0.into(),
-                    response_cid: attestation.response_cid.0.into(),
-                    signature: concat_bytes!(
-                        65,
-                        [&[attestation.v], &attestation.r.0, &attestation.s.0]
-                    )
-                    .into(),
-                }
+                AttestationProtobuf::new(
+                    indexer_request.request,
+                    original_response,
+                    indexer_request.receipt.allocation(),
+                    &attestation,
+                )
                 .encode(&mut self.write_buf)
                 .unwrap();
                 let record: rdkafka::producer::BaseRecord<(), [u8], ()> =
@@ -277,6 +287,39 @@ impl Reporter {
             }
         }
 
+        for evidence in client_request.dispute_evidence {
+            tracing::warn!(
+                deployment = %evidence.deployment,
+                indexers = ?evidence.attestations.each_ref().map(|a| a.indexer),
+                "conflicting attestations"
+            );
+            let [a, b] = evidence.attestations.map(|a| {
+                AttestationProtobuf::new(
+                    evidence.request.clone(),
+                    a.response,
+                    a.allocation,
+                    &a.attestation,
+                )
+            });
+            DisputeEvidenceProtobuf {
+                attestation_1: Some(a),
+                attestation_2: Some(b),
+            }
+            .encode(&mut self.write_buf)
+            .unwrap();
+            let record: rdkafka::producer::BaseRecord<(), [u8], ()> =
+                rdkafka::producer::BaseRecord::to(self.dispute_evidence_topic)
+                    .payload(&self.write_buf);
+            self.kafka_producer
+                .send(record)
+                .map_err(|(err, _)| err)
+                .context(anyhow!(
+                    "failed to send to topic {}",
+                    self.dispute_evidence_topic
+                ))?;
+            self.write_buf.clear();
+        }
+
         serde_json::to_writer(&mut self.write_buf, &client_request_payload).unwrap();
         let record: rdkafka::producer::BaseRecord<(), [u8], ()> =
             rdkafka::producer::BaseRecord::to(self.client_request_topic).payload(&self.write_buf);
@@ -315,3 +358,32 @@ pub struct AttestationProtobuf {
     #[prost(bytes, tag = "7")]
     signature: Vec<u8>,
 }
+
+impl AttestationProtobuf {
+    fn new(
+        request: String,
+        response: String,
+        allocation: Address,
+        attestation: &Attestation,
+    ) -> Self {
+        const MAX_PAYLOAD_BYTES: usize = 10_000;
+        Self {
+            request: Some(request).filter(|r| r.len() <= MAX_PAYLOAD_BYTES),
+            response: Some(response).filter(|r| r.len() <= MAX_PAYLOAD_BYTES),
+            allocation: allocation.0 .0.into(),
+            subgraph_deployment: attestation.deployment.0.into(),
+            request_cid: attestation.request_cid.0.into(),
+            response_cid: attestation.response_cid.0.into(),
+            signature: concat_bytes!(65, [&[attestation.v], &attestation.r.0, &attestation.s.0])
+                .into(),
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct DisputeEvidenceProtobuf {
+    #[prost(message, optional, tag = "1")]
+    attestation_1: Option<AttestationProtobuf>,
+    #[prost(message, optional, tag = "2")]
+    attestation_2: Option<AttestationProtobuf>,
+}
@@ -1,7 +1,10 @@
 use std::collections::HashMap;
 
 use alloy_primitives::Address;
-use gateway_framework::auth::api_keys::{APIKey, QueryStatus};
+use gateway_framework::auth::{
+    api_keys::{APIKey, QueryStatus},
+    CrossCheck, QueryLimits,
+};
 use serde::Deserialize;
 use tokio::{
     sync::watch,
@@ -59,6 +62,12 @@ impl Client {
             subgraphs: Vec<String>,
             #[serde(default)]
             domains: Vec<String>,
+            #[serde(default)]
+            response_extensions: bool,
+            #[serde(default)]
+            cross_check: Option<CrossCheck>,
+            #[serde(default)]
+            query_limits: Option<QueryLimits>,
         }
 
         let response = self
@@ -84,6 +93,9 @@ impl Client {
                         .into_iter()
                         .filter_map(|s| s.parse().ok())
                         .collect(),
+                    response_extensions: api_key.response_extensions,
+                    cross_check: api_key.cross_check,
+                    query_limits: api_key.query_limits,
                 };
                 (api_key.key.clone(), api_key)
             })
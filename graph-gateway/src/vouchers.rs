@@ -1,5 +1,21 @@
-use alloy_primitives::{Address, FixedBytes, U256};
-use axum::{body::Bytes, extract::State, http::StatusCode};
+//! Endpoints signing vouchers for the legacy Scalar receipts collected by indexers, and RAVs for
+//! TAP receipts.
+//!
+//! Requests for legacy vouchers must be signed by the allocation key, held by the allocation's
+//! indexer, or by an allowlisted address. The signature is sent in the `X-Voucher-Signature`
+//! header, as a hex-encoded EIP-191 signature of the keccak256 hash of the request body. The
+//! receipt ID ranges vouchered for each allocation are recorded, so that receipts can't be
+//! collected twice.
+
+use std::collections::HashSet;
+
+use alloy_primitives::{keccak256, Address, FixedBytes, U256};
+use axum::{
+    body::Bytes,
+    extract::State,
+    http::{HeaderMap, StatusCode},
+};
+use ethers::core::types::Signature as EthSignature;
 use gateway_framework::{
     json::{json_response, JsonResponse},
     metrics::METRICS,
@@ -9,17 +25,93 @@ use receipts::{self, combine_partial_vouchers, receipts_to_partial_voucher, rece
 use secp256k1::{PublicKey, Secp256k1, SecretKey};
 use serde::Deserialize;
 use serde_json::json;
+use tap_core::{
+    rav::ReceiptAggregateVoucher, receipt::Receipt as TapReceipt,
+    signed_message::EIP712SignedMessage,
+};
+
+use self::ranges::{Kind, ReceiptID, VoucherRanges};
+use crate::receipts::ReceiptSigner;
+
+pub mod ranges;
+
+/// Header containing the signature of the request body.
+const SIGNATURE_HEADER: &str = "x-voucher-signature";
+
+/// Size of each legacy receipt in a collect-receipts payload: `fee (32) ‖ id (15) ‖ signature (65)`.
+const RECEIPT_SIZE: usize = 112;
+const RECEIPT_ID_OFFSET: usize = 32;
 
 lazy_static! {
     static ref SECP256K1: Secp256k1<secp256k1::All> = Secp256k1::new();
 }
 
+/// State of the legacy voucher endpoints.
+pub struct Vouchers {
+    /// Legacy vouchers are signed by the `receipts` crate from the secret key itself, rather than
+    /// from a digest, so they can't be signed through a `ReceiptSigningBackend`. Legacy vouchers
+    /// are rejected without it.
+    signer: Option<SecretKey>,
+    /// Addresses allowed to request vouchers for any allocation.
+    allowlist: HashSet<Address>,
+    ranges: VoucherRanges,
+}
+
+impl Vouchers {
+    pub fn new(
+        signer: Option<SecretKey>,
+        allowlist: HashSet<Address>,
+        ranges: VoucherRanges,
+    ) -> Self {
+        Self {
+            signer,
+            allowlist,
+            ranges,
+        }
+    }
+
+    /// The secret key signing legacy vouchers, if set.
+    fn signer(&self) -> Result<&SecretKey, String> {
+        self.signer
+            .as_ref()
+            .ok_or_else(|| "Legacy vouchers are not supported by this gateway".to_string())
+    }
+
+    /// Check that the request body is signed by the allocation key or an allowlisted address.
+    fn authenticate(
+        &self,
+        allocation: Address,
+        headers: &HeaderMap,
+        payload: &[u8],
+    ) -> Result<(), String> {
+        let signature = headers
+            .get(SIGNATURE_HEADER)
+            .ok_or_else(|| format!("Missing {SIGNATURE_HEADER} header"))?
+            .to_str()
+            .map_err(|_| "Invalid request signature".to_string())?;
+        let signature = hex::decode(signature.trim_start_matches("0x"))
+            .ok()
+            .and_then(|bytes| EthSignature::try_from(bytes.as_slice()).ok())
+            .ok_or_else(|| "Invalid request signature".to_string())?;
+        let signer = signature
+            .recover(keccak256(payload).as_slice())
+            .map(|signer| Address::from(signer.0))
+            .map_err(|_| "Invalid request signature".to_string())?;
+        if (signer != allocation) && !self.allowlist.contains(&signer) {
+            tracing::warn!(%allocation, %signer, "unauthorized voucher request");
+            return Err("Request not signed by the allocation's indexer".into());
+        }
+        Ok(())
+    }
+}
+
 pub async fn handle_collect_receipts(
-    State(signer): State<&'static SecretKey>,
+    State(vouchers): State<&'static Vouchers>,
+    headers: HeaderMap,
     payload: Bytes,
 ) -> Result<JsonResponse, (StatusCode, String)> {
     let _timer = METRICS.collect_receipts.duration.start_timer();
-    match process_oneshot_voucher(signer, &payload) {
+    match process_oneshot_voucher(vouchers, &headers, &payload).await {
         Ok(response) => {
             METRICS.collect_receipts.ok.inc();
             Ok(response)
@@ -32,8 +124,14 @@ pub async fn handle_collect_receipts(
     }
 }
 
-fn process_oneshot_voucher(signer: &SecretKey, payload: &Bytes) -> Result<JsonResponse, String> {
+async fn process_oneshot_voucher(
+    vouchers: &Vouchers,
+    headers: &HeaderMap,
+    payload: &Bytes,
+) -> Result<JsonResponse, String> {
+    let signer = vouchers.signer()?;
     let (allocation_id, receipts) = parse_receipts(payload)?;
+    vouchers.authenticate(allocation_id.into(), headers, payload)?;
     let allocation_signer = PublicKey::from_secret_key(&SECP256K1, signer);
     let voucher = receipts_to_voucher(&allocation_id, &allocation_signer, signer, receipts)
         .map_err(|err| err.to_string())?;
@@ -48,6 +146,17 @@ fn process_oneshot_voucher(signer: &SecretKey, payload: &Bytes) -> Result<JsonRe
         tracing::error!(excessive_voucher_fees = %voucher.fees);
         return Err("Voucher value too large".into());
     }
+    let (receipt_id_min, receipt_id_max) = receipt_id_range(receipts)?;
+    vouchers
+        .ranges
+        .record_receipts(
+            allocation_id.into(),
+            Kind::Voucher,
+            receipt_id_min,
+            receipt_id_max,
+        )
+        .await
+        .map_err(|err| err.to_string())?;
     Ok(json_response(
         [],
         json!({
@@ -59,11 +168,12 @@ fn process_oneshot_voucher(signer: &SecretKey, payload: &Bytes) -> Result<JsonRe
 }
 
 pub async fn handle_partial_voucher(
-    State(signer): State<&'static SecretKey>,
+    State(vouchers): State<&'static Vouchers>,
+    headers: HeaderMap,
     payload: Bytes,
 ) -> Result<JsonResponse, (StatusCode, String)> {
     let _timer = METRICS.partial_voucher.duration.start_timer();
-    match process_partial_voucher(signer, &payload) {
+    match process_partial_voucher(vouchers, &headers, &payload).await {
         Ok(response) => {
             METRICS.partial_voucher.ok.inc();
             Ok(response)
@@ -76,8 +186,14 @@ pub async fn handle_partial_voucher(
     }
 }
 
-fn process_partial_voucher(signer: &SecretKey, payload: &Bytes) -> Result<JsonResponse, String> {
+async fn process_partial_voucher(
+    vouchers: &Vouchers,
+    headers: &HeaderMap,
+    payload: &Bytes,
+) -> Result<JsonResponse, String> {
+    let signer = vouchers.signer()?;
     let (allocation_id, receipts) = parse_receipts(payload)?;
+    vouchers.authenticate(allocation_id.into(), headers, payload)?;
     let allocation_signer = PublicKey::from_secret_key(&SECP256K1, signer);
     let partial_voucher =
         receipts_to_partial_voucher(&allocation_id, &allocation_signer, signer, receipts)
@@ -93,6 +209,16 @@ fn process_partial_voucher(signer: &SecretKey, payload: &Bytes) -> Result<JsonRe
         tracing::error!(excessive_voucher_fees = %partial_voucher.voucher.fees);
         return Err("Voucher value too large".into());
     }
+    vouchers
+        .ranges
+        .record_receipts(
+            allocation_id.into(),
+            Kind::Partial,
+            partial_voucher.receipt_id_min.into(),
+            partial_voucher.receipt_id_max.into(),
+        )
+        .await
+        .map_err(|err| err.to_string())?;
     Ok(json_response(
         [],
         json!({
@@ -106,11 +232,12 @@ fn process_partial_voucher(signer: &SecretKey, payload: &Bytes) -> Result<JsonRe
 }
 
 pub async fn handle_voucher(
-    State(signer): State<&'static SecretKey>,
+    State(vouchers): State<&'static Vouchers>,
+    headers: HeaderMap,
     payload: Bytes,
 ) -> Result<JsonResponse, (StatusCode, String)> {
     let _timer = METRICS.voucher.duration.start_timer();
-    match process_voucher(signer, &payload) {
+    match process_voucher(vouchers, &headers, &payload).await {
         Ok(response) => {
             METRICS.voucher.ok.inc();
             Ok(response)
@@ -123,10 +250,16 @@ pub async fn handle_voucher(
     }
 }
 
-fn process_voucher(signer: &SecretKey, payload: &Bytes) -> Result<JsonResponse, String> {
+async fn process_voucher(
+    vouchers: &Vouchers,
+    headers: &HeaderMap,
+    payload: &Bytes,
+) -> Result<JsonResponse, String> {
+    let signer = vouchers.signer()?;
     let request =
         serde_json::from_slice::<VoucherRequest>(payload).map_err(|err| err.to_string())?;
     let allocation_id = request.allocation;
+    vouchers.authenticate(allocation_id, headers, payload)?;
     let partial_vouchers = request
         .partial_vouchers
         .into_iter()
@@ -153,6 +286,15 @@ fn process_voucher(signer: &SecretKey, payload: &Bytes) -> Result<JsonResponse,
         tracing::error!(excessive_voucher_fees = %voucher.fees);
         return Err("Voucher value too large".into());
     }
+    let ranges: Vec<(ReceiptID, ReceiptID)> = partial_vouchers
+        .iter()
+        .map(|pv| (pv.receipt_id_min.into(), pv.receipt_id_max.into()))
+        .collect();
+    vouchers
+        .ranges
+        .record_partial_vouchers(allocation_id, &ranges)
+        .await
+        .map_err(|err| err.to_string())?;
     Ok(json_response(
         [],
         json!({
@@ -163,6 +305,42 @@ fn process_voucher(signer: &SecretKey, payload: &Bytes) -> Result<JsonResponse,
     ))
 }
 
+pub async fn handle_rav(
+    State(signer): State<&'static ReceiptSigner>,
+    payload: Bytes,
+) -> Result<JsonResponse, (StatusCode, String)> {
+    let _timer = METRICS.rav.duration.start_timer();
+    match process_rav(signer, &payload).await {
+        Ok(response) => {
+            METRICS.rav.ok.inc();
+            Ok(response)
+        }
+        Err(rav_err) => {
+            METRICS.rav.err.inc();
+            tracing::info!(%rav_err);
+            Err((StatusCode::BAD_REQUEST, rav_err))
+        }
+    }
+}
+
+async fn process_rav(signer: &ReceiptSigner, payload: &Bytes) -> Result<JsonResponse, String> {
+    let request = serde_json::from_slice::<RavRequest>(payload).map_err(|err| err.to_string())?;
+    let rav = signer
+        .aggregate_receipts(request.allocation, &request.receipts, request.previous_rav)
+        .await
+        .map_err(|err| err.to_string())?;
+    tracing::info!(
+        allocation = %request.allocation,
+        receipts = request.receipts.len(),
+        value_aggregate = %rav.message.value_aggregate,
+        "RAV request",
+    );
+    Ok(json_response(
+        [],
+        serde_json::to_value(rav).map_err(|err| err.to_string())?,
+    ))
+}
+
 fn parse_receipts(payload: &[u8]) -> Result<([u8; 20], &[u8]), String> {
     if payload.len() < 20 {
         return Err("Invalid request data".into());
@@ -172,6 +350,21 @@ fn parse_receipts(payload: &[u8]) -> Result<([u8; 20], &[u8]), String> {
     Ok((allocation_id, &payload[20..]))
 }
 
+/// The minimum and maximum receipt IDs of the receipts in a collect-receipts payload.
+fn receipt_id_range(receipts: &[u8]) -> Result<(ReceiptID, ReceiptID), String> {
+    if receipts.is_empty() || (receipts.len() % RECEIPT_SIZE != 0) {
+        return Err("Invalid request data".into());
+    }
+    let ids = receipts.chunks_exact(RECEIPT_SIZE).map(|receipt| {
+        ReceiptID::from_slice(
+            &receipt[RECEIPT_ID_OFFSET..(RECEIPT_ID_OFFSET + ReceiptID::len_bytes())],
+        )
+    });
+    let min = ids.clone().min().unwrap();
+    let max = ids.max().unwrap();
+    Ok((min, max))
+}
+
 #[derive(Deserialize)]
 #[serde(rename_all = "camelCase")]
 struct VoucherRequest {
@@ -187,5 +380,77 @@ struct PartialVoucher {
     receipt_id_max: ReceiptID,
 }
 
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RavRequest {
+    allocation: Address,
+    receipts: Vec<EIP712SignedMessage<TapReceipt>>,
+    #[serde(default)]
+    previous_rav: Option<EIP712SignedMessage<ReceiptAggregateVoucher>>,
+}
+
 type Signature = FixedBytes<65>;
-type ReceiptID = FixedBytes<15>;
+
+#[cfg(test)]
+mod tests {
+    use ethers::signers::{LocalWallet, Signer as _};
+
+    use super::*;
+
+    fn signed_headers(wallet: &LocalWallet, payload: &[u8]) -> HeaderMap {
+        let signature = wallet
+            .sign_hash(ethers::utils::hash_message(keccak256(payload)))
+            .unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            SIGNATURE_HEADER,
+            format!("0x{}", hex::encode(signature.to_vec()))
+                .parse()
+                .unwrap(),
+        );
+        headers
+    }
+
+    #[test]
+    fn authenticate_requests() {
+        //* Given
+        let allocation_key = LocalWallet::from_bytes(&[0x01; 32]).unwrap();
+        let allowlisted_key = LocalWallet::from_bytes(&[0x02; 32]).unwrap();
+        let other_key = LocalWallet::from_bytes(&[0x03; 32]).unwrap();
+        let signer = SecretKey::from_slice(&[0xcd; 32]).unwrap();
+        let vouchers = Vouchers::new(
+            Some(signer),
+            HashSet::from([Address::from(allowlisted_key.address().0)]),
+            VoucherRanges::default(),
+        );
+        let allocation = Address::from(allocation_key.address().0);
+        let payload = b"receipts";
+
+        //* When
+        let by_allocation_key = vouchers.authenticate(
+            allocation,
+            &signed_headers(&allocation_key, payload),
+            payload,
+        );
+        let by_allowlisted_key = vouchers.authenticate(
+            allocation,
+            &signed_headers(&allowlisted_key, payload),
+            payload,
+        );
+        let by_other_key =
+            vouchers.authenticate(allocation, &signed_headers(&other_key, payload), payload);
+        let tampered = vouchers.authenticate(
+            allocation,
+            &signed_headers(&allocation_key, payload),
+            b"other receipts",
+        );
+        let unsigned = vouchers.authenticate(allocation, &HeaderMap::new(), payload);
+
+        //* Then
+        assert!(by_allocation_key.is_ok());
+        assert!(by_allowlisted_key.is_ok());
+        assert!(by_other_key.is_err());
+        assert!(tampered.is_err());
+        assert!(unsigned.is_err());
+    }
+}
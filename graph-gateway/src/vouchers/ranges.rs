@@ -0,0 +1,260 @@
+//! Record of the receipt ID ranges vouchered per allocation, so that receipts can't be collected
+//! twice.
+//!
+//! Ranges are optionally persisted to an append-only file of newline-delimited JSON entries,
+//! replayed when opened. Unlike the receipt ledger, entries are written before the voucher is
+//! returned, since a lost entry would allow the receipts to be collected again after a restart.
+//! Writes are synced on a blocking thread, while the ranges stay locked so that concurrent requests
+//! can't voucher the same receipts.
+
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io::{BufRead as _, BufReader, Write as _},
+    path::Path,
+    sync::Arc,
+};
+
+use alloy_primitives::{Address, FixedBytes};
+use anyhow::Context as _;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+use crate::receipts::ledger::truncate_partial_entry;
+
+pub type ReceiptID = FixedBytes<15>;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Kind {
+    /// A partial voucher, to be combined into a voucher.
+    Partial,
+    /// A voucher, redeemable on-chain.
+    Voucher,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Entry {
+    pub allocation: Address,
+    pub kind: Kind,
+    pub receipt_id_min: ReceiptID,
+    pub receipt_id_max: ReceiptID,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Range {
+    kind: Kind,
+    min: ReceiptID,
+    max: ReceiptID,
+}
+
+impl Range {
+    fn overlaps(&self, min: &ReceiptID, max: &ReceiptID) -> bool {
+        (self.min <= *max) && (*min <= self.max)
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum RangeError {
+    #[error("receipts already vouchered in range {0}..={1}")]
+    AlreadyVouchered(ReceiptID, ReceiptID),
+    #[error("partial vouchers overlap")]
+    OverlappingPartialVouchers,
+    #[error("failed to record vouchered range: {0}")]
+    Storage(String),
+}
+
+#[derive(Default)]
+pub struct VoucherRanges {
+    ranges: Mutex<HashMap<Address, Vec<Range>>>,
+    file: Option<Arc<parking_lot::Mutex<File>>>,
+}
+
+impl VoucherRanges {
+    /// Open the ranges file, creating it if necessary, and replay it.
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let mut ranges: HashMap<Address, Vec<Range>> = HashMap::new();
+        if path.exists() {
+            // The voucher of a partial entry, left by a write interrupted by a crash, was never
+            // returned.
+            truncate_partial_entry(path)?;
+            for entry in read(path)? {
+                ranges.entry(entry.allocation).or_default().push(Range {
+                    kind: entry.kind,
+                    min: entry.receipt_id_min,
+                    max: entry.receipt_id_max,
+                });
+            }
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("failed to open {}", path.display()))?;
+        Ok(Self {
+            ranges: Mutex::new(ranges),
+            file: Some(Arc::new(parking_lot::Mutex::new(file))),
+        })
+    }
+
+    /// Record the receipt ID range of a voucher, or partial voucher, issued directly from
+    /// receipts. Fails if any receipt in the range has already been vouchered.
+    pub async fn record_receipts(
+        &self,
+        allocation: Address,
+        kind: Kind,
+        min: ReceiptID,
+        max: ReceiptID,
+    ) -> Result<(), RangeError> {
+        let mut ranges = self.ranges.lock().await;
+        let allocation_ranges = ranges.entry(allocation).or_default();
+        if let Some(range) = allocation_ranges.iter().find(|r| r.overlaps(&min, &max)) {
+            return Err(RangeError::AlreadyVouchered(range.min, range.max));
+        }
+        let range = Range { kind, min, max };
+        self.append(allocation, &[range]).await?;
+        allocation_ranges.push(range);
+        Ok(())
+    }
+
+    /// Record the receipt ID ranges of partial vouchers combined into a voucher. The ranges may
+    /// match previously issued partial vouchers, but must not overlap each other or any receipts
+    /// already included in a voucher.
+    pub async fn record_partial_vouchers(
+        &self,
+        allocation: Address,
+        partial_vouchers: &[(ReceiptID, ReceiptID)],
+    ) -> Result<(), RangeError> {
+        let mut sorted = partial_vouchers.to_vec();
+        sorted.sort();
+        if sorted.windows(2).any(|w| w[1].0 <= w[0].1) {
+            return Err(RangeError::OverlappingPartialVouchers);
+        }
+
+        let mut ranges = self.ranges.lock().await;
+        let allocation_ranges = ranges.entry(allocation).or_default();
+        for (min, max) in &sorted {
+            let vouchered = allocation_ranges
+                .iter()
+                .filter(|r| r.kind == Kind::Voucher)
+                .find(|r| r.overlaps(min, max));
+            if let Some(range) = vouchered {
+                return Err(RangeError::AlreadyVouchered(range.min, range.max));
+            }
+        }
+        let new_ranges: Vec<Range> = sorted
+            .into_iter()
+            .map(|(min, max)| Range {
+                kind: Kind::Voucher,
+                min,
+                max,
+            })
+            .collect();
+        self.append(allocation, &new_ranges).await?;
+        allocation_ranges.extend(new_ranges);
+        Ok(())
+    }
+
+    async fn append(&self, allocation: Address, ranges: &[Range]) -> Result<(), RangeError> {
+        let file = match &self.file {
+            Some(file) => file.clone(),
+            None => return Ok(()),
+        };
+        let mut lines = Vec::new();
+        for range in ranges {
+            let entry = Entry {
+                allocation,
+                kind: range.kind,
+                receipt_id_min: range.min,
+                receipt_id_max: range.max,
+            };
+            serde_json::to_writer(&mut lines, &entry)
+                .map_err(|err| RangeError::Storage(err.to_string()))?;
+            lines.push(b'\n');
+        }
+        tokio::task::spawn_blocking(move || {
+            let mut file = file.lock();
+            file.write_all(&lines).and_then(|_| file.sync_data())
+        })
+        .await
+        .map_err(|err| RangeError::Storage(err.to_string()))?
+        .map_err(|err| RangeError::Storage(err.to_string()))
+    }
+}
+
+/// Read all entries from the ranges file.
+pub fn read(path: &Path) -> anyhow::Result<Vec<Entry>> {
+    let file = File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+    let mut entries = Vec::new();
+    for (index, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let entry = serde_json::from_str(&line)
+            .with_context(|| format!("invalid entry on line {}", index + 1))?;
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(last_byte: u8) -> ReceiptID {
+        ReceiptID::with_last_byte(last_byte)
+    }
+
+    #[tokio::test]
+    async fn reject_overlapping_ranges() {
+        //* Given
+        let path = std::env::temp_dir().join(format!(
+            "gateway-voucher-ranges-{}.jsonl",
+            rand::random::<u64>()
+        ));
+        let allocation = Address::with_last_byte(1);
+        let ranges = VoucherRanges::open(&path).unwrap();
+
+        //* When
+        let partial_a = ranges
+            .record_receipts(allocation, Kind::Partial, id(1), id(10))
+            .await;
+        let partial_b = ranges
+            .record_receipts(allocation, Kind::Partial, id(11), id(20))
+            .await;
+        let recollected = ranges
+            .record_receipts(allocation, Kind::Partial, id(5), id(15))
+            .await;
+        let other_allocation = ranges
+            .record_receipts(Address::with_last_byte(2), Kind::Voucher, id(5), id(15))
+            .await;
+        let combined = ranges
+            .record_partial_vouchers(allocation, &[(id(11), id(20)), (id(1), id(10))])
+            .await;
+        drop(ranges);
+        let reopened = VoucherRanges::open(&path).unwrap();
+        let recombined = reopened
+            .record_partial_vouchers(allocation, &[(id(1), id(10))])
+            .await;
+        let overlapping_partials = reopened
+            .record_partial_vouchers(allocation, &[(id(30), id(40)), (id(35), id(45))])
+            .await;
+        std::fs::remove_file(&path).unwrap();
+
+        //* Then
+        assert!(partial_a.is_ok());
+        assert!(partial_b.is_ok());
+        assert!(matches!(
+            recollected,
+            Err(RangeError::AlreadyVouchered(min, max)) if (min == id(1)) && (max == id(10))
+        ));
+        assert!(other_allocation.is_ok());
+        assert!(combined.is_ok());
+        assert!(matches!(recombined, Err(RangeError::AlreadyVouchered(..))));
+        assert!(matches!(
+            overlapping_partials,
+            Err(RangeError::OverlappingPartialVouchers)
+        ));
+    }
+}